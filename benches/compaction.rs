@@ -0,0 +1,54 @@
+use criterion::*;
+use kvs::{KvStore, KvsEngine};
+use tempfile::TempDir;
+
+// Number of overwrites made after the live set is written. Keeps the stale data below the
+// automatic compaction threshold so that only the forced compaction is measured.
+const CHURN: usize = 4000;
+
+fn fill(store: &KvStore, live_keys: usize) {
+    store.clear().unwrap();
+    for i in 0..live_keys {
+        store.set(format!("key{}", i), "v".repeat(100)).unwrap();
+    }
+    for i in 0..CHURN {
+        store
+            .set(format!("key{}", i % live_keys), "s".repeat(100))
+            .unwrap();
+    }
+}
+
+// Bytes freed by compacting a freshly filled store
+fn reclaimed_bytes(live_keys: usize) -> u32 {
+    let temp = TempDir::new().expect("can't open tempdir");
+    let store = KvStore::open(temp.path()).expect("can't open kvs");
+    fill(&store, live_keys);
+    let before = store.disk_size().unwrap();
+    store.compact().unwrap();
+    (before - store.disk_size().unwrap()) as u32
+}
+
+fn compaction_bench(c: &mut Criterion) {
+    let temp = TempDir::new().expect("can't open tempdir");
+    let store = KvStore::open(temp.path()).expect("can't open kvs");
+
+    c.bench(
+        "compaction",
+        ParameterizedBenchmark::new(
+            "compact kvs",
+            move |b, &live_keys| {
+                b.iter_batched(
+                    || fill(&store, live_keys),
+                    |_| store.compact().expect("compaction failed"),
+                    BatchSize::PerIteration,
+                )
+            },
+            vec![100, 1000, 10000],
+        )
+        .throughput(|&live_keys| Throughput::Bytes(reclaimed_bytes(live_keys)))
+        .sample_size(10),
+    );
+}
+
+criterion_group!(benches, compaction_bench);
+criterion_main!(benches);
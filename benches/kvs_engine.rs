@@ -1,7 +1,11 @@
 use criterion::*;
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{
+    DurabilityMode, IndexBackend, KvStore, KvStoreOptions, KvsEngine, ReadMode, SledKvsEngine,
+};
 use rand::{distributions::Alphanumeric, rngs::StdRng, Rng, SeedableRng};
 use std::path::Path;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 
 static WRITE_SEED: u64 = 12345;
@@ -104,6 +108,176 @@ fn read_bench_kvs(c: &mut Criterion) {
     });
 }
 
+// Compares the two read modes on random reads from a store that's already filled
+fn read_mode_bench_kvs(c: &mut Criterion) {
+    let data = gen_read_data();
+    let modes = vec![ReadMode::Buffered, ReadMode::Mmap];
+
+    c.bench_function_over_inputs(
+        "random read kvs",
+        move |b, &mode| {
+            let temp = TempDir::new().expect("can't open tempdir");
+            let options = KvStoreOptions {
+                read_mode: mode,
+                ..Default::default()
+            };
+            let kvs = KvStore::open_with_options(temp.path(), options).expect("can't open kvs");
+            let write_data = data.iter().cloned().map(|s| (s.clone(), s)).collect();
+            write_loop(&kvs, write_data);
+
+            let mut rng: StdRng = SeedableRng::seed_from_u64(READ_SEED);
+            b.iter(|| {
+                let key = data[rng.gen_range(0, data.len())].clone();
+                kvs.get(key).expect("read failed")
+            })
+        },
+        modes,
+    );
+}
+
+// Sequential writes with and without space reserved for the log up front. Preallocation pays off
+// most on filesystems where appends fragment the file, which a fresh tempdir usually isn't.
+fn preallocate_write_bench_kvs(c: &mut Criterion) {
+    let data = gen_write_data();
+    let sizes = vec![None, Some(64 * 1024 * 1024)];
+
+    c.bench_function_over_inputs(
+        "preallocated write kvs",
+        move |b, &size| {
+            let temp = TempDir::new().expect("can't open tempdir");
+            let options = KvStoreOptions {
+                preallocate_bytes: size,
+                ..Default::default()
+            };
+            let kvs = KvStore::open_with_options(temp.path(), options).expect("can't open kvs");
+            b.iter_batched(
+                || data.clone(),
+                |data| write_loop(&kvs, data),
+                BatchSize::SmallInput,
+            )
+        },
+        sizes,
+    );
+}
+
+// Writes left to the OS to persist, or synced to disk one by one
+fn durability_write_bench_kvs(c: &mut Criterion) {
+    let data = gen_write_data();
+    let modes = vec![DurabilityMode::OsBuffered, DurabilityMode::Sync];
+
+    c.bench_function_over_inputs(
+        "durable write kvs",
+        move |b, &durability| {
+            let temp = TempDir::new().expect("can't open tempdir");
+            let options = KvStoreOptions {
+                durability,
+                ..Default::default()
+            };
+            let kvs = KvStore::open_with_options(temp.path(), options).expect("can't open kvs");
+            b.iter_batched(
+                || data.clone(),
+                |data| write_loop(&kvs, data),
+                BatchSize::SmallInput,
+            )
+        },
+        modes,
+    );
+}
+
+// Eight threads writing at once, with every write flushed on its own or with writes committed
+// in groups
+fn group_commit_bench_kvs(c: &mut Criterion) {
+    let data = gen_write_data();
+    let windows = vec![
+        None,
+        Some(Duration::from_micros(100)),
+        Some(Duration::from_millis(1)),
+    ];
+
+    c.bench_function_over_inputs(
+        "concurrent write kvs",
+        move |b, &window| {
+            let temp = TempDir::new().expect("can't open tempdir");
+            let options = KvStoreOptions {
+                group_commit_window: window,
+                ..Default::default()
+            };
+            let kvs = KvStore::open_with_options(temp.path(), options).expect("can't open kvs");
+            b.iter(|| {
+                thread::scope(|scope| {
+                    for chunk in data.chunks(data.len() / 8) {
+                        let kvs = kvs.clone();
+                        scope.spawn(move || write_loop(&kvs, chunk.to_vec()));
+                    }
+                })
+            })
+        },
+        windows,
+    );
+}
+
+// Four threads mixing reads and writes of keys that are already in the store, with the given
+// number of writes out of every ten operations, on each index backend
+fn index_backend_bench(c: &mut Criterion, name: &str, writes_per_ten: usize) {
+    let data = gen_write_data();
+    let backends = vec![IndexBackend::Evmap, IndexBackend::RwLock];
+
+    c.bench_function_over_inputs(
+        name,
+        move |b, &backend| {
+            let temp = TempDir::new().expect("can't open tempdir");
+            let options = KvStoreOptions {
+                index_backend: backend,
+                ..Default::default()
+            };
+            let kvs = KvStore::open_with_options(temp.path(), options).expect("can't open kvs");
+            write_loop(&kvs, data.clone());
+            b.iter(|| {
+                thread::scope(|scope| {
+                    for chunk in data.chunks(data.len() / 4) {
+                        let kvs = kvs.clone();
+                        scope.spawn(move || {
+                            for (i, (key, value)) in chunk.iter().enumerate() {
+                                if i % 10 < writes_per_ten {
+                                    kvs.set(key.clone(), value.clone()).expect("write failed");
+                                } else {
+                                    kvs.get(key.clone()).expect("read failed");
+                                }
+                            }
+                        });
+                    }
+                })
+            })
+        },
+        backends,
+    );
+}
+
+fn read_heavy_index_bench_kvs(c: &mut Criterion) {
+    index_backend_bench(c, "read-heavy index kvs", 1);
+}
+
+fn write_heavy_index_bench_kvs(c: &mut Criterion) {
+    index_backend_bench(c, "write-heavy index kvs", 9);
+}
+
+// Values big enough that decoding them dominates the read
+fn large_read_bench_kvs(c: &mut Criterion) {
+    let sizes = vec![64 * 1024, 1024 * 1024];
+
+    c.bench_function_over_inputs(
+        "large value read kvs",
+        |b, &size| {
+            let temp = TempDir::new().expect("can't open tempdir");
+            let kvs = new_kvs(temp.path());
+            kvs.set("key".to_owned(), "v".repeat(size))
+                .expect("write failed");
+            b.iter(|| kvs.get("key".to_owned()).expect("read failed"))
+        },
+        sizes,
+    );
+}
+
 fn read_bench_sled(c: &mut Criterion) {
     let data = gen_read_data();
     let temp = TempDir::new().expect("can't open tempdir");
@@ -129,6 +303,13 @@ criterion_group!(
     write_bench_kvs,
     write_bench_sled,
     read_bench_kvs,
+    read_mode_bench_kvs,
+    large_read_bench_kvs,
+    preallocate_write_bench_kvs,
+    durability_write_bench_kvs,
+    group_commit_bench_kvs,
+    read_heavy_index_bench_kvs,
+    write_heavy_index_bench_kvs,
     read_bench_sled
 );
 criterion_main!(benches);
@@ -0,0 +1,58 @@
+use criterion::*;
+use kvs::{KvStore, KvStoreOptions, KvsEngine, WriteOp};
+use std::collections::HashMap;
+use tempfile::TempDir;
+
+// Records written per write_batch call while building a log
+const CHUNK: usize = 10000;
+
+// Writes records to a new store that never compacts, so every record stays in the log. With churn,
+// the records only cover a tenth as many keys, so most of them are stale.
+fn build_log(records: usize, churn: bool) -> TempDir {
+    let temp = TempDir::new().expect("can't open tempdir");
+    let options = KvStoreOptions {
+        never_compact: true,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp.path(), options).expect("can't open kvs");
+    let keys = if churn { records / 10 } else { records };
+    for start in (0..records).step_by(CHUNK) {
+        let ops = (start..records.min(start + CHUNK))
+            .map(|i| WriteOp::Set(format!("key{}", i % keys), "v".repeat(32)))
+            .collect();
+        for result in store.write_batch(ops).expect("write failed") {
+            result.expect("set failed");
+        }
+    }
+    temp
+}
+
+// Opening rebuilds the index from the whole log, stale records included, so throughput counts
+// every record read rather than the live keys
+fn open_bench(c: &mut Criterion) {
+    let inputs: Vec<(usize, bool)> = [10_000, 100_000, 1_000_000]
+        .iter()
+        .flat_map(|&records| vec![(records, false), (records, true)])
+        .collect();
+    let logs: HashMap<_, _> = inputs
+        .iter()
+        .map(|&(records, churn)| ((records, churn), build_log(records, churn)))
+        .collect();
+
+    c.bench(
+        "open",
+        ParameterizedBenchmark::new(
+            "open kvs (records, churn)",
+            move |b, input| {
+                let dir = logs[input].path();
+                b.iter(|| KvStore::open(dir).expect("can't open kvs"))
+            },
+            inputs,
+        )
+        .throughput(|&(records, _)| Throughput::Elements(records as u32))
+        .sample_size(10),
+    );
+}
+
+criterion_group!(benches, open_bench);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+use criterion::*;
+use kvs::protocol::{Codec, Message, SET};
+use std::io::Cursor;
+
+// Number of SET requests in the batch
+const BATCH: usize = 1000;
+
+fn set_batch() -> Vec<Message> {
+    (0..BATCH)
+        .map(|i| Message::Array(vec![SET.to_owned(), format!("key{}", i), "v".repeat(100)]))
+        .collect()
+}
+
+fn encode(batch: &[Message], codec: Codec) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for msg in batch {
+        msg.write(&mut buf, codec).expect("encode failed");
+    }
+    buf
+}
+
+fn decode(buf: &[u8], codec: Codec) {
+    let mut reader = Cursor::new(buf);
+    for _ in 0..BATCH {
+        black_box(Message::read(&mut reader, codec).expect("decode failed"));
+    }
+}
+
+// Round trip of a set-heavy batch through each codec, measured in messages per second
+fn codec_bench(c: &mut Criterion) {
+    let batch = set_batch();
+    for &codec in &[Codec::Cbor, Codec::Bincode] {
+        let bytes = encode(&batch, codec).len();
+        println!("{:?}: {} bytes/message", codec, bytes / BATCH);
+    }
+
+    c.bench(
+        "protocol",
+        ParameterizedBenchmark::new(
+            "set batch round trip",
+            move |b, &codec| b.iter(|| decode(&encode(&batch, codec), codec)),
+            vec![Codec::Cbor, Codec::Bincode],
+        )
+        .throughput(|_| Throughput::Elements(BATCH as u32)),
+    );
+}
+
+criterion_group!(benches, codec_bench);
+criterion_main!(benches);
@@ -1,7 +1,7 @@
 use criterion::*;
 use crossbeam::sync::WaitGroup;
 use kvs::{
-    client::ThreadedKvsClient,
+    client::{KvsClient, ThreadedKvsClient},
     server::KvsServer,
     thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool},
     KvStore, KvsEngine, Result,
@@ -12,10 +12,6 @@ use std::path::Path;
 use std::thread::{spawn, JoinHandle};
 use tempfile::TempDir;
 
-fn tcp_addr() -> SocketAddr {
-    "127.0.0.1:4000".parse().unwrap()
-}
-
 fn gen_string(rng: &mut impl Rng) -> String {
     (0..1000).map(|_| rng.sample(Alphanumeric)).collect()
 }
@@ -37,19 +33,23 @@ fn new_kvs(path: &Path) -> KvStore {
 struct ServerHandle<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> {
     thread: JoinHandle<Result<()>>,
     server: KvsServer<E, P>,
+    addr: SocketAddr,
 }
 
 impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> ServerHandle<E, P> {
+    // Runs the server on a free port
     fn run(server: &KvsServer<E, P>) -> Self {
         let server_clone = server.clone();
         let bind_event = WaitGroup::new();
         let cloned_event = WaitGroup::clone(&bind_event);
-        let thread = spawn(move || server_clone.run(&tcp_addr(), Some(cloned_event)));
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let thread = spawn(move || server_clone.run(&addr, Some(cloned_event)));
         // Wait for server to finish binding so we don't get "connection refused"
         bind_event.wait();
         Self {
             server: server.clone(),
             thread,
+            addr: server.local_addr().expect("server didn't bind"),
         }
     }
 }
@@ -57,7 +57,7 @@ impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> ServerHandle<E, P> {
 impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> Drop for ServerHandle<E, P> {
     // Shuts down the server and joins the thread. This work is done outside the benchmark.
     fn drop(&mut self) {
-        self.server.shutdown(&tcp_addr()).expect("shutdown failed");
+        self.server.shutdown(&self.addr).expect("shutdown failed");
         // Replace server thread with zombie thread so we can check if server ran successfully
         let thread = std::mem::replace(&mut self.thread, spawn(move || Ok(())));
         // If server failed, just panic
@@ -79,11 +79,8 @@ fn write_threaded_kvstore<P: ThreadPool + Send + Sync + 'static>(c: &mut Criteri
             let kvs = new_kvs(&temp.path());
             let kvs_clone = kvs.clone();
             let server = KvsServer::<_, P>::new(kvs, threads).expect("server problem");
-            let client = ThreadedKvsClient::<P>::new(tcp_addr(), threads).expect("client problem");
-
-            // We only care about dropping this value
-            #[allow(unused)]
             let handle = ServerHandle::run(&server);
+            let client = ThreadedKvsClient::<P>::new(handle.addr, threads).expect("client problem");
 
             b.iter_batched(
                 || {
@@ -106,9 +103,91 @@ fn write_threaded_kvstore_rayon(c: &mut Criterion) {
     write_threaded_kvstore::<RayonThreadPool>(c, "write to KVS server with Rayon threadpool");
 }
 
+// One connection sending a batch of 100 sets, which a coalescing server writes with one flush
+fn write_batch_coalescing(c: &mut Criterion) {
+    let data = gen_data(99999);
+    // The routine runs for every sample, so both servers start once up front and keep running
+    // until the benchmark is done
+    let servers: Vec<_> = [false, true]
+        .iter()
+        .map(|&coalesce| {
+            let temp = TempDir::new().expect("can't open tempdir");
+            let kvs = new_kvs(temp.path());
+            let server =
+                KvsServer::<_, SharedQueueThreadPool>::new(kvs.clone(), 4).expect("server problem");
+            let server = if coalesce {
+                server.with_write_coalescing()
+            } else {
+                server
+            };
+            (temp, kvs, ServerHandle::run(&server))
+        })
+        .collect();
+
+    c.bench_function_over_inputs(
+        "write 100 set batch to KVS server",
+        move |b, &&coalesce| {
+            let (_, ref kvs, ref handle) = servers[coalesce as usize];
+            b.iter_batched(
+                || {
+                    kvs.clear().unwrap();
+                    data.clone()
+                },
+                |data| {
+                    let client = KvsClient::new(&handle.addr).expect("client problem");
+                    for key in client.set(data.into_iter()).expect("set failed") {
+                        key.expect("set failed");
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        },
+        &[false, true],
+    );
+}
+
+// Many connections reading at once, from a server that runs reads on its usual pool and from one
+// with a separate read pool
+fn read_many_connections(c: &mut Criterion) {
+    let data = gen_data(99999);
+    let keys: Vec<_> = data.iter().map(|(key, _)| key.clone()).collect();
+    let servers: Vec<_> = [false, true]
+        .iter()
+        .map(|&read_pool| {
+            let temp = TempDir::new().expect("can't open tempdir");
+            let kvs = new_kvs(temp.path());
+            for (key, value) in data.clone() {
+                kvs.set(key, value).expect("set failed");
+            }
+            let server =
+                KvsServer::<_, SharedQueueThreadPool>::new(kvs, 4).expect("server problem");
+            let server = if read_pool {
+                server.with_read_pool(4).expect("read pool problem")
+            } else {
+                server
+            };
+            let handle = ServerHandle::run(&server);
+            let client = ThreadedKvsClient::<SharedQueueThreadPool>::new(handle.addr, 16)
+                .expect("client problem");
+            (temp, handle, client)
+        })
+        .collect();
+
+    c.bench_function_over_inputs(
+        "read from KVS server over 16 connections",
+        move |b, &&read_pool| {
+            let (_, _, ref client) = servers[read_pool as usize];
+            b.iter(|| client.get(keys.clone(), |_| Ok(())).expect("get failed"))
+        },
+        &[false, true],
+    );
+}
+
 criterion_group!(
     benches,
     write_threaded_kvstore_rayon,
-    write_threaded_kvstore_queue
+    write_threaded_kvstore_queue,
+    write_batch_coalescing,
+    read_many_connections
 );
 criterion_main!(benches);
@@ -1,17 +1,40 @@
 use failure::ensure;
 use kvs::client::KvsClient;
-use kvs::Result;
+use kvs::{KeyNotFound, Result};
+use log::{debug, LevelFilter};
+use std::env;
+use std::io;
 use std::iter::once;
 use std::net::SocketAddr;
+use std::process::exit;
+use std::time::{Duration, UNIX_EPOCH};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
-enum Args {
+#[structopt(name = "kvs-client")]
+struct Args {
+    /// Most verbose level of logs to print: off, error, warn, info, debug, or trace
+    #[structopt(
+        name = "log-level",
+        long = "log-level",
+        default_value = "warn",
+        raw(global = "true")
+    )]
+    log_level: LevelFilter,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
     #[structopt(name = "get")]
     Get {
         key: String,
         #[structopt(name = "addr", long = "addr")]
         addr: Option<SocketAddr>,
+        /// Milliseconds to wait for the server to accept the connection
+        #[structopt(name = "timeout", long = "timeout", default_value = "1000")]
+        timeout: u64,
     },
 
     #[structopt(name = "set")]
@@ -20,6 +43,9 @@ enum Args {
         value: String,
         #[structopt(name = "addr", long = "addr")]
         addr: Option<SocketAddr>,
+        /// Milliseconds to wait for the server to accept the connection
+        #[structopt(name = "timeout", long = "timeout", default_value = "1000")]
+        timeout: u64,
     },
 
     #[structopt(name = "rm")]
@@ -27,19 +53,62 @@ enum Args {
         key: String,
         #[structopt(name = "addr", long = "addr")]
         addr: Option<SocketAddr>,
+        /// Milliseconds to wait for the server to accept the connection
+        #[structopt(name = "timeout", long = "timeout", default_value = "1000")]
+        timeout: u64,
     },
+
+    #[structopt(name = "status")]
+    Status {
+        #[structopt(name = "addr", long = "addr")]
+        addr: Option<SocketAddr>,
+        /// Milliseconds to wait for the server to accept the connection
+        #[structopt(name = "timeout", long = "timeout", default_value = "1000")]
+        timeout: u64,
+    },
+}
+
+// Verbosity that makes stderrlog print the given level and everything above it
+fn verbosity(level: LevelFilter) -> usize {
+    (level as usize).saturating_sub(1)
 }
 
 fn get_addr(addr: Option<SocketAddr>) -> SocketAddr {
     addr.unwrap_or("127.0.0.1:4000".parse().unwrap())
 }
 
+// Sends the token from KVS_TOKEN, for servers that require a secret. Exits with a short message
+// if the server can't be reached within the timeout, since that's almost always a server that
+// isn't running rather than something worth a full error.
+fn connect(addr: Option<SocketAddr>, timeout: u64) -> Result<KvsClient> {
+    ensure!(timeout > 0, "timeout must be positive");
+    let addr = get_addr(addr);
+    debug!("Connecting to {} with a timeout of {}ms", addr, timeout);
+    let client = match KvsClient::connect_timeout(&addr, Duration::from_millis(timeout)) {
+        Ok(client) => client,
+        Err(err) if err.downcast_ref::<io::Error>().is_some() => {
+            eprintln!("server not reachable at {}", addr);
+            exit(1);
+        }
+        Err(err) => return Err(err),
+    };
+    Ok(match env::var("KVS_TOKEN") {
+        Ok(token) => client.with_token(token),
+        Err(_) => client,
+    })
+}
+
 fn main() -> Result<()> {
     let args = Args::from_args();
+    stderrlog::new()
+        .module(module_path!())
+        .quiet(args.log_level == LevelFilter::Off)
+        .verbosity(verbosity(args.log_level))
+        .init()?;
 
-    match args {
-        Args::Get { key, addr } => {
-            let (k, value) = KvsClient::new(&get_addr(addr))?
+    match args.command {
+        Command::Get { key, addr, timeout } => {
+            let (k, value) = connect(addr, timeout)?
                 .get(once(key.clone()))?
                 .next()
                 .unwrap()?;
@@ -51,20 +120,46 @@ fn main() -> Result<()> {
             };
         }
 
-        Args::Set { key, value, addr } => {
-            let k = KvsClient::new(&get_addr(addr))?
+        Command::Set {
+            key,
+            value,
+            addr,
+            timeout,
+        } => {
+            let k = connect(addr, timeout)?
                 .set(once((key.clone(), value)))?
                 .next()
                 .unwrap()?;
             ensure!(k == key, "server returned unexpected key {}", k);
         }
 
-        Args::Remove { key, addr } => {
-            let k = KvsClient::new(&get_addr(addr))?
+        Command::Remove { key, addr, timeout } => {
+            let res = connect(addr, timeout)?
                 .remove(once(key.clone()))?
                 .next()
-                .unwrap()?;
-            ensure!(k == key, "server returned unexpected key {}", k);
+                .unwrap();
+
+            match res {
+                Ok(k) => ensure!(k == key, "server returned unexpected key {}", k),
+                // Removing a missing key is a normal user error, so report it without the noise
+                // of a full error
+                Err(ref err) if err.downcast_ref::<KeyNotFound>().is_some() => {
+                    eprintln!("{}", err);
+                    exit(1);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Command::Status { addr, timeout } => {
+            let status = connect(addr, timeout)?.status()?;
+            let started_at = status.started_at.duration_since(UNIX_EPOCH)?;
+            println!("uptime: {}s", status.uptime.as_secs());
+            println!("started at: {} (UNIX time)", started_at.as_secs());
+            let connections = status.connections;
+            println!("active connections: {}", connections.active);
+            println!("accepted connections: {}", connections.accepted);
+            println!("rejected connections: {}", connections.rejected);
         }
     };
 
@@ -1,14 +1,14 @@
-use failure::ensure;
-use kvs::server::KvsServer;
+use failure::{ensure, format_err};
+use kvs::server::{KvsServer, DEFAULT_MAX_BATCH_SIZE};
 use kvs::thread_pool::SharedQueueThreadPool;
-use kvs::{KvStore, Result, SledKvsEngine};
-use log::info;
+use kvs::{KvStore, KvsEngine, Result, SledKvsEngine};
+use log::{info, LevelFilter};
 use std::convert::{TryFrom, TryInto};
-use std::env::current_dir;
+use std::env::{self, current_dir};
 use std::fs;
 use std::io::ErrorKind;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use stderrlog;
 use structopt::StructOpt;
 
@@ -19,12 +19,26 @@ struct Args {
     addr: Option<SocketAddr>,
     #[structopt(long = "engine")]
     engine: Option<String>,
+    #[structopt(long = "data-dir", parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+    /// Most verbose level of logs to print: off, error, warn, info, debug, or trace
+    #[structopt(long = "log-level", default_value = "debug")]
+    log_level: LevelFilter,
+    /// Reject batches of more requests than this, which defaults to 100000
+    #[structopt(long = "max-batch-size")]
+    max_batch_size: Option<u32>,
 }
 
 struct Config {
     addr: SocketAddr,
     engine: String,
+    // Directory holding the engine's own files, separate from engine.txt
+    data_dir: PathBuf,
+    // Secret that clients must send, read from KVS_SECRET so it doesn't show up in process lists
+    secret: Option<String>,
     threads: u32,
+    log_level: LevelFilter,
+    max_batch_size: u32,
 }
 
 impl TryFrom<Args> for Config {
@@ -66,15 +80,31 @@ impl TryFrom<Args> for Config {
             },
         };
 
+        // Each engine gets its own subdirectory so their files never mix
+        let data_dir = match args.data_dir {
+            Some(dir) => dir,
+            None => current_dir()?.join("data"),
+        }
+        .join(&engine);
+
         // Use magic number 20 for thread count
         Ok(Config {
             addr,
             engine,
+            data_dir,
+            secret: env::var("KVS_SECRET").ok(),
             threads: 20,
+            log_level: args.log_level,
+            max_batch_size: args.max_batch_size.unwrap_or(DEFAULT_MAX_BATCH_SIZE),
         })
     }
 }
 
+// Verbosity that makes stderrlog print the given level and everything above it
+fn verbosity(level: LevelFilter) -> usize {
+    (level as usize).saturating_sub(1)
+}
+
 fn open_existing_file(path: &Path) -> Result<Option<String>> {
     match fs::read_to_string(path) {
         Ok(s) => Ok(Some(s)),
@@ -85,30 +115,97 @@ fn open_existing_file(path: &Path) -> Result<Option<String>> {
     }
 }
 
+// Files the engine kept right in the directory the server was started in, before each engine got
+// its own data directory
+fn legacy_files(dir: &Path, engine: &str) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+        let legacy = match engine {
+            "kvs" => {
+                (name.starts_with("kvs_") && name.ends_with(".cbor")) || name.starts_with("blob_")
+            }
+            _ => ["conf", "db", "blobs"].contains(&name) || name.starts_with("snap."),
+        };
+        if legacy {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+// Moves the engine's files from where an older server kept them into the data directory, so an
+// upgraded server doesn't come up with an empty store. If the data directory already has files
+// of its own there's no telling which are current, so that's left to the user.
+fn migrate_legacy_layout(config: &Config) -> Result<()> {
+    let dir = current_dir()?;
+    let files = legacy_files(&dir, &config.engine)?;
+    if files.is_empty() {
+        return Ok(());
+    }
+    ensure!(
+        fs::read_dir(&config.data_dir)?.next().is_none(),
+        "{} has {} files from an older kvs-server, but {} already has data; move one of them out \
+         of the way",
+        dir.display(),
+        config.engine,
+        config.data_dir.display()
+    );
+    for file in &files {
+        let dest = config.data_dir.join(file.file_name().unwrap());
+        fs::rename(file, &dest).map_err(|err| {
+            format_err!(
+                "couldn't move {} to {}: {}",
+                file.display(),
+                dest.display(),
+                err
+            )
+        })?;
+    }
+    info!(
+        "Moved {} files from an older kvs-server into {}",
+        files.len(),
+        config.data_dir.display()
+    );
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let args = Args::from_args();
     let config: Config = args.try_into()?;
 
     stderrlog::new()
         .module(module_path!())
-        .verbosity(3)
+        .quiet(config.log_level == LevelFilter::Off)
+        .verbosity(verbosity(config.log_level))
         .init()?;
 
     info!("Version {}", env!("CARGO_PKG_VERSION"));
     info!("Engine: {}", config.engine);
     info!("Socket Address: {}", config.addr);
+    info!("Data directory: {}", config.data_dir.display());
+    fs::create_dir_all(&config.data_dir)?;
+    migrate_legacy_layout(&config)?;
 
     match &config.engine[..] {
-        "kvs" => KvsServer::<_, SharedQueueThreadPool>::new(
-            KvStore::open(&current_dir()?)?,
-            config.threads,
-        )?
-        .run(&config.addr, None),
-        "sled" => KvsServer::<_, SharedQueueThreadPool>::new(
-            SledKvsEngine::open(&current_dir()?)?,
-            config.threads,
-        )?
-        .run(&config.addr, None),
+        "kvs" => run(KvStore::open(&config.data_dir)?, &config),
+        "sled" => run(SledKvsEngine::open(&config.data_dir)?, &config),
         _ => unreachable!(),
     }
 }
+
+fn run(engine: impl KvsEngine, config: &Config) -> Result<()> {
+    let mut server = KvsServer::<_, SharedQueueThreadPool>::new(engine, config.threads)?;
+    if let Some(ref secret) = config.secret {
+        info!("Requiring clients to authenticate");
+        server = server.with_secret(secret);
+    }
+    server = server.with_max_batch_size(config.max_batch_size)?;
+    server.run(&config.addr, None)?;
+    info!("Server summary: {:?}", server.summary());
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use failure::format_err;
+use kvs::{KvStore, KvsEngine, Result, SledKvsEngine};
+use log::{info, LevelFilter};
+use std::env::current_dir;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+// Works on the data of a kvs-server in the same directory, which must not be running at the same
+// time, since the two would write to the store independently
+#[derive(StructOpt)]
+#[structopt(name = "kvs")]
+struct Args {
+    /// Directory the server keeps its data in, which defaults to data in the current directory
+    #[structopt(long = "data-dir", parse(from_os_str), raw(global = "true"))]
+    data_dir: Option<PathBuf>,
+    /// Most verbose level of logs to print: off, error, warn, info, debug, or trace
+    #[structopt(long = "log-level", default_value = "warn", raw(global = "true"))]
+    log_level: LevelFilter,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Write every key and value in the store to a file as JSON lines
+    #[structopt(name = "dump")]
+    Dump {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+
+    /// Set every key and value in a file written by dump, leaving other keys alone
+    #[structopt(name = "load")]
+    Load {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Replace everything in the store, so it ends up holding exactly what's in the file. The
+        /// file is read in full first, and the kvs engine swaps in its contents in one step, so a
+        /// failed load leaves the store as it was
+        #[structopt(long = "replace")]
+        replace: bool,
+    },
+}
+
+// Verbosity that makes stderrlog print the given level and everything above it
+fn verbosity(level: LevelFilter) -> usize {
+    (level as usize).saturating_sub(1)
+}
+
+fn run(engine: impl KvsEngine, command: Command) -> Result<()> {
+    match command {
+        Command::Dump { file } => {
+            let count = engine.export_json(File::create(file)?)?;
+            println!("Dumped {} records", count);
+        }
+        Command::Load { file, replace } => {
+            let file = File::open(file)?;
+            let count = if replace {
+                engine.replace_json(file)?
+            } else {
+                engine.import_json(file)?
+            };
+            println!("Loaded {} records", count);
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::from_args();
+    stderrlog::new()
+        .module(module_path!())
+        .quiet(args.log_level == LevelFilter::Off)
+        .verbosity(verbosity(args.log_level))
+        .init()?;
+
+    // Same engine and directory that kvs-server would pick, without remembering the engine
+    let engine = match fs::read_to_string(current_dir()?.join("engine.txt")) {
+        Ok(engine) => engine,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => "kvs".to_owned(),
+        Err(err) => return Err(err.into()),
+    };
+    let data_dir = match args.data_dir {
+        Some(dir) => dir,
+        None => current_dir()?.join("data"),
+    }
+    .join(&engine);
+    info!("Engine: {}", engine);
+    info!("Data directory: {}", data_dir.display());
+    fs::create_dir_all(&data_dir)?;
+
+    match &engine[..] {
+        "kvs" => run(KvStore::open(&data_dir)?, args.command),
+        "sled" => run(SledKvsEngine::open(&data_dir)?, args.command),
+        engine => Err(format_err!("Unknown engine {} in engine.txt", engine)),
+    }
+}
@@ -1,6 +1,7 @@
 use crate::protocol::*;
 use crate::thread_pool::ThreadPool;
 use crate::Result;
+use crossbeam::channel::{unbounded, Receiver};
 use crossbeam::sync::WaitGroup;
 use failure::{ensure, format_err};
 use std::io::prelude::*;
@@ -8,6 +9,10 @@ use std::io::{BufReader, BufWriter};
 use std::iter::ExactSizeIterator;
 use std::net::{Shutdown, SocketAddr, TcpStream};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Outcome of a single GET request: the key along with its value, if it exists
+pub type GetResult = Result<(String, Option<String>)>;
 
 /// Client that sends TCP requests to KVS server.
 /// Holds the TCP stream for its entire lifetime.
@@ -15,79 +20,157 @@ pub struct KvsClient {
     // These should point to same address
     reader: BufReader<TcpStream>,
     writer: BufWriter<TcpStream>,
+    timeout: Option<Duration>,
+    // Deadline attached to every request in the batch being sent
+    deadline: Option<u64>,
+    token: Option<String>,
+    codec: Codec,
+    // Values of SET and SETNX requests at least this long are sent compressed
+    compress_above: Option<usize>,
 }
 
 impl KvsClient {
     /// Create a new client on an address
     pub fn new(addr: &SocketAddr) -> Result<Self> {
-        let stream = TcpStream::connect(addr)?;
+        Self::from_stream(TcpStream::connect(addr)?)
+    }
+
+    /// Same as new, but gives up on connecting after the timeout instead of waiting for the OS to
+    /// give up. Fails if the timeout is zero.
+    pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> Result<Self> {
+        Self::from_stream(TcpStream::connect_timeout(addr, timeout)?)
+    }
+
+    fn from_stream(stream: TcpStream) -> Result<Self> {
         let stream_clone = stream.try_clone()?;
 
         Ok(Self {
             reader: BufReader::new(stream),
             writer: BufWriter::new(stream_clone),
+            timeout: None,
+            deadline: None,
+            token: None,
+            codec: Codec::default(),
+            compress_above: None,
         })
     }
 
-    fn set_write(&mut self, key: String, value: String) -> Result<()> {
-        let req = Message::Array(vec![SET.to_owned(), key, value]);
-        req.write(&mut self.writer)?;
-        Ok(())
+    /// Send a secret token when connecting, for servers that require one
+    pub fn with_token(self, token: String) -> Self {
+        Self {
+            token: Some(token),
+            ..self
+        }
     }
 
-    fn read_key(&mut self) -> Result<String> {
-        let res = Message::read(&mut self.reader)?;
+    /// Encode requests and replies with the codec instead of CBOR. Only use codecs that the
+    /// server understands, since servers that predate codecs will misread every request.
+    pub fn with_codec(self, codec: Codec) -> Self {
+        Self { codec, ..self }
+    }
 
-        match res {
-            Message::Error(err) => Err(format_err!("Error: {}", err)),
-            Message::Array(mut arr) => {
-                ensure!(
-                    arr.len() == 1,
-                    "unexpected server output: {}",
-                    arr.join(" ")
-                );
+    /// Deflate the value of every SET and SETNX request whose value is at least threshold bytes
+    /// long, while shorter values are sent as they are. Each request says whether its value is
+    /// compressed, so one batch can mix both. Only use this with servers that understand
+    /// compressed requests.
+    pub fn with_compression(self, threshold: usize) -> Self {
+        Self {
+            compress_above: Some(threshold),
+            ..self
+        }
+    }
+
+    /// Give up on requests that take longer than the timeout. Reads and writes on the connection
+    /// fail once they block for longer than the timeout, and every request carries a deadline of
+    /// the timeout from when it was sent, so the server also skips requests that it gets to too
+    /// late. Those fail with DeadlineExceeded. Fails if the timeout is zero.
+    pub fn with_timeout(self, timeout: Duration) -> Result<Self> {
+        self.reader.get_ref().set_read_timeout(Some(timeout))?;
+        self.writer.get_ref().set_write_timeout(Some(timeout))?;
+        Ok(Self {
+            timeout: Some(timeout),
+            ..self
+        })
+    }
+
+    // Write a request, attaching the batch deadline if there is one and compressing its value if
+    // it's big enough
+    fn write_request(&mut self, arr: Vec<String>) -> Result<()> {
+        let compress = match (arr.first().map(|s| &s[..]), self.compress_above) {
+            (Some(SET), Some(threshold)) | (Some(SETNX), Some(threshold)) => {
+                arr.len() == 3 && arr[2].len() >= threshold
+            }
+            _ => false,
+        };
+        let req = match self.deadline {
+            _ if compress => Message::compress_value(self.deadline, arr)?,
+            Some(deadline) => Message::Deadline(deadline, arr),
+            None => Message::Array(arr),
+        };
+        req.write(&mut self.writer, self.codec)
+    }
 
-                Ok(arr.remove(0))
+    fn read_reply(&mut self) -> Result<Vec<String>> {
+        match Message::read(&mut self.reader, self.codec)? {
+            Message::Error(code, err) => Err(code.into_error(err)),
+            Message::Array(arr) => Ok(arr),
+            Message::Deadline(..) | Message::Compressed(..) => {
+                Err(format_err!("unexpected request from server"))
             }
         }
     }
 
-    fn get_write(&mut self, key: String) -> Result<()> {
-        let req = Message::Array(vec![GET.to_owned(), key]);
-        req.write(&mut self.writer)?;
-        Ok(())
+    fn set_write(&mut self, key: String, value: String) -> Result<()> {
+        self.write_request(vec![SET.to_owned(), key, value])
     }
 
-    fn read_pair(&mut self) -> Result<(String, Option<String>)> {
-        let res = Message::read(&mut self.reader)?;
+    fn read_key(&mut self) -> Result<String> {
+        let mut arr = self.read_reply()?;
+        ensure!(
+            arr.len() == 1,
+            "unexpected server output: {}",
+            arr.join(" ")
+        );
+
+        Ok(arr.remove(0))
+    }
 
-        match res {
-            Message::Error(err) => Err(format_err!("Error: {}", err)),
-            // Return value format for GET is [key] or [key, value]
-            Message::Array(mut arr) => {
-                ensure!(
-                    arr.len() == 1 || arr.len() == 2,
-                    "unexpected server output: {}",
-                    arr.join(" ")
-                );
+    fn get_write(&mut self, key: String) -> Result<()> {
+        self.write_request(vec![GET.to_owned(), key])
+    }
 
-                let key = arr.remove(0);
-                Ok((key, arr.pop()))
-            }
-        }
+    fn read_pair(&mut self) -> Result<(String, Option<String>)> {
+        // Return value format for GET is [key] or [key, value]
+        let mut arr = self.read_reply()?;
+        ensure!(
+            arr.len() == 1 || arr.len() == 2,
+            "unexpected server output: {}",
+            arr.join(" ")
+        );
+
+        let key = arr.remove(0);
+        Ok((key, arr.pop()))
     }
 
     fn remove_write(&mut self, key: String) -> Result<()> {
-        let req = Message::Array(vec![REMOVE.to_owned(), key]);
-        req.write(&mut self.writer)?;
-        Ok(())
+        self.write_request(vec![REMOVE.to_owned(), key])
     }
 
-    // Length is restricted to a single byte
-    // Write this to the start of every stream to tell server how many requests we are sending
-    fn write_length(&mut self, len: u8) -> Result<()> {
-        self.writer.write_all(&[len])?;
-        Ok(())
+    // Write this to the start of every stream to introduce the client and tell server how many
+    // requests we are sending. This also starts the clock on the batch's deadline.
+    fn write_length(&mut self, len: usize) -> Result<()> {
+        ensure!(
+            len <= u32::MAX as usize,
+            "batch of {} requests is too large",
+            len
+        );
+        self.deadline = self.timeout.map(deadline_after);
+        Handshake {
+            token: self.token.take(),
+            codec: self.codec,
+        }
+        .write(&mut self.writer)?;
+        write_batch_len(&mut self.writer, len as u32)
     }
 
     // Need to call this after all writes are done so that server actually receives data
@@ -106,7 +189,7 @@ impl KvsClient {
         kv_pairs: impl ExactSizeIterator<Item = (String, String)>,
     ) -> Result<impl Iterator<Item = Result<String>> + 'a> {
         let batch_size = kv_pairs.len();
-        self.write_length(batch_size as u8)?;
+        self.write_length(batch_size)?;
 
         for (key, value) in kv_pairs {
             self.set_write(key, value)?;
@@ -116,13 +199,29 @@ impl KvsClient {
         Ok((0..batch_size).map(move |_| self.read_key()))
     }
 
+    /// Send a batch of SET requests without waiting for or reading any replies, for writes where
+    /// latency matters more than knowing whether they worked. Returns once the whole batch is
+    /// sent, and the server keeps running it after the connection closes. Errors from the server,
+    /// such as a rejected token or a failed write, are silently dropped, so there is no way to
+    /// tell which writes landed.
+    pub fn set_nowait(
+        mut self,
+        kv_pairs: impl ExactSizeIterator<Item = (String, String)>,
+    ) -> Result<()> {
+        self.write_length(kv_pairs.len())?;
+        for (key, value) in kv_pairs {
+            self.set_write(key, value)?;
+        }
+        self.finish_writing()
+    }
+
     /// Send a GET request to the server. Key may not exist
     pub fn get<'a>(
         mut self,
         keys: impl ExactSizeIterator<Item = String>,
     ) -> Result<impl Iterator<Item = Result<(String, Option<String>)>> + 'a> {
         let batch_size = keys.len();
-        self.write_length(batch_size as u8)?;
+        self.write_length(batch_size)?;
 
         for key in keys {
             self.get_write(key)?;
@@ -138,7 +237,7 @@ impl KvsClient {
         keys: impl ExactSizeIterator<Item = String>,
     ) -> Result<impl Iterator<Item = Result<String>> + 'a> {
         let batch_size = keys.len();
-        self.write_length(batch_size as u8)?;
+        self.write_length(batch_size)?;
 
         for key in keys {
             self.remove_write(key)?;
@@ -147,29 +246,291 @@ impl KvsClient {
 
         Ok((0..batch_size).map(move |_| self.read_key()))
     }
+
+    /// Stream every key-value pair in the store over this connection, which is much faster than
+    /// getting each key separately. The iterator ends after the last pair or the first error.
+    pub fn dump<'a>(mut self) -> Result<impl Iterator<Item = Result<(String, String)>> + 'a> {
+        self.write_length(1)?;
+        self.write_request(vec![DUMP.to_owned()])?;
+        self.finish_writing()?;
+
+        let mut done = false;
+        Ok(std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let pair = self.read_reply().and_then(|mut arr| {
+                ensure!(
+                    arr.len() == 2 || arr.is_empty(),
+                    "unexpected server output: {}",
+                    arr.join(" ")
+                );
+                Ok(arr.pop().map(|value| (arr.remove(0), value)))
+            });
+
+            match pair {
+                Ok(Some(pair)) => Some(Ok(pair)),
+                // An empty array marks the end of the dump
+                Ok(None) => {
+                    done = true;
+                    None
+                }
+                Err(err) => {
+                    done = true;
+                    Some(Err(err))
+                }
+            }
+        }))
+    }
+
+    /// Fetch one page of a paginated scan, starting from the cursor of an earlier page or from
+    /// SCAN_START. Each page holds up to limit pairs in key order.
+    pub fn scan(mut self, cursor: &str, limit: usize) -> Result<ScanPage> {
+        self.write_length(1)?;
+        self.write_request(vec![SCAN.to_owned(), cursor.to_owned(), limit.to_string()])?;
+        self.finish_writing()?;
+        self.read_scan_page()
+    }
+
+    /// Fetch the next page of a scan, with the same limit as the page the cursor came from
+    pub fn scan_continue(mut self, cursor: &str) -> Result<ScanPage> {
+        self.write_length(1)?;
+        self.write_request(vec![SCAN_CONTINUE.to_owned(), cursor.to_owned()])?;
+        self.finish_writing()?;
+        self.read_scan_page()
+    }
+
+    /// Fetch every pair whose key starts with the prefix, in key order, in one round trip
+    pub fn get_prefix(mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.write_length(1)?;
+        self.write_request(vec![SCAN_PREFIX.to_owned(), prefix])?;
+        self.finish_writing()?;
+
+        // Return value format for SCAN_PREFIX is [key, value, key, value, ...]
+        let arr = self.read_reply()?;
+        ensure!(
+            arr.len() % 2 == 0,
+            "unexpected server output: {}",
+            arr.join(" ")
+        );
+        let mut arr = arr.into_iter();
+        let mut pairs = Vec::with_capacity(arr.len() / 2);
+        while let (Some(key), Some(value)) = (arr.next(), arr.next()) {
+            pairs.push((key, value));
+        }
+        Ok(pairs)
+    }
+
+    fn read_scan_page(&mut self) -> Result<ScanPage> {
+        // Return value format for SCAN is [cursor, key, value, key, value, ...]
+        let arr = self.read_reply()?;
+        ensure!(
+            arr.len() % 2 == 1,
+            "unexpected server output: {}",
+            arr.join(" ")
+        );
+
+        let mut arr = arr.into_iter();
+        let cursor = arr.next().filter(|cursor| cursor != SCAN_START);
+        let mut pairs = Vec::with_capacity(arr.len() / 2);
+        while let (Some(key), Some(value)) = (arr.next(), arr.next()) {
+            pairs.push((key, value));
+        }
+        Ok(ScanPage { pairs, cursor })
+    }
+
+    /// Set a key only if it doesn't exist yet, returning whether it was set. Out of many
+    /// concurrent calls for the same key, exactly one succeeds.
+    pub fn set_nx(mut self, key: String, value: String) -> Result<bool> {
+        self.write_length(1)?;
+        self.write_request(vec![SETNX.to_owned(), key, value])?;
+        self.finish_writing()?;
+
+        // Return value format for SETNX is [key, "1"] or [key, "0"]
+        let arr = self.read_reply()?;
+        match arr.get(1).map(|s| &s[..]) {
+            Some("1") if arr.len() == 2 => Ok(true),
+            Some("0") if arr.len() == 2 => Ok(false),
+            _ => Err(format_err!("unexpected server output: {}", arr.join(" "))),
+        }
+    }
+
+    /// Get the value of a key only if its version is above the given version, such as the
+    /// version of a value cached by an earlier call. Version 0 always gets the value.
+    pub fn get_if_newer(mut self, key: String, version: u64) -> Result<IfNewer> {
+        self.write_length(1)?;
+        self.write_request(vec![GET_IF_NEWER.to_owned(), key, version.to_string()])?;
+        self.finish_writing()?;
+
+        // Return value format for GET_IF_NEWER is [key], [key, version] or [key, version, value]
+        let arr = self.read_reply()?;
+        ensure!(
+            !arr.is_empty() && arr.len() <= 3,
+            "unexpected server output: {}",
+            arr.join(" ")
+        );
+        let mut arr = arr.into_iter().skip(1);
+        Ok(match (arr.next(), arr.next()) {
+            (None, _) => IfNewer::Missing,
+            (Some(version), None) => IfNewer::NotModified(version.parse()?),
+            (Some(version), Some(value)) => IfNewer::Modified(value, version.parse()?),
+        })
+    }
+
+    /// Ask the server how long it has been running
+    pub fn status(mut self) -> Result<ServerStatus> {
+        self.write_length(1)?;
+        self.write_request(vec![STATUS.to_owned()])?;
+        self.finish_writing()?;
+
+        ServerStatus::from_reply(&self.read_reply()?)
+    }
+}
+
+/// Reply to a GET_IF_NEWER request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IfNewer {
+    /// The key doesn't exist
+    Missing,
+    /// The value hasn't changed since the given version, and is now at this version
+    NotModified(u64),
+    /// The value and its version, which is above the given version
+    Modified(String, u64),
 }
 
+/// One page of a paginated scan
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanPage {
+    /// Key-value pairs in the page, in key order
+    pub pairs: Vec<(String, String)>,
+    /// Cursor for fetching the next page, or None if this was the last one
+    pub cursor: Option<String>,
+}
+
+/// Incrementally builds a batch of set, get, and remove requests that are all sent over one
+/// connection. Sending does not consume the builder, so a batch can be reused.
+#[derive(Debug, Clone, Default)]
+pub struct BatchBuilder {
+    requests: Vec<Vec<String>>,
+}
+
+impl BatchBuilder {
+    /// Create an empty batch
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a SET request to the batch
+    pub fn push_set(&mut self, key: String, value: String) -> &mut Self {
+        self.requests.push(vec![SET.to_owned(), key, value]);
+        self
+    }
+
+    /// Add a GET request to the batch
+    pub fn push_get(&mut self, key: String) -> &mut Self {
+        self.requests.push(vec![GET.to_owned(), key]);
+        self
+    }
+
+    /// Add a REMOVE request to the batch
+    pub fn push_remove(&mut self, key: String) -> &mut Self {
+        self.requests.push(vec![REMOVE.to_owned(), key]);
+        self
+    }
+
+    /// Number of requests in the batch
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Whether the batch has no requests
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Send the batch using a client. Returns one response per request, in the order the server
+    /// finished them. Each response is the request's key, along with the value for successful GET
+    /// requests.
+    pub fn send(
+        &self,
+        mut client: KvsClient,
+    ) -> Result<impl Iterator<Item = Result<(String, Option<String>)>>> {
+        let batch_size = self.requests.len();
+        client.write_length(batch_size)?;
+
+        for req in &self.requests {
+            client.write_request(req.clone())?;
+        }
+        client.finish_writing()?;
+
+        Ok((0..batch_size).map(move |_| client.read_pair()))
+    }
+}
+
+fn connect(addr: &SocketAddr, token: Option<String>) -> Result<KvsClient> {
+    let client = KvsClient::new(addr)?;
+    Ok(match token {
+        Some(token) => client.with_token(token),
+        None => client,
+    })
+}
+
+/// Most requests a ThreadedKvsClient sends over one connection, unless configured otherwise
+pub const DEFAULT_CHUNK_SIZE: usize = 10_000;
+
 /// Uses a threadpool to send multiple set or get requests
 pub struct ThreadedKvsClient<P: ThreadPool> {
     addr: SocketAddr,
     pool: P,
     threads: u32,
+    token: Option<String>,
+    chunk_size: usize,
+}
+
+// Splits a thread's share of the requests into the batches sent over each connection
+fn chunks<T>(requests: Vec<T>, chunk_size: usize) -> Vec<Vec<T>> {
+    let mut requests = requests.into_iter().peekable();
+    let mut chunks = Vec::new();
+    while requests.peek().is_some() {
+        chunks.push(requests.by_ref().take(chunk_size).collect());
+    }
+    chunks
 }
 
 impl<P: ThreadPool> ThreadedKvsClient<P> {
-    /// Create a new client on an address
+    /// Create a new client on an address. Fails if threads is zero.
     pub fn new(addr: SocketAddr, threads: u32) -> Result<Self> {
+        ensure!(threads > 0, "client needs at least one thread");
         Ok(Self {
             addr,
             pool: P::new(threads)?,
             threads,
+            token: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
         })
     }
 
+    /// Send at most chunk_size requests per connection. Each thread sends its share of a large
+    /// call as several batches one after another, which bounds the memory a batch takes up on
+    /// both ends. Fails if the chunk size is zero.
+    pub fn with_chunk_size(self, chunk_size: usize) -> Result<Self> {
+        ensure!(chunk_size > 0, "chunk size must be positive");
+        Ok(Self { chunk_size, ..self })
+    }
+
+    /// Send a secret token on every connection, for servers that require one
+    pub fn with_token(self, token: String) -> Self {
+        Self {
+            token: Some(token),
+            ..self
+        }
+    }
+
     // Returns amount of requests to be batched in each thread
     fn divide_work(&self, num_requests: usize) -> Vec<usize> {
         let threads = self.threads as usize;
-        // Don't worry about overflow for now
+        // new makes sure threads isn't zero, and no share is more than num_requests / threads + 1,
+        // which can't overflow since it's at most num_requests
         let per_thread = num_requests / threads;
         let mut remainder = num_requests % threads;
 
@@ -200,22 +561,26 @@ impl<P: ThreadPool> ThreadedKvsClient<P> {
             let batch: Vec<_> = kv_pairs.by_ref().take(batch_size).collect();
             let result = Arc::clone(&result);
             let wg = wg.clone();
-            let addr = self.addr.clone();
+            let addr = self.addr;
+            let token = self.token.clone();
+            let chunk_size = self.chunk_size;
 
             // Instead of panicking, all errors are sent to the outer result so we can track them
             // from the main thread
             self.pool.spawn(move || {
-                let res = (|| {
-                    let client = KvsClient::new(&addr)?;
-                    client.set(batch.into_iter())
-                })();
-
-                match res {
-                    Err(err) => *result.lock().unwrap() = Err(err),
-                    Ok(response) => {
-                        // If we get any error responses, it's an error
-                        if let Some(err) = response.into_iter().filter_map(Result::err).next() {
-                            *result.lock().unwrap() = Err(err);
+                for chunk in chunks(batch, chunk_size) {
+                    let res = (|| {
+                        let client = connect(&addr, token.clone())?;
+                        client.set(chunk.into_iter())
+                    })();
+
+                    match res {
+                        Err(err) => *result.lock().unwrap() = Err(err),
+                        Ok(response) => {
+                            // If we get any error responses, it's an error
+                            if let Some(err) = response.into_iter().filter_map(Result::err).next() {
+                                *result.lock().unwrap() = Err(err);
+                            }
                         }
                     }
                 }
@@ -231,6 +596,51 @@ impl<P: ThreadPool> ThreadedKvsClient<P> {
         std::mem::replace(&mut *result, Ok(()))
     }
 
+    /// Remove multiple keys concurrently. Blocks until all requests are done and returns Error if
+    /// any operations failed, including removals of keys that don't exist.
+    pub fn remove(&self, keys: Vec<String>) -> Result<()> {
+        let wg = WaitGroup::new();
+        let result = Arc::new(Mutex::new(Ok(())));
+
+        let distribution = self.divide_work(keys.len());
+        assert_eq!(distribution.iter().sum::<usize>(), keys.len());
+        let mut keys = keys.into_iter();
+
+        for batch_size in distribution {
+            let batch: Vec<_> = keys.by_ref().take(batch_size).collect();
+            let result = Arc::clone(&result);
+            let wg = wg.clone();
+            let addr = self.addr;
+            let token = self.token.clone();
+            let chunk_size = self.chunk_size;
+
+            self.pool.spawn(move || {
+                for chunk in chunks(batch, chunk_size) {
+                    let res = (|| {
+                        let client = connect(&addr, token.clone())?;
+                        client.remove(chunk.into_iter())
+                    })();
+
+                    match res {
+                        Err(err) => *result.lock().unwrap() = Err(err),
+                        Ok(response) => {
+                            if let Some(err) = response.into_iter().filter_map(Result::err).next() {
+                                *result.lock().unwrap() = Err(err);
+                            }
+                        }
+                    }
+                }
+
+                drop(wg);
+            })
+        }
+
+        wg.wait();
+
+        let mut result = result.lock().unwrap();
+        std::mem::replace(&mut *result, Ok(()))
+    }
+
     /// Get multiple keys concurrently. Blocks until all requests are done and returns Error is any
     /// operations failed. Instead of returning the values the method takes a fallible handler
     /// closure that processes each retrieved value concurrently.
@@ -251,26 +661,30 @@ impl<P: ThreadPool> ThreadedKvsClient<P> {
             let batch: Vec<_> = keys.by_ref().take(batch_size).collect();
             let result = Arc::clone(&result);
             let wg = wg.clone();
-            let addr = self.addr.clone();
+            let addr = self.addr;
+            let token = self.token.clone();
+            let chunk_size = self.chunk_size;
             let mut handler = handler.clone();
 
             // Again, no panicking
             self.pool.spawn(move || {
-                let handler_result = (|| {
-                    let client = KvsClient::new(&addr)?;
-                    client.get(batch.into_iter())
-                })();
-
-                match handler_result {
-                    Err(err) => *result.lock().unwrap() = Err(err),
-                    Ok(response) => {
-                        if let Some(err) = response
-                            .into_iter()
-                            .map(|r| r.and_then(&mut handler))
-                            .filter_map(Result::err)
-                            .next()
-                        {
-                            *result.lock().unwrap() = Err(err);
+                for chunk in chunks(batch, chunk_size) {
+                    let handler_result = (|| {
+                        let client = connect(&addr, token.clone())?;
+                        client.get(chunk.into_iter())
+                    })();
+
+                    match handler_result {
+                        Err(err) => *result.lock().unwrap() = Err(err),
+                        Ok(response) => {
+                            if let Some(err) = response
+                                .into_iter()
+                                .map(|r| r.and_then(&mut handler))
+                                .filter_map(Result::err)
+                                .next()
+                            {
+                                *result.lock().unwrap() = Err(err);
+                            }
                         }
                     }
                 }
@@ -284,4 +698,52 @@ impl<P: ThreadPool> ThreadedKvsClient<P> {
         let mut result = result.lock().unwrap();
         std::mem::replace(&mut *result, Ok(()))
     }
+
+    /// Get multiple keys concurrently and stream the results back to the calling thread. Results
+    /// are sent in the order the requests complete, not the order of the keys. The receiver is
+    /// disconnected once every result has been sent. Connection failures show up as errors in the
+    /// stream.
+    pub fn get_stream(&self, keys: Vec<String>) -> Result<Receiver<GetResult>> {
+        let (sender, receiver) = unbounded();
+
+        let distribution = self.divide_work(keys.len());
+        assert_eq!(distribution.iter().sum::<usize>(), keys.len());
+        let mut keys = keys.into_iter();
+
+        for batch_size in distribution {
+            let batch: Vec<_> = keys.by_ref().take(batch_size).collect();
+            let sender = sender.clone();
+            let addr = self.addr;
+            let token = self.token.clone();
+            let chunk_size = self.chunk_size;
+
+            self.pool.spawn(move || {
+                for chunk in chunks(batch, chunk_size) {
+                    let response = (|| {
+                        let client = connect(&addr, token.clone())?;
+                        client.get(chunk.into_iter())
+                    })();
+
+                    // Sending only fails if the receiver was dropped, in which case nobody cares
+                    // about the results anymore
+                    match response {
+                        Err(err) => {
+                            if sender.send(Err(err)).is_err() {
+                                return;
+                            }
+                        }
+                        Ok(response) => {
+                            for res in response {
+                                if sender.send(res).is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                }
+            });
+        }
+
+        Ok(receiver)
+    }
 }
@@ -0,0 +1,351 @@
+use crate::{IndexBackend, Range};
+use std::collections::{BinaryHeap, HashMap};
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock};
+
+// Rough heap cost of one index entry apart from the key's bytes: the key's String, the offsets
+// stored inline, and the hash table's control byte and spare capacity
+const INDEX_ENTRY_OVERHEAD: usize = 64;
+
+// Write side of the in-memory index, which maps every live key to where its value is in the log,
+// along with the generation of that log. Changes only become visible, to reads and to the writer
+// alike, once refresh is called, and all changes made since the last refresh become visible at
+// once.
+pub(crate) trait IndexWriter: Send {
+    fn generation(&self) -> u64;
+    fn get(&self, key: &str) -> Option<Range>;
+    fn len(&self) -> usize;
+    // Snapshot of every entry
+    fn entries(&self) -> Vec<(String, Range)>;
+
+    fn insert(&mut self, key: String, range: Range);
+    fn remove(&mut self, key: String);
+    // Removes every entry
+    fn purge(&mut self);
+    fn set_generation(&mut self, gen: u64);
+    fn refresh(&mut self);
+}
+
+// Read side of the index. Each handle is only used by one thread at a time, and clones are how
+// other threads get their own handle.
+pub(crate) trait IndexReader: Send {
+    fn generation(&self) -> u64;
+    // Looks up a key along with the generation, both from the same refresh
+    fn get_with_generation(&self, key: &str) -> (Option<Range>, u64);
+    fn contains_key(&self, key: &str) -> bool;
+    fn len(&self) -> usize;
+    // Snapshot of every key
+    fn keys(&self) -> Vec<String>;
+    // Snapshot of the limit smallest keys that sort after the given key, in order
+    fn smallest_keys_after(&self, after: Option<&str>, limit: usize) -> Vec<String>;
+    // Estimate of the heap memory used by the index, see KvStore::index_memory_bytes
+    fn memory_bytes(&self) -> usize;
+    fn boxed_clone(&self) -> Box<dyn IndexReader>;
+}
+
+impl Clone for Box<dyn IndexReader> {
+    fn clone(&self) -> Self {
+        self.boxed_clone()
+    }
+}
+
+// Picks out the limit smallest keys that sort after a key from keys offered in any order. The
+// largest of them sits on top of a heap so it can be swapped out for a smaller one, which takes
+// log(limit) time per key instead of sorting every key.
+struct SmallestKeys<'a> {
+    after: Option<&'a str>,
+    limit: usize,
+    heap: BinaryHeap<String>,
+}
+
+impl<'a> SmallestKeys<'a> {
+    fn new(after: Option<&'a str>, limit: usize) -> Self {
+        Self {
+            after,
+            limit,
+            heap: BinaryHeap::with_capacity(limit),
+        }
+    }
+
+    fn offer(&mut self, key: &str) {
+        if self.after.is_some_and(|after| key <= after) {
+            return;
+        }
+        if self.heap.len() < self.limit {
+            self.heap.push(key.to_owned());
+        } else if let Some(mut largest) = self.heap.peek_mut() {
+            if key < &largest[..] {
+                *largest = key.to_owned();
+            }
+        }
+    }
+
+    fn into_sorted_vec(self) -> Vec<String> {
+        self.heap.into_sorted_vec()
+    }
+}
+
+// Creates an empty index for a log of the given generation
+pub(crate) fn new_index(
+    backend: IndexBackend,
+    gen: u64,
+) -> (Box<dyn IndexWriter>, Box<dyn IndexReader>) {
+    match backend {
+        IndexBackend::Evmap => {
+            let (reader, writer) = evmap::with_meta(gen);
+            (Box::new(writer), Box::new(PooledIndex::new(reader)))
+        }
+        IndexBackend::RwLock => {
+            let map = Arc::new(RwLock::new(LockedMap {
+                map: HashMap::new(),
+                gen,
+            }));
+            let writer = LockedWriter {
+                map: Arc::clone(&map),
+                pending: Vec::new(),
+            };
+            (Box::new(writer), Box::new(LockedReader(map)))
+        }
+    }
+}
+
+type EvmapWriter = evmap::WriteHandle<String, (u64, u64), u64>;
+type EvmapReader = evmap::ReadHandle<String, (u64, u64), u64>;
+
+impl IndexWriter for EvmapWriter {
+    fn generation(&self) -> u64 {
+        self.meta().unwrap()
+    }
+
+    fn get(&self, key: &str) -> Option<Range> {
+        self.get_and(key, |v| Range::new(v[0]))
+    }
+
+    fn len(&self) -> usize {
+        EvmapReader::len(self)
+    }
+
+    fn entries(&self) -> Vec<(String, Range)> {
+        self.map_into(|k, v| (k.to_owned(), Range::new(v[0])))
+    }
+
+    fn insert(&mut self, key: String, range: Range) {
+        self.update(key, (range.start, range.end));
+    }
+
+    fn remove(&mut self, key: String) {
+        self.empty(key);
+    }
+
+    fn purge(&mut self) {
+        EvmapWriter::purge(self);
+    }
+
+    fn set_generation(&mut self, gen: u64) {
+        self.set_meta(gen);
+    }
+
+    fn refresh(&mut self) {
+        EvmapWriter::refresh(self);
+    }
+}
+
+// Read handle to the index that goes back to a shared pool when dropped. evmap keeps every read
+// handle ever created and checks all of them on each write, so cloning the store for every
+// request would make writes slower and slower. Clones reuse pooled handles instead, which caps
+// the handles at the most clones alive at once.
+struct PooledIndex {
+    handle: Option<EvmapReader>,
+    pool: Arc<Mutex<Vec<EvmapReader>>>,
+}
+
+impl PooledIndex {
+    fn new(handle: EvmapReader) -> Self {
+        Self {
+            handle: Some(handle),
+            pool: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn handle(&self) -> &EvmapReader {
+        self.handle.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledIndex {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.pool.lock().unwrap().push(handle);
+        }
+    }
+}
+
+impl IndexReader for PooledIndex {
+    fn generation(&self) -> u64 {
+        self.handle().meta().unwrap()
+    }
+
+    fn get_with_generation(&self, key: &str) -> (Option<Range>, u64) {
+        self.handle()
+            .meta_get_and(key, |v| Range::new(v[0]))
+            .unwrap()
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        self.handle().contains_key(key)
+    }
+
+    fn len(&self) -> usize {
+        self.handle().len()
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.handle().map_into(|k, _| k.clone())
+    }
+
+    fn smallest_keys_after(&self, after: Option<&str>, limit: usize) -> Vec<String> {
+        let mut smallest = SmallestKeys::new(after, limit);
+        self.handle().for_each(|key, _| smallest.offer(key));
+        smallest.into_sorted_vec()
+    }
+
+    // Doubled because evmap keeps two copies of the map so reads never wait for writes
+    fn memory_bytes(&self) -> usize {
+        let mut bytes = 0;
+        self.handle()
+            .for_each(|key, _| bytes += key.len() + INDEX_ENTRY_OVERHEAD);
+        bytes * 2
+    }
+
+    fn boxed_clone(&self) -> Box<dyn IndexReader> {
+        let handle = self.pool.lock().unwrap().pop();
+        Box::new(Self {
+            handle: Some(handle.unwrap_or_else(|| self.handle().clone())),
+            pool: Arc::clone(&self.pool),
+        })
+    }
+}
+
+// Single map behind a lock, which reads share and refreshes take over
+struct LockedMap {
+    map: HashMap<String, Range>,
+    gen: u64,
+}
+
+// Change made by the writer that isn't visible yet
+enum Change {
+    Insert(String, Range),
+    Remove(String),
+    Purge,
+    SetGeneration(u64),
+}
+
+// Holds on to changes until a refresh applies all of them under one write lock, which keeps
+// reads from seeing half of a batch, just like evmap does
+struct LockedWriter {
+    map: Arc<RwLock<LockedMap>>,
+    pending: Vec<Change>,
+}
+
+impl IndexWriter for LockedWriter {
+    fn generation(&self) -> u64 {
+        self.map.read().unwrap().gen
+    }
+
+    fn get(&self, key: &str) -> Option<Range> {
+        self.map.read().unwrap().map.get(key).cloned()
+    }
+
+    fn len(&self) -> usize {
+        self.map.read().unwrap().map.len()
+    }
+
+    fn entries(&self) -> Vec<(String, Range)> {
+        let map = self.map.read().unwrap();
+        map.map
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    fn insert(&mut self, key: String, range: Range) {
+        self.pending.push(Change::Insert(key, range));
+    }
+
+    fn remove(&mut self, key: String) {
+        self.pending.push(Change::Remove(key));
+    }
+
+    fn purge(&mut self) {
+        self.pending.push(Change::Purge);
+    }
+
+    fn set_generation(&mut self, gen: u64) {
+        self.pending.push(Change::SetGeneration(gen));
+    }
+
+    fn refresh(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let mut map = self.map.write().unwrap();
+        for change in mem::take(&mut self.pending) {
+            match change {
+                Change::Insert(key, range) => {
+                    map.map.insert(key, range);
+                }
+                Change::Remove(key) => {
+                    map.map.remove(&key);
+                }
+                Change::Purge => map.map.clear(),
+                Change::SetGeneration(gen) => map.gen = gen,
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct LockedReader(Arc<RwLock<LockedMap>>);
+
+impl IndexReader for LockedReader {
+    fn generation(&self) -> u64 {
+        self.0.read().unwrap().gen
+    }
+
+    fn get_with_generation(&self, key: &str) -> (Option<Range>, u64) {
+        let map = self.0.read().unwrap();
+        (map.map.get(key).cloned(), map.gen)
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        self.0.read().unwrap().map.contains_key(key)
+    }
+
+    fn len(&self) -> usize {
+        self.0.read().unwrap().map.len()
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.0.read().unwrap().map.keys().cloned().collect()
+    }
+
+    fn smallest_keys_after(&self, after: Option<&str>, limit: usize) -> Vec<String> {
+        let mut smallest = SmallestKeys::new(after, limit);
+        for key in self.0.read().unwrap().map.keys() {
+            smallest.offer(key);
+        }
+        smallest.into_sorted_vec()
+    }
+
+    fn memory_bytes(&self) -> usize {
+        let map = self.0.read().unwrap();
+        map.map
+            .keys()
+            .map(|key| key.len() + INDEX_ENTRY_OVERHEAD)
+            .sum()
+    }
+
+    fn boxed_clone(&self) -> Box<dyn IndexReader> {
+        Box::new(self.clone())
+    }
+}
@@ -1,29 +1,57 @@
 #![deny(missing_docs)]
 //! Implements an in-memory key-value storage system.
-use evmap;
-use failure::{Error, Fail};
-use log::error;
+use failure::{ensure, format_err, Error, Fail};
+use index::{new_index, IndexReader, IndexWriter};
+use log::{error, warn};
+use memmap2::Mmap;
 use serde::{Deserialize, Serialize};
-use serde_cbor::{to_writer, Deserializer};
-use std::cell::{RefCell, RefMut};
-use std::collections::HashMap;
-use std::fs::{read_dir, remove_file, rename, File, OpenOptions};
+use serde_cbor::{from_slice, to_vec, to_writer, Deserializer};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::{read_dir, OpenOptions};
 use std::io::prelude::*;
-use std::io::{BufReader, BufWriter, Seek, SeekFrom};
+use std::io::{BufReader, BufWriter, Cursor, ErrorKind, Seek, SeekFrom};
+use std::mem;
+use std::ops::Bound;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use storage::{FileStorage, LogFile, LogStorage};
 
 /// Custom Result type used for KvStore operations.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Iterator over the key-value pairs of an engine, returned by KvsEngine::iter
+pub type EngineIter<'a> = Box<dyn Iterator<Item = Result<(String, String)>> + 'a>;
+
 /// Client for sending KVSEngine requests
 pub mod client;
+/// Shares background work and open stores between many KvStores in one process
+pub mod manager;
 /// Network protocol for communicating between server and client
 pub mod protocol;
+/// Engines that pick other engines at runtime
+pub mod routing;
 /// Server for handling KVSEngine requests
 pub mod server;
+/// Backends that hold the logs of a KvStore
+pub mod storage;
+/// Shared behavioural tests that any KvsEngine implementation should pass
+#[cfg(any(test, feature = "test-util"))]
+pub mod testsuite;
 /// Defines ThreadPool trait and implementation for concurrent KVS engine
 pub mod thread_pool;
+/// Typed values stored in an engine through serde
+pub mod typed;
+
+// Backends of the in-memory index
+mod index;
+// Tracing spans of the server
+mod spans;
 
 /// Error thrown by remove() when the key does not exist
 #[derive(Debug, Fail)]
@@ -35,24 +63,218 @@ pub struct KeyNotFound;
 #[fail(display = "File data corrupted")]
 pub struct CorruptData;
 
+// Raised by reads that find a corrupt record under CorruptRecordPolicy::Repair, so the store can
+// remove the key once it holds the writer
+#[derive(Debug, Fail)]
+#[fail(display = "Corrupt record for key {}", key)]
+struct CorruptRecord {
+    key: String,
+    gen: u64,
+    start: u64,
+}
+
+/// Error returned by the server for requests whose deadline passed before they were handled
+#[derive(Debug, Fail)]
+#[fail(display = "Deadline exceeded")]
+pub struct DeadlineExceeded;
+
+/// Error returned by the server when a connection doesn't present the right secret
+#[derive(Debug, Fail)]
+#[fail(display = "Unauthorized")]
+pub struct Unauthorized;
+
+/// Error returned by the server for writes over its rate limit
+#[derive(Debug, Fail)]
+#[fail(display = "Rate limited")]
+pub struct RateLimited;
+
+/// Error returned by KvStore::get when reading the value takes longer than the read timeout
+#[derive(Debug, Fail)]
+#[fail(display = "Read timed out")]
+pub struct Timeout;
+
+/// Error returned by TypedStore::get when a stored value can't be decoded as the store's type
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid value for key {}: {}", key, source)]
+pub struct InvalidValue {
+    /// Key of the value
+    pub key: String,
+    /// Why decoding failed
+    #[cause]
+    pub source: serde_json::Error,
+}
+
+/// Error returned when a value is asked for as a String but isn't valid UTF-8, which can happen to
+/// KvStore values set with set_bytes, and to sled databases written by something other than the
+/// engine
+#[derive(Debug, Fail)]
+#[fail(display = "Value for key {} is not valid UTF-8", key)]
+pub struct NonUtf8Value {
+    /// Key of the value
+    pub key: String,
+}
+
+/// Error returned by the server for requests whose command it doesn't know, holding the command
+#[derive(Debug, Fail)]
+pub struct UnknownCommand(pub String);
+
+impl std::fmt::Display for UnknownCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Unknown command {:?}, expected one of: {}",
+            self.0,
+            protocol::COMMANDS.join(", ")
+        )
+    }
+}
+
+/// Error thrown by sets that would take a KvStore's logs past KvStoreOptions::max_disk_bytes
+#[derive(Debug, Fail)]
+#[fail(display = "Disk usage cap reached")]
+pub struct DiskFull;
+
+/// Error thrown by KvStore::open_with_options when the directory is already open in this process
+/// with different options
+#[derive(Debug, Fail)]
+#[fail(display = "Store is already open with different options")]
+pub struct OptionsMismatch;
+
+/// Error returned by KvStoreManager::open for a store that another manager already looks after
+#[derive(Debug, Fail)]
+#[fail(display = "Store is already managed by another KvStoreManager")]
+pub struct AlreadyManaged;
+
+/// Error thrown when writing to a store that was opened read-only
+#[derive(Debug, Fail)]
+#[fail(display = "Store is read-only")]
+pub struct ReadOnly;
+
+// Log format written by this version. Logs made before headers were added have no header and
+// are treated as LEGACY_FORMAT. Version 2 added the generation to the header, but encodes
+// commands the same way as version 1.
+const LEGACY_FORMAT: u32 = 0;
+const FORMAT_VERSION: u32 = 2;
+
+// Written at the start of every log file so the command encoding can change between versions
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogHeader {
+    #[serde(rename = "kvs")]
+    version: u32,
+    // Generation the log was written for, which is the source of truth over the file name. Older
+    // logs don't have it, so their name is trusted instead.
+    #[serde(rename = "g", default)]
+    generation: Option<u64>,
+}
+
+// Single-char tags keep the field names from dominating the size of small commands
 #[derive(Debug, Serialize, Deserialize)]
 enum Command {
+    #[serde(rename = "s")]
+    Set {
+        #[serde(rename = "k")]
+        key: String,
+        #[serde(rename = "v")]
+        value: String,
+        // Left out when empty, so untagged sets encode the same as before tags existed
+        #[serde(rename = "t", default, skip_serializing_if = "String::is_empty")]
+        tag: String,
+        // Unix time in seconds from which the value reads as absent, see KvStore::set_with_ttl
+        #[serde(rename = "e", default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        // Unix time in seconds at which the expiry was set, which is only kept along with one to
+        // tell whether the clock jumped, see KvStoreOptions::clock_skew_tolerance
+        #[serde(rename = "w", default, skip_serializing_if = "Option::is_none")]
+        written_at: Option<u64>,
+    },
+    // Set whose value is kept in a blob outside of the log, see KvStoreOptions::blob_threshold
+    #[serde(rename = "b")]
+    SetBlob {
+        #[serde(rename = "k")]
+        key: String,
+        #[serde(rename = "i")]
+        id: u64,
+        #[serde(rename = "n")]
+        len: u64,
+        #[serde(rename = "t", default, skip_serializing_if = "String::is_empty")]
+        tag: String,
+        // Whether the blob came from set_bytes, which means it doesn't have to be text
+        #[serde(rename = "y", default, skip_serializing_if = "std::ops::Not::not")]
+        binary: bool,
+        #[serde(rename = "e", default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        #[serde(rename = "w", default, skip_serializing_if = "Option::is_none")]
+        written_at: Option<u64>,
+    },
+    // Set of a value that doesn't have to be text, see KvStore::set_bytes
+    #[serde(rename = "y")]
+    SetBytes {
+        #[serde(rename = "k")]
+        key: String,
+        #[serde(rename = "v", with = "serde_bytes")]
+        value: Vec<u8>,
+        #[serde(rename = "e", default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        #[serde(rename = "w", default, skip_serializing_if = "Option::is_none")]
+        written_at: Option<u64>,
+    },
+    #[serde(rename = "r")]
+    Remove {
+        #[serde(rename = "k")]
+        key: String,
+    },
+    // New expiry for the live value of the key, see KvStore::touch. Compaction folds it into the
+    // set it applies to.
+    #[serde(rename = "t")]
+    Touch {
+        #[serde(rename = "k")]
+        key: String,
+        #[serde(rename = "e")]
+        expires_at: u64,
+        #[serde(rename = "w")]
+        written_at: u64,
+    },
+}
+
+// Command encoding used by LEGACY_FORMAT logs
+#[derive(Debug, Serialize, Deserialize)]
+enum LegacyCommand {
     Set { key: String, value: String },
     Remove { key: String },
 }
 
+impl From<LegacyCommand> for Command {
+    fn from(cmd: LegacyCommand) -> Self {
+        match cmd {
+            LegacyCommand::Set { key, value } => Command::Set {
+                key,
+                value,
+                tag: String::new(),
+                expires_at: None,
+                written_at: None,
+            },
+            LegacyCommand::Remove { key } => Command::Remove { key },
+        }
+    }
+}
+
 impl Command {
-    fn value(self) -> String {
+    fn key_str(&self) -> &str {
         match self {
-            Command::Set { value, .. } => value,
-            _ => panic!("Expected Set command"),
+            Command::Set { key, .. }
+            | Command::SetBytes { key, .. }
+            | Command::SetBlob { key, .. } => key,
+            Command::Remove { key } | Command::Touch { key, .. } => key,
         }
     }
 
     fn key(self) -> String {
         match self {
-            Command::Set { key, .. } => key,
-            Command::Remove { key } => key,
+            Command::Set { key, .. }
+            | Command::SetBytes { key, .. }
+            | Command::SetBlob { key, .. } => key,
+            Command::Remove { key } | Command::Touch { key, .. } => key,
         }
     }
 }
@@ -99,6 +321,12 @@ pub trait KvsEngine: Clone + Send + 'static {
     /// Otherwise, return None.
     fn get(&self, key: String) -> Result<Option<String>>;
 
+    /// Same as get, but also returns the version of the value. Every write to a key gives it a
+    /// higher version than it ever had, so an unchanged version means an unchanged value. The
+    /// reverse doesn't hold: engines may move a value to a higher version without a write, such
+    /// as when KvStore compacts. Versions are never 0.
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>>;
+
     /// Removes a key and its value from the storage.
     /// Does nothing if the key is not present in the storage.
     /// ```
@@ -118,13 +346,192 @@ pub trait KvsEngine: Clone + Send + 'static {
     /// ```
     fn remove(&self, key: String) -> Result<()>;
 
+    /// Same as set, but returns the value the key had before, or None if it didn't exist. No
+    /// other write can land between reading the old value and writing the new one.
+    fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>>;
+
+    /// Sets the key only if it doesn't exist yet, returning whether it was set. No other write
+    /// can create the key between checking for it and setting it, so out of many concurrent
+    /// calls for the same key exactly one succeeds.
+    fn set_nx(&self, key: String, value: String) -> Result<bool>;
+
+    /// Same as remove, but returns the value the key had before. Returns None instead of failing
+    /// if the key doesn't exist. No other write can land between reading the value and removing
+    /// it.
+    fn remove_and_get_old(&self, key: String) -> Result<Option<String>>;
+
     /// Remove all keys and values and clears underlying disc space
     fn clear(&self) -> Result<()>;
+
+    /// Approximate number of bytes the engine's data takes up on disc
+    fn disk_size(&self) -> Result<u64>;
+
+    /// Numbers about the engine's data for monitoring
+    fn engine_stats(&self) -> Result<EngineStats>;
+
+    /// Iterates over every key-value pair in the storage, in no particular order. Writes made
+    /// during the iteration may or may not show up.
+    fn iter(&self) -> Result<EngineIter<'_>>;
+
+    /// Returns up to limit key-value pairs whose keys sort after the given key, or from the
+    /// smallest key if it's None, in key order. Paging with the last key of each page visits
+    /// every key that exists for the whole scan. Keys set or removed during the scan may or may
+    /// not show up.
+    fn scan(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>>;
+
+    /// Returns every key-value pair whose key starts with the prefix, in key order. Keys set or
+    /// removed during the scan may or may not show up. By default this pages through scan from
+    /// the prefix, which engines with a faster way of finding the keys can override.
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        // The prefix is the only matching key that doesn't sort after the prefix
+        if let Some(value) = self.get(prefix.to_owned())? {
+            pairs.push((prefix.to_owned(), value));
+        }
+        let mut after = prefix.to_owned();
+        loop {
+            let page = self.scan(Some(&after), SCAN_PREFIX_PAGE)?;
+            // Matching keys sort together, so a key without the prefix ends the scan, as does
+            // running out of keys
+            let matching: Vec<_> = page
+                .into_iter()
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .collect();
+            let done = matching.len() < SCAN_PREFIX_PAGE;
+            pairs.extend(matching);
+            match pairs.last() {
+                Some((key, _)) if !done => after = key.clone(),
+                _ => return Ok(pairs),
+            }
+        }
+    }
+
+    /// Applies the writes in order, as if by calling set or remove for each, and returns the
+    /// result of each write. Engines can override this to make the whole batch durable at once
+    /// instead of flushing every write. The batch as a whole only fails if something goes wrong
+    /// that may have left any of its writes unapplied. The writes aren't atomic, so reads can see
+    /// some of them before others.
+    fn write_batch(&self, ops: Vec<WriteOp>) -> Result<Vec<Result<()>>> {
+        Ok(ops
+            .into_iter()
+            .map(|op| match op {
+                WriteOp::Set(key, value) => self.set(key, value),
+                WriteOp::Remove(key) => self.remove(key),
+            })
+            .collect())
+    }
+
+    /// Writes every key-value pair to the writer as JSON, one {"key": ..., "value": ...} object
+    /// per line, and returns how many pairs were written. Pairs are streamed from iter, so the
+    /// same goes for writes made during the export. Fails on values that aren't valid UTF-8.
+    fn export_json<W: Write>(&self, writer: W) -> Result<u64> {
+        let mut writer = BufWriter::new(writer);
+        let mut count = 0;
+        for pair in self.iter()? {
+            let (key, value) = pair?;
+            serde_json::to_writer(&mut writer, &JsonPair { key, value })?;
+            writer.write_all(b"\n")?;
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Sets every pair read from JSON in the format written by export_json, and returns how many
+    /// pairs were read. Keys that aren't in the JSON are left alone, so use replace_json to
+    /// restore the store to exactly what was exported. Pairs are written in batches, so a failure
+    /// can leave some of them set.
+    fn import_json<R: Read>(&self, reader: R) -> Result<u64> {
+        let pairs = serde_json::Deserializer::from_reader(BufReader::new(reader));
+        let mut count = 0;
+        let mut batch = Vec::new();
+        for pair in pairs.into_iter::<JsonPair>() {
+            let JsonPair { key, value } = pair?;
+            batch.push(WriteOp::Set(key, value));
+            count += 1;
+            if batch.len() == IMPORT_BATCH {
+                for result in self.write_batch(mem::take(&mut batch))? {
+                    result?;
+                }
+            }
+        }
+        for result in self.write_batch(batch)? {
+            result?;
+        }
+        Ok(count)
+    }
+
+    /// Replaces everything in the store with the pairs read from JSON in the format written by
+    /// export_json, and returns how many pairs were read. The whole JSON is read before the store
+    /// is touched, so malformed JSON leaves the store as it was. By default the store is then
+    /// cleared and the pairs set in batches, so a failed write can still leave only some of them;
+    /// engines that can swap in new data in one step override this.
+    fn replace_json<R: Read>(&self, reader: R) -> Result<u64> {
+        let pairs = read_json_pairs(reader)?;
+        let count = pairs.len() as u64;
+        self.clear()?;
+        let mut ops = pairs
+            .into_iter()
+            .map(|(key, value)| WriteOp::Set(key, value));
+        loop {
+            let batch: Vec<_> = ops.by_ref().take(IMPORT_BATCH).collect();
+            if batch.is_empty() {
+                return Ok(count);
+            }
+            for result in self.write_batch(batch)? {
+                result?;
+            }
+        }
+    }
+}
+
+// Line of the JSON written by KvsEngine::export_json
+#[derive(Serialize, Deserialize)]
+struct JsonPair {
+    key: String,
+    value: String,
+}
+
+// Every pair in JSON written by KvsEngine::export_json, read in full so that malformed JSON is
+// found before anything is written
+fn read_json_pairs(reader: impl Read) -> Result<Vec<(String, String)>> {
+    serde_json::Deserializer::from_reader(BufReader::new(reader))
+        .into_iter::<JsonPair>()
+        .map(|pair| {
+            let JsonPair { key, value } = pair?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+/// One write in a batch passed to KvsEngine::write_batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteOp {
+    /// Set a key to a value
+    Set(String, String),
+    /// Remove a key, which fails with KeyNotFound if it doesn't exist
+    Remove(String),
 }
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const CLOCK_SKEW_TOLERANCE: Duration = Duration::from_secs(5 * 60);
+
+// Pairs asked for per scan by the default KvsEngine::scan_prefix
+const SCAN_PREFIX_PAGE: usize = 100;
+// Pairs written per batch by KvsEngine::import_json
+const IMPORT_BATCH: usize = 1000;
 
 /// Key-value store for storing strings.
+///
+/// Writes are appended to a log and only added to the in-memory index once they've been flushed,
+/// so an acknowledged set or remove survives a crash of the process. A crash in the middle of a
+/// write leaves no index entry behind, and the partial record is truncated from the log the next
+/// time the store is opened.
+///
+/// Surviving a crash of the whole machine takes a sync, which only checkpoint does for regular
+/// writes. Everything written before a checkpoint survives such a crash once it returns. The store
+/// also syncs every log that compaction writes, and, on Unix, the directory of a FileStorage
+/// whenever a log is created or renamed into place, so the logs themselves can't disappear.
 /// ```
 /// use kvs::Result;
 ///
@@ -143,394 +550,3625 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 pub struct KvStore {
     reader: KvsReader,
     writer: Arc<Mutex<KvsWriter>>,
+    index_build: Arc<IndexBuild>,
+    normalize_key: Option<KeyNormalizer>,
+    value_codec: Option<Arc<dyn ValueCodec>>,
+    // Only tracked when the number of keys is capped
+    recency: Option<Arc<Mutex<Recency>>>,
+    // Set on stores opened from a directory, which are shared by every open of that directory
+    registered: Option<Arc<Registered>>,
+    group_commit: Option<Arc<GroupCommit>>,
+    // Signalled along with the writer's lock whenever a requested compaction is dealt with
+    compaction_done: Arc<Condvar>,
+    // Set by the writer for as long as it's compacting, so it can be read without the writer
+    compacting: Arc<AtomicBool>,
 }
 
-impl KvsEngine for KvStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
-    }
-
-    fn get(&self, key: String) -> Result<Option<String>> {
-        self.reader.get(key)
-    }
+type GroupSender = SyncSender<Result<()>>;
 
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
-    }
+// Where the record of each key is in a log
+type Offsets = HashMap<String, (u64, u64)>;
 
-    fn clear(&self) -> Result<()> {
-        self.writer.lock().unwrap().clear()
-    }
+// Writes waiting to be committed together. The first write to arrive at an empty group leads it:
+// it waits out the window while others join, then writes the whole group with one write_batch
+// and hands every write its own result.
+struct GroupCommit {
+    window: Duration,
+    pending: Mutex<Vec<(WriteOp, GroupSender)>>,
 }
 
-impl KvStore {
-    /// Loads the in-memory index of the storage from a file to construct a KvStore
-    pub fn open(dir: &Path) -> Result<Self> {
-        // Get the existing KVS log file with the largest generation, if it exists
-        let gen = all_log_files(&dir, None)?
-            .iter()
-            .filter_map(|path| {
-                path.file_stem()
-                    .and_then(std::ffi::OsStr::to_str)
-                    .filter(|name| name.starts_with("kvs_"))
-                    .and_then(|name| name.rsplit("_").next())
-                    .and_then(|s| s.parse::<u64>().ok())
-            })
-            .max();
-        let gen = gen.unwrap_or(0);
-        let log_path = log_path(&dir, gen);
+// Stores opened from a directory, keyed by the canonical path of the directory. Opening the same
+// directory twice in one process would otherwise make two writers append to the same log. The
+// registry's lock is only held to find a directory's slot, while the slot's own lock is held for
+// the whole open, so opening one directory doesn't hold up opening any other.
+type Registry = Mutex<HashMap<PathBuf, Arc<Slot>>>;
+type Slot = Mutex<Weak<Registered>>;
 
-        let (index_r, index_w) = evmap::with_meta(gen);
-        let dir = Arc::new(dir.to_owned());
-        let writer = BufWriter::new(open_write().create(true).open(&log_path)?);
-        let reader = BufReader::new(open_read().open(&log_path)?);
+fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Registry::default)
+}
 
-        let mut writer = KvsWriter {
-            dir: dir.clone(),
-            index: index_w,
-            stale_bytes: 0,
-            writer,
-            reader,
-        };
+// Store in the registry, which lives as long as any handle to it. Handles are made by cloning an
+// unregistered store, so the store here doesn't keep itself alive.
+struct Registered {
+    dir: PathBuf,
+    options: KvStoreOptions,
+    store: Mutex<KvStore>,
+}
 
-        let reader = KvsReader {
-            dir: dir.clone(),
-            index: index_r,
-            reader: RefCell::new((None, gen)),
-        };
+impl Registered {
+    fn handle(self: Arc<Self>) -> KvStore {
+        let mut store = self.store.lock().unwrap().clone();
+        store.registered = Some(self);
+        store
+    }
+}
 
-        writer.build_index()?;
+impl Drop for Registered {
+    fn drop(&mut self) {
+        prune_slot(&self.dir);
+    }
+}
 
-        Ok(Self {
-            reader,
-            writer: Arc::new(Mutex::new(writer)),
-        })
+// Removes the slot of a directory once nothing is open or being opened there
+fn prune_slot(dir: &Path) {
+    let mut registry = registry().lock().unwrap();
+    // Slots are only handed out under the registry's lock, so if the registry holds the only
+    // reference nobody can be opening the directory, and its lock is free
+    if registry.get(dir).is_some_and(|slot| {
+        Arc::strong_count(slot) == 1
+            && slot
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .strong_count()
+                == 0
+    }) {
+        registry.remove(dir);
     }
 }
 
-fn log_path(dir: &Path, gen: u64) -> PathBuf {
-    dir.join(&format!("kvs_{}.cbor", gen))
+/// Function that maps every key to the form that gets stored, see KvStoreOptions::normalize_key
+pub type KeyNormalizer = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Encoding applied to values on their way into the log and undone on their way out, see
+/// KvStoreOptions::value_codec. Only the value of a set goes through it, while keys and the
+/// framing of each record stay CBOR.
+pub trait ValueCodec: Send + Sync {
+    /// Turns a value into what gets written to the log
+    fn encode(&self, value: String) -> Result<String>;
+    /// Turns what encode wrote back into the value
+    fn decode(&self, stored: String) -> Result<String>;
 }
 
-fn compacted_log_path(dir: &Path) -> PathBuf {
-    dir.join("kvs_compact.cbor")
+/// Function called around every compaction, see KvStoreOptions::on_compaction
+pub type CompactionListener = Arc<dyn Fn(CompactionEvent) + Send + Sync>;
+
+/// Stage of a compaction, passed to KvStoreOptions::on_compaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionEvent {
+    /// A compaction is about to rewrite the log
+    Started {
+        /// Bytes of overwritten and removed values in the log, which is what compaction frees
+        stale_bytes: u64,
+    },
+    /// A compaction finished successfully. Failed compactions never finish.
+    Finished {
+        /// How much smaller the store got on disk
+        reclaimed_bytes: u64,
+        /// Time since the compaction started
+        duration: Duration,
+    },
 }
 
-fn open_read() -> OpenOptions {
-    let mut opt = OpenOptions::new();
-    opt.read(true);
-    opt
+/// Summary of a finished compaction, as returned by KvStore::compaction_history
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionRecord {
+    /// Wall-clock time when the compaction finished
+    pub finished_at: SystemTime,
+    /// Bytes of overwritten and removed values in the log when the compaction started
+    pub stale_bytes: u64,
+    /// How much smaller the store got on disk
+    pub reclaimed_bytes: u64,
+    /// How long the compaction took
+    pub duration: Duration,
 }
 
-fn open_write() -> OpenOptions {
-    let mut opt = OpenOptions::new();
-    opt.append(true);
-    opt
+// Most compactions KvStore::compaction_history remembers
+const COMPACTION_HISTORY_LEN: usize = 32;
+
+/// Point-in-time numbers about a KvStore, for monitoring
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KvStoreStats {
+    /// Number of keys that have a value
+    pub keys: usize,
+    /// Generation of the current log
+    pub generation: u64,
+    /// Bytes in the current log taken up by overwritten or removed data
+    pub stale_bytes: u64,
+    /// Estimate of the memory used by the index, see KvStore::index_memory_bytes
+    pub index_memory_bytes: usize,
+    /// Expiring records found at open that were written too far in the future, see
+    /// KvStoreOptions::clock_skew_tolerance
+    pub skewed_records: u64,
 }
 
-fn all_log_files(dir: &Path, preserve_gen: Option<u64>) -> Result<Vec<PathBuf>> {
-    read_dir(dir)?
-        .map(|entry| {
-            let entry = entry?;
-            let path = entry.path();
+/// Numbers about the data of any engine, as returned by KvsEngine::engine_stats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EngineStats {
+    /// Number of keys that have a value
+    pub live_keys: u64,
+    /// Bytes taken up by overwritten or removed data that hasn't been reclaimed yet, for engines
+    /// that keep track of it
+    pub stale_bytes: Option<u64>,
+    /// Same as KvsEngine::disk_size
+    pub disk_bytes: u64,
+}
 
-            if entry.metadata()?.is_file() {
-                if let (Some(extension), Some(stem)) = (path.extension(), path.file_stem()) {
-                    if extension == "cbor" {
-                        // Wipe out every cbor file except the one that maps to the generation we want
-                        // to keep
-                        let useless = if let Some(gen) = preserve_gen {
-                            stem != &format!("kvs_{}", gen)[..]
-                        } else {
-                            true
-                        };
+/// Durable position in a store's log, as returned by KvStore::checkpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Generation of the log the position is in
+    pub generation: u64,
+    /// Length of the log at the checkpoint. Everything before it is on disk.
+    pub offset: u64,
+}
 
-                        if useless {
-                            return Ok(Some(path));
-                        }
-                    }
-                }
-            }
+/// Findings from checking a store with verify
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Generation of the log that was checked
+    pub generation: u64,
+    /// Records that were read successfully
+    pub valid_records: u64,
+    /// Records that couldn't be read. Records have no framing, so checking stops at the first
+    /// invalid one and this is never more than 1.
+    pub invalid_records: u64,
+    /// Bytes from the first invalid record to the end of the log
+    pub invalid_bytes: u64,
+    /// Set records that were overwritten or removed later on. These are expected in any log and
+    /// are dropped by compaction.
+    pub unreachable_records: u64,
+    /// Remove and touch records for keys that had no value at that point in the log
+    pub orphaned_records: u64,
+    /// Keys that have a value
+    pub live_keys: u64,
+    /// Descriptions of everything that's wrong with the store, which is empty for a healthy store
+    pub inconsistencies: Vec<String>,
+}
 
-            Ok(None)
-        })
-        .filter_map(Result::transpose)
-        .collect()
+impl VerifyReport {
+    /// Whether no inconsistencies were found
+    pub fn is_ok(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
 }
 
-// There will only ever be one writer for every KvStore
-struct KvsWriter {
-    dir: Arc<PathBuf>,
-    writer: BufWriter<File>,
-    reader: BufReader<File>,
-    index: evmap::WriteHandle<String, (u64, u64), u64>,
-    stale_bytes: u64,
+/// Outcome of bringing a store up to the current log format with migrate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// Format version the store was in before migrating
+    pub from_version: u32,
+    /// Format version the store is in now, which is always the one this version writes
+    pub to_version: u32,
+    /// Whether the log had to be rewritten. False if it was already in the current format.
+    pub migrated: bool,
+    /// Generation of the live log afterwards
+    pub generation: u64,
 }
 
-impl KvsWriter {
-    // This is only ever called from open(), so we don't need to worry about synchronization
-    fn build_index(&mut self) -> Result<()> {
-        // Read from beginning
-        let mut start = self.reader.seek(SeekFrom::Start(0))?;
-        let mut index: HashMap<_, Range> = HashMap::new();
+/// Lists the generations of every log file in a directory, sorted from oldest to newest. Only the
+/// newest one is live; older ones are leftovers that compaction couldn't delete yet. Files that
+/// aren't KvStore logs are ignored. Doesn't modify anything.
+pub fn generations(dir: &Path) -> Result<Vec<u64>> {
+    log_generations(dir)
+}
 
-        // Check if EOF has been reached
-        while !self.reader.fill_buf()?.is_empty() {
-            // For some reason calling byte_offset() on CBOR deserializers does not work for
-            // files, so we have to get log offsets using seek() instead.
-            // Deserialize command manually
-            let mut de = Deserializer::from_reader(&mut self.reader);
-            let cmd = serde::de::Deserialize::deserialize(&mut de)?;
-            let end = self.reader.seek(SeekFrom::Current(0))?;
+/// Checks the integrity of the KvStore in a directory without modifying anything. Scans the
+/// newest log the same way open does, but keeps going after finding problems and reports them
+/// instead of failing. Also rereads the record behind every index entry, checks that the blobs of
+/// live keys are there in full, and flags leftover files from other generations, unfinished
+/// compactions, or blobs that no record refers to. Returns Err only if the files can't be read at
+/// all.
+pub fn verify(dir: &Path) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let gen = match live_generation(&FileStorage::new(dir))? {
+        Some(gen) => gen,
+        // A store without any logs is just empty
+        None => return Ok(report),
+    };
+    report.generation = gen;
 
-            match cmd {
-                Command::Set { key, .. } => {
-                    if let Some(old) = index.get(&key) {
-                        self.stale_bytes += old.len();
-                    }
-                    index.insert(key, Range::new((start, end)));
-                }
-                Command::Remove { key } => {
-                    match index.get(&key) {
-                        None => {
-                            error!(
-                                "Data corrupted, as remove was found in file before set for key {}",
-                                key
-                            );
-                            return Err(CorruptData.into());
-                        }
-                        Some(old) => self.stale_bytes += old.len(),
-                    }
-                    index.remove(&key);
-                }
-            };
+    for file in all_log_files(dir, Some(gen))? {
+        report
+            .inconsistencies
+            .push(format!("Stray log file {}", file.display()));
+    }
 
-            start = end;
+    let log_path = log_path(dir, gen);
+    let mut reader = BufReader::new(open_read().open(&log_path)?);
+    let file_len = reader.get_ref().metadata()?.len();
+    let version = match read_header(&mut reader) {
+        Ok(header) => header.version,
+        Err(err) => {
+            report.invalid_records += 1;
+            report.invalid_bytes = file_len;
+            report
+                .inconsistencies
+                .push(format!("Invalid log header: {}", err));
+            return Ok(report);
         }
+    };
 
-        self.index
-            .extend(index.into_iter().map(|(k, r)| (k, (r.start, r.end))));
-        self.index.refresh();
-
+    let mut index: HashMap<String, Range> = HashMap::new();
+    // Blobs that any record refers to, including overwritten ones, whose blobs stay until the
+    // next compaction
+    let mut referenced_blobs = HashSet::new();
+    let mut valid_end = reader.stream_position()?;
+    let scan = scan_log(&mut reader, version, |cmd, range| {
+        report.valid_records += 1;
+        valid_end = range.end;
+        if let Command::SetBlob { id, .. } = cmd {
+            referenced_blobs.insert(id);
+        }
+        match cmd {
+            Command::Set { key, .. }
+            | Command::SetBytes { key, .. }
+            | Command::SetBlob { key, .. } => {
+                if index.insert(key, range).is_some() {
+                    report.unreachable_records += 1;
+                }
+            }
+            Command::Remove { key } => {
+                if index.remove(&key).is_some() {
+                    report.unreachable_records += 1;
+                } else {
+                    report.orphaned_records += 1;
+                    report.inconsistencies.push(format!(
+                        "Remove for key {} without a value at offset {}",
+                        key, range.start
+                    ));
+                }
+            }
+            Command::Touch { key, .. } => {
+                if !index.contains_key(&key) {
+                    report.orphaned_records += 1;
+                    report.inconsistencies.push(format!(
+                        "Touch for key {} without a value at offset {}",
+                        key, range.start
+                    ));
+                }
+            }
+        }
         Ok(())
+    });
+    if let Err(err) = scan {
+        report.invalid_records += 1;
+        report.invalid_bytes = file_len - valid_end;
+        report
+            .inconsistencies
+            .push(format!("Invalid record at offset {}: {}", valid_end, err));
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        let value = self.index.get_and(&key, |v| Range::new(v[0]));
-
-        if let Some(value) = value {
-            let cmd = Command::Remove { key };
-
-            to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-
-            // Remove key from index AFTER committing the command to disc.
-            // We can use this order for remove and set because the file changes for those
-            // operations are additive, so file updates won't mess up concurrent reads.
-            self.index.empty(cmd.key().clone());
-            self.index.refresh();
-            self.stale_bytes += value.len();
-
-            if self.stale_bytes > COMPACTION_THRESHOLD {
-                self.compaction()?;
+    // Every index entry must lead back to a set for the same key, and the blob of a live blob set
+    // must be there in full
+    report.live_keys = index.len() as u64;
+    for (key, range) in index {
+        reader.seek(SeekFrom::Start(range.start))?;
+        match read_command(&mut reader, version) {
+            Ok(Command::Set { key: ref found, .. })
+            | Ok(Command::SetBytes { key: ref found, .. })
+                if *found == key => {}
+            Ok(Command::SetBlob {
+                key: ref found,
+                id,
+                len,
+                ..
+            }) if *found == key => {
+                let path = blob_path(dir, id);
+                match open_read().open(&path).and_then(|file| file.metadata()) {
+                    Ok(metadata) if metadata.len() == len => {}
+                    Ok(metadata) => report.inconsistencies.push(format!(
+                        "Blob {} for key {} has {} bytes instead of {}",
+                        path.display(),
+                        key,
+                        metadata.len(),
+                        len
+                    )),
+                    Err(err) => report.inconsistencies.push(format!(
+                        "Blob {} for key {} can't be read: {}",
+                        path.display(),
+                        key,
+                        err
+                    )),
+                }
             }
-            Ok(())
-        } else {
-            Err(KeyNotFound.into())
+            _ => report.inconsistencies.push(format!(
+                "Index entry for key {} at offset {} doesn't point to its value",
+                key, range.start
+            )),
         }
     }
 
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set { key, value };
-
-        // Get the offset of the next command
-        let start = self.writer.seek(SeekFrom::End(0))?;
-        // Write to file
-        to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        let end = self.writer.seek(SeekFrom::End(0))?;
-
-        let key = cmd.key();
-        // Update stale_bytes if necessary
-        if let Some(old) = self.index.get_and(&key, |v| Range::new(v[0])) {
-            self.stale_bytes += old.len();
+    for id in FileStorage::new(dir).blobs()? {
+        if !referenced_blobs.contains(&id) {
+            report.inconsistencies.push(format!(
+                "Orphaned blob file {}",
+                blob_path(dir, id).display()
+            ));
         }
-        // Insert the offset into the index
-        self.index.update(key, (start, end));
-        self.index.refresh();
+    }
 
-        if self.stale_bytes > COMPACTION_THRESHOLD {
-            self.compaction()?;
-        }
+    Ok(report)
+}
 
-        Ok(())
+/// Reads the format version of the live log in a directory, or None if there's no store there.
+/// Logs from before the format was versioned read as version 0. Doesn't modify anything.
+pub fn format_version(dir: &Path) -> Result<Option<u32>> {
+    let storage = FileStorage::new(dir);
+    match live_generation(&storage)? {
+        Some(gen) => Ok(Some(
+            read_header(&mut BufReader::new(storage.read(gen)?))?.version,
+        )),
+        None => Ok(None),
     }
+}
 
-    // Might cause read failures, but will guarantee removal of all files
-    fn clear(&mut self) -> Result<()> {
-        let gen = self.index.meta().unwrap();
+/// Rewrites the store in a directory into the current log format if it's in an older one. The
+/// live records are copied into a new log the same way compaction does, which only replaces the
+/// old log once it's fully written and synced, so a crash part way leaves the old store intact.
+/// Does nothing for stores that are already current, including empty ones, so it's safe to run
+/// on every upgrade.
+pub fn migrate(dir: &Path) -> Result<MigrationReport> {
+    let from_version = format_version(dir)?.unwrap_or(FORMAT_VERSION);
+    let mut report = MigrationReport {
+        from_version,
+        to_version: FORMAT_VERSION,
+        migrated: false,
+        generation: live_generation(&FileStorage::new(dir))?.unwrap_or(0),
+    };
+    if from_version == FORMAT_VERSION {
+        return Ok(report);
+    }
+
+    let store = KvStore::open(dir)?;
+    store.compact()?;
+    report.migrated = true;
+    report.generation = store.checkpoint()?.generation;
+    Ok(report)
+}
+
+/// Options for opening a KvStore
+#[derive(Clone, Default)]
+pub struct KvStoreOptions {
+    /// Build the index in a background thread instead of during open, so that opening a store
+    /// with a huge log returns right away. Until the index is ready every operation blocks, and
+    /// afterwards the store behaves exactly as if it was opened normally. Errors from reading the
+    /// log are returned by the operations instead of by open.
+    pub lazy_index: bool,
+    /// Applied to every key passed to the store before it's used, so keys that normalize to the
+    /// same string refer to the same entry. For example, lowercasing makes keys case-insensitive.
+    /// The normalized key is what gets persisted and what iteration returns. Keys are used as-is
+    /// when this is None. Changing the normalizer of an existing store is unsupported, since keys
+    /// stored under the old one would no longer be found.
+    pub normalize_key: Option<KeyNormalizer>,
+    /// Passes every value through the codec before it's written and after it's read, so values
+    /// can be stored in a form of the caller's choosing, such as payloads that are already
+    /// serialized or compressed and shouldn't be touched. Compaction copies records as they are
+    /// in the log without decoding them, so the codec is never applied twice, and history
+    /// returns decoded values too. Like the normalizer, the codec of an existing store can't be
+    /// changed, since values written with the old one would be decoded with the new one.
+    pub value_codec: Option<Arc<dyn ValueCodec>>,
+    /// Cap on the number of keys. Once a set goes over the cap, the least recently used keys are
+    /// removed until the store is back under it, where both get and set count as a use. This
+    /// turns the store into a bounded cache, so data can disappear without being removed. Which
+    /// keys were used recently isn't persisted, so after a reopen every existing key counts as
+    /// equally old.
+    pub max_keys: Option<usize>,
+    /// How get reads values from the log
+    pub read_mode: ReadMode,
+    /// When writes become visible to reads
+    pub read_consistency: ReadConsistency,
+    /// Never compact the log on its own, so every write ever made stays in it and can be read
+    /// back with KvStore::history. The log grows without bound, since overwritten and removed
+    /// values are never reclaimed. Calling compact or clear still discards the history.
+    pub never_compact: bool,
+    /// Compact once writes have left more than this many bytes of overwritten and removed values
+    /// in the log. Higher thresholds compact less often at the cost of more disk space, which
+    /// suits large values, while lower ones keep small stores small. Defaults to 1 MiB.
+    pub compaction_threshold: Option<u64>,
+    /// Give up on a get whose value takes longer than this to read, returning a Timeout error,
+    /// which keeps a hung disk or network filesystem from hanging the caller too. Each such read
+    /// runs on a new helper thread. Reads can't be cancelled, so a helper that times out is left
+    /// behind holding its open log until the read finishes, which may be never, and its handle
+    /// opens the log again for the next get. Only reading the value is timed, not opening the log
+    /// or any writes, since a write given up halfway would leave the writer in an unknown state.
+    pub read_timeout: Option<Duration>,
+    /// Share at most this many open logs between every handle of the store, instead of letting
+    /// each handle keep its own log open for as long as it lives. Every get that reads a value
+    /// checks a log out of the pool and returns it afterwards, waiting for one to be returned
+    /// when all of them are in use, so gets on more threads than there are logs contend for them.
+    /// With a read timeout, waiting for a log counts against it. Logs left behind by timed out
+    /// reads count against the cap until their reads finish.
+    pub max_open_logs: Option<usize>,
+    /// Called when a compaction starts and when it finishes, whether it was triggered by writes,
+    /// by a KvStoreManager, or by calling compact. Runs on the thread doing the compaction while
+    /// it holds the store's writer, so it should be quick and must not use the store.
+    pub on_compaction: Option<CompactionListener>,
+    /// Reserve this many bytes of disk space for every log the store writes to, when the log is
+    /// opened or created by a compaction, without changing the log's length. Appends then fill
+    /// space that's already allocated instead of fragmenting the file, and a disk without enough
+    /// space fails the open instead of a later write. Logs still grow past the reserved space as
+    /// needed. Only supported on Linux, through fallocate, on filesystems that implement it; it
+    /// does nothing elsewhere and for stores not kept in files. Reserved space counts towards
+    /// disk usage but not towards disk_size.
+    pub preallocate_bytes: Option<u64>,
+    /// Cap on the bytes the logs take up, which makes the store bounded. A set that would take
+    /// the logs past the cap first compacts the log, unless the store was opened with
+    /// never_compact or has no stale data, and fails with DiskFull if that doesn't free enough
+    /// space. Removes are never refused, so a full store can always make room. Compaction writes
+    /// the new log before deleting the old one, so the logs briefly take up more than the cap
+    /// while it runs.
+    pub max_disk_bytes: Option<u64>,
+    /// Keep values longer than this many bytes in blobs of their own, outside of the log, which
+    /// only holds a reference to the blob. Compaction then copies the reference instead of the
+    /// value, so stores that mix small values with a few huge ones compact quickly, and deletes
+    /// the blobs of values that were overwritten or removed. Reading such a value reads its whole
+    /// blob. The storage has to support blobs, which FileStorage keeps as blob_<id> files next to
+    /// the logs. Values are stored inline when this is None, and logs in the legacy format keep
+    /// every value inline until they're compacted.
+    pub blob_threshold: Option<usize>,
+    /// Commit concurrent sets and removes in groups instead of flushing the log for each one.
+    /// The first write of a group waits this long for others to join, then all of them are
+    /// written with a single flush, and each call returns once its group is flushed. This trades
+    /// the latency of every write, which now waits for the window, for fewer flushes when many
+    /// threads write at once. Since a flush only hands the log to the OS, this only pays off
+    /// where that's slow, such as on network filesystems; on a local disk the window costs far
+    /// more than the flushes it saves. Other writes, like set_nx or write_batch, don't join
+    /// groups.
+    pub group_commit_window: Option<Duration>,
+    /// Structure that holds the in-memory index
+    pub index_backend: IndexBackend,
+    /// What reads do when the record a key points to doesn't decode into a set of that key
+    pub on_corrupt_record: CorruptRecordPolicy,
+    /// How far writes get before they return
+    pub durability: DurabilityMode,
+    /// How far ahead of the clock the write time of an expiring record may be before open warns
+    /// about it. Records from the future mean that the clock was set back since they were
+    /// written, or that they were written on a machine whose clock was ahead, and either way
+    /// their keys may expire later than intended. Defaults to 5 minutes.
+    pub clock_skew_tolerance: Option<Duration>,
+    /// Call sweep_expired on a background thread this often, so that expired keys don't hold on
+    /// to memory until something else removes them. The thread keeps going until every handle
+    /// to the store is gone. Stores opened read-only never sweep.
+    pub sweep_interval: Option<Duration>,
+}
 
-        // Perform cleaup
-        for file in all_log_files(&self.dir, Some(gen))? {
-            if let Err(err) = remove_file(&file) {
-                error!(
-                    "Failed to remove {} during compaction: {}",
-                    file.display(),
-                    err
-                );
+impl KvStoreOptions {
+    // Whether a second open with these options can share a store opened with the other ones
+    fn same_as(&self, other: &Self) -> bool {
+        fn same_arc<T: ?Sized>(a: &Option<Arc<T>>, b: &Option<Arc<T>>) -> bool {
+            match (a, b) {
+                (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+                (None, None) => true,
+                _ => false,
             }
         }
-        // Truncate current log file
-        self.writer.get_mut().set_len(0)?;
 
-        // Update index and generation
-        self.index.purge();
-        self.index.set_meta(gen);
-        self.index.refresh();
-        self.stale_bytes = 0;
-        Ok(())
+        self.lazy_index == other.lazy_index
+            && same_arc(&self.normalize_key, &other.normalize_key)
+            && same_arc(&self.value_codec, &other.value_codec)
+            && self.max_keys == other.max_keys
+            && self.read_mode == other.read_mode
+            && self.read_consistency == other.read_consistency
+            && self.never_compact == other.never_compact
+            && self.compaction_threshold == other.compaction_threshold
+            && self.read_timeout == other.read_timeout
+            && self.max_open_logs == other.max_open_logs
+            && same_arc(&self.on_compaction, &other.on_compaction)
+            && self.preallocate_bytes == other.preallocate_bytes
+            && self.max_disk_bytes == other.max_disk_bytes
+            && self.blob_threshold == other.blob_threshold
+            && self.group_commit_window == other.group_commit_window
+            && self.index_backend == other.index_backend
+            && self.on_corrupt_record == other.on_corrupt_record
+            && self.durability == other.durability
+            && self.clock_skew_tolerance == other.clock_skew_tolerance
+            && self.sweep_interval == other.sweep_interval
     }
+}
 
-    fn compaction(&mut self) -> Result<()> {
-        let compact_path = compacted_log_path(&self.dir);
-        let mut compact_file = BufWriter::new(open_write().create_new(true).open(&compact_path)?);
+/// One write to a key, as returned by KvStore::history
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// Value that was set, or None if the key was removed
+    pub value: Option<HistoryValue>,
+    /// Offset of the write's record in the current log
+    pub offset: u64,
+}
 
-        // The following operations modify multiple object state, and failure at any point must
-        // guarantee a consistent object state (reader, writer, index all refer to same file).
-        // Also, even on a panic the disc data we care about must not be corrupted.
+/// Value set by one write in a key's history
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HistoryValue {
+    /// Value set as text
+    Text(String),
+    /// Value set with set_bytes, which is kept as it was written whether or not it's UTF-8
+    Bytes(Vec<u8>),
+}
 
-        let mut new_offsets = Vec::with_capacity(self.index.len());
-        // Use our index to figure out what data is fresh
-        let index: Vec<_> = self.index.map_into(|k, v| (k.to_owned(), Range::new(v[0])));
-        for (key, offset) in index {
-            self.reader.seek(SeekFrom::Start(offset.start))?;
-            let new_offset = compact_file.seek(SeekFrom::Current(0))?;
+/// Ways a KvStore can read values from its log
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadMode {
+    /// Seek to the value and read it through a buffer
+    #[default]
+    Buffered,
+    /// Map the log into memory and decode values straight from the mapped bytes, which saves a
+    /// syscall and a copy on every read. The log is remapped when it moves to a new generation or
+    /// grows past the mapped bytes. Storages that can't map logs fall back to buffered reads.
+    Mmap,
+}
 
-            let mut bytes = self.reader.by_ref().bytes();
-            for _ in 0..offset.len() {
-                let buf = [bytes.next().ok_or(CorruptData)??];
-                compact_file.write_all(&buf)?;
-            }
+/// How far every write to a KvStore gets before it returns. Either way, writes reach the log in
+/// the order they were made, through the single buffer of the store's writer, and only show up in
+/// the index once they've left that buffer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DurabilityMode {
+    /// Hand the write to the OS and leave persisting it to the OS, without waiting for the disk.
+    /// Writes survive the process crashing, but a crash of the machine can lose the last few
+    /// seconds of them. The OS writes the log out in order, so what's left on recovery is always
+    /// every write up to some point, never a later write without an earlier one.
+    #[default]
+    OsBuffered,
+    /// Also wait for the write to reach the disk, so a write that returned survives a crash of
+    /// the machine, at the cost of a disk sync per write. A write_batch syncs once for the
+    /// whole batch.
+    Sync,
+}
+
+/// What a KvStore does when a read finds that the record a key points to is corrupt. Only the
+/// record's key is affected, so every policy keeps serving other keys. Corruption is logged
+/// either way. Records are only checked when they're read, and errors from reading the log
+/// itself are always returned.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CorruptRecordPolicy {
+    /// Fail the read with a CorruptData error
+    #[default]
+    Error,
+    /// Treat the key as absent, leaving the record and the index as they are, so every read of
+    /// the key runs into the record again
+    SkipAsAbsent,
+    /// Treat the key as absent and remove it, which appends a remove to the log so the key stays
+    /// gone. Read-only stores leave the key alone, like SkipAsAbsent.
+    Repair,
+}
+
+/// Structures that can hold the in-memory index of a KvStore. Both make a write visible to reads
+/// only once it's flushed, and all writes of a batch at once; they differ in what that costs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexBackend {
+    /// An evmap, which keeps two copies of the map so that reads never wait for writes. Every
+    /// refresh swaps the copies and waits for reads to move off the old one, then applies the
+    /// writes again to that copy, which makes refreshes costly and doubles the memory used.
+    /// Suits read-heavy workloads.
+    #[default]
+    Evmap,
+    /// A single map behind a RwLock. Writes are applied under the write lock on each refresh,
+    /// which is cheap, but blocks reads for as long as it takes. Suits write-heavy workloads.
+    RwLock,
+}
+
+/// When writes to a KvStore become visible to reads
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadConsistency {
+    /// Every write is visible to reads on all handles as soon as it returns. This refreshes the
+    /// index on every write, which waits for reads on other threads to move off the old copy of
+    /// the index.
+    #[default]
+    Latest,
+    /// Writes are made visible in batches, once enough of them pile up or when sync_reads,
+    /// compact, or clear is called. Writes get cheaper, but reads on any handle, including the
+    /// one that wrote, can miss the latest writes until then. This applies to get, iter, scan,
+    /// and the key count in stats. Writes themselves always see every earlier write.
+    Refreshed,
+}
+
+// Most writes a Refreshed store makes before making them visible to reads
+const REFRESH_BATCH: usize = 64;
+
+// Order in which the live keys were first set, laid out like Recency. Overwrites keep a key's
+// place, while removing a key forgets it, so setting it again moves it to the end.
+#[derive(Default)]
+struct InsertionOrder {
+    next: u64,
+    seqs: HashMap<String, u64>,
+    order: BTreeMap<u64, String>,
+}
+
+impl InsertionOrder {
+    // Puts the key at the end unless it's already there
+    fn insert(&mut self, key: &str) {
+        if !self.seqs.contains_key(key) {
+            self.seqs.insert(key.to_owned(), self.next);
+            self.order.insert(self.next, key.to_owned());
+            self.next += 1;
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(seq) = self.seqs.remove(key) {
+            self.order.remove(&seq);
+        }
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &String> {
+        self.order.values()
+    }
+}
+
+// Tracks how recently each key was used, so that the least recently used keys can be evicted
+struct Recency {
+    max_keys: usize,
+    // Filled from the index on first use, since the log doesn't record accesses
+    seeded: bool,
+    tick: u64,
+    ticks: HashMap<String, u64>,
+    order: BTreeMap<u64, String>,
+}
+
+impl Recency {
+    fn new(max_keys: usize) -> Self {
+        Self {
+            max_keys,
+            seeded: false,
+            tick: 0,
+            ticks: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn seed(&mut self, index: &dyn IndexReader) {
+        if !self.seeded {
+            for key in index.keys() {
+                self.insert(key);
+            }
+            self.seeded = true;
+        }
+    }
+
+    // Marks a key as the most recently used one
+    fn insert(&mut self, key: String) {
+        self.tick += 1;
+        if let Some(old) = self.ticks.insert(key.clone(), self.tick) {
+            self.order.remove(&old);
+        }
+        self.order.insert(self.tick, key);
+    }
+
+    // Same as insert, but ignores keys that aren't tracked, like ones that were just evicted
+    fn touch(&mut self, key: &str) {
+        if self.ticks.contains_key(key) {
+            self.insert(key.to_owned());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.ticks.clear();
+        self.order.clear();
+    }
+
+    // Stops tracking and returns the least recently used key if there are too many keys
+    fn pop_excess(&mut self) -> Option<String> {
+        if self.ticks.len() <= self.max_keys {
+            return None;
+        }
+        let tick = *self.order.keys().next()?;
+        let key = self.order.remove(&tick)?;
+        self.ticks.remove(&key);
+        Some(key)
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        if let Some(ref group) = self.group_commit {
+            return self.commit_in_group(group, WriteOp::Set(key, value));
+        }
+        self.index_build.wait()?;
+        let key = self.normalize(key);
+        let value = self.encode(value)?;
+        let mut writer = self.lock_writer()?;
+        self.set_locked(&mut writer, key, value, String::new())
+    }
+
+    fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>> {
+        self.index_build.wait()?;
+        let key = self.normalize(key);
+        let value = self.encode(value)?;
+        // Holding the writer while reading keeps other writes from changing the old value
+        let mut writer = self.lock_writer()?;
+        writer.refresh_index();
+        let old = self.read_locked(&mut writer, key.clone())?;
+        self.set_locked(&mut writer, key, value, String::new())?;
+        Ok(old.map(|(value, _)| value))
+    }
+
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        self.index_build.wait()?;
+        let key = self.normalize(key);
+        let value = self.encode(value)?;
+        // Holding the writer while checking keeps other writes from creating the key in between
+        let mut writer = self.lock_writer()?;
+        writer.refresh_index();
+        if self.reader.index.contains_key(&key) && !writer.expired(&key) {
+            return Ok(false);
+        }
+        self.set_locked(&mut writer, key, value, String::new())?;
+        Ok(true)
+    }
+
+    fn remove_and_get_old(&self, key: String) -> Result<Option<String>> {
+        self.index_build.wait()?;
+        let key = self.normalize(key);
+        let mut writer = self.lock_writer()?;
+        writer.refresh_index();
+        let old = self.read_locked(&mut writer, key.clone())?;
+        if old.is_some() {
+            self.remove_locked(&mut writer, key)?;
+        }
+        Ok(old.map(|(value, _)| value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.get_versioned(key)?.map(|(value, _)| value))
+    }
+
+    // Versions come from where the value is in the log, so a compaction gives every key a new one
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        match self.lookup(key.clone())? {
+            Some((value, _, version)) => Ok(Some((value.into_text(&key)?, version))),
+            None => Ok(None),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        if let Some(ref group) = self.group_commit {
+            return self.commit_in_group(group, WriteOp::Remove(key));
+        }
+        self.index_build.wait()?;
+        let key = self.normalize(key);
+        let mut writer = self.lock_writer()?;
+        self.remove_locked(&mut writer, key)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.index_build.wait()?;
+        let mut writer = self.lock_writer()?;
+        writer.clear()?;
+        if let Some(ref recency) = self.recency {
+            recency
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+        Ok(())
+    }
+
+    // Includes stale data and logs from older generations that haven't been removed yet
+    fn disk_size(&self) -> Result<u64> {
+        Ok(self.reader.storage.size()?)
+    }
+
+    fn engine_stats(&self) -> Result<EngineStats> {
+        let stats = self.stats()?;
+        Ok(EngineStats {
+            live_keys: stats.keys as u64,
+            stale_bytes: Some(stats.stale_bytes),
+            disk_bytes: self.disk_size()?,
+        })
+    }
+
+    // Iterates over a snapshot of the keys, skipping keys that get removed along the way
+    fn iter(&self) -> Result<EngineIter<'_>> {
+        self.index_build.wait()?;
+        let keys: Vec<String> = self.reader.index.keys();
+        Ok(Box::new(keys.into_iter().filter_map(
+            move |key| match self.read(key.clone()) {
+                Ok(Some((value, _))) => Some(Ok((key, value))),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            },
+        )))
+    }
+
+    // The index has no order, so each call picks the smallest keys after the cursor out of all of
+    // them. Keys that are gone by the time they're read leave the page short, so the scan picks
+    // again from the last key it read until the page is full or there are no keys left.
+    fn scan(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.index_build.wait()?;
+        let mut pairs = Vec::new();
+        let mut after = after.map(str::to_owned);
+        while pairs.len() < limit {
+            let wanted = limit - pairs.len();
+            let keys = self
+                .reader
+                .index
+                .smallest_keys_after(after.as_deref(), wanted);
+            let exhausted = keys.len() < wanted;
+            for key in keys {
+                after = Some(key.clone());
+                if let Some((value, _)) = self.read(key.clone())? {
+                    pairs.push((key, value));
+                }
+            }
+            if exhausted {
+                break;
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.iter_prefix(prefix)?.collect()
+    }
+
+    // Swaps in the new pairs in one step with replace_all, so a failure part way through leaves
+    // the store as it was
+    fn replace_json<R: Read>(&self, reader: R) -> Result<u64> {
+        let pairs = read_json_pairs(reader)?;
+        let count = pairs.len() as u64;
+        self.replace_all(pairs)?;
+        Ok(count)
+    }
+
+    // Capped stores evict after every set, so they apply the writes one by one, though still
+    // under a single lock
+    fn write_batch(&self, ops: Vec<WriteOp>) -> Result<Vec<Result<()>>> {
+        self.index_build.wait()?;
+        let ops = ops
+            .into_iter()
+            .map(|op| {
+                Ok(match op {
+                    WriteOp::Set(key, value) => {
+                        WriteOp::Set(self.normalize(key), self.encode(value)?)
+                    }
+                    WriteOp::Remove(key) => WriteOp::Remove(self.normalize(key)),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut writer = self.lock_writer()?;
+        if self.recency.is_none() {
+            return writer.write_batch(ops);
+        }
+        Ok(ops
+            .into_iter()
+            .map(|op| match op {
+                WriteOp::Set(key, value) => self.set_locked(&mut writer, key, value, String::new()),
+                WriteOp::Remove(key) => self.remove_locked(&mut writer, key),
+            })
+            .collect())
+    }
+}
+
+impl KvStore {
+    /// Loads the in-memory index of the storage from a file to construct a KvStore
+    pub fn open(dir: &Path) -> Result<Self> {
+        Self::open_with_options(dir, KvStoreOptions::default())
+    }
+
+    /// Same as open, but with options that change how the store behaves.
+    ///
+    /// A directory is only ever opened once per process. While any handle to its store is alive,
+    /// opening the directory again returns another handle to the same store, as long as the
+    /// options are the same as the first time; otherwise it fails with OptionsMismatch. Options
+    /// holding functions or codecs are only the same if they hold the same Arc.
+    pub fn open_with_options(dir: &Path, options: KvStoreOptions) -> Result<Self> {
+        let dir = dir.canonicalize()?;
+        let slot = registry()
+            .lock()
+            .unwrap()
+            .entry(dir.clone())
+            .or_default()
+            .clone();
+        let opened = Self::open_slot(&slot, &dir, options);
+        drop(slot);
+        // A failed open leaves behind a slot that nothing will be registered in
+        if opened.is_err() {
+            prune_slot(&dir);
+        }
+        opened
+    }
+
+    fn open_slot(slot: &Slot, dir: &Path, options: KvStoreOptions) -> Result<Self> {
+        let mut slot = slot.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(registered) = slot.upgrade() {
+            if !registered.options.same_as(&options) {
+                return Err(OptionsMismatch.into());
+            }
+            return Ok(registered.handle());
+        }
+
+        let store = Self::open_with_storage(FileStorage::new(dir), options.clone())?;
+        let registered = Arc::new(Registered {
+            dir: dir.to_owned(),
+            options,
+            store: Mutex::new(store),
+        });
+        *slot = Arc::downgrade(&registered);
+        Ok(registered.handle())
+    }
+
+    /// Same as open_with_options, but keeps the logs in the given storage instead of files in a
+    /// directory. The store opens every log it needs through the storage, so it can be backed by
+    /// memory or any other medium that behaves like a file.
+    pub fn open_with_storage(storage: impl LogStorage, options: KvStoreOptions) -> Result<Self> {
+        let gen = live_generation(&storage)?;
+
+        Self::open_at(Arc::new(storage), gen.unwrap_or(0), false, options)
+    }
+
+    /// Loads the store from the log of a specific generation, even if newer generations exist.
+    /// Meant for recovery tools that need to inspect older data, so the returned store is
+    /// read-only and fails with ReadOnly on any write. Fails if the generation's log doesn't
+    /// exist.
+    pub fn open_generation(dir: &Path, gen: u64) -> Result<Self> {
+        Self::open_at(
+            Arc::new(FileStorage::new(dir)),
+            gen,
+            true,
+            KvStoreOptions::default(),
+        )
+    }
+
+    /// Every write to the key that's still in the log, oldest first, including removes. Unless
+    /// the store was opened with never_compact, writes from before the last compaction are gone.
+    /// Values set with set_bytes come back as the bytes that were set.
+    pub fn history(&self, key: String) -> Result<Vec<HistoryEntry>> {
+        self.index_build.wait()?;
+        let key = self.normalize(key);
+        let history = self.lock_writer()?.history(&key)?;
+        history
+            .into_iter()
+            .map(|(value, offset)| {
+                let value = match value {
+                    Some(StoredValue::Text(value)) => Some(HistoryValue::Text(self.decode(value)?)),
+                    Some(StoredValue::Bytes(value)) => Some(HistoryValue::Bytes(value)),
+                    None => None,
+                };
+                Ok(HistoryEntry { value, offset })
+            })
+            .collect()
+    }
+
+    /// Compact the log right away instead of waiting for enough stale data to pile up
+    pub fn compact(&self) -> Result<()> {
+        self.index_build.wait()?;
+        let mut writer = self.lock_writer()?;
+        writer.check_writable()?;
+        writer.compaction()
+    }
+
+    /// Replace everything in the store with the given pairs, where later pairs win over earlier
+    /// ones with the same key. The pairs are written to the log of a new generation, which then
+    /// takes over in one step like a compaction, so reads see either all of the old data or all
+    /// of the new, never a mix. Other writes wait until the replacement is done. If writing the
+    /// new log fails, the store keeps its old data. Stores with max_keys evict down to the cap on
+    /// the next set.
+    pub fn replace_all(&self, pairs: impl IntoIterator<Item = (String, String)>) -> Result<()> {
+        self.index_build.wait()?;
+        let pairs = pairs
+            .into_iter()
+            .map(|(key, value)| Ok((self.normalize(key), self.encode(value)?)));
+        let mut writer = self.lock_writer()?;
+        writer.replace_all(pairs)?;
+        if let Some(ref recency) = self.recency {
+            let mut recency = recency.lock().unwrap_or_else(PoisonError::into_inner);
+            *recency = Recency::new(recency.max_keys);
+        }
+        Ok(())
+    }
+
+    // Hands compaction off to someone else. Once enough stale data piles up, the next write calls
+    // request instead of compacting, and nothing else is requested until compact_if_due runs.
+    // Returns false without changing anything if compaction was already handed off, since only
+    // one manager can look after a store.
+    pub(crate) fn defer_compaction(&self, request: CompactionRequest) -> bool {
+        // Setting the request is fine even if a panic left the rest of the writer in a bad state
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        if writer.compaction_request.is_some() {
+            return false;
+        }
+        writer.compaction_request = Some(request);
+        writer.compaction_requested = false;
+        true
+    }
+
+    // Makes writes compact on their own again after defer_compaction
+    pub(crate) fn resume_compaction(&self) {
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        writer.compaction_request = None;
+        writer.compaction_requested = false;
+        self.compaction_done.notify_all();
+    }
+
+    /// Wait for the compaction that a write handed off to a KvStoreManager's background thread,
+    /// if there is one that hasn't finished yet. Returns right away when there isn't, including
+    /// for stores that aren't managed, whose writes finish compacting before they return. Also
+    /// returns if the compaction fails, in which case the next write that finds enough stale data
+    /// asks for another one.
+    pub fn wait_for_compaction(&self) -> Result<()> {
+        self.index_build.wait()?;
+        let mut writer = self.lock_writer()?;
+        while writer.compaction_requested {
+            writer = self
+                .compaction_done
+                .wait(writer)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        Ok(())
+    }
+
+    /// Compact only if that would reclaim at least min_reclaim bytes of stale data, so that a
+    /// scheduler doesn't rewrite every live value to get back a few bytes. Returns whether
+    /// compaction ran.
+    pub fn compact_if(&self, min_reclaim: u64) -> Result<bool> {
+        self.index_build.wait()?;
+        let mut writer = self.lock_writer()?;
+        writer.check_writable()?;
+        if writer.stale_bytes < min_reclaim {
+            return Ok(false);
+        }
+        writer.compaction()?;
+        Ok(true)
+    }
+
+    /// Whether a compaction of the store is running right now, no matter what started it. Writes
+    /// that compact on their own do so before returning, so this only ever reads true from
+    /// another thread, while stores managed by a KvStoreManager compact in the background.
+    pub fn is_compacting(&self) -> bool {
+        self.compacting.load(Ordering::SeqCst)
+    }
+
+    /// Make every write made so far visible to reads, for stores opened with
+    /// ReadConsistency::Refreshed. Does nothing for other stores, since their writes are visible
+    /// right away.
+    pub fn sync_reads(&self) -> Result<()> {
+        self.index_build.wait()?;
+        self.lock_writer()?.refresh_index();
+        Ok(())
+    }
+
+    // Compacts if enough stale data has piled up for a write to have done it
+    pub(crate) fn compact_if_due(&self) -> Result<()> {
+        self.index_build.wait()?;
+        let mut writer = self.lock_writer()?;
+        let result = if writer.compaction_due() {
+            writer.compaction()
+        } else {
+            Ok(())
+        };
+        // Even a failed compaction is done with, so the next write can ask for another
+        writer.compaction_requested = false;
+        self.compaction_done.notify_all();
+        result
+    }
+
+    /// Every key in the store, taken from a snapshot of the index. The index has no order, so
+    /// keys come back in an arbitrary order that can change between calls unless sorted is set,
+    /// which sorts them lexicographically at a cost of O(n log n) for n keys on every call. Use
+    /// scan instead to page through large stores in key order.
+    pub fn keys(&self, sorted: bool) -> Result<Vec<String>> {
+        self.index_build.wait()?;
+        let mut keys: Vec<String> = self.reader.index.keys();
+        if sorted {
+            keys.sort_unstable();
+        }
+        Ok(keys)
+    }
+
+    /// Iterate over the pairs whose keys start with the prefix, in key order. Like iter, the keys
+    /// are a snapshot taken up front, while each value is only read once the iterator gets to
+    /// it, and keys removed in the meantime are skipped. The index has no order, so every call
+    /// sorts the matching keys.
+    pub fn iter_prefix(&self, prefix: &str) -> Result<EngineIter<'_>> {
+        self.index_build.wait()?;
+        let mut keys: Vec<String> = self.reader.index.keys();
+        keys.retain(|key| key.starts_with(prefix));
+        keys.sort_unstable();
+        Ok(Box::new(keys.into_iter().filter_map(
+            move |key| match self.read(key.clone()) {
+                Ok(Some((value, _))) => Some(Ok((key, value))),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            },
+        )))
+    }
+
+    /// Every key in the store, in the order the keys were first set. Overwriting a key keeps its
+    /// place, while removing it drops it, so a key that's set again after a remove goes last. The
+    /// order survives compactions and reopening, since the log is kept in this order. Tracking it
+    /// takes two more copies of every key in memory along with about 100 bytes of overhead per
+    /// key, none of which index_memory_bytes counts.
+    pub fn keys_insertion_order(&self) -> Result<Vec<String>> {
+        self.index_build.wait()?;
+        let writer = self.lock_writer()?;
+        Ok(writer.insertion_order.keys().cloned().collect())
+    }
+
+    /// Estimate of the heap memory used by the in-memory index, which grows with the number and
+    /// length of keys. Counts the bytes of every key plus a fixed overhead per entry, doubled
+    /// for the evmap backend, which keeps two copies of the map so reads never wait for writes.
+    /// This is a rough figure for capacity planning, not an exact measurement. While a lazy index
+    /// is still being built it only covers the keys indexed so far.
+    pub fn index_memory_bytes(&self) -> usize {
+        self.reader.index.memory_bytes()
+    }
+
+    /// Number of log handles the store has open across all of its handles, which is the two
+    /// kept by the writer plus every log opened for reading values. Mapped logs count too, even
+    /// though they hold a mapping rather than a file descriptor.
+    pub fn open_fds(&self) -> usize {
+        2 + self.reader.logs.state.lock().unwrap().0
+    }
+
+    // Locks the writer. A panic in the middle of a write poisons the lock and can leave the writer
+    // halfway through a change, so the writer is rebuilt from the log before it's used again.
+    // The lock stays poisoned if that fails, so the next caller tries again.
+    fn lock_writer(&self) -> Result<MutexGuard<'_, KvsWriter>> {
+        match self.writer.lock() {
+            Ok(writer) => Ok(writer),
+            Err(poisoned) => {
+                let mut writer = poisoned.into_inner();
+                writer.recover()?;
+                self.writer.clear_poison();
+                Ok(writer)
+            }
+        }
+    }
+
+    // Adds a write to the current group, leading the group if it's the first, and waits for the
+    // group to be written
+    fn commit_in_group(&self, group: &GroupCommit, op: WriteOp) -> Result<()> {
+        let (sender, receiver) = sync_channel(1);
+        let leader = {
+            let mut pending = group.pending.lock().unwrap();
+            pending.push((op, sender));
+            pending.len() == 1
+        };
+
+        if leader {
+            thread::sleep(group.window);
+            let (ops, senders): (Vec<_>, Vec<_>) = mem::take(&mut *group.pending.lock().unwrap())
+                .into_iter()
+                .unzip();
+            match self.write_batch(ops) {
+                Ok(results) => {
+                    for (sender, result) in senders.into_iter().zip(results) {
+                        let _ = sender.send(result);
+                    }
+                }
+                // Errors can't be cloned, so every write of the group gets a copy of the message
+                Err(err) => {
+                    for sender in senders {
+                        let _ = sender.send(Err(format_err!("Group commit failed: {}", err)));
+                    }
+                }
+            }
+        }
+
+        // The leader only drops the senders without sending if it panicked
+        receiver
+            .recv()
+            .map_err(|_| format_err!("Group commit failed: leader panicked"))?
+    }
+
+    fn set_locked(
+        &self,
+        writer: &mut KvsWriter,
+        key: String,
+        value: String,
+        tag: String,
+    ) -> Result<()> {
+        let cmd = Command::Set {
+            key,
+            value,
+            tag,
+            expires_at: None,
+            written_at: None,
+        };
+        self.write_locked(writer, cmd)
+    }
+
+    // Writes a set, evicting keys if that takes the store over its cap. Needs the writer lock.
+    fn write_locked(&self, writer: &mut KvsWriter, cmd: Command) -> Result<()> {
+        match self.recency {
+            None => writer.set(cmd),
+            Some(ref recency) => {
+                let key = cmd.key_str().to_owned();
+                writer.set(cmd)?;
+                let mut recency = recency.lock().unwrap_or_else(PoisonError::into_inner);
+                recency.seed(&*self.reader.index);
+                recency.insert(key);
+                while let Some(old) = recency.pop_excess() {
+                    match writer.remove(old) {
+                        Err(ref err) if err.downcast_ref::<KeyNotFound>().is_some() => (),
+                        res => res?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn remove_locked(&self, writer: &mut KvsWriter, key: String) -> Result<()> {
+        writer.remove(key.clone())?;
+        if let Some(ref recency) = self.recency {
+            recency
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(&key);
+        }
+        Ok(())
+    }
+
+    // Shared by every get, which all count as a use of the key for Recency
+    fn lookup(&self, key: String) -> Result<Option<(StoredValue, String, u64)>> {
+        self.index_build.wait()?;
+        let key = self.normalize(key);
+        match self.recency {
+            None => self.read_stored(None, key),
+            Some(ref recency) => {
+                let value = self.read_stored(None, key.clone())?;
+                if value.is_some() {
+                    let mut recency = recency.lock().unwrap_or_else(PoisonError::into_inner);
+                    recency.seed(&*self.reader.index);
+                    recency.touch(&key);
+                }
+                Ok(value)
+            }
+        }
+    }
+
+    fn read(&self, key: String) -> Result<Option<(String, u64)>> {
+        Ok(self
+            .read_tagged(key)?
+            .map(|(value, _, version)| (value, version)))
+    }
+
+    fn read_tagged(&self, key: String) -> Result<Option<(String, String, u64)>> {
+        match self.read_stored(None, key.clone())? {
+            Some((value, tag, version)) => Ok(Some((value.into_text(&key)?, tag, version))),
+            None => Ok(None),
+        }
+    }
+
+    // Same as read, for callers that already hold the writer
+    fn read_locked(&self, writer: &mut KvsWriter, key: String) -> Result<Option<(String, u64)>> {
+        match self.read_stored(Some(writer), key.clone())? {
+            Some((value, _, version)) => Ok(Some((value.into_text(&key)?, version))),
+            None => Ok(None),
+        }
+    }
+
+    // Reads a value and its tag, decoding text values, and removing its key if the record turns
+    // out to be corrupt under CorruptRecordPolicy::Repair. Callers that hold the writer pass it
+    // in, while the rest lock it only for the repair.
+    fn read_stored(
+        &self,
+        writer: Option<&mut KvsWriter>,
+        key: String,
+    ) -> Result<Option<(StoredValue, String, u64)>> {
+        match self.reader.get_stored(key) {
+            Err(err) => match err.downcast::<CorruptRecord>() {
+                Ok(corrupt) => {
+                    match writer {
+                        Some(writer) => self.repair(writer, corrupt)?,
+                        None => self.repair(&mut *self.lock_writer()?, corrupt)?,
+                    }
+                    Ok(None)
+                }
+                Err(err) => Err(err),
+            },
+            Ok(Some((StoredValue::Text(value), tag, version))) => {
+                Ok(Some((StoredValue::Text(self.decode(value)?), tag, version)))
+            }
+            Ok(stored) => Ok(stored),
+        }
+    }
+
+    // Removes a key whose record is corrupt, unless a write replaced the record in the meantime.
+    // Read-only stores can't remove anything, so the key just reads as absent.
+    fn repair(&self, writer: &mut KvsWriter, corrupt: CorruptRecord) -> Result<()> {
+        let current = writer.index.generation() == corrupt.gen
+            && writer
+                .lookup(&corrupt.key)
+                .is_some_and(|range| range.start == corrupt.start);
+        if !current || writer.read_only {
+            return Ok(());
+        }
+        warn!("Removing key {} with a corrupt record", corrupt.key);
+        self.remove_locked(writer, corrupt.key)
+    }
+
+    fn normalize(&self, key: String) -> String {
+        match self.normalize_key {
+            Some(ref normalize_key) => normalize_key(&key),
+            None => key,
+        }
+    }
+
+    fn encode(&self, value: String) -> Result<String> {
+        match self.value_codec {
+            Some(ref codec) => codec.encode(value),
+            None => Ok(value),
+        }
+    }
+
+    fn decode(&self, stored: String) -> Result<String> {
+        match self.value_codec {
+            Some(ref codec) => codec.decode(stored),
+            None => Ok(stored),
+        }
+    }
+
+    /// Set a value along with a tag, such as its content type. The tag is kept with the value
+    /// until the key is next written. Plain sets store an empty tag.
+    pub fn set_tagged(&self, key: String, value: String, tag: String) -> Result<()> {
+        // Group commit only batches untagged sets, so tagged ones go straight to the writer
+        self.index_build.wait()?;
+        let key = self.normalize(key);
+        let value = self.encode(value)?;
+        let mut writer = self.lock_writer()?;
+        self.set_locked(&mut writer, key, value, tag)
+    }
+
+    /// Set a value that reads as absent once the TTL has passed, which is rounded up to whole
+    /// seconds of wall clock time. Expired values are only removed from disk by the next
+    /// compaction, and until then still count towards the store's keys. Any later write to the
+    /// key replaces the TTL along with the value.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        // Group commit only batches plain sets, so expiring ones go straight to the writer
+        self.index_build.wait()?;
+        let key = self.normalize(key);
+        let value = self.encode(value)?;
+        let expires_at = expiry_after(ttl)?;
+        let mut writer = self.lock_writer()?;
+        let cmd = Command::Set {
+            key,
+            value,
+            tag: String::new(),
+            expires_at: Some(expires_at),
+            written_at: Some(unix_time()),
+        };
+        self.write_locked(&mut writer, cmd)
+    }
+
+    /// Remove every key whose value expired, returning how many there were. Expired values read
+    /// as absent either way, but otherwise stay in the index until the next compaction, so this
+    /// frees their memory sooner and lets the next compaction count them as stale. All of the
+    /// removes are written at once, and reads of a key that's being swept find nothing whether
+    /// they come before or after it. See KvStoreOptions::sweep_interval to sweep periodically.
+    pub fn sweep_expired(&self) -> Result<u64> {
+        self.index_build.wait()?;
+        self.lock_writer()?.sweep_expired()
+    }
+
+    /// Give the value of the key a new TTL, counted from now, without writing the value again.
+    /// This also works on keys that had no TTL. Returns false if the key has no value, including
+    /// when its value already expired.
+    pub fn touch(&self, key: String, ttl: Duration) -> Result<bool> {
+        self.index_build.wait()?;
+        let key = self.normalize(key);
+        let expires_at = expiry_after(ttl)?;
+        self.lock_writer()?.touch(key, expires_at)
+    }
+
+    /// Get a value along with the tag it was set with, which is empty for plain sets
+    pub fn get_tagged(&self, key: String) -> Result<Option<(String, String)>> {
+        match self.lookup(key.clone())? {
+            Some((value, tag, _)) => Ok(Some((value.into_text(&key)?, tag))),
+            None => Ok(None),
+        }
+    }
+
+    /// Set a key to a value that doesn't have to be text. The bytes are stored as they are,
+    /// without going through the value codec. Gets and the other methods that return values as
+    /// Strings still work on keys set this way as long as the bytes are valid UTF-8, and fail
+    /// with NonUtf8Value otherwise.
+    pub fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        // Group commit only batches text sets, so binary ones go straight to the writer
+        self.index_build.wait()?;
+        let key = self.normalize(key);
+        let mut writer = self.lock_writer()?;
+        let cmd = Command::SetBytes {
+            key,
+            value,
+            expires_at: None,
+            written_at: None,
+        };
+        self.write_locked(&mut writer, cmd)
+    }
+
+    /// Get a value as bytes, whether it was set as text or with set_bytes
+    pub fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        Ok(self.lookup(key)?.map(|(value, _, _)| value.into_bytes()))
+    }
+
+    /// Set a key to whatever resolver returns given its current value, if any, and the incoming
+    /// one. No other write can land between reading the current value and storing the result, so
+    /// this can build keep-max, append or last-writer-wins style updates. Returns the stored value.
+    pub fn set_with_merge(
+        &self,
+        key: String,
+        incoming: String,
+        resolver: impl FnOnce(Option<&str>, &str) -> String,
+    ) -> Result<String> {
+        self.index_build.wait()?;
+        let key = self.normalize(key);
+        let mut writer = self.lock_writer()?;
+        writer.refresh_index();
+        let current = self.read_locked(&mut writer, key.clone())?;
+        let merged = resolver(current.as_ref().map(|(value, _)| value.as_str()), &incoming);
+        let value = self.encode(merged.clone())?;
+        self.set_locked(&mut writer, key, value, String::new())?;
+        Ok(merged)
+    }
+
+    /// Collect current numbers about the store
+    pub fn stats(&self) -> Result<KvStoreStats> {
+        self.index_build.wait()?;
+        // Hold the writer so the numbers all describe the same moment
+        let writer = self.lock_writer()?;
+        Ok(KvStoreStats {
+            keys: self.reader.index.len(),
+            generation: self.reader.index.generation(),
+            stale_bytes: writer.stale_bytes,
+            index_memory_bytes: self.index_memory_bytes(),
+            skewed_records: writer.skewed_records,
+        })
+    }
+
+    /// The most recent successful compactions of this store since it was opened, oldest first.
+    /// Only the last 32 are kept. Compare how much each reclaimed and how long it took to decide
+    /// whether to move the compaction threshold.
+    pub fn compaction_history(&self) -> Result<Vec<CompactionRecord>> {
+        let writer = self.lock_writer()?;
+        Ok(writer.compaction_history.iter().cloned().collect())
+    }
+
+    /// Flush the log and make it durable, then return where it ends. Every write that finished
+    /// before the call is in the log before the checkpoint's offset. A compaction moves the store
+    /// to a new generation, after which checkpoints of older generations no longer point into the
+    /// current log.
+    pub fn checkpoint(&self) -> Result<Checkpoint> {
+        self.index_build.wait()?;
+        self.lock_writer()?.checkpoint()
+    }
+
+    fn open_at(
+        storage: Arc<dyn LogStorage>,
+        gen: u64,
+        read_only: bool,
+        options: KvStoreOptions,
+    ) -> Result<Self> {
+        ensure!(
+            options.max_open_logs != Some(0),
+            "max_open_logs must be positive"
+        );
+        let (index_w, index_r) = new_index(options.index_backend, gen);
+        // Read-only stores never open their log for writing, so the log is never created
+        let writer = if read_only {
+            storage.read(gen)?
+        } else {
+            storage.append(gen)?
+        };
+        let mut writer = BufWriter::new(writer);
+        if !read_only && writer.get_ref().is_empty()? {
+            write_header(&mut writer, gen)?;
+            writer.flush()?;
+        }
+        let reader = BufReader::new(storage.read(gen)?);
+
+        let mut writer = KvsWriter {
+            storage: Arc::clone(&storage),
+            index: index_w,
+            stale_bytes: 0,
+            read_only,
+            never_compact: options.never_compact,
+            compaction_threshold: options.compaction_threshold.unwrap_or(COMPACTION_THRESHOLD),
+            on_compaction: options.on_compaction,
+            consistency: options.read_consistency,
+            unrefreshed: HashMap::new(),
+            compaction_request: None,
+            compaction_history: VecDeque::new(),
+            insertion_order: InsertionOrder::default(),
+            compaction_requested: false,
+            // Replaced with the version found in the header once the index is built
+            version: FORMAT_VERSION,
+            preallocate_bytes: options.preallocate_bytes,
+            max_disk_bytes: options.max_disk_bytes,
+            durability: options.durability,
+            compacting: Arc::new(AtomicBool::new(false)),
+            blob_threshold: options.blob_threshold,
+            blobs: HashMap::new(),
+            // Ids are never reused while a reader could still look for the blob with that id
+            next_blob: storage.blobs()?.into_iter().max().map_or(0, |id| id + 1),
+            expiries: HashMap::new(),
+            touches: Touches::default(),
+            clock_skew_tolerance: options.clock_skew_tolerance.unwrap_or(CLOCK_SKEW_TOLERANCE),
+            skewed_records: 0,
+            writer,
+            reader,
+        };
+        if !read_only {
+            writer.preallocate()?;
+        }
+
+        let reader = KvsReader {
+            storage,
+            mode: options.read_mode,
+            index: index_r,
+            log: RefCell::new(None),
+            logs: Arc::new(LogHandles {
+                max: options.max_open_logs,
+                state: Mutex::new((0, Vec::new())),
+                freed: Condvar::new(),
+            }),
+            timeout: options.read_timeout,
+            on_corrupt: options.on_corrupt_record,
+            touches: Arc::clone(&writer.touches),
+        };
+
+        let compacting = Arc::clone(&writer.compacting);
+        let (index_build, writer) = if options.lazy_index {
+            let index_build = Arc::new(IndexBuild::pending());
+            let writer = Arc::new(Mutex::new(writer));
+
+            let build = Arc::clone(&index_build);
+            let build_writer = Arc::clone(&writer);
+            thread::spawn(move || {
+                // Waiters have to hear about a panic too, or they'd wait forever
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    build_writer.lock().unwrap().build_index()
+                }));
+                build.finish(result.unwrap_or_else(|_| Err(format_err!("Index build panicked"))));
+            });
+
+            (index_build, writer)
+        } else {
+            writer.build_index()?;
+            (
+                Arc::new(IndexBuild::finished()),
+                Arc::new(Mutex::new(writer)),
+            )
+        };
+        if let (Some(interval), false) = (options.sweep_interval, read_only) {
+            spawn_sweeper(Arc::downgrade(&writer), Arc::clone(&index_build), interval);
+        }
+
+        Ok(Self {
+            reader,
+            writer,
+            index_build,
+            normalize_key: options.normalize_key,
+            value_codec: options.value_codec,
+            recency: options
+                .max_keys
+                .map(|max_keys| Arc::new(Mutex::new(Recency::new(max_keys)))),
+            registered: None,
+            group_commit: options.group_commit_window.map(|window| {
+                Arc::new(GroupCommit {
+                    window,
+                    pending: Mutex::new(Vec::new()),
+                })
+            }),
+            compaction_done: Arc::new(Condvar::new()),
+            compacting,
+        })
+    }
+}
+
+// Sweeps the store every interval until every handle to it is gone, which the thread only notices
+// once the next interval is over, see KvStoreOptions::sweep_interval
+fn spawn_sweeper(writer: Weak<Mutex<KvsWriter>>, index_build: Arc<IndexBuild>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        // Stores whose index failed to build fail every operation anyway
+        if index_build.wait().is_err() {
+            return;
+        }
+        // A writer poisoned by a panic is left for the next call on the store to recover
+        let swept = match writer.lock() {
+            Ok(mut writer) => writer.sweep_expired(),
+            Err(_) => continue,
+        };
+        if let Err(err) = swept {
+            error!("Failed to sweep expired keys: {}", err);
+        }
+    });
+}
+
+// Tracks an index that may still be getting built in the background
+struct IndexBuild {
+    // Lets waiters skip the lock once the index is ready
+    ready: AtomicBool,
+    // Holds the result of the build once it's over. Errors are kept as strings since they're
+    // handed out to every waiter.
+    result: Mutex<Option<std::result::Result<(), String>>>,
+    cond: Condvar,
+}
+
+impl IndexBuild {
+    fn pending() -> Self {
+        Self {
+            ready: AtomicBool::new(false),
+            result: Mutex::new(None),
+            cond: Condvar::new(),
+        }
+    }
+
+    fn finished() -> Self {
+        Self {
+            ready: AtomicBool::new(true),
+            result: Mutex::new(Some(Ok(()))),
+            cond: Condvar::new(),
+        }
+    }
+
+    fn finish(&self, result: Result<()>) {
+        match result {
+            Ok(()) => self.ready.store(true, Ordering::SeqCst),
+            Err(ref err) => error!("Failed to build index: {}", err),
+        }
+        *self.result.lock().unwrap() = Some(result.map_err(|err| err.to_string()));
+        self.cond.notify_all();
+    }
+
+    // Block until the build is over, returning an error if it failed
+    fn wait(&self) -> Result<()> {
+        if self.ready.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let mut result = self.result.lock().unwrap();
+        while result.is_none() {
+            result = self.cond.wait(result).unwrap();
+        }
+        match *result {
+            Some(Err(ref err)) => Err(format_err!("Failed to build index: {}", err)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(&format!("kvs_{}.cbor", gen))
+}
+
+fn compacted_log_path(dir: &Path) -> PathBuf {
+    dir.join("kvs_compact.cbor")
+}
+
+fn blob_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("blob_{}", id))
+}
+
+// Parses the id out of the name of a blob file, which like log names has to be exactly what
+// blob_path would produce
+fn parse_blob_id(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    let id = name.strip_prefix("blob_")?.parse().ok()?;
+    if name == format!("blob_{}", id) {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+fn write_header(writer: &mut impl Write, gen: u64) -> Result<()> {
+    to_writer(
+        writer,
+        &LogHeader {
+            version: FORMAT_VERSION,
+            generation: Some(gen),
+        },
+    )?;
+    Ok(())
+}
+
+// Returns the header of a log and leaves the reader right after it. Logs without a header are
+// rewound to the start, since their first command is at offset 0.
+fn read_header(reader: &mut (impl BufRead + Seek)) -> Result<LogHeader> {
+    reader.seek(SeekFrom::Start(0))?;
+    if reader.fill_buf()?.is_empty() {
+        return Ok(LogHeader {
+            version: FORMAT_VERSION,
+            generation: None,
+        });
+    }
+
+    let mut de = Deserializer::from_reader(&mut *reader);
+    let value: serde_cbor::Value = serde::de::Deserialize::deserialize(&mut de)?;
+    match serde_cbor::value::from_value::<LogHeader>(value) {
+        Ok(LogHeader { version, .. }) if version > FORMAT_VERSION => {
+            Err(format_err!("Unsupported log format version {}", version))
+        }
+        Ok(header) => Ok(header),
+        Err(_) => {
+            reader.seek(SeekFrom::Start(0))?;
+            Ok(LogHeader {
+                version: LEGACY_FORMAT,
+                generation: None,
+            })
+        }
+    }
+}
+
+// Fails if the header of a log says it belongs to another generation
+fn check_generation(header: &LogHeader, gen: u64) -> Result<()> {
+    match header.generation {
+        Some(written) if written != gen => Err(format_err!(
+            "Log of generation {} was written for generation {}",
+            gen,
+            written
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Picks the generation a store should open, which is the newest log that agrees with its
+// header. A crash can leave older logs behind after a compaction, and a log can end up under the
+// wrong name if it's copied around by hand. Those are skipped and get removed by the next
+// compaction. Returns None if there are no logs at all.
+fn live_generation(storage: &dyn LogStorage) -> Result<Option<u64>> {
+    let mut gens = storage.generations()?;
+    gens.sort_unstable_by(|a, b| b.cmp(a));
+    for &gen in &gens {
+        // A log with a broken header is still picked, so that the error comes from building its
+        // index like any other corruption
+        let header = match read_header(&mut BufReader::new(storage.read(gen)?)) {
+            Ok(header) => header,
+            Err(_) => return Ok(Some(gen)),
+        };
+        match check_generation(&header, gen) {
+            Ok(()) => return Ok(Some(gen)),
+            Err(err) => warn!("Skipping log: {}", err),
+        }
+    }
+    if gens.is_empty() {
+        Ok(None)
+    } else {
+        Err(format_err!(
+            "No log agrees with the generation in its header"
+        ))
+    }
+}
+
+// Whether an error from reading a log means it ended in the middle of a record
+fn is_torn_record(err: &Error) -> bool {
+    err.downcast_ref::<serde_cbor::error::Error>()
+        .is_some_and(serde_cbor::error::Error::is_eof)
+}
+
+fn read_command(reader: impl Read, version: u32) -> Result<Command> {
+    let mut de = Deserializer::from_reader(reader);
+    if version == LEGACY_FORMAT {
+        let cmd: LegacyCommand = serde::de::Deserialize::deserialize(&mut de)?;
+        Ok(cmd.into())
+    } else {
+        Ok(serde::de::Deserialize::deserialize(&mut de)?)
+    }
+}
+
+// Same as read_command, but for a record that's already in memory
+fn decode_command(bytes: &[u8], version: u32) -> Result<Command> {
+    if version == LEGACY_FORMAT {
+        let cmd: LegacyCommand = from_slice(bytes)?;
+        Ok(cmd.into())
+    } else {
+        Ok(from_slice(bytes)?)
+    }
+}
+
+// Walks every command from the reader's position to the end of the log, passing each one to
+// visit along with its location in the file
+fn scan_log(
+    reader: &mut (impl BufRead + Seek),
+    version: u32,
+    mut visit: impl FnMut(Command, Range) -> Result<()>,
+) -> Result<()> {
+    let mut start = reader.stream_position()?;
+
+    // Check if EOF has been reached
+    while !reader.fill_buf()?.is_empty() {
+        // For some reason calling byte_offset() on CBOR deserializers does not work for
+        // files, so we have to get log offsets using seek() instead.
+        let cmd = read_command(&mut *reader, version)?;
+        let end = reader.stream_position()?;
+        visit(cmd, Range::new((start, end)))?;
+        start = end;
+    }
+
+    Ok(())
+}
+
+// Finds the generations of all log files in a directory, sorted from oldest to newest
+fn log_generations(dir: &Path) -> Result<Vec<u64>> {
+    let mut gens: Vec<u64> = all_log_files(dir, None)?
+        .iter()
+        .filter_map(|path| log_file_generation(path))
+        .collect();
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+// Parses the generation out of the name of a log file. Only names that log_path would produce for
+// that generation count, so kvs_compact.cbor and names like kvs_007.cbor are ignored.
+fn log_file_generation(path: &Path) -> Option<u64> {
+    if path.extension()? != "cbor" {
+        return None;
+    }
+    let stem = path.file_stem()?.to_str()?;
+    let gen = stem.strip_prefix("kvs_")?.parse().ok()?;
+    if stem == format!("kvs_{}", gen) {
+        Some(gen)
+    } else {
+        None
+    }
+}
+
+fn open_read() -> OpenOptions {
+    let mut opt = OpenOptions::new();
+    opt.read(true);
+    opt
+}
+
+fn open_write() -> OpenOptions {
+    let mut opt = OpenOptions::new();
+    opt.append(true);
+    opt
+}
+
+fn all_log_files(dir: &Path, preserve_gen: Option<u64>) -> Result<Vec<PathBuf>> {
+    read_dir(dir)?
+        .map(|entry| {
+            let entry = entry?;
+            let path = entry.path();
+
+            if entry.metadata()?.is_file() {
+                if let (Some(extension), Some(stem)) = (path.extension(), path.file_stem()) {
+                    if extension == "cbor" {
+                        // Wipe out every cbor file except the one that maps to the generation we want
+                        // to keep
+                        let useless = if let Some(gen) = preserve_gen {
+                            stem != &format!("kvs_{}", gen)[..]
+                        } else {
+                            true
+                        };
+
+                        if useless {
+                            return Ok(Some(path));
+                        }
+                    }
+                }
+            }
+
+            Ok(None)
+        })
+        .filter_map(Result::transpose)
+        .collect()
+}
+
+type LogReader = BufReader<Box<dyn LogFile>>;
+
+// Log opened by a reader in one of the read modes
+enum OpenLog {
+    // Records are read into the buffer, which is kept around at the size of the largest record
+    // read so far so that large values don't allocate it again on every read
+    Buffered(LogReader, Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl OpenLog {
+    fn read_header(&mut self) -> Result<LogHeader> {
+        match self {
+            OpenLog::Buffered(reader, _) => read_header(reader),
+            OpenLog::Mapped(map) => read_header(&mut Cursor::new(&map[..])),
+        }
+    }
+
+    // The range gives the exact size of the record, so it's read in one go and decoded from
+    // memory. That lets the value's String be allocated at its final size instead of growing as
+    // it's decoded.
+    fn read_command(&mut self, range: &Range, version: u32) -> Result<Command> {
+        match self {
+            OpenLog::Buffered(reader, bytes) => {
+                reader.seek(SeekFrom::Start(range.start))?;
+                bytes.clear();
+                bytes.reserve_exact(range.len() as usize);
+                reader.take(range.len()).read_to_end(bytes)?;
+                decode_command(bytes, version)
+            }
+            OpenLog::Mapped(map) => {
+                decode_command(&map[range.start as usize..range.end as usize], version)
+            }
+        }
+    }
+
+    // Whether the range can be read from the open log. A mapped log only covers the bytes it had
+    // when it was mapped, so it misses anything appended since.
+    fn covers(&self, range: &Range) -> bool {
+        match self {
+            OpenLog::Buffered(..) => true,
+            OpenLog::Mapped(map) => range.end <= map.len() as u64,
+        }
+    }
+}
+
+// Called by writes that want a compaction done for them, see KvStore::defer_compaction
+pub(crate) type CompactionRequest = Box<dyn Fn() + Send>;
+
+// There will only ever be one writer for every KvStore
+struct KvsWriter {
+    storage: Arc<dyn LogStorage>,
+    writer: BufWriter<Box<dyn LogFile>>,
+    reader: LogReader,
+    index: Box<dyn IndexWriter>,
+    stale_bytes: u64,
+    read_only: bool,
+    never_compact: bool,
+    compaction_threshold: u64,
+    consistency: ReadConsistency,
+    // Index changes made since the last refresh, which only Refreshed stores have. None means
+    // the key was removed.
+    unrefreshed: HashMap<String, Option<Range>>,
+    compaction_request: Option<CompactionRequest>,
+    on_compaction: Option<CompactionListener>,
+    // Most recent successful compactions, oldest first
+    compaction_history: VecDeque<CompactionRecord>,
+    insertion_order: InsertionOrder,
+    // Whether a compaction was requested that hasn't happened yet
+    compaction_requested: bool,
+    // Format of the current log, which new commands are appended in
+    version: u32,
+    preallocate_bytes: Option<u64>,
+    max_disk_bytes: Option<u64>,
+    durability: DurabilityMode,
+    compacting: Arc<AtomicBool>,
+    blob_threshold: Option<usize>,
+    // Blob holding the value of every key whose value isn't inline
+    blobs: HashMap<String, BlobRef>,
+    next_blob: u64,
+    // When the value of every key set with a TTL expires
+    expiries: HashMap<String, u64>,
+    touches: Touches,
+    clock_skew_tolerance: Duration,
+    // Records found by the last index build that were written too far in the future
+    skewed_records: u64,
+}
+
+// Expiries that touches gave the values of keys, which readers can't find in the sets they read.
+// Each one only applies to the set at the same place as when the key was touched, so a later
+// write to the key leaves it behind harmlessly until the next compaction, which folds the live
+// ones into the sets it copies.
+type Touches = Arc<RwLock<HashMap<String, Touched>>>;
+
+#[derive(Debug, Clone, Copy)]
+struct Touched {
+    gen: u64,
+    start: u64,
+    expires_at: u64,
+    written_at: u64,
+}
+
+// Where a value kept outside of the log is
+#[derive(Debug, Clone, Copy)]
+struct BlobRef {
+    id: u64,
+    len: u64,
+}
+
+impl Command {
+    // Blob that the command puts the value of its key in, if any
+    fn blob(&self) -> Option<BlobRef> {
+        match *self {
+            Command::SetBlob { id, len, .. } => Some(BlobRef { id, len }),
+            _ => None,
+        }
+    }
+
+    fn expires_at(&self) -> Option<u64> {
+        match *self {
+            Command::Set { expires_at, .. }
+            | Command::SetBlob { expires_at, .. }
+            | Command::SetBytes { expires_at, .. } => expires_at,
+            _ => None,
+        }
+    }
+
+    fn written_at(&self) -> Option<u64> {
+        match *self {
+            Command::Set { written_at, .. }
+            | Command::SetBlob { written_at, .. }
+            | Command::SetBytes { written_at, .. } => written_at,
+            Command::Touch { written_at, .. } => Some(written_at),
+            Command::Remove { .. } => None,
+        }
+    }
+
+    // Same set with the expiry replaced, which leaves other commands alone
+    fn with_expiry(mut self, new_expiry: u64, new_written_at: u64) -> Command {
+        match self {
+            Command::Set {
+                ref mut expires_at,
+                ref mut written_at,
+                ..
+            }
+            | Command::SetBlob {
+                ref mut expires_at,
+                ref mut written_at,
+                ..
+            }
+            | Command::SetBytes {
+                ref mut expires_at,
+                ref mut written_at,
+                ..
+            } => {
+                *expires_at = Some(new_expiry);
+                *written_at = Some(new_written_at);
+            }
+            _ => (),
+        }
+        self
+    }
+}
+
+// Records the blob that now holds the key's value, if any, returning the size of the blob that
+// held its old value
+fn track_blob(blobs: &mut HashMap<String, BlobRef>, key: &str, blob: Option<BlobRef>) -> u64 {
+    let old = match blob {
+        Some(blob) => blobs.insert(key.to_owned(), blob),
+        None => blobs.remove(key),
+    };
+    old.map_or(0, |blob| blob.len)
+}
+
+// Records when the key's value expires, if it does
+fn track_expiry(expiries: &mut HashMap<String, u64>, key: &str, expires_at: Option<u64>) {
+    match expires_at {
+        Some(expires_at) => expiries.insert(key.to_owned(), expires_at),
+        None => expiries.remove(key),
+    };
+}
+
+// Wall clock time for expiries, which never goes backwards: setting the system clock back while
+// the process runs can't bring expired keys back, while setting it forward, as NTP does to correct
+// a slow clock, is followed right away
+fn now() -> SystemTime {
+    // Latest time returned so far, in nanoseconds since the epoch
+    static LAST: AtomicU64 = AtomicU64::new(0);
+    let wall = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64);
+    let last = LAST.fetch_max(wall, Ordering::SeqCst);
+    UNIX_EPOCH + Duration::from_nanos(last.max(wall))
+}
+
+fn unix_time() -> u64 {
+    now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+// Unix time in seconds at which a value set now with the TTL expires, rounded up so that values
+// never expire early
+fn expiry_after(ttl: Duration) -> Result<u64> {
+    let expires_at = now() + ttl + Duration::from_nanos(999_999_999);
+    Ok(expires_at.duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+fn is_expired(expires_at: Option<u64>) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= unix_time())
+}
+
+// Marks the writer as compacting until it's dropped, even if the compaction fails or panics
+struct Compacting(Arc<AtomicBool>);
+
+impl Compacting {
+    fn new(flag: &Arc<AtomicBool>) -> Self {
+        flag.store(true, Ordering::SeqCst);
+        Self(Arc::clone(flag))
+    }
+}
+
+impl Drop for Compacting {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl KvsWriter {
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(ReadOnly.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn checkpoint(&mut self) -> Result<Checkpoint> {
+        // Read-only stores never write, so their log is as durable as it's going to get
+        if !self.read_only {
+            self.writer.flush()?;
+            self.writer.get_mut().sync()?;
+        }
+        Ok(Checkpoint {
+            generation: self.index.generation(),
+            offset: self.writer.get_ref().len()?,
+        })
+    }
+
+    fn write_command(&mut self, cmd: &Command) -> Result<()> {
+        encode_command(&mut self.writer, cmd, self.version)
+    }
+
+    // Hands what's been written to the OS, and waits for the disk too if the durability mode
+    // asks for it
+    fn commit(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if self.durability == DurabilityMode::Sync {
+            self.writer.get_mut().sync()?;
+        }
+        Ok(())
+    }
+
+    // This is only ever called from open(), so we don't need to worry about synchronization
+    fn build_index(&mut self) -> Result<()> {
+        // Read from the first command after the header
+        let header = read_header(&mut self.reader)?;
+        check_generation(&header, self.index.generation())?;
+        self.version = header.version;
+        let mut index: HashMap<_, Range> = HashMap::new();
+        // The log is in write order, so the order keys first show up in is the insertion order
+        let mut order = InsertionOrder::default();
+        let mut stale_bytes = 0;
+        let mut blobs: HashMap<String, BlobRef> = HashMap::new();
+        let mut expiries = HashMap::new();
+        let mut touches = HashMap::new();
+        let gen = self.index.generation();
+        // Records written after the latest plausible time, and how far past it the latest one is
+        let plausible = unix_time() + self.clock_skew_tolerance.as_secs();
+        let mut skewed = (0, 0);
+        let mut valid_end = self.reader.stream_position()?;
+
+        let scan = scan_log(&mut self.reader, self.version, |cmd, range| {
+            valid_end = range.end;
+            if let Some(written_at) = cmd.written_at().filter(|&at| at > plausible) {
+                skewed.0 += 1;
+                skewed.1 = skewed.1.max(written_at - plausible);
+            }
+            let blob = cmd.blob();
+            let expires_at = cmd.expires_at();
+            match cmd {
+                Command::Set { key, .. }
+                | Command::SetBytes { key, .. }
+                | Command::SetBlob { key, .. } => {
+                    if let Some(old) = index.get(&key) {
+                        stale_bytes += old.len();
+                    }
+                    stale_bytes += track_blob(&mut blobs, &key, blob);
+                    track_expiry(&mut expiries, &key, expires_at);
+                    order.insert(&key);
+                    index.insert(key, range);
+                }
+                Command::Remove { key } => {
+                    match index.get(&key) {
+                        None => {
+                            error!(
+                                "Data corrupted, as remove was found in file before set for key {}",
+                                key
+                            );
+                            return Err(CorruptData.into());
+                        }
+                        Some(old) => stale_bytes += old.len(),
+                    }
+                    stale_bytes += track_blob(&mut blobs, &key, None);
+                    expiries.remove(&key);
+                    order.remove(&key);
+                    index.remove(&key);
+                }
+                // Touches never hold a value, so compaction always drops them
+                Command::Touch {
+                    key,
+                    expires_at,
+                    written_at,
+                } => {
+                    stale_bytes += range.len();
+                    if let Some(set) = index.get(&key) {
+                        let touched = Touched {
+                            gen,
+                            start: set.start,
+                            expires_at,
+                            written_at,
+                        };
+                        touches.insert(key.clone(), touched);
+                        expiries.insert(key, expires_at);
+                    }
+                }
+            };
+            Ok(())
+        });
+        if let Err(err) = scan {
+            if !is_torn_record(&err) {
+                return Err(err);
+            }
+            self.discard_torn_record(valid_end)?;
+        }
+        if skewed.0 > 0 {
+            warn!(
+                "Found {} records written up to {}s past the clock skew tolerance, so the clock \
+                 may have been set back and keys may expire late",
+                skewed.0, skewed.1
+            );
+        }
+        self.skewed_records = skewed.0;
+        // Values that expired are left out only now, since a remove later in the log would
+        // otherwise find nothing to remove
+        expiries.retain(|key, expires_at| {
+            if !is_expired(Some(*expires_at)) {
+                return true;
+            }
+            if let Some(old) = index.remove(key) {
+                stale_bytes += old.len();
+            }
+            stale_bytes += track_blob(&mut blobs, key, None);
+            order.remove(key);
+            false
+        });
+        // Only touches of the set that's still live count
+        touches.retain(|key, touched| index.get(key).is_some_and(|set| set.start == touched.start));
+        self.stale_bytes += stale_bytes;
+        self.insertion_order = order;
+        self.blobs = blobs;
+        self.expiries = expiries;
+        *self.touches.write().unwrap_or_else(PoisonError::into_inner) = touches;
+
+        for (key, range) in index {
+            self.index.insert(key, range);
+        }
+        self.index.refresh();
+
+        Ok(())
+    }
+
+    // A crash in the middle of appending a command leaves part of it at the end of the log. The
+    // command was never acknowledged and isn't in the index, so its bytes are dropped.
+    fn discard_torn_record(&mut self, valid_end: u64) -> Result<()> {
+        let file = self.writer.get_mut();
+        let len = file.len()?;
+        warn!(
+            "Found incomplete record in the last {} bytes of the log",
+            len - valid_end
+        );
+        // Read-only stores never modify the log, so they just skip the torn bytes
+        if !self.read_only {
+            file.set_len(valid_end)?;
+            file.sync()?;
+        }
+        Ok(())
+    }
+
+    // Puts the writer back into a consistent state after a panic interrupted one of its methods.
+    // Whatever the interrupted write had buffered is dropped instead of flushed, along with the
+    // temporary log of an interrupted compaction, which would keep the next one from starting.
+    // Then the index is rebuilt from the log, which also cuts off a record that was only partly
+    // written. A record that was fully written is kept, even though its write never returned.
+    fn recover(&mut self) -> Result<()> {
+        warn!("Rebuilding the store after a panic during a write");
+        let gen = self.index.generation();
+        let file = if self.read_only {
+            self.storage.read(gen)?
+        } else {
+            self.storage.remove_except(gen)?;
+            self.storage.append(gen)?
+        };
+        let interrupted = mem::replace(&mut self.writer, BufWriter::new(file));
+        drop(interrupted.into_parts());
+        self.reader = BufReader::new(self.storage.read(gen)?);
+
+        self.index.purge();
+        self.unrefreshed.clear();
+        self.stale_bytes = 0;
+        self.compaction_requested = false;
+        self.build_index()
+    }
+
+    // Location of a key's value, including writes that haven't been refreshed yet
+    fn lookup(&self, key: &str) -> Option<Range> {
+        match self.unrefreshed.get(key) {
+            Some(range) => range.clone(),
+            None => self.index.get(key),
+        }
+    }
+
+    // Makes a write to the index visible to reads, right away or once the batch is full
+    fn publish(&mut self, key: String, range: Option<Range>) {
+        if self.consistency == ReadConsistency::Latest {
+            self.index.refresh();
+            return;
+        }
+        self.unrefreshed.insert(key, range);
+        if self.unrefreshed.len() >= REFRESH_BATCH {
+            self.refresh_index();
+        }
+    }
+
+    // Makes every write so far visible to reads
+    fn refresh_index(&mut self) {
+        if !self.unrefreshed.is_empty() {
+            self.index.refresh();
+            self.unrefreshed.clear();
+        }
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.check_writable()?;
+        // An expired value is as good as gone, even though it's still in the log
+        let value = self.lookup(&key).filter(|_| !self.expired(&key));
+
+        if let Some(value) = value {
+            let cmd = Command::Remove { key };
+
+            self.write_command(&cmd)?;
+            self.commit()?;
+
+            // Remove key from index AFTER committing the command to disc.
+            // We can use this order for remove and set because the file changes for those
+            // operations are additive, so file updates won't mess up concurrent reads.
+            let key = cmd.key();
+            self.stale_bytes += value.len() + self.track_blob(&key, None);
+            self.expiries.remove(&key);
+            self.insertion_order.remove(&key);
+            self.index.remove(key.clone());
+            self.publish(key, None);
+
+            self.maybe_compact()?;
+            Ok(())
+        } else {
+            Err(KeyNotFound.into())
+        }
+    }
+
+    // Writes a Set or SetBytes command
+    fn set(&mut self, cmd: Command) -> Result<()> {
+        self.check_writable()?;
+        if self.max_disk_bytes.is_some() {
+            let mut bytes = Vec::new();
+            encode_command(&mut bytes, &cmd, self.version)?;
+            self.make_room(bytes.len() as u64)?;
+        }
+        let cmd = self.move_to_blob(cmd, self.version)?;
+
+        // Get the offset of the next command
+        let start = self.writer.seek(SeekFrom::End(0))?;
+        // Write to file
+        self.write_command(&cmd)?;
+        self.commit()?;
+        let end = self.writer.seek(SeekFrom::End(0))?;
+
+        let blob = cmd.blob();
+        let expires_at = cmd.expires_at();
+        let key = cmd.key();
+        // Update stale_bytes if necessary
+        if let Some(old) = self.lookup(&key) {
+            self.stale_bytes += old.len();
+        }
+        self.stale_bytes += self.track_blob(&key, blob);
+        track_expiry(&mut self.expiries, &key, expires_at);
+        // Insert the offset into the index
+        self.insertion_order.insert(&key);
+        self.index.insert(key.clone(), Range::new((start, end)));
+        self.publish(key, Some(Range::new((start, end))));
+
+        self.maybe_compact()
+    }
+
+    // Removes every expired key with a single flush, like write_batch
+    fn sweep_expired(&mut self) -> Result<u64> {
+        self.check_writable()?;
+        let expired: Vec<String> = self
+            .expiries
+            .iter()
+            .filter(|&(_, &expires_at)| is_expired(Some(expires_at)))
+            .map(|(key, _)| key.clone())
+            .collect();
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        let mut bytes = Vec::new();
+        for key in &expired {
+            encode_command(
+                &mut bytes,
+                &Command::Remove { key: key.clone() },
+                self.version,
+            )?;
+        }
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.write_all(&bytes)?;
+        self.commit()?;
+
+        for key in &expired {
+            if let Some(old) = self.lookup(key) {
+                self.stale_bytes += old.len();
+            }
+            self.stale_bytes += self.track_blob(key, None);
+            self.expiries.remove(key);
+            self.insertion_order.remove(key);
+            self.index.remove(key.clone());
+        }
+        match self.consistency {
+            ReadConsistency::Latest => self.index.refresh(),
+            _ => {
+                for key in &expired {
+                    self.publish(key.clone(), None);
+                }
+            }
+        }
+
+        self.maybe_compact()?;
+        Ok(expired.len() as u64)
+    }
+
+    // Gives the live value of the key a new expiry, returning whether there was one
+    fn touch(&mut self, key: String, expires_at: u64) -> Result<bool> {
+        self.check_writable()?;
+        let written_at = unix_time();
+        let cmd = Command::Touch {
+            key,
+            expires_at,
+            written_at,
+        };
+        if self.max_disk_bytes.is_some() {
+            let mut bytes = Vec::new();
+            encode_command(&mut bytes, &cmd, self.version)?;
+            self.make_room(bytes.len() as u64)?;
+        }
+        // Looked up only now, since making room can compact
+        let set = match self.lookup(cmd.key_str()) {
+            Some(set) if !self.expired(cmd.key_str()) => set,
+            _ => return Ok(false),
+        };
+
+        let start = self.writer.seek(SeekFrom::End(0))?;
+        self.write_command(&cmd)?;
+        self.commit()?;
+        let end = self.writer.seek(SeekFrom::End(0))?;
+
+        let key = cmd.key();
+        self.stale_bytes += end - start;
+        self.expiries.insert(key.clone(), expires_at);
+        let touched = Touched {
+            gen: self.index.generation(),
+            start: set.start,
+            expires_at,
+            written_at,
+        };
+        self.touches
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, touched);
+
+        self.maybe_compact()?;
+        Ok(true)
+    }
+
+    // Writes every command of the batch with a single flush, and only then updates the index,
+    // since readers can only see what's been flushed
+    fn write_batch(&mut self, ops: Vec<WriteOp>) -> Result<Vec<Result<()>>> {
+        self.check_writable()?;
+        // Room is made before anything is looked up, since compacting moves every record
+        if self.max_disk_bytes.is_some() {
+            let mut bytes = Vec::new();
+            for op in &ops {
+                if let WriteOp::Set(key, value) = op {
+                    let cmd = Command::Set {
+                        key: key.clone(),
+                        value: value.clone(),
+                        tag: String::new(),
+                        expires_at: None,
+                        written_at: None,
+                    };
+                    encode_command(&mut bytes, &cmd, self.version)?;
+                }
+            }
+            self.make_room(bytes.len() as u64)?;
+        }
+        let start = self.writer.seek(SeekFrom::End(0))?;
+        let mut bytes = Vec::new();
+        let mut results = Vec::with_capacity(ops.len());
+        // Where every key written by the batch ends up, in the order of the writes, along with
+        // the blob its value is in
+        let mut changes: Vec<(String, Option<Range>, Option<BlobRef>)> = Vec::new();
+        let mut written: HashMap<String, Option<Range>> = HashMap::new();
+
+        for op in ops {
+            let (key, cmd) = match op {
+                WriteOp::Set(key, value) => (
+                    key.clone(),
+                    Command::Set {
+                        key,
+                        value,
+                        tag: String::new(),
+                        expires_at: None,
+                        written_at: None,
+                    },
+                ),
+                WriteOp::Remove(key) => (key.clone(), Command::Remove { key }),
+            };
+            let old = match written.get(&key) {
+                Some(range) => range.clone(),
+                None => self.lookup(&key),
+            };
+            // Values set by the batch itself never expire
+            let live = old.is_some() && (written.contains_key(&key) || !self.expired(&key));
+            if let (Command::Remove { .. }, false) = (&cmd, live) {
+                results.push(Err(KeyNotFound.into()));
+                continue;
+            }
+            let cmd = self.move_to_blob(cmd, self.version)?;
+
+            let offset = start + bytes.len() as u64;
+            encode_command(&mut bytes, &cmd, self.version)?;
+            let range = match cmd {
+                Command::Set { .. } | Command::SetBytes { .. } | Command::SetBlob { .. } => {
+                    Some(Range::new((offset, start + bytes.len() as u64)))
+                }
+                Command::Remove { .. } | Command::Touch { .. } => None,
+            };
+            if let Some(old) = old {
+                self.stale_bytes += old.len();
+            }
+            written.insert(key.clone(), range.clone());
+            changes.push((key, range, cmd.blob()));
+            results.push(Ok(()));
+        }
 
-            // Update new index with offsets in the new file
-            new_offsets.push((key, (new_offset, new_offset + offset.len())));
+        self.writer.write_all(&bytes)?;
+        self.commit()?;
+
+        // Refreshing once for the whole batch is the point, so Latest stores skip publish
+        let latest = self.consistency == ReadConsistency::Latest;
+        for (key, range, blob) in changes {
+            // Blobs are only tracked once the log refers to them, so a failed write can't get a
+            // blob that's still in use deleted
+            self.stale_bytes += self.track_blob(&key, blob);
+            // Batches never set a TTL
+            self.expiries.remove(&key);
+            match range {
+                Some(ref range) => {
+                    self.insertion_order.insert(&key);
+                    self.index.insert(key.clone(), range.clone());
+                }
+                None => {
+                    self.insertion_order.remove(&key);
+                    self.index.remove(key.clone());
+                }
+            };
+            if !latest {
+                self.publish(key, range);
+            }
+        }
+        if latest {
+            self.index.refresh();
+        }
+
+        self.maybe_compact()?;
+        Ok(results)
+    }
+
+    // Makes sure the logs stay within max_disk_bytes once the given bytes of sets are appended,
+    // compacting if they wouldn't
+    fn make_room(&mut self, bytes: u64) -> Result<()> {
+        let max = match self.max_disk_bytes {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        if self.storage.size()? + bytes <= max {
+            return Ok(());
+        }
+        if !self.never_compact && self.stale_bytes > 0 {
+            self.compaction()?;
+            if self.storage.size()? + bytes <= max {
+                return Ok(());
+            }
+        }
+        warn!("Refusing write of {} bytes over the disk cap", bytes);
+        Err(DiskFull.into())
+    }
+
+    fn compaction_due(&self) -> bool {
+        !self.read_only && !self.never_compact && self.stale_bytes > self.compaction_threshold
+    }
+
+    fn maybe_compact(&mut self) -> Result<()> {
+        if !self.compaction_due() {
+            return Ok(());
+        }
+        match self.compaction_request {
+            Some(ref request) => {
+                if !self.compaction_requested {
+                    self.compaction_requested = true;
+                    request();
+                }
+                Ok(())
+            }
+            None => self.compaction(),
+        }
+    }
+
+    // Every set and remove of the key in the current log, oldest first, as the value it was set to
+    // or None for removes, along with the offset of its record
+    fn history(&mut self, key: &str) -> Result<Vec<(Option<StoredValue>, u64)>> {
+        let version = read_header(&mut self.reader)?.version;
+        let storage = Arc::clone(&self.storage);
+        let mut entries = Vec::new();
+        scan_log(&mut self.reader, version, |cmd, range| {
+            match cmd {
+                Command::Set { key: k, value, .. } if k == key => {
+                    entries.push((Some(StoredValue::Text(value)), range.start))
+                }
+                Command::SetBytes { key: k, value, .. } if k == key => {
+                    entries.push((Some(StoredValue::Bytes(value)), range.start))
+                }
+                // Blobs are only deleted by moving to a new log, so every blob in this one exists
+                Command::SetBlob {
+                    key: k, id, binary, ..
+                } if k == key => {
+                    let value = storage.read_blob(id)?;
+                    let value = if binary {
+                        StoredValue::Bytes(value)
+                    } else {
+                        StoredValue::Text(String::from_utf8(value).map_err(|_| CorruptData)?)
+                    };
+                    entries.push((Some(value), range.start))
+                }
+                Command::Remove { key: k } if k == key => entries.push((None, range.start)),
+                _ => (),
+            }
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+
+    // Moves to a new generation with an empty log instead of truncating the current one, so that
+    // readers holding the old log see the generation change and reopen
+    fn clear(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let new_gen = self.index.generation() + 1;
+        let mut compact_file = BufWriter::new(self.storage.create_temp()?);
+        write_header(&mut compact_file, new_gen)?;
+        compact_file.flush()?;
+        compact_file.get_mut().sync()?;
+
+        self.storage.commit_temp(new_gen)?;
+        self.start_generation(new_gen)?;
+
+        // Update index and generation
+        self.index.purge();
+        self.index.set_generation(new_gen);
+        self.index.refresh();
+        self.unrefreshed.clear();
+        self.insertion_order = InsertionOrder::default();
+        self.blobs.clear();
+        self.expiries.clear();
+        self.clear_touches();
+
+        self.remove_stale_logs(new_gen)
+    }
+
+    fn compaction(&mut self) -> Result<()> {
+        let _compacting = Compacting::new(&self.compacting);
+        let listener = self.on_compaction.clone();
+        let stale_bytes = self.stale_bytes;
+        if let Some(ref listener) = listener {
+            listener(CompactionEvent::Started { stale_bytes });
+        }
+        let start = Instant::now();
+        let size = self.storage.size()?;
+        self.rewrite_log()?;
+
+        let record = CompactionRecord {
+            finished_at: SystemTime::now(),
+            stale_bytes,
+            reclaimed_bytes: size.saturating_sub(self.storage.size()?),
+            duration: start.elapsed(),
+        };
+        if self.compaction_history.len() == COMPACTION_HISTORY_LEN {
+            self.compaction_history.pop_front();
+        }
+        self.compaction_history.push_back(record);
+        if let Some(listener) = listener {
+            listener(CompactionEvent::Finished {
+                reclaimed_bytes: record.reclaimed_bytes,
+                duration: record.duration,
+            });
         }
+        Ok(())
+    }
+
+    // Copies the live records into the log of a new generation and switches over to it
+    fn rewrite_log(&mut self) -> Result<()> {
+        // The live set is read from the index, so it has to hold every write
+        self.refresh_index();
+        let new_gen = self.index.generation() + 1;
+        let mut compact_file = BufWriter::new(self.storage.create_temp()?);
+        // Compaction always writes the current format, which migrates legacy logs
+        write_header(&mut compact_file, new_gen)?;
+
+        // The following operations modify multiple object state, and failure at any point must
+        // guarantee a consistent object state (reader, writer, index all refer to same file).
+        // Also, even on a panic the disc data we care about must not be corrupted.
+
+        let mut new_offsets = Vec::with_capacity(self.index.len());
+        // Use our index to figure out what data is fresh, and copy it in insertion order so that
+        // the order can be rebuilt from the new log
+        // Expired values are dropped instead of copied, which is what finally reclaims them
+        let (expired, mut entries): (Vec<_>, Vec<_>) = self
+            .index
+            .entries()
+            .into_iter()
+            .partition(|(key, _)| self.expired(key));
+        entries.sort_unstable_by_key(|(key, _)| self.insertion_order.seqs.get(key).copied());
+        let gen = self.index.generation();
+        let touches = self
+            .touches
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        for (key, offset) in entries {
+            self.reader.seek(SeekFrom::Start(offset.start))?;
+            let new_offset = compact_file.stream_position()?;
+            let touched = touches
+                .get(&key)
+                .filter(|touched| touched.gen == gen && touched.start == offset.start);
 
-        let new_gen = self.index.meta().unwrap() + 1;
-        let new_log_path = log_path(&self.dir, new_gen);
+            let new_len = if let Some(touched) = touched {
+                let cmd = read_command(&mut self.reader, self.version)?;
+                let cmd = cmd.with_expiry(touched.expires_at, touched.written_at);
+                encode_command(&mut compact_file, &cmd, FORMAT_VERSION)?;
+                compact_file.stream_position()? - new_offset
+            } else if self.version != LEGACY_FORMAT {
+                let mut bytes = self.reader.by_ref().bytes();
+                for _ in 0..offset.len() {
+                    let buf = [bytes.next().ok_or(CorruptData)??];
+                    compact_file.write_all(&buf)?;
+                }
+                offset.len()
+            } else {
+                let cmd = read_command(&mut self.reader, self.version)?;
+                to_writer(&mut compact_file, &cmd)?;
+                compact_file.stream_position()? - new_offset
+            };
+
+            // Update new index with offsets in the new file
+            new_offsets.push((key, Range::new((new_offset, new_offset + new_len))));
+        }
 
         // Do compact file writes and renames first, since failing those operations don't affect
-        // our current readers and writer.
+        // our current readers and writer. The new log is synced before it replaces the old one,
+        // which is removed afterwards, so a crash of the machine can't leave only a partial copy.
         compact_file.flush()?;
-        rename(&compact_path, &new_log_path)?;
+        compact_file.get_mut().sync()?;
+        self.storage.commit_temp(new_gen)?;
 
         // Next create file handles to the new compacted files. If this fails we fall back to using
         // the uncompacted file.
-        let writer = open_write().open(&new_log_path)?;
-        let reader = open_read().open(&new_log_path)?;
+        self.start_generation(new_gen)?;
 
         // Finally we do the infallible mutations, including index and generation updates.
+        self.index.set_generation(new_gen);
+        for (k, o) in new_offsets {
+            self.index.insert(k, o);
+        }
+        for (key, _) in expired {
+            self.track_blob(&key, None);
+            self.expiries.remove(&key);
+            self.insertion_order.remove(&key);
+            self.index.remove(key);
+        }
+        self.index.refresh();
+        // Readers have moved on to the new log, where the touches are part of the sets
+        self.clear_touches();
+
+        self.remove_stale_logs(new_gen)
+    }
+
+    // Fills the log of a new generation with the pairs and switches over to it, swapping the whole
+    // index in a single refresh. An error from any of the pairs fails the whole replacement.
+    fn replace_all(&mut self, pairs: impl Iterator<Item = Result<(String, String)>>) -> Result<()> {
+        self.check_writable()?;
+        let gen = self.index.generation();
+        let new_gen = gen + 1;
+        let written = self.write_pairs(new_gen, pairs).and_then(|offsets| {
+            self.storage.commit_temp(new_gen)?;
+            Ok(offsets)
+        });
+        let (offsets, order, stale_bytes, blobs) = match written {
+            Ok(written) => written,
+            Err(err) => {
+                // Leaving the temporary log behind would keep the next compaction from starting
+                if let Err(err) = self.storage.remove_except(gen) {
+                    error!("Failed to remove temporary log: {}", err);
+                }
+                return Err(err);
+            }
+        };
+        self.start_generation(new_gen)?;
+
+        self.index.purge();
+        self.index.set_generation(new_gen);
+        for (key, offset) in offsets {
+            self.index.insert(key, Range::new(offset));
+        }
+        self.index.refresh();
+        self.unrefreshed.clear();
+        self.stale_bytes = stale_bytes;
+        self.insertion_order = order;
+        self.blobs = blobs;
+        self.expiries.clear();
+        self.clear_touches();
+
+        self.remove_stale_logs(new_gen)
+    }
+
+    // Writes the pairs into the temporary log, returning where each key ended up and the order of
+    // the keys, along with the size of the pairs that later ones overwrote and the blobs of the
+    // values that aren't inline
+    fn write_pairs(
+        &mut self,
+        gen: u64,
+        pairs: impl Iterator<Item = Result<(String, String)>>,
+    ) -> Result<(Offsets, InsertionOrder, u64, HashMap<String, BlobRef>)> {
+        let mut file = BufWriter::new(self.storage.create_temp()?);
+        write_header(&mut file, gen)?;
+        let mut offset = file.stream_position()?;
+
+        let mut offsets = HashMap::new();
+        let mut order = InsertionOrder::default();
+        let mut stale_bytes = 0;
+        let mut blobs = HashMap::new();
+        let mut bytes = Vec::new();
+        for pair in pairs {
+            let (key, value) = pair?;
+            order.insert(&key);
+            let cmd = Command::Set {
+                key,
+                value,
+                tag: String::new(),
+                expires_at: None,
+                written_at: None,
+            };
+            let cmd = self.move_to_blob(cmd, FORMAT_VERSION)?;
+            bytes.clear();
+            encode_command(&mut bytes, &cmd, FORMAT_VERSION)?;
+            file.write_all(&bytes)?;
+            let end = offset + bytes.len() as u64;
+            let blob = cmd.blob();
+            let key = cmd.key();
+            stale_bytes += track_blob(&mut blobs, &key, blob);
+            if let Some((start, end)) = offsets.insert(key, (offset, end)) {
+                stale_bytes += end - start;
+            }
+            offset = end;
+        }
+        file.flush()?;
+        file.get_mut().sync()?;
+        Ok((offsets, order, stale_bytes, blobs))
+    }
+
+    // Points the writer at the log of a new generation, which must already exist and start with a
+    // header. Doesn't touch the index.
+    fn start_generation(&mut self, gen: u64) -> Result<()> {
+        let writer = self.storage.append(gen)?;
+        let reader = self.storage.read(gen)?;
+
         self.writer = BufWriter::new(writer);
         self.reader = BufReader::new(reader);
         self.stale_bytes = 0;
+        self.compaction_requested = false;
+        self.version = FORMAT_VERSION;
+        self.preallocate()
+    }
 
-        self.index.set_meta(new_gen);
-        for (k, o) in new_offsets {
-            self.index.update(k, o);
+    fn preallocate(&mut self) -> Result<()> {
+        if let Some(len) = self.preallocate_bytes {
+            self.writer.get_mut().preallocate(len)?;
         }
-        self.index.refresh();
+        Ok(())
+    }
 
-        // On Windows removing files still open by reader will fail, so we don't worry too much
-        // about it
-        for file in all_log_files(&self.dir, Some(new_gen))? {
-            if let Err(err) = remove_file(&file) {
-                error!(
-                    "Failed to remove {} during compaction: {}",
-                    file.display(),
-                    err
-                );
+    // Also deletes the blobs that no key refers to anymore, which is only safe once readers have
+    // moved on to the new generation, since a read that started earlier may still look for them
+    fn remove_stale_logs(&self, gen: u64) -> Result<()> {
+        self.storage.remove_except(gen)?;
+        let live: HashSet<u64> = self.blobs.values().map(|blob| blob.id).collect();
+        for id in self.storage.blobs()? {
+            if !live.contains(&id) {
+                if let Err(err) = self.storage.remove_blob(id) {
+                    error!("Failed to remove blob {}: {}", id, err);
+                }
             }
         }
-
         Ok(())
     }
+
+    // Puts the value of a set in a blob if it's over the threshold. Legacy logs can't refer to
+    // blobs, so their values always stay inline.
+    fn move_to_blob(&mut self, cmd: Command, version: u32) -> Result<Command> {
+        let threshold = match self.blob_threshold {
+            Some(threshold) if version != LEGACY_FORMAT => threshold,
+            _ => return Ok(cmd),
+        };
+        let (expires_at, written_at) = (cmd.expires_at(), cmd.written_at());
+        let (key, value, tag, binary) = match cmd {
+            Command::Set {
+                key, value, tag, ..
+            } if value.len() > threshold => (key, value.into_bytes(), tag, false),
+            Command::SetBytes { key, value, .. } if value.len() > threshold => {
+                (key, value, String::new(), true)
+            }
+            cmd => return Ok(cmd),
+        };
+        let id = self.next_blob;
+        self.next_blob += 1;
+        self.storage.write_blob(id, &value)?;
+        Ok(Command::SetBlob {
+            key,
+            id,
+            len: value.len() as u64,
+            tag,
+            binary,
+            expires_at,
+            written_at,
+        })
+    }
+
+    fn track_blob(&mut self, key: &str, blob: Option<BlobRef>) -> u64 {
+        track_blob(&mut self.blobs, key, blob)
+    }
+
+    fn expired(&self, key: &str) -> bool {
+        is_expired(self.expiries.get(key).copied())
+    }
+
+    fn clear_touches(&self) {
+        self.touches
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+}
+
+// Appends a command to a log in the log's format
+fn encode_command(writer: impl Write, cmd: &Command, version: u32) -> Result<()> {
+    match (version, cmd) {
+        (LEGACY_FORMAT, Command::Set { tag, .. }) if !tag.is_empty() => {
+            return Err(format_err!("Tags can't be written to a legacy log"))
+        }
+        (LEGACY_FORMAT, Command::Set { expires_at, .. }) if expires_at.is_some() => {
+            return Err(format_err!(
+                "Expiring keys can't be written to a legacy log"
+            ))
+        }
+        (LEGACY_FORMAT, Command::Set { key, value, .. }) => to_writer(
+            writer,
+            &LegacyCommand::Set {
+                key: key.clone(),
+                value: value.clone(),
+            },
+        )?,
+        (LEGACY_FORMAT, Command::Remove { key }) => {
+            to_writer(writer, &LegacyCommand::Remove { key: key.clone() })?
+        }
+        (LEGACY_FORMAT, Command::SetBlob { .. }) => {
+            return Err(format_err!("Blobs can't be referred to from a legacy log"))
+        }
+        (LEGACY_FORMAT, Command::SetBytes { .. }) => {
+            return Err(format_err!(
+                "Binary values can't be written to a legacy log"
+            ))
+        }
+        (LEGACY_FORMAT, Command::Touch { .. }) => {
+            return Err(format_err!("Touches can't be written to a legacy log"))
+        }
+        _ => to_writer(writer, cmd)?,
+    }
+    Ok(())
+}
+
+// Value as it's read from the log. Values set with set_bytes stay bytes until it's known whether
+// the caller wants them as text.
+enum StoredValue {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl StoredValue {
+    fn into_text(self, key: &str) -> Result<String> {
+        match self {
+            StoredValue::Text(value) => Ok(value),
+            StoredValue::Bytes(value) => utf8_value(key, value),
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            StoredValue::Text(value) => value.into_bytes(),
+            StoredValue::Bytes(value) => value,
+        }
+    }
+}
+
+fn utf8_value(key: &str, value: Vec<u8>) -> Result<String> {
+    String::from_utf8(value).map_err(|_| {
+        NonUtf8Value {
+            key: key.to_owned(),
+        }
+        .into()
+    })
 }
 
 // There can be multiple readers running concurrently with one writer
 struct KvsReader {
-    dir: Arc<PathBuf>,
-    reader: RefCell<(Option<BufReader<File>>, u64)>,
-    index: evmap::ReadHandle<String, (u64, u64), u64>,
+    storage: Arc<dyn LogStorage>,
+    mode: ReadMode,
+    // Log kept open by this handle between reads, which is always None when logs are pooled
+    log: RefCell<Option<ReaderLog>>,
+    logs: Arc<LogHandles>,
+    index: Box<dyn IndexReader>,
+    timeout: Option<Duration>,
+    on_corrupt: CorruptRecordPolicy,
+    touches: Touches,
+}
+
+// Log opened by a reader, along with its generation and format version
+struct ReaderLog {
+    log: OpenLog,
+    gen: u64,
+    version: u32,
+    slot: LogSlot,
+}
+
+// Counts the logs opened by every reader of a store. When logs are pooled it also caps them and
+// holds the ones that aren't checked out.
+struct LogHandles {
+    max: Option<usize>,
+    // Number of open logs, and the pooled logs that are free to check out
+    state: Mutex<(usize, Vec<ReaderLog>)>,
+    freed: Condvar,
+}
+
+// Counts as one open log until dropped. Pooled logs hold on to their pool, so this only holds on
+// weakly to avoid a cycle.
+struct LogSlot(Weak<LogHandles>);
+
+impl Drop for LogSlot {
+    fn drop(&mut self) {
+        if let Some(handles) = self.0.upgrade() {
+            handles.state.lock().unwrap().0 -= 1;
+            handles.freed.notify_one();
+        }
+    }
+}
+
+// What a reader gets to read from: a log that may or may not be usable, or a slot to open one in
+enum Checkout {
+    Log(ReaderLog),
+    Slot(LogSlot),
+}
+
+// Reads a record on a helper thread, waiting for it for at most the timeout. The helper owns the
+// log while it reads, so if it times out the log stays with it, still counted as open, and the
+// caller's reader is left without one.
+fn read_with_timeout(
+    mut log: ReaderLog,
+    range: Range,
+    timeout: Duration,
+) -> Result<(ReaderLog, Result<Command>)> {
+    let (sender, receiver) = sync_channel(1);
+    thread::Builder::new()
+        .name("kvs-read".to_owned())
+        .spawn(move || {
+            let cmd = log.log.read_command(&range, log.version);
+            // Only fails if the caller already gave up
+            let _ = sender.send((log, cmd));
+        })?;
+    receiver.recv_timeout(timeout).map_err(|err| match err {
+        RecvTimeoutError::Timeout => Timeout.into(),
+        RecvTimeoutError::Disconnected => format_err!("Read thread panicked"),
+    })
 }
 
 impl KvsReader {
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let (offset, current_gen) = self.index.meta_get_and(&key, |v| Range::new(v[0])).unwrap();
+    // Expiry that a touch gave the set at the given place, if any
+    fn touched(&self, key: &str, gen: u64, start: u64) -> Option<u64> {
+        let touches = self.touches.read().unwrap_or_else(PoisonError::into_inner);
+        touches
+            .get(key)
+            .filter(|touched| touched.gen == gen && touched.start == start)
+            .map(|touched| touched.expires_at)
+    }
 
-        let (mut reader, mut gen) = RefMut::map_split(self.reader.borrow_mut(), |(r, g)| (r, g));
-        if current_gen > *gen || reader.is_none() {
-            *reader = Some(BufReader::new(
-                open_read().open(&log_path(&self.dir, current_gen))?,
-            ));
-            *gen = current_gen;
+    // Returns the value along with its tag and version
+    fn get_stored(&self, key: String) -> Result<Option<(StoredValue, String, u64)>> {
+        loop {
+            // The offset is copied out so the index's read guard is released before any I/O. A
+            // guard held across a slow read would make the writer's refresh wait for it, which
+            // would hold up every write.
+            let (offset, current_gen) = self.index.get_with_generation(&key);
+            let offset = match offset {
+                Some(offset) => offset,
+                None => return Ok(None),
+            };
+            let version = value_version(current_gen, offset.start);
+
+            // Any generation change means the open log is no longer the live one, even when the
+            // file with the same name still exists
+            let log = match self.checkout(current_gen)? {
+                Checkout::Log(log) if log.gen == current_gen && log.log.covers(&offset) => Ok(log),
+                Checkout::Log(log) => self.open_log(current_gen, log.slot),
+                Checkout::Slot(slot) => self.open_log(current_gen, slot),
+            };
+            let log = match log {
+                Ok(log) => log,
+                // A compaction can finish and delete the log between reading the index and
+                // opening the file. In that case the index has already moved on to a newer
+                // generation, so read it again instead of failing.
+                Err(ref err)
+                    if err
+                        .downcast_ref::<std::io::Error>()
+                        .is_some_and(|err| err.kind() == ErrorKind::NotFound)
+                        && self.index.generation() > current_gen =>
+                {
+                    continue
+                }
+                Err(err) => return Err(err),
+            };
+
+            let (log, cmd) = match self.timeout {
+                Some(timeout) => read_with_timeout(log, offset.clone(), timeout)?,
+                None => {
+                    let mut log = log;
+                    let cmd = log.log.read_command(&offset, log.version);
+                    (log, cmd)
+                }
+            };
+            self.checkin(log);
+            // Expired values stay in the log until the next compaction, which could be after any
+            // number of restarts, so every read has to check
+            if let Ok(ref found) = cmd {
+                let expires_at = self
+                    .touched(&key, current_gen, offset.start)
+                    .or_else(|| found.expires_at());
+                if found.key_str() == key && is_expired(expires_at) {
+                    return Ok(None);
+                }
+            }
+            return match cmd {
+                Ok(Command::Set {
+                    key: found,
+                    value,
+                    tag,
+                    ..
+                }) if found == key => Ok(Some((StoredValue::Text(value), tag, version))),
+                Ok(Command::SetBytes {
+                    key: found, value, ..
+                }) if found == key => Ok(Some((StoredValue::Bytes(value), String::new(), version))),
+                Ok(Command::SetBlob {
+                    key: found,
+                    id,
+                    tag,
+                    binary,
+                    ..
+                }) if found == key => match self.storage.read_blob(id) {
+                    Ok(bytes) if binary => Ok(Some((StoredValue::Bytes(bytes), tag, version))),
+                    Ok(bytes) => match String::from_utf8(bytes) {
+                        Ok(value) => Ok(Some((StoredValue::Text(value), tag, version))),
+                        Err(_) => self.corrupt_record(key, current_gen, offset),
+                    },
+                    // Like the log, the blob can be deleted by a compaction that finished after
+                    // the index was read
+                    Err(ref err)
+                        if err.kind() == ErrorKind::NotFound
+                            && self.index.generation() > current_gen =>
+                    {
+                        continue
+                    }
+                    Err(err) => Err(err.into()),
+                },
+                // The log couldn't be read at all, which says nothing about the record
+                Err(err) if err.downcast_ref::<std::io::Error>().is_some() => Err(err),
+                _ => self.corrupt_record(key, current_gen, offset),
+            };
+        }
+    }
+
+    // Handles a record that doesn't decode into a set of its key, according to the policy
+    fn corrupt_record<T>(&self, key: String, gen: u64, range: Range) -> Result<Option<T>> {
+        error!(
+            "Corrupt record for key {} at offset {} of generation {}",
+            key, range.start, gen
+        );
+        match self.on_corrupt {
+            CorruptRecordPolicy::Error => Err(CorruptData.into()),
+            CorruptRecordPolicy::SkipAsAbsent => Ok(None),
+            CorruptRecordPolicy::Repair => Err(CorruptRecord {
+                key,
+                gen,
+                start: range.start,
+            }
+            .into()),
+        }
+    }
+
+    // Take this handle's log, or one from the pool. A full pool waits for a log to come back, for
+    // at most the read timeout.
+    fn checkout(&self, gen: u64) -> Result<Checkout> {
+        let max = match self.logs.max {
+            Some(max) => max,
+            None => {
+                if let Some(log) = self.log.borrow_mut().take() {
+                    return Ok(Checkout::Log(log));
+                }
+                self.logs.state.lock().unwrap().0 += 1;
+                return Ok(Checkout::Slot(LogSlot(Arc::downgrade(&self.logs))));
+            }
+        };
+
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.logs.state.lock().unwrap();
+        loop {
+            let (ref mut open, ref mut free) = *state;
+            if let Some(i) = free.iter().position(|log| log.gen == gen) {
+                return Ok(Checkout::Log(free.swap_remove(i)));
+            }
+            if *open < max {
+                *open += 1;
+                return Ok(Checkout::Slot(LogSlot(Arc::downgrade(&self.logs))));
+            }
+            // Only logs of older generations are free, so reuse the slot of one of them
+            if let Some(log) = free.pop() {
+                return Ok(Checkout::Log(log));
+            }
+
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(Timeout.into());
+                    }
+                    self.logs
+                        .freed
+                        .wait_timeout(state, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                None => self.logs.freed.wait(state).unwrap(),
+            };
         }
-        let mut reader = reader.as_mut().unwrap();
+    }
 
-        if let Some(offset) = offset {
-            reader.seek(SeekFrom::Start(offset.start))?;
-            let mut de = Deserializer::from_reader(&mut reader);
-            let cmd: Command = serde::de::Deserialize::deserialize(&mut de).expect("bad offset");
-            Ok(Some(cmd.value()))
+    fn checkin(&self, log: ReaderLog) {
+        if self.logs.max.is_some() {
+            self.logs.state.lock().unwrap().1.push(log);
+            self.logs.freed.notify_one();
         } else {
-            Ok(None)
+            *self.log.borrow_mut() = Some(log);
         }
     }
+
+    fn open_log(&self, gen: u64, slot: LogSlot) -> Result<ReaderLog> {
+        let map = match self.mode {
+            ReadMode::Mmap => self.storage.map(gen)?,
+            ReadMode::Buffered => None,
+        };
+        let mut log = match map {
+            Some(map) => OpenLog::Mapped(map),
+            None => OpenLog::Buffered(BufReader::new(self.storage.read(gen)?), Vec::new()),
+        };
+        let version = log.read_header()?.version;
+        Ok(ReaderLog {
+            log,
+            gen,
+            version,
+            slot,
+        })
+    }
 }
 
 impl Clone for KvsReader {
     fn clone(&self) -> Self {
         Self {
-            reader: RefCell::new((None, 0)),
-            dir: self.dir.clone(),
+            log: RefCell::new(None),
+            logs: Arc::clone(&self.logs),
+            storage: Arc::clone(&self.storage),
+            mode: self.mode,
             index: self.index.clone(),
+            timeout: self.timeout,
+            on_corrupt: self.on_corrupt,
+            touches: Arc::clone(&self.touches),
         }
     }
 }
 
 /// KvsEngine wrapper around sled DB engine
 #[derive(Clone)]
-pub struct SledKvsEngine(sled::Db);
+pub struct SledKvsEngine {
+    db: sled::Db,
+    dir: Arc<PathBuf>,
+}
 
 impl SledKvsEngine {
     /// Creates or loads sled database at specified path using default configuration
     pub fn open(path: &Path) -> Result<Self> {
-        Ok(Self(sled::Db::start_default(path)?))
+        Ok(Self {
+            db: sled::Db::start_default(path)?,
+            dir: Arc::new(path.to_owned()),
+        })
+    }
+
+    /// Set a value that reads as absent once the TTL has passed, the same way as
+    /// KvStore::set_with_ttl. sled drops the value from disk the next time it's written or removed.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let record = self.expiring_record(value, Some(expiry_after(ttl)?))?;
+        self.db.set(&key, record)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // Encodes a value under a new version. Sled's IDs only ever go up, even across restarts.
+    fn record(&self, value: String) -> Result<Vec<u8>> {
+        self.expiring_record(value, None)
+    }
+
+    fn expiring_record(&self, value: String, expires_at: Option<u64>) -> Result<Vec<u8>> {
+        let version = self.db.generate_id()? + 1;
+        Ok(to_vec(&SledRecord {
+            value,
+            version,
+            expires_at,
+        })?)
+    }
+}
+
+// Version of the value whose record starts at the offset of a log. Later writes land at higher
+// offsets or in newer generations, which both make for higher versions, as long as logs stay
+// under 1 TiB. Offsets are never 0, since every log starts with a header.
+fn value_version(gen: u64, offset: u64) -> u64 {
+    (gen << 40) | offset
+}
+
+// Format of the values stored in sled. Values are wrapped in a record encoded with the same CBOR
+// format as the KvStore log, so that data stored alongside a value can be added the same way for
+// both engines. Databases from before records existed hold the bare value instead, which reads
+// as version 0 without an expiry.
+#[derive(Debug, Serialize, Deserialize)]
+struct SledRecord {
+    #[serde(rename = "v")]
+    value: String,
+    // Records written before versions existed read as version 0, which is older than any write
+    #[serde(rename = "n", default)]
+    version: u64,
+    // Unix time in seconds from which the value reads as absent, see set_with_ttl
+    #[serde(rename = "e", default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+// Total size of all files under a directory
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+// SledRecord as it's read back, with the value left as bytes so that records whose value isn't
+// valid UTF-8 fail with NonUtf8Value rather than as corrupt data
+#[derive(Deserialize)]
+struct RawSledRecord {
+    #[serde(rename = "v", with = "serde_bytes")]
+    value: Vec<u8>,
+    #[serde(rename = "n", default)]
+    version: u64,
+    #[serde(rename = "e", default)]
+    expires_at: Option<u64>,
+}
+
+fn decode_record(key: &str, bytes: &[u8]) -> Result<SledRecord> {
+    // Records are CBOR maps, whose first byte is in a range that no UTF-8 text can start with,
+    // so anything else is a bare value from before records existed
+    let record = if let Some(0xa0..=0xbf) = bytes.first() {
+        from_slice(bytes).map_err(|err| {
+            error!("Failed to decode sled record for key {}: {}", key, err);
+            CorruptData
+        })?
+    } else {
+        RawSledRecord {
+            value: bytes.to_vec(),
+            version: 0,
+            expires_at: None,
+        }
+    };
+    let value = String::from_utf8(record.value).map_err(|_| {
+        error!("Sled value for key {} is not valid UTF-8", key);
+        NonUtf8Value {
+            key: key.to_owned(),
+        }
+    })?;
+    Ok(SledRecord {
+        value,
+        version: record.version,
+        expires_at: record.expires_at,
+    })
+}
+
+// Decodes a pair from a sled iterator, skipping expired values
+fn live_pair(pair: sled::Result<(Vec<u8>, sled::IVec)>) -> Result<Option<(String, String)>> {
+    let (key, bytes) = pair?;
+    let key = String::from_utf8(key)?;
+    Ok(live_record(&key, &bytes)?.map(|record| (key, record.value)))
+}
+
+// Same as decode_record, except that expired values read as absent
+fn live_record(key: &str, bytes: &[u8]) -> Result<Option<SledRecord>> {
+    let record = decode_record(key, bytes)?;
+    if is_expired(record.expires_at) {
+        return Ok(None);
     }
+    Ok(Some(record))
 }
 
 impl KvsEngine for SledKvsEngine {
     fn get(&self, key: String) -> Result<Option<String>> {
-        let out = self.0.get(&key).map(|s| {
-            s.as_ref()
-                .map(|s| String::from_utf8(s.to_vec()).expect("non-string in sled DB"))
-        })?;
-        Ok(out)
+        Ok(self.get_versioned(key)?.map(|(value, _)| value))
+    }
+
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        match self.db.get(&key)? {
+            Some(bytes) => {
+                Ok(live_record(&key, &bytes)?.map(|record| (record.value, record.version)))
+            }
+            None => Ok(None),
+        }
     }
 
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.0.set(&key, value.into_bytes())?;
-        self.0.flush()?;
+        self.db.set(&key, self.record(value)?)?;
+        self.db.flush()?;
         Ok(())
     }
 
+    // Removing an expired value still deletes it, but fails as if it was already gone
     fn remove(&self, key: String) -> Result<()> {
-        self.0.del(&key)?.ok_or(KeyNotFound)?;
-        self.0.flush()?;
-        Ok(())
+        let old = self.db.del(&key)?;
+        self.db.flush()?;
+        match old {
+            Some(bytes) if live_record(&key, &bytes)?.is_some() => Ok(()),
+            _ => Err(KeyNotFound.into()),
+        }
+    }
+
+    fn write_batch(&self, ops: Vec<WriteOp>) -> Result<Vec<Result<()>>> {
+        let mut results = Vec::with_capacity(ops.len());
+        for op in ops {
+            results.push(match op {
+                WriteOp::Set(key, value) => {
+                    self.db.set(&key, self.record(value)?)?;
+                    Ok(())
+                }
+                WriteOp::Remove(key) => match self.db.del(&key)? {
+                    Some(bytes) if live_record(&key, &bytes)?.is_some() => Ok(()),
+                    _ => Err(KeyNotFound.into()),
+                },
+            });
+        }
+        self.db.flush()?;
+        Ok(results)
+    }
+
+    // sled hands back the old value from the write itself, so this is atomic without locking
+    fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>> {
+        let old = self.db.set(&key, self.record(value)?)?;
+        self.db.flush()?;
+        Ok(match old {
+            Some(bytes) => live_record(&key, &bytes)?.map(|record| record.value),
+            None => None,
+        })
+    }
+
+    // An expired value counts as absent, so it's swapped out as long as nothing replaced it
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        let record = self.record(value)?;
+        let mut current = self.db.get(&key)?;
+        loop {
+            if let Some(ref bytes) = current {
+                if live_record(&key, bytes)?.is_some() {
+                    return Ok(false);
+                }
+            }
+            match self.db.cas(&key, current, Some(record.clone()))? {
+                Ok(()) => break,
+                Err(found) => current = found,
+            }
+        }
+        self.db.flush()?;
+        Ok(true)
+    }
+
+    fn remove_and_get_old(&self, key: String) -> Result<Option<String>> {
+        let old = self.db.del(&key)?;
+        self.db.flush()?;
+        Ok(match old {
+            Some(bytes) => live_record(&key, &bytes)?.map(|record| record.value),
+            None => None,
+        })
     }
 
     fn clear(&self) -> Result<()> {
-        self.0.clear()?;
+        self.db.clear()?;
         Ok(())
     }
+
+    // sled doesn't report its own size, so add up everything in its directory
+    fn disk_size(&self) -> Result<u64> {
+        dir_size(&self.dir)
+    }
+
+    // sled reclaims space on its own without saying how much is left to reclaim, and counting
+    // its keys walks the whole tree
+    fn engine_stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            live_keys: self.db.len() as u64,
+            stale_bytes: None,
+            disk_bytes: self.disk_size()?,
+        })
+    }
+
+    // Unlike KvStore, sled iterates in key order
+    fn iter(&self) -> Result<EngineIter<'_>> {
+        Ok(Box::new(
+            self.db
+                .iter()
+                .filter_map(|pair| live_pair(pair).transpose()),
+        ))
+    }
+
+    fn scan(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        let start = match after {
+            Some(after) => Bound::Excluded(after.as_bytes()),
+            None => Bound::Unbounded,
+        };
+        self.db
+            .range::<&[u8], _>((start, Bound::Unbounded))
+            .filter_map(|pair| live_pair(pair).transpose())
+            .take(limit)
+            .collect()
+    }
 }
@@ -0,0 +1,141 @@
+use crate::{AlreadyManaged, KvStore, KvStoreOptions, Result};
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use log::error;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::thread::Builder;
+
+// Open stores by directory. Dropping it closes all of them, so that stores still in use after the
+// manager is gone go back to compacting on their own.
+#[derive(Default)]
+struct Stores(Mutex<HashMap<PathBuf, KvStore>>);
+
+impl Stores {
+    // Nothing panics while holding the lock with the map half-updated, so a poisoned map is
+    // still fine to use
+    fn lock(&self) -> MutexGuard<'_, HashMap<PathBuf, KvStore>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for Stores {
+    fn drop(&mut self) {
+        let stores = self.0.get_mut().unwrap_or_else(PoisonError::into_inner);
+        for store in stores.values() {
+            store.resume_compaction();
+        }
+    }
+}
+
+/// Opens many small stores in one process, such as one store per tenant, without paying for each
+/// of them separately.
+///
+/// Every directory is opened at most once. Opening it again returns a handle to the same store,
+/// which shares its writer, log handles, index, and pool of index read handles with every other
+/// handle, so handles are cheap to open and drop. Each handle opens its own reader for the log
+/// the first time it gets a value.
+///
+/// Stores opened through the manager never compact on the writer's thread. Instead, a write that
+/// finds enough stale data queues its store for a single background thread, which compacts stores
+/// one at a time. This keeps the cost of N stores at one compaction thread, and takes compaction
+/// off the latency of writes.
+///
+/// A store can only be looked after by one manager at a time, so opening a store through a manager
+/// fails with AlreadyManaged while another manager has it open.
+///
+/// Stores stay open until they're closed, even if no handle is left. Handles of a closed store
+/// keep working and go back to compacting on their own, and the store is dropped with the last of
+/// them. Dropping the last handle to the manager closes every store and stops the background
+/// thread.
+#[derive(Clone)]
+pub struct KvStoreManager {
+    stores: Arc<Stores>,
+    options: KvStoreOptions,
+    compactions: Sender<PathBuf>,
+}
+
+impl KvStoreManager {
+    /// Create a manager that opens every store with the given options, and start its compaction
+    /// thread
+    pub fn new(options: KvStoreOptions) -> Result<Self> {
+        let stores = Arc::new(Stores::default());
+        let (sender, receiver) = unbounded();
+        let weak = Arc::downgrade(&stores);
+        Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || Self::compact_stores(weak, receiver))?;
+
+        Ok(Self {
+            stores,
+            options,
+            compactions: sender,
+        })
+    }
+
+    /// Handle to the store in a directory, opening the store if it isn't open yet. Paths that
+    /// lead to the same directory get the same store.
+    pub fn open(&self, dir: &Path) -> Result<KvStore> {
+        let dir = dir.canonicalize()?;
+        if let Some(store) = self.stores.lock().get(&dir) {
+            return Ok(store.clone());
+        }
+
+        // Opening can take a while to read the log, so it's done without holding up every other
+        // store. Another thread could open the same store meanwhile, and then its handle is kept.
+        let store = KvStore::open_with_options(&dir, self.options.clone())?;
+        let mut stores = self.stores.lock();
+        if let Some(store) = stores.get(&dir) {
+            return Ok(store.clone());
+        }
+        let sender = self.compactions.clone();
+        let queued = dir.clone();
+        let deferred = store.defer_compaction(Box::new(move || {
+            // Only fails once the compaction thread is gone, at which point nobody compacts
+            let _ = sender.send(queued.clone());
+        }));
+        if !deferred {
+            return Err(AlreadyManaged.into());
+        }
+        stores.insert(dir, store.clone());
+        Ok(store)
+    }
+
+    /// Stop keeping the store in a directory open. Returns whether it was open.
+    pub fn close(&self, dir: &Path) -> Result<bool> {
+        let dir = dir.canonicalize()?;
+        match self.stores.lock().remove(&dir) {
+            Some(store) => {
+                store.resume_compaction();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Number of stores that are open
+    pub fn len(&self) -> usize {
+        self.stores.lock().len()
+    }
+
+    /// Whether no stores are open
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Body of the compaction thread. Stores are looked up again for every request, so requests
+    // for closed stores are dropped.
+    fn compact_stores(stores: Weak<Stores>, receiver: Receiver<PathBuf>) {
+        for dir in receiver {
+            let store = match stores.upgrade() {
+                Some(stores) => stores.lock().get(&dir).cloned(),
+                None => return,
+            };
+            if let Some(store) = store {
+                if let Err(err) = store.compact_if_due() {
+                    error!("Failed to compact {}: {}", dir.display(), err);
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,13 @@
-use crate::Result;
+use crate::{DeadlineExceeded, KeyNotFound, RateLimited, ReadOnly, Result, Unauthorized};
+use bincode::Options;
+use failure::{ensure, format_err, Error};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use serde::{Deserialize, Serialize};
 use serde_cbor::{to_writer, Deserializer};
 use std::io::prelude::*;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[allow(missing_docs)]
 pub const GET: &str = "get";
@@ -9,31 +15,436 @@ pub const GET: &str = "get";
 pub const SET: &str = "set";
 #[allow(missing_docs)]
 pub const REMOVE: &str = "remove";
+#[allow(missing_docs)]
+pub const STATUS: &str = "status";
+/// Sets a key only if it doesn't exist with [setnx, key, value]. The server replies with
+/// [key, "1"] if it set the key and [key, "0"] if the key already existed.
+pub const SETNX: &str = "setnx";
+/// Gets a value only if it changed with [get_if_newer, key, version]. The server replies with
+/// [key] if the key doesn't exist, [key, version] if the stored version is not above the given
+/// version, and [key, version, value] otherwise. Version 0 always gets the value.
+pub const GET_IF_NEWER: &str = "get_if_newer";
+/// Asks for every key-value pair in the store. The server replies with one [key, value] array per
+/// pair, followed by an empty array once all pairs are sent. An error reply also ends the dump.
+pub const DUMP: &str = "dump";
+/// Starts a paginated scan with [scan, cursor, limit], where the cursor is SCAN_START or a
+/// cursor from an earlier reply. The server replies with [next cursor, key, value, key, value, ...]
+/// holding up to limit pairs in key order. The next cursor is SCAN_START once the scan is over.
+pub const SCAN: &str = "scan";
+/// Continues a scan with [scan_continue, cursor], reusing the limit stored in the cursor
+pub const SCAN_CONTINUE: &str = "scan_continue";
+/// Asks for every pair whose key starts with a prefix with [scan_prefix, prefix]. The server
+/// replies with [key, value, key, value, ...] holding all of them in key order.
+pub const SCAN_PREFIX: &str = "scan_prefix";
+/// Every command the server understands
+pub const COMMANDS: &[&str] = &[
+    GET,
+    SET,
+    REMOVE,
+    SETNX,
+    GET_IF_NEWER,
+    STATUS,
+    DUMP,
+    SCAN,
+    SCAN_CONTINUE,
+    SCAN_PREFIX,
+];
+/// Cursor that starts a scan from the smallest key, and that ends a scan when it's returned
+pub const SCAN_START: &str = "0";
+/// Most bytes the value of a Compressed request may inflate to. Deflate can shrink a value by
+/// over a thousand times, so without a cap a small request could make the server allocate
+/// gigabytes.
+pub const MAX_DECOMPRESSED: u64 = 64 * 1024 * 1024;
+
+/// Reply to a STATUS request, describing how long the server has been running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerStatus {
+    /// Time since the server was created
+    pub uptime: Duration,
+    /// Wall-clock time when the server was created
+    pub started_at: SystemTime,
+    /// Connections the server has handled, including the one asking for the status
+    pub connections: ConnectionStats,
+}
+
+/// Counts of the connections a server has handled since it was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Connections being served right now. A connection counts until every reply to it is sent.
+    pub active: usize,
+    /// Every connection accepted, including rejected ones
+    pub accepted: u64,
+    /// Connections closed without serving any requests, because the server was paused, the
+    /// handshake had a missing or wrong token, or the batch was too large
+    pub rejected: u64,
+}
+
+impl ServerStatus {
+    /// Encode the status as the reply array [status, uptime ms, start time as UNIX ms, active
+    /// connections, accepted connections, rejected connections]
+    pub fn to_reply(&self) -> Vec<String> {
+        let started_at = self
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        vec![
+            STATUS.to_owned(),
+            self.uptime.as_millis().to_string(),
+            started_at.as_millis().to_string(),
+            self.connections.active.to_string(),
+            self.connections.accepted.to_string(),
+            self.connections.rejected.to_string(),
+        ]
+    }
+
+    /// Decode the reply array produced by to_reply
+    pub fn from_reply(arr: &[String]) -> Result<Self> {
+        ensure!(
+            arr.len() == 6 && arr[0] == STATUS,
+            "unexpected server output: {}",
+            arr.join(" ")
+        );
+        Ok(Self {
+            uptime: Duration::from_millis(arr[1].parse()?),
+            started_at: UNIX_EPOCH + Duration::from_millis(arr[2].parse()?),
+            connections: ConnectionStats {
+                active: arr[3].parse()?,
+                accepted: arr[4].parse()?,
+                rejected: arr[5].parse()?,
+            },
+        })
+    }
+}
+
+/// Position of a paginated scan. Clients should treat its token as opaque.
+///
+/// The cursor records the last key that was returned rather than an index position, so it stays
+/// valid across writes. That makes scans best-effort: every key that exists for the whole scan
+/// is returned once, but keys set or removed in the meantime may or may not be.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanCursor {
+    /// Number of pairs in each page
+    pub limit: usize,
+    /// Last key returned so far, or None before the first page
+    pub after: Option<String>,
+}
+
+impl ScanCursor {
+    /// Encode the cursor as a token. Tokens of cursors before the first page are SCAN_START.
+    pub fn to_token(&self) -> String {
+        match self.after {
+            Some(ref after) => {
+                let hex: String = after.bytes().map(|b| format!("{:02x}", b)).collect();
+                format!("{}:{}", self.limit, hex)
+            }
+            None => SCAN_START.to_owned(),
+        }
+    }
+
+    /// Decode a token produced by to_token. SCAN_START decodes with the given limit, since it
+    /// doesn't store one.
+    pub fn from_token(token: &str, limit: usize) -> Result<Self> {
+        if token == SCAN_START {
+            return Ok(Self { limit, after: None });
+        }
+        let invalid = || format_err!("invalid scan cursor {}", token);
+        let mut parts = token.splitn(2, ':');
+        let limit = parts.next().ok_or_else(invalid)?.parse()?;
+        let hex = parts.next().ok_or_else(invalid)?;
+        ensure!(hex.len() % 2 == 0, "invalid scan cursor {}", token);
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(Self {
+            limit,
+            after: Some(String::from_utf8(bytes)?),
+        })
+    }
+}
+
+/// Write the number of requests in a batch, which starts every request stream. Multi-byte fields
+/// on the wire are always little-endian so that every platform agrees on their meaning.
+pub fn write_batch_len(mut writer: impl Write, len: u32) -> Result<()> {
+    writer.write_all(&len.to_le_bytes())?;
+    Ok(())
+}
+
+/// Read the number of requests in a batch
+pub fn read_batch_len(mut reader: impl Read) -> Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// How messages after the handshake are encoded. The handshake itself is always CBOR.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    /// Self-describing CBOR, where every message frames itself
+    #[default]
+    Cbor,
+    /// Bincode behind a 4-byte little-endian length prefix. Messages are a little smaller and
+    /// much cheaper to encode and decode than CBOR, but they can only be read by code that knows
+    /// their exact layout, so both ends must be built from the same version of this crate.
+    Bincode,
+}
+
+/// First message on every connection, sent by the client before the batch length
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Handshake {
+    /// Shared secret, which the server checks if it was configured with one
+    #[serde(rename = "t")]
+    pub token: Option<String>,
+    /// Codec of every later message on the connection, in both directions. Servers that predate
+    /// codecs ignore it and keep using CBOR.
+    #[serde(rename = "c", default)]
+    pub codec: Codec,
+}
+
+impl Handshake {
+    /// Deserialize a handshake from a Reader
+    pub fn read(reader: impl Read) -> Result<Self> {
+        let mut de = Deserializer::from_reader(reader);
+        let handshake = serde::de::Deserialize::deserialize(&mut de)?;
+        Ok(handshake)
+    }
+
+    /// Serialize and send the handshake to a Writer
+    pub fn write(&self, writer: impl Write) -> Result<()> {
+        to_writer(writer, &self)?;
+        Ok(())
+    }
+}
 
 /// Representation of a message sent over TCP between server and client
 /// Transmitted over the network in the form of CBOR messages
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "t", content = "c")]
 pub enum Message {
     /// List of strings used to represent commands and return values
     #[serde(rename = "a")]
     Array(Vec<String>),
     #[serde(rename = "e")]
-    /// Error message inidicating failure
-    Error(String),
+    /// Error message inidicating failure, along with the kind of failure
+    Error(ErrorCode, String),
+    /// Request that the server should only start before the deadline, given in milliseconds
+    /// since the UNIX epoch. The server checks the deadline right before running the request and
+    /// replies with a DeadlineExceeded error if it has passed. Requests that already started are
+    /// never interrupted. Both ends use their own wall clock, so clock skew between them shifts
+    /// the deadline.
+    #[serde(rename = "d")]
+    Deadline(u64, Vec<String>),
+    /// Request whose last argument, the value, is deflated and sent as raw bytes, along with the
+    /// deadline if the request has one. Clients pick this per request, so only values that are
+    /// worth compressing need to be. The server decompresses it back into the Array or Deadline
+    /// request it was made from before running it. Build it with Message::compress_value.
+    #[serde(rename = "z")]
+    Compressed(
+        Option<u64>,
+        Vec<String>,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+    ),
+}
+
+// Bincode can't decode the adjacently tagged layout that keeps CBOR messages small, so it encodes
+// this untagged copy of Message instead. Writes borrow the message rather than cloning it.
+#[derive(Serialize)]
+enum BincodeRef<'a> {
+    Array(&'a [String]),
+    Error(ErrorCode, &'a str),
+    Deadline(u64, &'a [String]),
+    Compressed(
+        Option<u64>,
+        &'a [String],
+        #[serde(with = "serde_bytes")] &'a [u8],
+    ),
+}
+
+// Owned version of BincodeRef, which must list the variants in the same order
+#[derive(Deserialize)]
+enum BincodeOwned {
+    Array(Vec<String>),
+    Error(ErrorCode, String),
+    Deadline(u64, Vec<String>),
+    Compressed(
+        Option<u64>,
+        Vec<String>,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+    ),
+}
+
+impl<'a> From<&'a Message> for BincodeRef<'a> {
+    fn from(msg: &'a Message) -> Self {
+        match msg {
+            Message::Array(arr) => BincodeRef::Array(arr),
+            Message::Error(code, err) => BincodeRef::Error(*code, err),
+            Message::Deadline(deadline, arr) => BincodeRef::Deadline(*deadline, arr),
+            Message::Compressed(deadline, arr, value) => {
+                BincodeRef::Compressed(*deadline, arr, value)
+            }
+        }
+    }
+}
+
+impl From<BincodeOwned> for Message {
+    fn from(msg: BincodeOwned) -> Self {
+        match msg {
+            BincodeOwned::Array(arr) => Message::Array(arr),
+            BincodeOwned::Error(code, err) => Message::Error(code, err),
+            BincodeOwned::Deadline(deadline, arr) => Message::Deadline(deadline, arr),
+            BincodeOwned::Compressed(deadline, arr, value) => {
+                Message::Compressed(deadline, arr, value)
+            }
+        }
+    }
+}
+
+// Varint lengths and tags, which take a byte each for most messages instead of 4 or 8
+fn bincode_options() -> impl bincode::Options {
+    bincode::DefaultOptions::new()
+}
+
+/// Deadline for a request that must start within the timeout from now
+pub fn deadline_after(timeout: Duration) -> u64 {
+    let deadline = SystemTime::now() + timeout;
+    deadline
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Whether a deadline from deadline_after has passed
+pub fn deadline_passed(deadline: u64) -> bool {
+    SystemTime::now() > UNIX_EPOCH + Duration::from_millis(deadline)
+}
+
+/// Identifies the kind of error a server reply represents, so clients can tell errors apart
+/// without parsing the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// Any error without a more specific code
+    Other,
+    /// The requested key does not exist
+    KeyNotFound,
+    /// The request's deadline passed before the server got to it
+    DeadlineExceeded,
+    /// The connection's handshake didn't have the server's secret
+    Unauthorized,
+    /// The request was a write over the server's rate limit
+    RateLimited,
+    /// The request was a write while the server or store was read-only
+    ReadOnly,
+}
+
+impl ErrorCode {
+    /// Find the code for an error raised on the server
+    pub fn of(err: &Error) -> Self {
+        if err.downcast_ref::<KeyNotFound>().is_some() {
+            ErrorCode::KeyNotFound
+        } else if err.downcast_ref::<DeadlineExceeded>().is_some() {
+            ErrorCode::DeadlineExceeded
+        } else if err.downcast_ref::<Unauthorized>().is_some() {
+            ErrorCode::Unauthorized
+        } else if err.downcast_ref::<RateLimited>().is_some() {
+            ErrorCode::RateLimited
+        } else if err.downcast_ref::<ReadOnly>().is_some() {
+            ErrorCode::ReadOnly
+        } else {
+            ErrorCode::Other
+        }
+    }
+
+    /// Turn an error reply back into an error, recreating the original error type if possible
+    pub fn into_error(self, msg: String) -> Error {
+        match self {
+            ErrorCode::KeyNotFound => KeyNotFound.into(),
+            ErrorCode::DeadlineExceeded => DeadlineExceeded.into(),
+            ErrorCode::Unauthorized => Unauthorized.into(),
+            ErrorCode::RateLimited => RateLimited.into(),
+            ErrorCode::ReadOnly => ReadOnly.into(),
+            ErrorCode::Other => format_err!("Error: {}", msg),
+        }
+    }
 }
 
 impl Message {
+    /// Build a Compressed request out of a request whose last argument is its value, attaching
+    /// the deadline if there is one
+    pub fn compress_value(deadline: Option<u64>, mut arr: Vec<String>) -> Result<Self> {
+        let value = arr
+            .pop()
+            .ok_or_else(|| format_err!("request has no value"))?;
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(value.as_bytes())?;
+        Ok(Message::Compressed(deadline, arr, encoder.finish()?))
+    }
+
+    /// Turn a Compressed request back into the request it was built from. Other messages are
+    /// returned as they are. Fails if the value isn't valid deflate data or UTF-8, or inflates to
+    /// more than MAX_DECOMPRESSED bytes.
+    pub fn decompress(self) -> Result<Self> {
+        match self {
+            Message::Compressed(deadline, mut arr, value) => {
+                let mut decoded = String::new();
+                // One byte past the cap is enough to tell that the value is over it
+                DeflateDecoder::new(&value[..])
+                    .take(MAX_DECOMPRESSED + 1)
+                    .read_to_string(&mut decoded)?;
+                ensure!(
+                    decoded.len() as u64 <= MAX_DECOMPRESSED,
+                    "compressed value inflates to over {} bytes",
+                    MAX_DECOMPRESSED
+                );
+                arr.push(decoded);
+                Ok(match deadline {
+                    Some(deadline) => Message::Deadline(deadline, arr),
+                    None => Message::Array(arr),
+                })
+            }
+            msg => Ok(msg),
+        }
+    }
+
     /// Serialize a message from a Reader
-    pub fn read(reader: impl Read) -> Result<Self> {
-        let mut de = Deserializer::from_reader(reader);
-        let msg = serde::de::Deserialize::deserialize(&mut de)?;
-        Ok(msg)
+    pub fn read(mut reader: impl Read, codec: Codec) -> Result<Self> {
+        match codec {
+            Codec::Cbor => {
+                let mut de = Deserializer::from_reader(reader);
+                let msg = serde::de::Deserialize::deserialize(&mut de)?;
+                Ok(msg)
+            }
+            // Bincode doesn't say where a message ends, so the length prefix has to. The bytes
+            // are read as they arrive rather than allocated up front, so a bogus length can't
+            // allocate more than the stream actually holds.
+            Codec::Bincode => {
+                let mut len = [0; 4];
+                reader.read_exact(&mut len)?;
+                let len = u32::from_le_bytes(len);
+                let mut bytes = Vec::new();
+                reader.take(u64::from(len)).read_to_end(&mut bytes)?;
+                ensure!(bytes.len() == len as usize, "truncated message");
+                Ok(bincode_options()
+                    .deserialize::<BincodeOwned>(&bytes)?
+                    .into())
+            }
+        }
     }
 
     /// Deserialize and send the message to a Writer
-    pub fn write(&self, writer: impl Write) -> Result<()> {
-        to_writer(writer, &self)?;
+    pub fn write(&self, mut writer: impl Write, codec: Codec) -> Result<()> {
+        match codec {
+            Codec::Cbor => to_writer(writer, &self)?,
+            Codec::Bincode => {
+                let bytes = bincode_options().serialize(&BincodeRef::from(self))?;
+                ensure!(
+                    bytes.len() <= u32::MAX as usize,
+                    "message of {} bytes is too large",
+                    bytes.len()
+                );
+                writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+                writer.write_all(&bytes)?;
+            }
+        }
         Ok(())
     }
 }
@@ -0,0 +1,577 @@
+use crate::{EngineIter, EngineStats, KvsEngine, Result, WriteOp};
+use std::cmp::Reverse;
+use std::iter;
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+// Pairs copied per write_batch by SwappableEngine::swap, and read per scan by its iter
+const SWAP_BATCH: usize = 1000;
+
+// Object-safe copy of KvsEngine, which can't be made into a trait object itself because it
+// requires Clone
+trait ErasedEngine: Send {
+    fn set(&self, key: String, value: String) -> Result<()>;
+    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>>;
+    fn remove(&self, key: String) -> Result<()>;
+    fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>>;
+    fn set_nx(&self, key: String, value: String) -> Result<bool>;
+    fn remove_and_get_old(&self, key: String) -> Result<Option<String>>;
+    fn clear(&self) -> Result<()>;
+    fn disk_size(&self) -> Result<u64>;
+    fn engine_stats(&self) -> Result<EngineStats>;
+    fn iter(&self) -> Result<EngineIter<'_>>;
+    fn scan(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>>;
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>>;
+    fn write_batch(&self, ops: Vec<WriteOp>) -> Result<Vec<Result<()>>>;
+    fn boxed_clone(&self) -> Box<dyn ErasedEngine>;
+}
+
+// Holds an engine behind ErasedEngine
+struct Erased<E>(E);
+
+impl<E: KvsEngine> ErasedEngine for Erased<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.0.get_versioned(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+
+    fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>> {
+        self.0.set_and_get_old(key, value)
+    }
+
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        self.0.set_nx(key, value)
+    }
+
+    fn remove_and_get_old(&self, key: String) -> Result<Option<String>> {
+        self.0.remove_and_get_old(key)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.0.clear()
+    }
+
+    fn disk_size(&self) -> Result<u64> {
+        self.0.disk_size()
+    }
+
+    fn engine_stats(&self) -> Result<EngineStats> {
+        self.0.engine_stats()
+    }
+
+    fn iter(&self) -> Result<EngineIter<'_>> {
+        self.0.iter()
+    }
+
+    fn scan(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.0.scan(after, limit)
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.0.scan_prefix(prefix)
+    }
+
+    fn write_batch(&self, ops: Vec<WriteOp>) -> Result<Vec<Result<()>>> {
+        self.0.write_batch(ops)
+    }
+
+    fn boxed_clone(&self) -> Box<dyn ErasedEngine> {
+        Box::new(Erased(self.0.clone()))
+    }
+}
+
+/// Engine of any type, chosen at runtime. Engines of different types can be kept together once
+/// they're wrapped, and the wrapper passes every call straight through, including write_batch, so
+/// engines that batch their writes keep doing so. Cloning clones the wrapped engine.
+pub struct DynEngine(Box<dyn ErasedEngine>);
+
+impl DynEngine {
+    /// Wrap an engine
+    pub fn new(engine: impl KvsEngine) -> Self {
+        DynEngine(Box::new(Erased(engine)))
+    }
+}
+
+impl Clone for DynEngine {
+    fn clone(&self) -> Self {
+        DynEngine(self.0.boxed_clone())
+    }
+}
+
+impl KvsEngine for DynEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.0.get_versioned(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+
+    fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>> {
+        self.0.set_and_get_old(key, value)
+    }
+
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        self.0.set_nx(key, value)
+    }
+
+    fn remove_and_get_old(&self, key: String) -> Result<Option<String>> {
+        self.0.remove_and_get_old(key)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.0.clear()
+    }
+
+    fn disk_size(&self) -> Result<u64> {
+        self.0.disk_size()
+    }
+
+    fn engine_stats(&self) -> Result<EngineStats> {
+        self.0.engine_stats()
+    }
+
+    fn iter(&self) -> Result<EngineIter<'_>> {
+        self.0.iter()
+    }
+
+    fn scan(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.0.scan(after, limit)
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.0.scan_prefix(prefix)
+    }
+
+    fn write_batch(&self, ops: Vec<WriteOp>) -> Result<Vec<Result<()>>> {
+        self.0.write_batch(ops)
+    }
+}
+
+/// Engine that sends every key to one of several engines based on the key's prefix, such as
+/// `cache:` keys to a SledKvsEngine and `persist:` keys to a KvStore. Keys that match no prefix
+/// go to the default engine, and keys that match more than one go to the longest prefix. Keys
+/// are passed on whole, prefix included.
+///
+/// Each engine only ever holds its own keys, so iter and scan only return the keys of each engine
+/// that route back to it, and merge them together. Since routes partition the keys, clear clears
+/// every engine, including the default. The disk size and stats are the totals over every
+/// engine, and stale_bytes is only known if every engine knows it. Cloning clones every engine.
+/// ```
+/// use kvs::Result;
+///
+/// # fn main() -> Result<()> {
+///     use kvs::routing::RoutingEngine;
+///     use kvs::{KvStore, KvsEngine, SledKvsEngine};
+///     use tempfile::TempDir;
+///
+///     let cache_dir = TempDir::new().expect("unable to create temporary working directory");
+///     let persist_dir = TempDir::new().expect("unable to create temporary working directory");
+///     let engine = RoutingEngine::new(KvStore::open(persist_dir.path())?)
+///         .with_route("cache:", SledKvsEngine::open(cache_dir.path())?);
+///     engine.set("cache:a".to_owned(), "1".to_owned())?;
+///     engine.set("b".to_owned(), "2".to_owned())?;
+///     assert_eq!(engine.get("cache:a".to_owned())?, Some("1".to_owned()));
+/// #   Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct RoutingEngine {
+    // Longest prefix first, so the first match is the longest. The default engine is last, under
+    // the empty prefix, which matches every key.
+    routes: Vec<(String, DynEngine)>,
+}
+
+impl RoutingEngine {
+    /// Route every key to the default engine until routes are added
+    pub fn new(default: impl KvsEngine) -> Self {
+        Self {
+            routes: vec![(String::new(), DynEngine::new(default))],
+        }
+    }
+
+    /// Send keys that start with the prefix to the engine, replacing the engine of any route
+    /// with the same prefix. An empty prefix replaces the default engine.
+    pub fn with_route(mut self, prefix: &str, engine: impl KvsEngine) -> Self {
+        let engine = DynEngine::new(engine);
+        match self.routes.iter_mut().find(|(p, _)| p == prefix) {
+            Some(route) => route.1 = engine,
+            None => {
+                self.routes.push((prefix.to_owned(), engine));
+                self.routes.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+            }
+        }
+        self
+    }
+
+    // Position of the route the key goes to
+    fn route(&self, key: &str) -> usize {
+        self.routes
+            .iter()
+            .position(|(prefix, _)| key.starts_with(&prefix[..]))
+            .unwrap()
+    }
+
+    fn engine(&self, key: &str) -> &DynEngine {
+        &self.routes[self.route(key)].1
+    }
+
+    // Same as scan on a single engine, but skips keys that don't route to it
+    fn scan_route(
+        &self,
+        route: usize,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let engine = &self.routes[route].1;
+        let mut pairs = Vec::new();
+        let mut after = after.map(str::to_owned);
+        loop {
+            let page = engine.scan(after.as_ref().map(|s| &s[..]), limit)?;
+            let done = page.len() < limit;
+            after = page.last().map(|(key, _)| key.clone());
+            pairs.extend(page.into_iter().filter(|(key, _)| self.route(key) == route));
+            if done || pairs.len() >= limit {
+                pairs.truncate(limit);
+                return Ok(pairs);
+            }
+        }
+    }
+}
+
+impl KvsEngine for RoutingEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.engine(&key).set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine(&key).get(key)
+    }
+
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.engine(&key).get_versioned(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.engine(&key).remove(key)
+    }
+
+    fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>> {
+        self.engine(&key).set_and_get_old(key, value)
+    }
+
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        self.engine(&key).set_nx(key, value)
+    }
+
+    fn remove_and_get_old(&self, key: String) -> Result<Option<String>> {
+        self.engine(&key).remove_and_get_old(key)
+    }
+
+    fn clear(&self) -> Result<()> {
+        for (_, engine) in &self.routes {
+            engine.clear()?;
+        }
+        Ok(())
+    }
+
+    fn disk_size(&self) -> Result<u64> {
+        let mut size = 0;
+        for (_, engine) in &self.routes {
+            size += engine.disk_size()?;
+        }
+        Ok(size)
+    }
+
+    fn engine_stats(&self) -> Result<EngineStats> {
+        let mut total = EngineStats {
+            live_keys: 0,
+            stale_bytes: Some(0),
+            disk_bytes: 0,
+        };
+        for (_, engine) in &self.routes {
+            let stats = engine.engine_stats()?;
+            total.live_keys += stats.live_keys;
+            total.stale_bytes = total.stale_bytes.and_then(|t| Some(t + stats.stale_bytes?));
+            total.disk_bytes += stats.disk_bytes;
+        }
+        Ok(total)
+    }
+
+    fn iter(&self) -> Result<EngineIter<'_>> {
+        let mut iters = Vec::with_capacity(self.routes.len());
+        for (route, (_, engine)) in self.routes.iter().enumerate() {
+            iters.push(engine.iter()?.filter(move |pair| match pair {
+                Ok((key, _)) => self.route(key) == route,
+                Err(_) => true,
+            }));
+        }
+        Ok(Box::new(iters.into_iter().flatten()))
+    }
+
+    fn scan(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for route in 0..self.routes.len() {
+            pairs.extend(self.scan_route(route, after, limit)?);
+        }
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+
+    // Each engine gets its share of the batch in a single call, and the results are put back in
+    // the order of the writes
+    fn write_batch(&self, ops: Vec<WriteOp>) -> Result<Vec<Result<()>>> {
+        let mut batches: Vec<Vec<WriteOp>> = self.routes.iter().map(|_| Vec::new()).collect();
+        let mut order = Vec::with_capacity(ops.len());
+        for op in ops {
+            let route = match op {
+                WriteOp::Set(ref key, _) | WriteOp::Remove(ref key) => self.route(key),
+            };
+            order.push(route);
+            batches[route].push(op);
+        }
+
+        let mut results = Vec::with_capacity(batches.len());
+        for (batch, (_, engine)) in batches.into_iter().zip(&self.routes) {
+            let batch_results = if batch.is_empty() {
+                Vec::new()
+            } else {
+                engine.write_batch(batch)?
+            };
+            results.push(batch_results.into_iter());
+        }
+        Ok(order
+            .into_iter()
+            .map(|route| results[route].next().unwrap())
+            .collect())
+    }
+}
+
+/// Engine whose underlying engine can be replaced while it's in use, such as to move a running
+/// server from one engine type to another. Every clone shares the same underlying engine, so a
+/// swap through any of them is seen by all of them.
+///
+/// Each clone keeps its own clone of the underlying engine, so engines like KvStore hold on to the
+/// readers they open, and only clones it again after a swap. Calls on a single clone are run one
+/// at a time, so threads should have their own clones, as usual.
+pub struct SwappableEngine {
+    shared: Arc<Swappable>,
+    // This clone's copy of the underlying engine, along with the swap it was taken after
+    local: Mutex<(u64, DynEngine)>,
+}
+
+struct Swappable {
+    engine: Mutex<DynEngine>,
+    // Number of swaps so far, only changed while holding the engine's lock
+    swaps: AtomicU64,
+    // Writes hold this for reading, so that a swap can hold them off while it copies
+    writes: RwLock<()>,
+}
+
+impl SwappableEngine {
+    /// Wrap an engine so that it can be swapped out later
+    pub fn new(engine: impl KvsEngine) -> Self {
+        let engine = DynEngine::new(engine);
+        Self {
+            local: Mutex::new((0, engine.clone())),
+            shared: Arc::new(Swappable {
+                engine: Mutex::new(engine),
+                swaps: AtomicU64::new(0),
+                writes: RwLock::new(()),
+            }),
+        }
+    }
+
+    /// Copy every pair of the current engine into the new one, then make the new one current.
+    /// Pairs the new engine already has are kept unless the copy overwrites them.
+    ///
+    /// Writes wait from the start of the copy until the swap is done, so none of them can land
+    /// on the old engine after it's been copied. Reads keep going to the old engine during the
+    /// copy, and see the new one right after, so the data they see never goes back in time. If
+    /// the copy fails, the old engine stays current, and the new one is left with whatever was
+    /// copied so far.
+    pub fn swap(&self, new: impl KvsEngine) -> Result<()> {
+        let _writes = self
+            .shared
+            .writes
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let old = self.current();
+        let mut batch = Vec::with_capacity(SWAP_BATCH);
+        for pair in old.iter()? {
+            let (key, value) = pair?;
+            batch.push(WriteOp::Set(key, value));
+            if batch.len() == SWAP_BATCH {
+                copy_batch(&new, mem::take(&mut batch))?;
+            }
+        }
+        copy_batch(&new, batch)?;
+
+        let mut engine = self
+            .shared
+            .engine
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *engine = DynEngine::new(new);
+        self.shared.swaps.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // A clone of the current engine
+    fn current(&self) -> DynEngine {
+        let mut local = self.local.lock().unwrap_or_else(PoisonError::into_inner);
+        self.refresh(&mut local);
+        local.1.clone()
+    }
+
+    // Clones the underlying engine again if it was swapped since the local copy was taken
+    fn refresh(&self, local: &mut (u64, DynEngine)) {
+        if local.0 != self.shared.swaps.load(Ordering::SeqCst) {
+            let engine = self
+                .shared
+                .engine
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            *local = (self.shared.swaps.load(Ordering::SeqCst), engine.clone());
+        }
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&DynEngine) -> Result<T>) -> Result<T> {
+        let mut local = self.local.lock().unwrap_or_else(PoisonError::into_inner);
+        self.refresh(&mut local);
+        f(&local.1)
+    }
+
+    fn write<T>(&self, f: impl FnOnce(&DynEngine) -> Result<T>) -> Result<T> {
+        let _writes = self
+            .shared
+            .writes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.read(f)
+    }
+}
+
+// Writes part of a swap's copy, failing if any of its writes fail
+fn copy_batch(engine: &impl KvsEngine, batch: Vec<WriteOp>) -> Result<()> {
+    if !batch.is_empty() {
+        for result in engine.write_batch(batch)? {
+            result?;
+        }
+    }
+    Ok(())
+}
+
+impl Clone for SwappableEngine {
+    fn clone(&self) -> Self {
+        let local = self.local.lock().unwrap_or_else(PoisonError::into_inner);
+        Self {
+            shared: Arc::clone(&self.shared),
+            local: Mutex::new((local.0, local.1.clone())),
+        }
+    }
+}
+
+impl KvsEngine for SwappableEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.write(|engine| engine.set(key, value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.read(|engine| engine.get(key))
+    }
+
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.read(|engine| engine.get_versioned(key))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.write(|engine| engine.remove(key))
+    }
+
+    fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>> {
+        self.write(|engine| engine.set_and_get_old(key, value))
+    }
+
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        self.write(|engine| engine.set_nx(key, value))
+    }
+
+    fn remove_and_get_old(&self, key: String) -> Result<Option<String>> {
+        self.write(|engine| engine.remove_and_get_old(key))
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.write(|engine| engine.clear())
+    }
+
+    fn disk_size(&self) -> Result<u64> {
+        self.read(|engine| engine.disk_size())
+    }
+
+    fn engine_stats(&self) -> Result<EngineStats> {
+        self.read(|engine| engine.engine_stats())
+    }
+
+    // Pages through a clone of the current engine with scan, so the iterator doesn't hold on to
+    // this clone. Pairs come in key order, and a swap during the iteration isn't followed.
+    fn iter(&self) -> Result<EngineIter<'_>> {
+        let engine = self.current();
+        let mut page = Vec::<(String, String)>::new().into_iter();
+        let mut after: Option<String> = None;
+        let mut done = false;
+        Ok(Box::new(iter::from_fn(move || loop {
+            if let Some((key, value)) = page.next() {
+                after = Some(key.clone());
+                return Some(Ok((key, value)));
+            }
+            if done {
+                return None;
+            }
+            match engine.scan(after.as_ref().map(|s| &s[..]), SWAP_BATCH) {
+                Ok(pairs) => {
+                    done = pairs.len() < SWAP_BATCH;
+                    page = pairs.into_iter();
+                }
+                Err(err) => {
+                    done = true;
+                    return Some(Err(err));
+                }
+            }
+        })))
+    }
+
+    fn scan(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.read(|engine| engine.scan(after, limit))
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.read(|engine| engine.scan_prefix(prefix))
+    }
+
+    fn write_batch(&self, ops: Vec<WriteOp>) -> Result<Vec<Result<()>>> {
+        self.write(|engine| engine.write_batch(ops))
+    }
+}
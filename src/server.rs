@@ -1,13 +1,30 @@
 use crate::protocol::*;
+use crate::routing::SwappableEngine;
+use crate::spans::{self, Span};
 use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, Result};
+use crate::{
+    DeadlineExceeded, EngineStats, KvsEngine, RateLimited, ReadOnly, Result, Unauthorized,
+    UnknownCommand, WriteOp,
+};
 use crossbeam::channel::{bounded, Receiver, Sender};
 use crossbeam::sync::WaitGroup;
-use failure::{ensure, format_err};
+use failure::{ensure, format_err, Error};
 use log::{info, warn};
-use std::io::{BufReader, BufWriter, ErrorKind, Read};
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Write as _};
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter, ErrorKind};
+use std::mem;
 use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use subtle::ConstantTimeEq;
+
+/// Most requests a KvsServer accepts in one batch, unless configured otherwise. It's ten times
+/// what a ThreadedKvsClient sends over one connection by default.
+pub const DEFAULT_MAX_BATCH_SIZE: u32 = 100_000;
 
 /// Handles TCP KVSEngine requests. Can specify underlying threadpool and KVS engine.
 pub struct KvsServer<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> {
@@ -15,6 +32,230 @@ pub struct KvsServer<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> {
     pool: Arc<P>,
     receiver: Receiver<()>,
     sender: Sender<()>,
+    // Number of spawned jobs that haven't finished yet
+    active: Arc<AtomicUsize>,
+    // Never changes after creation, so each clone keeps its own copy
+    start: StartTime,
+    // Hash of the secret that clients must send, if there is one
+    secret_hash: Option<[u8; 32]>,
+    // Shared by every connection, so the limit applies to the server as a whole
+    write_limit: Option<Arc<TokenBucket>>,
+    // Shared by every clone, so pausing any of them pauses the one that's running
+    paused: Arc<AtomicBool>,
+    // Whether writes are refused, shared by every clone like paused. Writes hold a read lock on
+    // it while they run, so turning it on waits for writes that are already running.
+    read_only: Arc<RwLock<bool>>,
+    coalesce_writes: bool,
+    max_batch_size: u32,
+    // Address the running server is bound to, shared by every clone like paused
+    local_addr: Arc<Mutex<Option<SocketAddr>>>,
+    counters: Arc<Counters>,
+    read_pool: Option<Arc<ReadPool<E, P>>>,
+}
+
+fn hash_secret(secret: &str) -> [u8; 32] {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&Sha256::digest(secret.as_bytes()));
+    hash
+}
+
+// What to send back for a successful request
+enum Reply {
+    Array(Vec<String>),
+    // Stream every pair in the store
+    Dump,
+}
+
+// When the server was created, both for measuring uptime and for reporting to users
+#[derive(Clone, Copy)]
+struct StartTime {
+    instant: Instant,
+    wall: SystemTime,
+}
+
+impl StartTime {
+    fn status(&self, connections: ConnectionStats) -> ServerStatus {
+        ServerStatus {
+            uptime: self.instant.elapsed(),
+            started_at: self.wall,
+            connections,
+        }
+    }
+}
+
+// Allows bursts of up to capacity operations, refilling at rate operations per second
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    // Tokens left as of the last refill, along with when that was
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, capacity: u32) -> Self {
+        Self {
+            rate: f64::from(rate),
+            capacity: f64::from(capacity),
+            tokens: Mutex::new((f64::from(capacity), Instant::now())),
+        }
+    }
+
+    // Take a token if one is available
+    fn try_take(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        let (ref mut count, ref mut refilled) = *tokens;
+        let now = Instant::now();
+        let elapsed = now.duration_since(*refilled).as_secs_f64();
+        *count = (*count + elapsed * self.rate).min(self.capacity);
+        *refilled = now;
+
+        if *count >= 1.0 {
+            *count -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Engine clones that are handed out to requests and given back when they're done, so that each
+// clone keeps the readers it opened for the next request. The first clone always stays behind to
+// copy from, so there are only ever as many clones as requests running at once, plus one.
+struct Stores<E>(Mutex<Vec<E>>);
+
+impl<E: KvsEngine> Stores<E> {
+    fn new(engine: E) -> Self {
+        Stores(Mutex::new(vec![engine]))
+    }
+
+    fn take(&self) -> E {
+        let mut spare = self.0.lock().unwrap();
+        if spare.len() > 1 {
+            spare.pop().unwrap()
+        } else {
+            spare[0].clone()
+        }
+    }
+
+    fn give_back(&self, store: E) {
+        self.0.lock().unwrap().push(store);
+    }
+}
+
+// Pool that runs the read requests of every connection, see with_read_pool
+struct ReadPool<E, P> {
+    pool: P,
+    stores: Stores<E>,
+}
+
+// Whether a request only reads, and so can go to the read pool
+fn is_read(msg: &Message) -> bool {
+    let arr = match msg {
+        Message::Array(arr) | Message::Deadline(_, arr) => arr,
+        _ => return false,
+    };
+    matches!(
+        arr.first().map(|s| &s[..]),
+        Some(GET) | Some(GET_IF_NEWER) | Some(SCAN) | Some(SCAN_CONTINUE) | Some(SCAN_PREFIX)
+    )
+}
+
+// Counts a job as active for as long as it's alive, including while it's unwinding from a panic
+struct ActiveJob(Arc<AtomicUsize>);
+
+impl ActiveJob {
+    fn new(active: &Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::SeqCst);
+        Self(Arc::clone(active))
+    }
+}
+
+impl Drop for ActiveJob {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Connection and request counters shared by every clone of a server
+#[derive(Default)]
+struct Counters {
+    active: AtomicUsize,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    // Requests that got a reply, and how many of those replies were errors
+    requests: AtomicU64,
+    failed_requests: AtomicU64,
+    // Bytes of the keys and values of successful SET and SETNX requests that set their key
+    bytes_written: AtomicU64,
+}
+
+impl Counters {
+    fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            active: self.active.load(Ordering::SeqCst),
+            accepted: self.accepted.load(Ordering::SeqCst),
+            rejected: self.rejected.load(Ordering::SeqCst),
+        }
+    }
+
+    // Counts a connection that's turned away before anything is read from it
+    fn refuse(&self) {
+        self.accepted.fetch_add(1, Ordering::SeqCst);
+        self.rejected.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn count_write(&self, key: &str, value_len: usize) {
+        self.bytes_written
+            .fetch_add((key.len() + value_len) as u64, Ordering::SeqCst);
+    }
+
+    fn count_reply(&self, reply: &Message) {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        if let Message::Error(..) = reply {
+            self.failed_requests.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+// Counts a connection as active for as long as it's alive, like ActiveJob. The batch holds it, so
+// the connection stays active until the last of its requests is done.
+struct OpenConnection(Arc<Counters>);
+
+impl OpenConnection {
+    fn new(counters: &Arc<Counters>) -> Self {
+        counters.accepted.fetch_add(1, Ordering::SeqCst);
+        counters.active.fetch_add(1, Ordering::SeqCst);
+        Self(Arc::clone(counters))
+    }
+
+    fn reject(&self) {
+        self.0.rejected.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Report of the work a server has done since it was created, as returned by shutdown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerSummary {
+    /// Time since the server was created
+    pub uptime: Duration,
+    /// Requests that were replied to
+    pub requests: u64,
+    /// Requests that were replied to with an error
+    pub failed_requests: u64,
+    /// Bytes of the keys and values that SET and SETNX requests wrote
+    pub bytes_written: u64,
+    /// Connections the server has handled
+    pub connections: ConnectionStats,
+    /// Stats of the engine, or None if it couldn't report them
+    pub engine: Option<EngineStats>,
+    /// Jobs that were still running when the summary was taken
+    pub active_jobs: usize,
 }
 
 // Derive clone is not working properly, so we have to write this manually
@@ -25,6 +266,17 @@ impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> Clone for KvsServer<E,
             pool: self.pool.clone(),
             receiver: self.receiver.clone(),
             sender: self.sender.clone(),
+            active: self.active.clone(),
+            start: self.start,
+            secret_hash: self.secret_hash,
+            write_limit: self.write_limit.clone(),
+            paused: self.paused.clone(),
+            read_only: self.read_only.clone(),
+            coalesce_writes: self.coalesce_writes,
+            max_batch_size: self.max_batch_size,
+            local_addr: self.local_addr.clone(),
+            counters: self.counters.clone(),
+            read_pool: self.read_pool.clone(),
         }
     }
 }
@@ -39,11 +291,248 @@ impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> KvsServer<E, P> {
             pool: Arc::new(P::new(num_threads)?),
             sender,
             receiver,
+            active: Arc::new(AtomicUsize::new(0)),
+            start: StartTime {
+                instant: Instant::now(),
+                wall: SystemTime::now(),
+            },
+            secret_hash: None,
+            write_limit: None,
+            paused: Arc::new(AtomicBool::new(false)),
+            read_only: Arc::new(RwLock::new(false)),
+            coalesce_writes: false,
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            local_addr: Arc::new(Mutex::new(None)),
+            counters: Arc::new(Counters::default()),
+            read_pool: None,
+        })
+    }
+
+    /// Only accept connections whose handshake carries this secret. Without a secret every
+    /// connection is accepted. Only a hash of the secret is kept, and tokens are compared in
+    /// constant time.
+    pub fn with_secret(self, secret: &str) -> Self {
+        Self {
+            secret_hash: Some(hash_secret(secret)),
+            ..self
+        }
+    }
+
+    /// Cap SET and REMOVE requests across all connections to ops_per_sec, allowing bursts of up
+    /// to burst writes at once. Writes over the limit are rejected right away with a RateLimited
+    /// error instead of being queued, so clients decide whether to retry. Other requests are
+    /// never limited. Fails if either number is zero.
+    pub fn with_write_limit(self, ops_per_sec: u32, burst: u32) -> Result<Self> {
+        ensure!(
+            ops_per_sec > 0 && burst > 0,
+            "write limit and burst must be positive"
+        );
+        Ok(Self {
+            write_limit: Some(Arc::new(TokenBucket::new(ops_per_sec, burst))),
+            ..self
+        })
+    }
+
+    /// Hand all the SET and REMOVE requests of a batch to the engine in a single write_batch
+    /// call, so engines that support it flush the batch's writes once instead of once per write.
+    /// This reads the whole batch before running its writes, and their replies are sent together
+    /// once they're all done, after the replies of any reads that finished first. Other requests
+    /// still run concurrently as soon as they're read.
+    pub fn with_write_coalescing(self) -> Self {
+        Self {
+            coalesce_writes: true,
+            ..self
+        }
+    }
+
+    /// Reject batches that declare more than max requests, instead of DEFAULT_MAX_BATCH_SIZE, so
+    /// a client can't tie up a connection with a single bogus length. The connection gets a
+    /// "batch too large" error and is closed before any of its requests are read. Fails if max is
+    /// zero.
+    pub fn with_max_batch_size(self, max: u32) -> Result<Self> {
+        ensure!(max > 0, "max batch size must be positive");
+        Ok(Self {
+            max_batch_size: max,
+            ..self
+        })
+    }
+
+    /// Run every GET, GET_IF_NEWER, SCAN, SCAN_CONTINUE and SCAN_PREFIX request on a separate
+    /// pool of threads shared by all connections, instead of on the pool that reads requests and
+    /// runs writes. The read pool keeps its own engine clones, which hold on to the readers they
+    /// open from one request to the next, so a read-heavy server opens as many readers as it has
+    /// read threads rather than as many as it has connections. Fails if threads is zero.
+    pub fn with_read_pool(self, threads: u32) -> Result<Self> {
+        ensure!(threads > 0, "read pool must have threads");
+        let read_pool = ReadPool {
+            pool: P::new(threads)?,
+            stores: Stores::new(self.engine.clone()),
+        };
+        Ok(Self {
+            read_pool: Some(Arc::new(read_pool)),
+            ..self
         })
     }
 
-    /// Shutdown a server running on the specified address
-    pub fn shutdown(&self, addr: &SocketAddr) -> Result<()> {
+    // Check the token from a handshake against the configured secret
+    fn authorized(secret_hash: Option<[u8; 32]>, handshake: &Handshake) -> bool {
+        match (secret_hash, &handshake.token) {
+            (None, _) => true,
+            (Some(hash), Some(token)) => bool::from(hash_secret(token).ct_eq(&hash)),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Time since the server was created
+    pub fn uptime(&self) -> Duration {
+        self.start.instant.elapsed()
+    }
+
+    /// Wall-clock time when the server was created
+    pub fn started_at(&self) -> SystemTime {
+        self.start.wall
+    }
+
+    /// Address the server is listening on, or None if it isn't running. When run is given port
+    /// 0, this is where to find the port the OS picked. It's set by the time run signals that
+    /// binding has completed.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        *self.local_addr.lock().unwrap()
+    }
+
+    /// Counts of the connections the server has handled since it was created. The same counts
+    /// are part of the reply to a STATUS request.
+    pub fn connection_stats(&self) -> ConnectionStats {
+        self.counters.stats()
+    }
+
+    /// Counters of the server and stats of its engine, as shutdown reports them
+    pub fn summary(&self) -> ServerSummary {
+        ServerSummary {
+            uptime: self.uptime(),
+            requests: self.counters.requests.load(Ordering::SeqCst),
+            failed_requests: self.counters.failed_requests.load(Ordering::SeqCst),
+            bytes_written: self.counters.bytes_written.load(Ordering::SeqCst),
+            connections: self.counters.stats(),
+            engine: self
+                .engine
+                .engine_stats()
+                .map_err(|err| warn!("Engine stats FAILED for summary: {}", err))
+                .ok(),
+            active_jobs: self.active.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Renders the server's counters and its engine's stats in the Prometheus text exposition
+    /// format, ready to be served to a scraper. Names are namespaced under kvs_ and stay the same
+    /// across releases. Requests are counted once their reply is sent, and failed requests are
+    /// the ones that got an error back. If the engine can't report its stats, its series are left
+    /// out and the rest are still rendered.
+    pub fn prometheus_metrics(&self) -> String {
+        let connections = self.counters.stats();
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &dyn Display| {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            writeln!(out, "{} {}", name, value).unwrap();
+        };
+
+        metric(
+            "kvs_requests_total",
+            "counter",
+            "Requests that were replied to.",
+            &self.counters.requests.load(Ordering::SeqCst),
+        );
+        metric(
+            "kvs_request_errors_total",
+            "counter",
+            "Requests that were replied to with an error.",
+            &self.counters.failed_requests.load(Ordering::SeqCst),
+        );
+        metric(
+            "kvs_connections_active",
+            "gauge",
+            "Connections currently open.",
+            &connections.active,
+        );
+        metric(
+            "kvs_connections_accepted_total",
+            "counter",
+            "Connections accepted, including ones that were rejected afterwards.",
+            &connections.accepted,
+        );
+        metric(
+            "kvs_connections_rejected_total",
+            "counter",
+            "Connections turned away, while paused or for a bad handshake or batch.",
+            &connections.rejected,
+        );
+        metric(
+            "kvs_uptime_seconds",
+            "gauge",
+            "Seconds since the server was created.",
+            &self.uptime().as_secs_f64(),
+        );
+
+        match self.engine.engine_stats() {
+            Ok(stats) => {
+                metric(
+                    "kvs_live_keys",
+                    "gauge",
+                    "Keys that have a value.",
+                    &stats.live_keys,
+                );
+                if let Some(stale_bytes) = stats.stale_bytes {
+                    metric(
+                        "kvs_stale_bytes",
+                        "gauge",
+                        "Bytes of overwritten or removed data not reclaimed yet.",
+                        &stale_bytes,
+                    );
+                }
+                metric(
+                    "kvs_disk_bytes",
+                    "gauge",
+                    "Bytes the engine's data takes up on disk.",
+                    &stats.disk_bytes,
+                );
+            }
+            Err(err) => warn!("Engine stats FAILED for metrics: {}", err),
+        }
+        out
+    }
+
+    /// Stop serving new connections without shutting down. Connections that arrive while the
+    /// server is paused are closed right away, without reading anything from them, while requests
+    /// on connections that were already accepted keep running.
+    pub fn pause(&self) {
+        info!("Pause server");
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Serve new connections again after a pause
+    pub fn resume(&self) {
+        info!("Resume server");
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Refuse or allow SET, SETNX and REMOVE requests without shutting down, such as to keep the
+    /// data on disk stable during a backup. Refused writes fail with ReadOnly, while every other
+    /// request keeps being served. Turning read-only on waits for writes that are already running
+    /// to finish, so nothing is written once this returns.
+    pub fn set_read_only(&self, read_only: bool) {
+        info!("Set server read-only: {}", read_only);
+        *self.read_only.write().unwrap() = read_only;
+    }
+
+    /// Whether the server is refusing writes, see set_read_only
+    pub fn is_read_only(&self) -> bool {
+        *self.read_only.read().unwrap()
+    }
+
+    /// Shutdown a server running on the specified address. Returns a summary of the work the
+    /// server has done, which doesn't count requests that are still running.
+    pub fn shutdown(&self, addr: &SocketAddr) -> Result<ServerSummary> {
         info!("Send server shutdown signal at {}", addr);
         self.sender.send(())?;
 
@@ -58,13 +547,39 @@ impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> KvsServer<E, P> {
             _ => (),
         };
 
-        Ok(())
+        Ok(self.summary())
+    }
+
+    /// Shutdown a server running on the specified address, then wait up to the timeout for
+    /// in-flight requests to finish. Returns a summary of the work the server has done, whose
+    /// active_jobs are the jobs that were still running when the timeout expired, which is 0 if
+    /// everything finished in time. Stuck jobs are left running.
+    pub fn shutdown_timeout(&self, addr: &SocketAddr, timeout: Duration) -> Result<ServerSummary> {
+        self.shutdown(addr)?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let summary = self.summary();
+            if summary.active_jobs == 0 {
+                return Ok(summary);
+            }
+            if Instant::now() >= deadline {
+                warn!(
+                    "{} jobs still active after shutdown timeout",
+                    summary.active_jobs
+                );
+                return Ok(summary);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
     }
 
     /// Runs the server in an infinte loop to handle incoming requests. Can be cancelled by sending
-    /// message to the receiver.
+    /// message to the receiver. Binding to port 0 picks a free port, which local_addr reports.
     pub fn run(&self, addr: &SocketAddr, bind_event: Option<WaitGroup>) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
+        let addr = &listener.local_addr()?;
+        *self.local_addr.lock().unwrap() = Some(*addr);
         info!("Bind to {}", addr);
         // Signal that binding has completed and that we can start connecting
         bind_event.map(|event| drop(event));
@@ -78,109 +593,522 @@ impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> KvsServer<E, P> {
                 break;
             }
 
-            let stream = stream?;
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    *self.local_addr.lock().unwrap() = None;
+                    return Err(err.into());
+                }
+            };
+            if self.paused.load(Ordering::SeqCst) {
+                info!("Connection REJECTED while paused");
+                self.counters.refuse();
+                continue;
+            }
             let store = self.engine.clone();
             let pool = Arc::clone(&self.pool);
+            let active = Arc::clone(&self.active);
+            let start = self.start;
+            let secret_hash = self.secret_hash;
+            let write_limit = self.write_limit.clone();
+            let read_only = self.read_only.clone();
+            let coalesce_writes = self.coalesce_writes;
+            let max_batch_size = self.max_batch_size;
+            let read_pool = self.read_pool.clone();
+            let conn_job = ActiveJob::new(&self.active);
+            let connection = OpenConnection::new(&self.counters);
 
             self.pool.spawn(move || {
+                let _conn_job = conn_job;
+                let span = Span::connection(stream.peer_addr().ok());
                 let mut writer = BufWriter::new(stream.try_clone().expect("stream clone fail"));
                 let mut reader = BufReader::new(stream);
 
-                let len = {
-                    let mut len_buf = [0];
-                    reader.read_exact(&mut len_buf).expect("length read error");
-                    len_buf[0]
-                };
+                let handshake = Handshake::read(&mut reader).expect("handshake read error");
+                let codec = handshake.codec;
+                if !Self::authorized(secret_hash, &handshake) {
+                    warn!("Connection REJECTED with missing or wrong token");
+                    connection.reject();
+                    error_reply(Unauthorized.into())
+                        .write(&mut writer, codec)
+                        .expect("message write error");
+                    writer.flush().expect("message write error");
+                    // Read whatever the client already sent, so closing the connection doesn't
+                    // reset it before the client reads the error
+                    let _ = io::copy(&mut reader, &mut io::sink());
+                    return;
+                }
+
+                let len = read_batch_len(&mut reader).expect("length read error");
 
                 if len == 0 {
                     warn!("Batch FAILED with invalid length of 0");
-                    Message::Error("invalid batch length of 0".to_owned())
-                        .write(&mut writer)
+                    Message::Error(ErrorCode::Other, "invalid batch length of 0".to_owned())
+                        .write(&mut writer, codec)
                         .unwrap();
                     return;
                 }
+                if len > max_batch_size {
+                    warn!("Batch REJECTED with length {} over the maximum", len);
+                    connection.reject();
+                    Message::Error(ErrorCode::Other, "batch too large".to_owned())
+                        .write(&mut writer, codec)
+                        .expect("message write error");
+                    writer.flush().expect("message write error");
+                    return;
+                }
                 info!("{} requests incoming", len);
 
-                // Wrap reader and writer in mutexes so they can be sent to other threads.
-                // Need mutex protection around buffered writer/readers so we don't write/read
-                // garbage data from multiple threads.
-                let writer = Arc::new(Mutex::new(writer));
-                let reader = Arc::new(Mutex::new(reader));
+                let batch = Arc::new(Batch {
+                    writer: Mutex::new(writer),
+                    stores: Stores::new(store),
+                    start,
+                    write_limit,
+                    read_only,
+                    codec,
+                    connection,
+                    span,
+                });
+                if coalesce_writes {
+                    batch.span.in_scope(|| {
+                        Self::run_coalesced(&batch, reader, len, &pool, &active, read_pool.as_ref())
+                    });
+                    return;
+                }
 
+                // Read each request before spawning its job, so a batch only costs jobs for the
+                // requests that actually arrive, whatever length it declared
                 for i in 0..len {
+                    let msg = Message::read(&mut reader, batch.codec).expect("message read error");
+                    info!("Finished reading request {} from stream", i);
                     // Inexpensive Arc clones
-                    let writer = Arc::clone(&writer);
-                    let reader = Arc::clone(&reader);
-                    let mut store = E::clone(&store);
+                    let batch = Arc::clone(&batch);
+                    let read_pool = read_pool.clone();
+                    let request_job = ActiveJob::new(&active);
 
                     pool.spawn(move || {
-                        let msg = Message::read(&mut *reader.lock().unwrap())
-                            .expect("message read error");
-                        info!("Finished reading request {} from stream", i);
-
-                        let resp = match Self::handle_request(msg, &mut store) {
-                            Ok(value) => {
-                                info!("Request SUCCESS, reply: {}", value.join(" "));
-                                Message::Array(value)
-                            }
-                            Err(err) => {
-                                let err = err.as_fail().to_string();
-                                warn!("Request FAILED, reply: {}", err);
-                                Message::Error(err)
-                            }
-                        };
-
-                        resp.write(&mut *writer.lock().unwrap())
-                            .expect("message write error");
-                        info!("Finished writing response to stream");
+                        Self::dispatch(&batch, msg, read_pool.as_ref(), request_job);
                     });
                 }
             });
         }
 
+        *self.local_addr.lock().unwrap() = None;
         Ok(())
     }
 
-    // Get returns [key, value] or [key] if value is not found when successful
-    // Set and Remove return [key] when successful
-    fn handle_request(msg: Message, store: &mut E) -> Result<Vec<String>> {
-        match msg {
-            Message::Array(arr) => {
-                info!("Received TCP args: {}", arr.join(" "));
-
-                match arr.get(0).map(|s| &s[..]) {
-                    Some(GET) => {
-                        check_len(&arr, 2)?;
-                        let key = arr[1].to_owned();
-                        // If value does not exist, return empty list
-                        Ok(store
-                            .get(key.clone())?
-                            .map(|val| vec![key.clone(), val])
-                            .unwrap_or(vec![key]))
+    // Hands reads to the read pool if there is one, and runs anything else right away
+    fn dispatch(
+        batch: &Arc<Batch<E>>,
+        msg: Message,
+        read_pool: Option<&Arc<ReadPool<E, P>>>,
+        request_job: ActiveJob,
+    ) {
+        match read_pool {
+            Some(read_pool) if is_read(&msg) => {
+                let batch = Arc::clone(batch);
+                let read_pool_clone = Arc::clone(read_pool);
+                read_pool.pool.spawn(move || {
+                    let _request_job = request_job;
+                    let mut store = read_pool_clone.stores.take();
+                    Self::run_request(&batch, msg, &mut store);
+                    read_pool_clone.stores.give_back(store);
+                });
+            }
+            _ => {
+                let _request_job = request_job;
+                let mut store = batch.stores.take();
+                Self::run_request(batch, msg, &mut store);
+                batch.stores.give_back(store);
+            }
+        }
+    }
+
+    // Handles one request of a batch on the store and writes its reply
+    fn run_request(batch: &Batch<E>, msg: Message, store: &mut E) {
+        let span = batch.span.request();
+        span.in_scope(|| Self::run_request_in_span(batch, msg, store, &span));
+    }
+
+    fn run_request_in_span(batch: &Batch<E>, msg: Message, store: &mut E, span: &Span) {
+        let result = Self::handle_request(
+            msg,
+            store,
+            &batch.start,
+            &batch.connection.0,
+            batch.write_limit.as_deref(),
+            &batch.read_only,
+            span,
+        );
+
+        let mut writer = batch.writer.lock().unwrap();
+        let resp = match result {
+            Ok(Reply::Array(value)) => {
+                info!("Request SUCCESS, reply: {}", value.join(" "));
+                Message::Array(value)
+            }
+            // Dumps hold the writer lock throughout so that replies to other requests can't land
+            // in the middle of them
+            Ok(Reply::Dump) => {
+                match spans::engine("iter", || Self::dump(store, &mut *writer, batch.codec)) {
+                    Ok(count) => {
+                        info!("Request SUCCESS, dumped {} pairs", count);
+                        Message::Array(Vec::new())
                     }
+                    Err(err) => error_reply(err),
+                }
+            }
+            Err(err) => error_reply(err),
+        };
+
+        span.record_reply(&resp);
+        batch.write_reply(&mut writer, resp);
+        info!("Finished writing response to stream");
+    }
+
+    // Reads the whole batch before handling it, so that its writes reach the engine in a single
+    // write_batch call. Other requests are spawned as soon as they're read, like usual.
+    fn run_coalesced(
+        batch: &Arc<Batch<E>>,
+        mut reader: impl Read,
+        len: u32,
+        pool: &Arc<P>,
+        active: &Arc<AtomicUsize>,
+        read_pool: Option<&Arc<ReadPool<E, P>>>,
+    ) {
+        let mut ops = Vec::new();
+        let mut replies = Vec::new();
+        for i in 0..len {
+            let msg = Message::read(&mut reader, batch.codec).expect("message read error");
+            info!("Finished reading request {} from stream", i);
+            let msg = match msg.decompress() {
+                Ok(msg) => msg,
+                Err(err) => {
+                    replies.push(error_reply(err));
+                    continue;
+                }
+            };
+            match write_op(msg) {
+                Ok(_) if *batch.read_only.read().unwrap() => {
+                    replies.push(error_reply(ReadOnly.into()))
+                }
+                Ok(_) if batch.write_limit.as_ref().is_some_and(|l| !l.try_take()) => {
+                    replies.push(error_reply(RateLimited.into()))
+                }
+                Ok(op) => ops.push(op),
+                Err(msg) => {
+                    let batch = Arc::clone(batch);
+                    let read_pool = read_pool.cloned();
+                    let request_job = ActiveJob::new(active);
+                    pool.spawn(move || {
+                        Self::dispatch(&batch, msg, read_pool.as_ref(), request_job);
+                    });
+                }
+            }
+        }
 
-                    Some(SET) => {
-                        check_len(&arr, 3)?;
-                        let (key, value) = (&arr[1], &arr[2]);
-                        store.set(key.to_owned(), value.to_owned())?;
-                        Ok(vec![key.to_owned()])
+        if !ops.is_empty() {
+            // Each key along with the length of the value it's set to, if any
+            let keys: Vec<(String, Option<usize>)> = ops
+                .iter()
+                .map(|op| match op {
+                    WriteOp::Set(key, value) => (key.clone(), Some(value.len())),
+                    WriteOp::Remove(key) => (key.clone(), None),
+                })
+                .collect();
+            let store = batch.stores.take();
+            // Read-only could have been turned on while the batch was being read
+            let read_only = batch.read_only.read().unwrap();
+            let result = if *read_only {
+                Err(ReadOnly.into())
+            } else {
+                spans::engine("write_batch", || store.write_batch(ops))
+            };
+            drop(read_only);
+            match result {
+                Ok(results) => {
+                    info!("Wrote {} requests in one batch", keys.len());
+                    for ((key, value_len), result) in keys.into_iter().zip(results) {
+                        replies.push(match result {
+                            Ok(()) => {
+                                if let Some(value_len) = value_len {
+                                    batch.connection.0.count_write(&key, value_len);
+                                }
+                                Message::Array(vec![key])
+                            }
+                            Err(err) => error_reply(err),
+                        });
                     }
+                }
+                Err(err) => {
+                    let code = ErrorCode::of(&err);
+                    let err = err.as_fail().to_string();
+                    warn!("Write batch FAILED, reply: {}", err);
+                    replies.extend(keys.iter().map(|_| Message::Error(code, err.clone())));
+                }
+            }
+            batch.stores.give_back(store);
+        }
 
-                    Some(REMOVE) => {
-                        check_len(&arr, 2)?;
-                        let key = &arr[1];
-                        store.remove(key.to_owned())?;
-                        Ok(vec![key.to_owned()])
+        let mut writer = batch.writer.lock().unwrap();
+        for resp in replies {
+            batch.write_reply(&mut writer, resp);
+        }
+        info!("Finished writing write responses to stream");
+    }
+
+    // Get returns [key, value] or [key] if value is not found when successful
+    // Set and Remove return [key] when successful
+    // Setnx returns [key, "1"] if it set the key or [key, "0"] if not
+    // Status returns the reply described by ServerStatus
+    // Dump streams its reply separately
+    // Arguments are moved out of the request rather than copied, so large values reach the engine
+    // without being copied on the way
+    fn handle_request(
+        msg: Message,
+        store: &mut E,
+        start: &StartTime,
+        counters: &Counters,
+        write_limit: Option<&TokenBucket>,
+        read_only: &RwLock<bool>,
+        span: &Span,
+    ) -> Result<Reply> {
+        let mut arr = match msg.decompress()? {
+            Message::Array(arr) => arr,
+            Message::Deadline(deadline, arr) => {
+                if deadline_passed(deadline) {
+                    return Err(DeadlineExceeded.into());
+                }
+                arr
+            }
+            Message::Error(_, err) => return Err(format_err!("received error message {}", err)),
+            Message::Compressed(..) => unreachable!("decompressed request is still compressed"),
+        };
+
+        info!("Received TCP args: {}", arr.join(" "));
+        span.record_args(&arr);
+
+        let is_write = matches!(
+            arr.first().map(|s| &s[..]),
+            Some(SET) | Some(REMOVE) | Some(SETNX)
+        );
+        // Held until the write is done, see set_read_only
+        let _write_guard = if is_write {
+            let guard = read_only.read().unwrap();
+            if *guard {
+                return Err(ReadOnly.into());
+            }
+            Some(guard)
+        } else {
+            None
+        };
+        if is_write && write_limit.is_some_and(|limit| !limit.try_take()) {
+            return Err(RateLimited.into());
+        }
+
+        match arr.first().map(|s| &s[..]) {
+            Some(GET) => {
+                check_len(&arr, 2)?;
+                let key = mem::take(&mut arr[1]);
+                // If value does not exist, return empty list
+                Ok(Reply::Array(
+                    match spans::engine("get", || store.get(key.clone()))? {
+                        Some(val) => vec![key, val],
+                        None => vec![key],
+                    },
+                ))
+            }
+
+            Some(SET) => {
+                check_len(&arr, 3)?;
+                let (key, value) = (mem::take(&mut arr[1]), mem::take(&mut arr[2]));
+                let value_len = value.len();
+                spans::engine("set", || store.set(key.clone(), value))?;
+                counters.count_write(&key, value_len);
+                Ok(Reply::Array(vec![key]))
+            }
+
+            Some(REMOVE) => {
+                check_len(&arr, 2)?;
+                let key = mem::take(&mut arr[1]);
+                spans::engine("remove", || store.remove(key.clone()))?;
+                Ok(Reply::Array(vec![key]))
+            }
+
+            Some(SETNX) => {
+                check_len(&arr, 3)?;
+                let (key, value) = (mem::take(&mut arr[1]), mem::take(&mut arr[2]));
+                let value_len = value.len();
+                let set = spans::engine("set_nx", || store.set_nx(key.clone(), value))?;
+                if set {
+                    counters.count_write(&key, value_len);
+                }
+                Ok(Reply::Array(vec![
+                    key,
+                    if set { "1" } else { "0" }.to_owned(),
+                ]))
+            }
+
+            Some(GET_IF_NEWER) => {
+                check_len(&arr, 3)?;
+                let since: u64 = arr[2].parse()?;
+                let key = mem::take(&mut arr[1]);
+                let mut reply = vec![key.clone()];
+                if let Some((value, version)) =
+                    spans::engine("get_versioned", || store.get_versioned(key))?
+                {
+                    reply.push(version.to_string());
+                    if version > since {
+                        reply.push(value);
                     }
+                }
+                Ok(Reply::Array(reply))
+            }
+
+            Some(STATUS) => {
+                check_len(&arr, 1)?;
+                Ok(Reply::Array(start.status(counters.stats()).to_reply()))
+            }
+
+            Some(DUMP) => {
+                check_len(&arr, 1)?;
+                Ok(Reply::Dump)
+            }
+
+            Some(SCAN) => {
+                check_len(&arr, 3)?;
+                let cursor = ScanCursor::from_token(&arr[1], arr[2].parse()?)?;
+                Ok(Reply::Array(Self::scan(store, cursor)?))
+            }
+
+            Some(SCAN_CONTINUE) => {
+                check_len(&arr, 2)?;
+                let cursor = ScanCursor::from_token(&arr[1], 0)?;
+                Ok(Reply::Array(Self::scan(store, cursor)?))
+            }
 
-                    _ => Err(format_err!("invalid incoming message")),
+            Some(SCAN_PREFIX) => {
+                check_len(&arr, 2)?;
+                let pairs = spans::engine("scan_prefix", || store.scan_prefix(&arr[1]))?;
+                let mut reply = Vec::with_capacity(pairs.len() * 2);
+                for (key, value) in pairs {
+                    reply.push(key);
+                    reply.push(value);
                 }
+                Ok(Reply::Array(reply))
+            }
+
+            Some(cmd) => Err(UnknownCommand(cmd.to_owned()).into()),
+            None => Err(format_err!("empty request")),
+        }
+    }
+
+    // Builds the reply to a scan, which starts with the cursor of the next page
+    fn scan(store: &E, cursor: ScanCursor) -> Result<Vec<String>> {
+        ensure!(cursor.limit > 0, "scan limit must be positive");
+        // Ask for one extra pair to find out if the scan is over
+        let mut pairs = spans::engine("scan", || {
+            store.scan(cursor.after.as_ref().map(|s| &s[..]), cursor.limit + 1)
+        })?;
+        let next = if pairs.len() > cursor.limit {
+            pairs.truncate(cursor.limit);
+            ScanCursor {
+                limit: cursor.limit,
+                after: pairs.last().map(|(key, _)| key.clone()),
             }
-            Message::Error(err) => Err(format_err!("received error message {}", err)),
+            .to_token()
+        } else {
+            SCAN_START.to_owned()
+        };
+
+        let mut reply = vec![next];
+        for (key, value) in pairs {
+            reply.push(key);
+            reply.push(value);
+        }
+        Ok(reply)
+    }
+
+    // Writes a [key, value] message for every pair in the store and returns how many were sent
+    fn dump(store: &E, mut writer: impl Write, codec: Codec) -> Result<usize> {
+        let mut count = 0;
+        for pair in store.iter()? {
+            let (key, value) = pair?;
+            Message::Array(vec![key, value]).write(&mut writer, codec)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+impl<P: ThreadPool + Send + Sync + 'static> KvsServer<SwappableEngine, P> {
+    /// Move the running server onto a new engine, which can be of any type, such as to migrate
+    /// from KvStore to sled without stopping. Every pair is copied into the new engine first, and
+    /// requests are switched over once the copy is done. Writes wait for the whole copy, while
+    /// reads keep being served by the old engine until the switch, see SwappableEngine::swap.
+    pub fn swap_engine(&self, new: impl KvsEngine) -> Result<()> {
+        info!("Swap server engine");
+        self.engine.swap(new)
+    }
+}
+
+// Requests of a batch share this, along with the writer their replies go to
+struct Batch<E> {
+    // Need mutex protection around the buffered writer so we don't write garbage data from
+    // multiple threads
+    writer: Mutex<BufWriter<TcpStream>>,
+    // Requests borrow store clones from here, so a big batch only makes as many clones as there
+    // are requests running at once
+    stores: Stores<E>,
+    start: StartTime,
+    write_limit: Option<Arc<TokenBucket>>,
+    read_only: Arc<RwLock<bool>>,
+    codec: Codec,
+    connection: OpenConnection,
+    span: Span,
+}
+
+impl<E: KvsEngine> Batch<E> {
+    // A client that doesn't read its replies may have closed the connection already, which
+    // mustn't keep the rest of the batch from running, so failed writes are only logged
+    fn write_reply(&self, writer: &mut BufWriter<TcpStream>, resp: Message) {
+        self.connection.0.count_reply(&resp);
+        if let Err(err) = resp.write(writer, self.codec) {
+            warn!("Reply write FAILED: {}", err);
         }
     }
 }
 
+// The write a request makes, if it's a well-formed SET or REMOVE that's still within its deadline.
+// Anything else is handed back untouched to go through handle_request, which replies with the
+// right error.
+fn write_op(msg: Message) -> std::result::Result<WriteOp, Message> {
+    let (deadline, mut arr) = match msg {
+        Message::Array(arr) => (None, arr),
+        Message::Deadline(deadline, arr) if !deadline_passed(deadline) => (Some(deadline), arr),
+        msg => return Err(msg),
+    };
+    match (arr.first().map(|s| &s[..]), arr.len()) {
+        (Some(SET), 3) => {
+            let value = arr.pop().unwrap();
+            let key = arr.pop().unwrap();
+            Ok(WriteOp::Set(key, value))
+        }
+        (Some(REMOVE), 2) => Ok(WriteOp::Remove(arr.pop().unwrap())),
+        _ => Err(match deadline {
+            Some(deadline) => Message::Deadline(deadline, arr),
+            None => Message::Array(arr),
+        }),
+    }
+}
+
+fn error_reply(err: Error) -> Message {
+    let code = ErrorCode::of(&err);
+    let err = err.as_fail().to_string();
+    warn!("Request FAILED, reply: {}", err);
+    Message::Error(code, err)
+}
+
 fn check_len(arr: &[String], expected: usize) -> Result<()> {
     ensure!(
         arr.len() == expected,
@@ -0,0 +1,88 @@
+use crate::protocol::Message;
+use std::net::SocketAddr;
+
+// Spans emitted by the server when the tracing feature is on. Every connection gets a span, every
+// request a child span of its connection, and every engine call a child span of its request.
+// Without the feature these are all empty and compile away, so the server doesn't need cfgs of its
+// own.
+
+#[cfg(feature = "tracing")]
+#[derive(Clone)]
+pub(crate) struct Span(tracing::Span);
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone)]
+pub(crate) struct Span;
+
+#[cfg(feature = "tracing")]
+impl Span {
+    pub(crate) fn connection(peer: Option<SocketAddr>) -> Self {
+        let peer = peer.map(|peer| peer.to_string());
+        Span(tracing::info_span!(
+            "connection",
+            peer = peer.as_deref().unwrap_or("unknown")
+        ))
+    }
+
+    // The command and key are filled in once the request is read, and the result once it's done
+    pub(crate) fn request(&self) -> Self {
+        Span(tracing::info_span!(
+            parent: &self.0,
+            "request",
+            command = tracing::field::Empty,
+            key = tracing::field::Empty,
+            result = tracing::field::Empty,
+        ))
+    }
+
+    // Runs f with this span as the current one, which makes it the parent of any engine spans
+    pub(crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.0.in_scope(f)
+    }
+
+    pub(crate) fn record_args(&self, args: &[String]) {
+        if let Some(command) = args.first() {
+            self.0.record("command", command.as_str());
+        }
+        if let Some(key) = args.get(1) {
+            self.0.record("key", key.as_str());
+        }
+    }
+
+    pub(crate) fn record_reply(&self, reply: &Message) {
+        match reply {
+            Message::Error(_, err) => self.0.record("result", err.as_str()),
+            _ => self.0.record("result", "ok"),
+        };
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn connection(_peer: Option<SocketAddr>) -> Self {
+        Span
+    }
+
+    pub(crate) fn request(&self) -> Self {
+        Span
+    }
+
+    pub(crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        f()
+    }
+
+    pub(crate) fn record_args(&self, _args: &[String]) {}
+
+    pub(crate) fn record_reply(&self, _reply: &Message) {}
+}
+
+// Runs an engine operation in a span of its own, under the current request
+#[cfg(feature = "tracing")]
+pub(crate) fn engine<T>(op: &'static str, f: impl FnOnce() -> T) -> T {
+    tracing::info_span!("engine", op).in_scope(f)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn engine<T>(_op: &'static str, f: impl FnOnce() -> T) -> T {
+    f()
+}
@@ -0,0 +1,428 @@
+use crate::{
+    all_log_files, blob_path, compacted_log_path, log_generations, log_path, open_read, open_write,
+    parse_blob_id,
+};
+use log::error;
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::fs::{read, read_dir, remove_file, rename, File};
+use std::io::{self, prelude::*, ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Handle to one log, which can be read, written, and seeked like a file. Handles opened for
+/// appending always write at the end of the log, no matter where they were seeked to.
+pub trait LogFile: Read + Write + Seek + Send {
+    /// Current length of the log in bytes
+    fn len(&self) -> io::Result<u64>;
+
+    /// Whether the log has no bytes
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Cut the log down to the given length
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Make sure everything written so far is durable
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Reserve disk space for the log to grow to the given length, without changing its length.
+    /// Backends that can't reserve space do nothing.
+    fn preallocate(&mut self, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Backend that holds the logs of a KvStore. Every log belongs to a generation, and there's also
+/// a temporary log that compaction fills before promoting it to a generation.
+pub trait LogStorage: Send + Sync + 'static {
+    /// Open an existing log for reading. Fails with NotFound if it doesn't exist.
+    fn read(&self, gen: u64) -> io::Result<Box<dyn LogFile>>;
+
+    /// Open a log for appending, creating it if it doesn't exist. A log this creates must still
+    /// exist after a crash of the whole machine once this returns, though its contents are only
+    /// durable once synced.
+    fn append(&self, gen: u64) -> io::Result<Box<dyn LogFile>>;
+
+    /// Create an empty temporary log for appending. Fails if it already exists.
+    fn create_temp(&self) -> io::Result<Box<dyn LogFile>>;
+
+    /// Turn the temporary log into the log of a generation, replacing any log it already had. Like
+    /// creating a log in append, this must survive a crash of the whole machine once it returns.
+    fn commit_temp(&self, gen: u64) -> io::Result<()>;
+
+    /// Generations that currently have a log, in no particular order
+    fn generations(&self) -> io::Result<Vec<u64>>;
+
+    /// Delete every log, including the temporary one, except the one of the given generation.
+    /// Handles that are still open must keep working if the backend allows it.
+    fn remove_except(&self, gen: u64) -> io::Result<()>;
+
+    /// Total number of bytes taken up by all logs and blobs
+    fn size(&self) -> io::Result<u64>;
+
+    /// Map the log of a generation into memory, covering the bytes it has at the time of the
+    /// call. Backends that can't map logs return None, which makes the store read them through
+    /// buffers instead.
+    fn map(&self, _gen: u64) -> io::Result<Option<Mmap>> {
+        Ok(None)
+    }
+
+    /// Store a value outside of the logs under an id that's never been used before. The blob
+    /// must be durable once this returns, since the record that refers to it may be synced right
+    /// after. Backends that can't hold blobs fail with Unsupported, and so can't be used with
+    /// KvStoreOptions::blob_threshold.
+    fn write_blob(&self, _id: u64, _data: &[u8]) -> io::Result<()> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    /// Read back a whole blob. Fails with NotFound if it doesn't exist.
+    fn read_blob(&self, _id: u64) -> io::Result<Vec<u8>> {
+        Err(ErrorKind::NotFound.into())
+    }
+
+    /// Ids of every blob, in no particular order
+    fn blobs(&self) -> io::Result<Vec<u64>> {
+        Ok(Vec::new())
+    }
+
+    /// Delete a blob. Handles to logs that are still open are unaffected.
+    fn remove_blob(&self, _id: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl LogFile for File {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    // Only Linux has a way to allocate space without also extending the file. Filesystems that
+    // don't support it are treated like other platforms.
+    #[cfg(target_os = "linux")]
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let len = len.min(libc::off_t::MAX as u64) as libc::off_t;
+        let ret = unsafe { libc::fallocate(self.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) };
+        if ret == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EOPNOTSUPP) {
+            Ok(())
+        } else {
+            Err(err)
+        }
+    }
+}
+
+/// Keeps logs as files in a directory. This is what KvStore::open uses.
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    /// Store logs in an existing directory
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_owned(),
+        }
+    }
+}
+
+impl LogStorage for FileStorage {
+    fn read(&self, gen: u64) -> io::Result<Box<dyn LogFile>> {
+        Ok(Box::new(open_read().open(log_path(&self.dir, gen))?))
+    }
+
+    fn append(&self, gen: u64) -> io::Result<Box<dyn LogFile>> {
+        let path = log_path(&self.dir, gen);
+        let created = !path.exists();
+        let file = open_write().create(true).open(path)?;
+        if created {
+            sync_dir(&self.dir)?;
+        }
+        Ok(Box::new(file))
+    }
+
+    fn create_temp(&self) -> io::Result<Box<dyn LogFile>> {
+        Ok(Box::new(
+            open_write()
+                .create_new(true)
+                .open(compacted_log_path(&self.dir))?,
+        ))
+    }
+
+    fn commit_temp(&self, gen: u64) -> io::Result<()> {
+        rename(compacted_log_path(&self.dir), log_path(&self.dir, gen))?;
+        sync_dir(&self.dir)
+    }
+
+    fn generations(&self) -> io::Result<Vec<u64>> {
+        log_generations(&self.dir).map_err(to_io)
+    }
+
+    // On Windows removing files still open by reader will fail, so we don't worry too much about
+    // it
+    fn remove_except(&self, gen: u64) -> io::Result<()> {
+        for file in all_log_files(&self.dir, Some(gen)).map_err(to_io)? {
+            if let Err(err) = remove_file(&file) {
+                error!("Failed to remove {}: {}", file.display(), err);
+            }
+        }
+        Ok(())
+    }
+
+    // Includes log files from older generations that haven't been removed yet
+    fn size(&self) -> io::Result<u64> {
+        let mut size = 0;
+        for file in all_log_files(&self.dir, None).map_err(to_io)? {
+            size += file.metadata()?.len();
+        }
+        for id in self.blobs()? {
+            size += blob_path(&self.dir, id).metadata()?.len();
+        }
+        Ok(size)
+    }
+
+    fn map(&self, gen: u64) -> io::Result<Option<Mmap>> {
+        let file = open_read().open(log_path(&self.dir, gen))?;
+        // Mapped bytes are never modified while they're mapped. The current log only grows, and
+        // compaction writes new logs instead of rewriting old ones. Torn records are only
+        // truncated while opening a store, before any reads happen. A log removed while it's
+        // still mapped stays readable on Unix, since the mapping keeps the file alive.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Some(map))
+    }
+
+    fn write_blob(&self, id: u64, data: &[u8]) -> io::Result<()> {
+        let mut file = open_write()
+            .create_new(true)
+            .open(blob_path(&self.dir, id))?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        sync_dir(&self.dir)
+    }
+
+    fn read_blob(&self, id: u64) -> io::Result<Vec<u8>> {
+        read(blob_path(&self.dir, id))
+    }
+
+    fn blobs(&self) -> io::Result<Vec<u64>> {
+        let mut ids = Vec::new();
+        for entry in read_dir(&self.dir)? {
+            if let Some(id) = parse_blob_id(&entry?.path()) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    fn remove_blob(&self, id: u64) -> io::Result<()> {
+        remove_file(blob_path(&self.dir, id))
+    }
+}
+
+// Creating or renaming a file only changes its directory, which has to be synced on its own for
+// the change to survive a crash of the machine. Only Unix lets a directory be opened and synced
+// like a file, so this does nothing elsewhere.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn to_io(err: failure::Error) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+type Buffer = Arc<Mutex<Vec<u8>>>;
+
+/// Keeps logs in memory, so a store can be used without touching the filesystem. Clones share
+/// the same logs, so a store reopened from a clone sees the data of the previous one.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    logs: Arc<Mutex<HashMap<u64, Buffer>>>,
+    temp: Arc<Mutex<Option<Buffer>>>,
+    blobs: Arc<Mutex<HashMap<u64, Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    /// Create a storage without any logs
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl LogStorage for MemoryStorage {
+    fn read(&self, gen: u64) -> io::Result<Box<dyn LogFile>> {
+        match self.logs.lock().unwrap().get(&gen) {
+            Some(buf) => Ok(Box::new(MemoryLog::new(buf, false))),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn append(&self, gen: u64) -> io::Result<Box<dyn LogFile>> {
+        let mut logs = self.logs.lock().unwrap();
+        let buf = logs.entry(gen).or_default();
+        Ok(Box::new(MemoryLog::new(buf, true)))
+    }
+
+    fn create_temp(&self) -> io::Result<Box<dyn LogFile>> {
+        let mut temp = self.temp.lock().unwrap();
+        if temp.is_some() {
+            return Err(ErrorKind::AlreadyExists.into());
+        }
+        let buf = Buffer::default();
+        let log = MemoryLog::new(&buf, true);
+        *temp = Some(buf);
+        Ok(Box::new(log))
+    }
+
+    fn commit_temp(&self, gen: u64) -> io::Result<()> {
+        let buf = self
+            .temp
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(ErrorKind::NotFound)?;
+        self.logs.lock().unwrap().insert(gen, buf);
+        Ok(())
+    }
+
+    fn generations(&self) -> io::Result<Vec<u64>> {
+        Ok(self.logs.lock().unwrap().keys().cloned().collect())
+    }
+
+    // Open handles keep their buffer alive, so they still work after their log is removed
+    fn remove_except(&self, gen: u64) -> io::Result<()> {
+        self.logs.lock().unwrap().retain(|g, _| *g == gen);
+        *self.temp.lock().unwrap() = None;
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        let logs = self.logs.lock().unwrap();
+        let blobs = self.blobs.lock().unwrap();
+        Ok(logs
+            .values()
+            .map(|buf| buf.lock().unwrap().len() as u64)
+            .chain(blobs.values().map(|blob| blob.len() as u64))
+            .sum())
+    }
+
+    fn write_blob(&self, id: u64, data: &[u8]) -> io::Result<()> {
+        self.blobs.lock().unwrap().insert(id, data.to_vec());
+        Ok(())
+    }
+
+    fn read_blob(&self, id: u64) -> io::Result<Vec<u8>> {
+        match self.blobs.lock().unwrap().get(&id) {
+            Some(blob) => Ok(blob.clone()),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn blobs(&self) -> io::Result<Vec<u64>> {
+        Ok(self.blobs.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn remove_blob(&self, id: u64) -> io::Result<()> {
+        self.blobs.lock().unwrap().remove(&id);
+        Ok(())
+    }
+}
+
+// Handle to a log in a MemoryStorage, with its own position like a file handle
+struct MemoryLog {
+    buf: Buffer,
+    pos: u64,
+    append: bool,
+}
+
+impl MemoryLog {
+    fn new(buf: &Buffer, append: bool) -> Self {
+        Self {
+            buf: Arc::clone(buf),
+            pos: 0,
+            append,
+        }
+    }
+}
+
+impl Read for MemoryLog {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let buf = self.buf.lock().unwrap();
+        let start = (self.pos as usize).min(buf.len());
+        let n = out.len().min(buf.len() - start);
+        out[..n].copy_from_slice(&buf[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemoryLog {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut buf = self.buf.lock().unwrap();
+        if self.append {
+            self.pos = buf.len() as u64;
+        }
+        let start = self.pos as usize;
+        if buf.len() < start + data.len() {
+            buf.resize(start + data.len(), 0);
+        }
+        buf[start..start + data.len()].copy_from_slice(data);
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryLog {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.buf.lock().unwrap().len() as i64;
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl LogFile for MemoryLog {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.buf.lock().unwrap().len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.buf.lock().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,313 @@
+use crate::{KeyNotFound, KvsEngine, Result, WriteOp};
+use std::path::Path;
+use tempfile::TempDir;
+
+type Check<E> = fn(&Path, &dyn Fn(&Path) -> E) -> Result<()>;
+
+/// Checks that an engine follows the behaviour every KvsEngine is expected to have. `new` opens
+/// or creates an engine stored in the given directory. Each check runs in its own empty
+/// directory, and checks that reopen the engine drop every handle to it before calling `new`
+/// again. Panics if the engine breaks the contract and returns any error the engine raises.
+/// ```
+/// use kvs::{testsuite::run_conformance, KvStore, Result};
+///
+/// # fn main() -> Result<()> {
+///     run_conformance(|dir| KvStore::open(dir).expect("can't open kvs"))?;
+/// #   Ok(())
+/// # }
+/// ```
+pub fn run_conformance<E: KvsEngine>(new: impl Fn(&Path) -> E) -> Result<()> {
+    let checks: &[Check<E>] = &[
+        get_stored_value,
+        overwrite_value,
+        get_missing_key,
+        remove_key,
+        remove_missing_key,
+        clear,
+        empty_value,
+        shared_clones,
+        reopen,
+        iter,
+        scan,
+        scan_prefix,
+        get_old_value,
+        set_nx,
+        versions,
+        write_batch,
+    ];
+
+    for check in checks {
+        let temp_dir = TempDir::new()?;
+        check(temp_dir.path(), &new)?;
+    }
+
+    Ok(())
+}
+
+fn get_stored_value<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn overwrite_value<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn get_missing_key<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+fn remove_key<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Removing a missing key must fail with KeyNotFound, which the server and clients rely on
+fn remove_missing_key<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    let err = store.remove("key1".to_owned()).unwrap_err();
+    assert!(err.downcast_ref::<KeyNotFound>().is_some());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let err = store.remove("key1".to_owned()).unwrap_err();
+    assert!(err.downcast_ref::<KeyNotFound>().is_some());
+    Ok(())
+}
+
+fn clear<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.clear()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // The engine should still be usable after being cleared
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// An empty value is a value, not a missing key
+fn empty_value<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    store.set("key1".to_owned(), String::new())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(String::new()));
+    Ok(())
+}
+
+// Clones are handles to the same data
+fn shared_clones<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    let clone = store.clone();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(clone.get("key1".to_owned())?, Some("value1".to_owned()));
+    clone.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+fn reopen<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.remove("key3".to_owned())?;
+    drop(store);
+
+    let store = new(dir);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+fn scan<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    assert!(store.scan(None, 10)?.is_empty());
+
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key2".to_owned())?;
+
+    let page = store.scan(None, 2)?;
+    assert_eq!(
+        page,
+        vec![
+            ("key0".to_owned(), "value0".to_owned()),
+            ("key1".to_owned(), "value1".to_owned()),
+        ]
+    );
+    let page = store.scan(Some("key1"), 2)?;
+    assert_eq!(
+        page,
+        vec![
+            ("key3".to_owned(), "value3".to_owned()),
+            ("key4".to_owned(), "value4".to_owned()),
+        ]
+    );
+    assert!(store.scan(Some("key4"), 2)?.is_empty());
+    Ok(())
+}
+
+// Enough keys match to take more than one page for engines that page through scan
+fn scan_prefix<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    assert!(store.scan_prefix("a/")?.is_empty());
+
+    let mut expected = vec![("a/".to_owned(), "value".to_owned())];
+    for i in 0..250 {
+        expected.push((format!("a/{:03}", i), format!("value{}", i)));
+    }
+    for (key, value) in &expected {
+        store.set(key.clone(), value.clone())?;
+    }
+    for key in &["a", "a0", "b/1", ""] {
+        store.set(key.to_string(), "other".to_owned())?;
+    }
+    store.set("a/removed".to_owned(), "value".to_owned())?;
+    store.remove("a/removed".to_owned())?;
+
+    assert_eq!(store.scan_prefix("a/")?, expected);
+    assert_eq!(store.scan_prefix("a/24")?, expected[241..].to_vec());
+    assert!(store.scan_prefix("c")?.is_empty());
+    assert_eq!(store.scan_prefix("")?.len(), expected.len() + 4);
+    Ok(())
+}
+
+fn get_old_value<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    assert_eq!(
+        store.set_and_get_old("key1".to_owned(), "value1".to_owned())?,
+        None
+    );
+    assert_eq!(
+        store.set_and_get_old("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    assert_eq!(
+        store.remove_and_get_old("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.remove_and_get_old("key1".to_owned())?, None);
+    Ok(())
+}
+
+fn set_nx<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    assert!(store.set_nx("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_nx("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // A removed key can be set again
+    store.remove("key1".to_owned())?;
+    assert!(store.set_nx("key1".to_owned(), "value3".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Every write moves a key to a higher version
+fn versions<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    assert_eq!(store.get_versioned("key1".to_owned())?, None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let (value, v1) = store.get_versioned("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value1");
+    assert!(v1 > 0);
+    // Reads don't change the version
+    assert_eq!(store.get_versioned("key1".to_owned())?.unwrap().1, v1);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let v2 = store.get_versioned("key1".to_owned())?.unwrap().1;
+    assert!(v2 > v1);
+
+    // Versions keep going up after the key is removed, and after reopening
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    let v3 = store.get_versioned("key1".to_owned())?.unwrap().1;
+    assert!(v3 > v2);
+    drop(store);
+
+    let store = new(dir);
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    let (value, v4) = store.get_versioned("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value4");
+    assert!(v4 > v3);
+    Ok(())
+}
+
+fn write_batch<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let results = store.write_batch(vec![
+        WriteOp::Set("key2".to_owned(), "value2".to_owned()),
+        WriteOp::Remove("key1".to_owned()),
+        WriteOp::Remove("key1".to_owned()),
+        WriteOp::Set("key3".to_owned(), "value3".to_owned()),
+        WriteOp::Set("key3".to_owned(), "value4".to_owned()),
+        WriteOp::Remove("key2".to_owned()),
+    ])?;
+    assert_eq!(results.len(), 6);
+    // Only removing a key that an earlier write in the batch already removed fails
+    for (i, result) in results.iter().enumerate() {
+        match result {
+            Err(err) if i == 2 => assert!(err.downcast_ref::<KeyNotFound>().is_some()),
+            Err(err) => panic!("write {} failed: {}", i, err),
+            Ok(()) => assert_ne!(i, 2),
+        }
+    }
+
+    // The batch is durable
+    drop(store);
+    let store = new(dir);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    assert!(store.write_batch(Vec::new())?.is_empty());
+    Ok(())
+}
+
+fn iter<E: KvsEngine>(dir: &Path, new: &dyn Fn(&Path) -> E) -> Result<()> {
+    let store = new(dir);
+    assert_eq!(store.iter()?.count(), 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.remove("key3".to_owned())?;
+
+    let mut pairs = store.iter()?.collect::<Result<Vec<_>>>()?;
+    pairs.sort();
+    assert_eq!(
+        pairs,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value3".to_owned()),
+        ]
+    );
+    Ok(())
+}
@@ -1,15 +1,28 @@
 use crate::Result;
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use failure::ensure;
 use log::{error, info};
 use rayon;
-use std::thread::{spawn, JoinHandle};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{Builder, JoinHandle};
+use std::time::Duration;
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// Prefix of the names of pool threads, unless a different one is given
+pub const DEFAULT_THREAD_PREFIX: &str = "kvs-worker";
+
 /// Trait for constructing a new thread pool and spawning tasks for it
 pub trait ThreadPool: Sized {
     /// Constructs new pool with a specified number of threads
-    fn new(threads: u32) -> Result<Self>;
+    fn new(threads: u32) -> Result<Self> {
+        Self::with_name_prefix(threads, DEFAULT_THREAD_PREFIX)
+    }
+
+    /// Same as new, but threads are named "<prefix>-<index>" so they can be told apart in
+    /// debuggers and tools like top
+    fn with_name_prefix(threads: u32, prefix: &str) -> Result<Self>;
 
     /// Give the pool a task to complete
     fn spawn<F>(&self, job: F)
@@ -18,60 +31,225 @@ pub trait ThreadPool: Sized {
 }
 
 /// Spawns new thread for every job
-pub struct NaiveThreadPool;
+pub struct NaiveThreadPool {
+    prefix: String,
+    // Index of the next thread, used to name it
+    next: AtomicUsize,
+}
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(_threads: u32) -> Result<Self> {
-        Ok(Self)
+    fn with_name_prefix(_threads: u32, prefix: &str) -> Result<Self> {
+        Ok(Self {
+            prefix: prefix.to_owned(),
+            next: AtomicUsize::new(0),
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        spawn(job);
+        let idx = self.next.fetch_add(1, Ordering::Relaxed);
+        Builder::new()
+            .name(format!("{}-{}", self.prefix, idx))
+            .spawn(job)
+            .expect("failed to spawn thread");
     }
 }
 
 /// Sends tasks to a shared set of threads using a channel. Does not handle panics.
 pub struct SharedQueueThreadPool {
     sender: Sender<Job>,
+    // Only pools made with with_idle_timeout grow and shrink
+    idle: Option<Arc<IdleWorkers>>,
+    threads: u32,
+}
+
+// Shared by the workers of a pool that shrinks when idle
+struct IdleWorkers {
+    receiver: Receiver<Job>,
+    prefix: String,
+    timeout: Duration,
+    min: u32,
+    max: u32,
+    // Workers that are alive, and how many of them are waiting for a job
+    workers: AtomicU32,
+    waiting: AtomicU32,
+    // Index of the next worker, used to name it
+    next: AtomicU32,
+}
+
+impl IdleWorkers {
+    // Counts one more worker, unless the pool is already at its maximum
+    fn add_worker(&self) -> bool {
+        self.workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |workers| {
+                if workers < self.max {
+                    Some(workers + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    // Waits for a job, returning None once the worker should exit
+    fn next_job(&self) -> Option<Job> {
+        loop {
+            self.waiting.fetch_add(1, Ordering::SeqCst);
+            let job = self.receiver.recv_timeout(self.timeout);
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+            match job {
+                Ok(job) => return Some(job),
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.workers.fetch_sub(1, Ordering::SeqCst);
+                    return None;
+                }
+                // Only exit while there are more workers than the minimum
+                Err(RecvTimeoutError::Timeout) => {
+                    let shrunk =
+                        self.workers
+                            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |workers| {
+                                if workers > self.min {
+                                    Some(workers - 1)
+                                } else {
+                                    None
+                                }
+                            });
+                    // A job spawned while this worker still counted as waiting didn't start a
+                    // new worker, so stay on for it unless one has been started since
+                    if shrunk.is_ok() && (self.receiver.is_empty() || !self.add_worker()) {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl SharedQueueThreadPool {
-    fn new_thread(receiver: Receiver<Job>, idx: u32) -> JoinHandle<()> {
-        spawn(move || {
+    fn new_thread(
+        receiver: Receiver<Job>,
+        prefix: &str,
+        idx: u32,
+        idle: Option<Arc<IdleWorkers>>,
+    ) -> Result<JoinHandle<()>> {
+        let builder = Builder::new().name(format!("{}-{}", prefix, idx));
+        Ok(builder.spawn(move || {
             loop {
                 // We only care about handling unwind panics, since abort panics end every thread
                 // anyways
-                if let Err(_) = std::panic::catch_unwind(|| {
-                    let job = match receiver.recv() {
-                        Ok(job) => job,
-                        // Once sender has been dropped, worker threads should stop
-                        Err(_) => return,
+                match std::panic::catch_unwind(|| {
+                    let job = match idle {
+                        Some(ref idle) => idle.next_job(),
+                        None => receiver.recv().ok(),
+                    };
+                    // Once sender has been dropped, or the worker has been idle for too long,
+                    // the worker should stop
+                    let job = match job {
+                        Some(job) => job,
+                        None => return false,
                     };
 
                     info!("Thread {} received job", idx);
                     job();
                     info!("Thread {} finished job", idx);
+                    true
                 }) {
-                    eprintln!("Thread {} panicked", idx);
-                    error!("Thread {} panicked, continuing", idx);
+                    Ok(true) => (),
+                    Ok(false) => return,
+                    Err(_) => {
+                        eprintln!("Thread {} panicked", idx);
+                        error!("Thread {} panicked, continuing", idx);
+                    }
                 }
             }
+        })?)
+    }
+
+    /// Same as with_name_prefix, but workers that wait longer than timeout for a job exit, down
+    /// to min_threads workers. New workers are started again, up to threads workers, when jobs
+    /// are spawned faster than the waiting workers pick them up. This frees the threads of a
+    /// mostly idle pool, at the cost of starting a thread for each job of a burst that arrives
+    /// after the pool has shrunk. Fails if min_threads is above threads.
+    pub fn with_idle_timeout(
+        threads: u32,
+        min_threads: u32,
+        timeout: Duration,
+        prefix: &str,
+    ) -> Result<Self> {
+        ensure!(
+            min_threads <= threads,
+            "minimum threads can't be above the maximum"
+        );
+        let (tx, rx): (Sender<Job>, Receiver<Job>) = unbounded();
+        let idle = Arc::new(IdleWorkers {
+            receiver: rx.clone(),
+            prefix: prefix.to_owned(),
+            timeout,
+            min: min_threads,
+            max: threads,
+            workers: AtomicU32::new(threads),
+            waiting: AtomicU32::new(0),
+            next: AtomicU32::new(threads),
+        });
+
+        for idx in 0..threads {
+            Self::new_thread(rx.clone(), prefix, idx, Some(Arc::clone(&idle)))?;
+        }
+
+        Ok(Self {
+            sender: tx,
+            idle: Some(idle),
+            threads,
         })
     }
+
+    /// Number of worker threads that are alive, which only changes for pools made with
+    /// with_idle_timeout
+    pub fn workers(&self) -> u32 {
+        match self.idle {
+            Some(ref idle) => idle.workers.load(Ordering::SeqCst),
+            None => self.threads,
+        }
+    }
+
+    // Starts a worker if there are more queued jobs than workers waiting for them, and the pool
+    // isn't at its maximum
+    fn grow(idle: &Arc<IdleWorkers>) {
+        if idle.receiver.len() <= idle.waiting.load(Ordering::SeqCst) as usize {
+            return;
+        }
+        if !idle.add_worker() {
+            return;
+        }
+        let idx = idle.next.fetch_add(1, Ordering::SeqCst);
+        let spawned = Self::new_thread(
+            idle.receiver.clone(),
+            &idle.prefix,
+            idx,
+            Some(Arc::clone(idle)),
+        );
+        if let Err(err) = spawned {
+            idle.workers.fetch_sub(1, Ordering::SeqCst);
+            error!("Failed to start thread {}: {}", idx, err);
+        }
+    }
 }
 
 impl ThreadPool for SharedQueueThreadPool {
-    fn new(threads: u32) -> Result<Self> {
+    fn with_name_prefix(threads: u32, prefix: &str) -> Result<Self> {
         let (tx, rx): (Sender<Job>, Receiver<Job>) = unbounded();
 
         for idx in 0..threads {
-            Self::new_thread(rx.clone(), idx);
+            Self::new_thread(rx.clone(), prefix, idx, None)?;
         }
 
-        Ok(Self { sender: tx })
+        Ok(Self {
+            sender: tx,
+            idle: None,
+            threads,
+        })
     }
 
     // Performs panic recovery by replacing dead threads before sending messages
@@ -82,17 +260,24 @@ impl ThreadPool for SharedQueueThreadPool {
         self.sender
             .send(Box::new(job))
             .expect("all threads panicked");
+        if let Some(ref idle) = self.idle {
+            Self::grow(idle);
+        }
     }
 }
 
-/// Wraps rayon's threadpool
+/// Wraps rayon's threadpool. Jobs that panic are dropped and the pool keeps going.
 pub struct RayonThreadPool(rayon::ThreadPool);
 
 impl ThreadPool for RayonThreadPool {
-    fn new(threads: u32) -> Result<Self> {
+    fn with_name_prefix(threads: u32, prefix: &str) -> Result<Self> {
+        let prefix = prefix.to_owned();
         Ok(RayonThreadPool(
             rayon::ThreadPoolBuilder::new()
                 .num_threads(threads as usize)
+                .thread_name(move |idx| format!("{}-{}", prefix, idx))
+                // Without a handler rayon aborts the process when a spawned job panics
+                .panic_handler(|_| error!("Rayon job panicked, continuing"))
                 .build()?,
         ))
     }
@@ -101,6 +286,6 @@ impl ThreadPool for RayonThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        self.0.install(job);
+        self.0.spawn(job);
     }
 }
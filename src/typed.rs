@@ -0,0 +1,89 @@
+use crate::{InvalidValue, KvStore, KvsEngine, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+
+/// Stores values of one type in an engine by encoding them as JSON strings, so callers don't have
+/// to serialize at every call site. Keys are used as is, and the engine can still be used directly
+/// through `engine`.
+///
+/// Values that no longer decode as `V`, for example after its fields changed, fail with
+/// InvalidValue instead of being returned or dropped.
+/// ```
+/// use kvs::Result;
+///
+/// # fn main() -> Result<()> {
+///     use kvs::{typed::TypedStore, KvStore};
+///     use serde::{Deserialize, Serialize};
+///     use tempfile::TempDir;
+///
+///     #[derive(Debug, PartialEq, Serialize, Deserialize)]
+///     struct User {
+///         name: String,
+///         age: u32,
+///     }
+///
+///     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+///     let users: TypedStore<User> = TypedStore::new(KvStore::open(temp_dir.path())?);
+///     let user = User {
+///         name: "Alice".to_owned(),
+///         age: 30,
+///     };
+///     users.set("alice".to_owned(), &user)?;
+///     assert_eq!(users.get("alice".to_owned())?, Some(user));
+///     assert_eq!(users.get("bob".to_owned())?, None);
+/// #   Ok(())
+/// # }
+/// ```
+pub struct TypedStore<V, E: KvsEngine = KvStore> {
+    engine: E,
+    // Handles don't own any V, so they're Send and Sync whatever V is
+    value: PhantomData<fn() -> V>,
+}
+
+impl<V, E: KvsEngine> Clone for TypedStore<V, E> {
+    fn clone(&self) -> Self {
+        Self {
+            engine: self.engine.clone(),
+            value: PhantomData,
+        }
+    }
+}
+
+impl<V: Serialize + DeserializeOwned, E: KvsEngine> TypedStore<V, E> {
+    /// Wrap an engine. Values already in it have to be JSON encodings of `V` to be read back.
+    pub fn new(engine: E) -> Self {
+        Self {
+            engine,
+            value: PhantomData,
+        }
+    }
+
+    /// Serializes the value and maps the key to it, overwriting any previous value
+    pub fn set(&self, key: String, value: &V) -> Result<()> {
+        self.engine.set(key, serde_json::to_string(value)?)
+    }
+
+    /// Returns the value mapped to the key if there is one, or InvalidValue if it can't be
+    /// decoded as `V`
+    pub fn get(&self, key: String) -> Result<Option<V>> {
+        match self.engine.get(key.clone())? {
+            Some(value) => Ok(Some(decode(key, &value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the key and its value, failing with KeyNotFound if it doesn't exist
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.engine.remove(key)
+    }
+
+    /// Engine that holds the encoded values
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+}
+
+fn decode<V: DeserializeOwned>(key: String, value: &str) -> Result<V> {
+    serde_json::from_str(value).map_err(|source| InvalidValue { key, source }.into())
+}
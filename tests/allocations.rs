@@ -0,0 +1,66 @@
+use crossbeam::sync::WaitGroup;
+use kvs::client::KvsClient;
+use kvs::server::KvsServer;
+use kvs::thread_pool::SharedQueueThreadPool;
+use kvs::{KvStore, KvsEngine, Result};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::iter::once;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use tempfile::TempDir;
+
+// Counts allocations of exactly WATCHED_SIZE bytes, which is what copying a String of that length
+// allocates. Every other allocation passes through untouched.
+struct CountingAlloc;
+
+static WATCHED_SIZE: AtomicUsize = AtomicUsize::new(0);
+static WATCHED_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() == WATCHED_SIZE.load(Ordering::SeqCst) {
+            WATCHED_ALLOCS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+// A large value sent to the server should be allocated once when its request is decoded, and then
+// moved all the way to the engine instead of being copied
+#[test]
+fn large_value_set_copies_once() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(dir.path())?;
+    let server: KvsServer<_, SharedQueueThreadPool> = KvsServer::new(store.clone(), 1)?;
+
+    let bind_event = WaitGroup::new();
+    let cloned_event = bind_event.clone();
+    let server_clone = server.clone();
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let thread = thread::spawn(move || server_clone.run(&addr, Some(cloned_event)));
+    bind_event.wait();
+    let addr = server.local_addr().expect("server didn't bind");
+
+    // An odd size that nothing else is likely to allocate
+    let size = 3 * 1024 * 1024 + 17;
+    let value = "x".repeat(size);
+    WATCHED_SIZE.store(size, Ordering::SeqCst);
+    KvsClient::new(&addr)?
+        .set(once(("key".to_owned(), value)))?
+        .next()
+        .unwrap()?;
+    WATCHED_SIZE.store(0, Ordering::SeqCst);
+    assert_eq!(WATCHED_ALLOCS.load(Ordering::SeqCst), 1);
+
+    server.shutdown(&addr)?;
+    thread.join().expect("unexpected panic")?;
+    assert_eq!(store.get("key".to_owned())?.map(|v| v.len()), Some(size));
+    Ok(())
+}
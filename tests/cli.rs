@@ -1,6 +1,8 @@
 use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine, Result};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::path::PathBuf;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -164,6 +166,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -171,6 +174,40 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+// `--log-level` should control what both binaries print to stderr
+#[test]
+fn cli_log_level() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--addr", "127.0.0.1:4007", "--log-level", "warn"])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(!content.contains(env!("CARGO_PKG_VERSION")));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4008"])
+        .args(["--log-level", "debug"])
+        .assert()
+        .failure()
+        .stderr(contains("Connecting to 127.0.0.1:4008"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--log-level", "loud", "get", "key1"])
+        .assert()
+        .failure();
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -212,6 +249,105 @@ fn cli_wrong_engine() {
     }
 }
 
+// The server should keep engine files in the data directory and engine.txt where it was started
+#[test]
+fn cli_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("custom");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4005", "--data-dir"])
+        .arg(&data_dir)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4005"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert!(temp_dir.path().join("engine.txt").exists());
+    assert!(data_dir.join("kvs").join("kvs_0.cbor").exists());
+    assert!(!temp_dir.path().join("kvs_0.cbor").exists());
+    assert!(!temp_dir.path().join("data").exists());
+}
+
+// Pointing the client at an address with no server should fail quickly with a short message
+#[test]
+fn client_cli_unreachable_server() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "get",
+            "key1",
+            "--addr",
+            "127.0.0.1:4006",
+            "--timeout",
+            "200",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("server not reachable at 127.0.0.1:4006"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--timeout", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// Servers should move a store that an older server kept in the current directory into the data
+// directory, and refuse to start if both places have data
+#[test]
+fn cli_upgrade_old_layout() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::write(temp_dir.path().join("engine.txt"), "kvs")?;
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert!(!temp_dir.path().join("kvs_0.cbor").exists());
+    assert!(data_dir(&temp_dir).join("kvs_0.cbor").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already has data"));
+    assert!(temp_dir.path().join("kvs_0.cbor").exists());
+    Ok(())
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -224,6 +360,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // Wait for the server to exit so it releases the engine's files before the reopen
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(2));
 
@@ -305,6 +443,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // Wait for the server to exit so it releases the engine's files before the reopen
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -335,3 +475,88 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4003");
 }
+
+// `kvs dump` followed by `kvs load` should copy the store, adding to what's there unless
+// --replace is passed
+#[test]
+fn cli_dump_load() -> Result<()> {
+    let source_dir = TempDir::new().unwrap();
+    let store = KvStore::open(&data_dir(&source_dir))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["dump", "dump.json"])
+        .current_dir(&source_dir)
+        .assert()
+        .success()
+        .stdout(contains("Dumped 2 records"));
+    let dump = source_dir.path().join("dump.json");
+
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(&data_dir(&temp_dir))?;
+    store.set("key2".to_owned(), "old".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("load")
+        .arg(&dump)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Loaded 2 records"));
+    let store = KvStore::open(&data_dir(&temp_dir))?;
+    assert_eq!(store.keys(true)?, vec!["key1", "key2", "key3"]);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("load")
+        .arg(&dump)
+        .arg("--replace")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Loaded 2 records"));
+    let store = KvStore::open(&data_dir(&temp_dir))?;
+    assert_eq!(store.keys(true)?, vec!["key1", "key2"]);
+    drop(store);
+
+    // Loading a file that doesn't exist fails before clearing anything
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["load", "missing.json", "--replace"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    let store = KvStore::open(&data_dir(&temp_dir))?;
+    assert_eq!(store.keys(true)?, vec!["key1", "key2"]);
+    drop(store);
+
+    // So does loading a file that goes bad part way through
+    let mut bad = fs::read_to_string(&dump)?;
+    bad.push_str("{\"key\": \"key3\"");
+    let bad_dump = temp_dir.path().join("bad.json");
+    fs::write(&bad_dump, bad)?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("load")
+        .arg(&bad_dump)
+        .arg("--replace")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    let store = KvStore::open(&data_dir(&temp_dir))?;
+    assert_eq!(store.keys(true)?, vec!["key1", "key2"]);
+    Ok(())
+}
+
+// Where kvs and kvs-server keep the data of the kvs engine when run in the directory
+fn data_dir(dir: &TempDir) -> PathBuf {
+    let dir = dir.path().join("data").join("kvs");
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,753 @@
+use crossbeam::sync::WaitGroup;
+use kvs::client::{BatchBuilder, KvsClient, ThreadedKvsClient};
+use kvs::protocol::{
+    write_batch_len, Codec, ConnectionStats, ErrorCode, Handshake, Message, COMMANDS, GET,
+    SCAN_START,
+};
+use kvs::server::{KvsServer, DEFAULT_MAX_BATCH_SIZE};
+use kvs::thread_pool::SharedQueueThreadPool;
+use kvs::{KeyNotFound, KvStore, RateLimited, ReadOnly, Result, Unauthorized};
+use std::io::Write;
+use std::iter::once;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use tempfile::TempDir;
+
+// Runs a KVS server in the background for the duration of a test
+struct TestServer {
+    addr: SocketAddr,
+    server: Server,
+    thread: Option<JoinHandle<Result<()>>>,
+    // Keep the store's directory alive until the server is gone
+    _dir: TempDir,
+}
+
+type Server = KvsServer<KvStore, SharedQueueThreadPool>;
+
+impl TestServer {
+    fn run(addr: &str) -> Self {
+        Self::run_with(addr, |server| server)
+    }
+
+    // Lets the test configure the server before it starts
+    fn run_with(addr: &str, configure: impl FnOnce(Server) -> Server) -> Self {
+        let dir = TempDir::new().expect("unable to create temporary working directory");
+        let addr: SocketAddr = addr.parse().unwrap();
+        let store = KvStore::open(dir.path()).expect("can't open kvs");
+        let server = configure(KvsServer::new(store, 4).expect("server problem"));
+
+        let bind_event = WaitGroup::new();
+        let cloned_event = bind_event.clone();
+        let server_clone = server.clone();
+        let thread = thread::spawn(move || server_clone.run(&addr, Some(cloned_event)));
+        bind_event.wait();
+
+        Self {
+            addr,
+            server,
+            thread: Some(thread),
+            _dir: dir,
+        }
+    }
+
+    fn client(&self) -> KvsClient {
+        KvsClient::new(&self.addr).expect("client problem")
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.server.shutdown(&self.addr).expect("shutdown failed");
+        let thread = self.thread.take().unwrap();
+        thread
+            .join()
+            .expect("unexpected panic")
+            .expect("server error");
+    }
+}
+
+#[test]
+fn batch_builder_mixed_requests() -> Result<()> {
+    let server = TestServer::run("127.0.0.1:4010");
+
+    let mut batch = BatchBuilder::new();
+    batch
+        .push_set("key1".to_owned(), "value1".to_owned())
+        .push_set("key2".to_owned(), "value2".to_owned());
+    assert_eq!(batch.len(), 2);
+    let keys: Result<Vec<_>> = batch.send(server.client())?.collect();
+    assert_eq!(keys?.len(), 2);
+
+    let mut batch = BatchBuilder::new();
+    batch
+        .push_get("key1".to_owned())
+        .push_remove("key2".to_owned())
+        .push_get("key3".to_owned());
+
+    // The builder can be sent more than once
+    let mut responses: Vec<_> = batch.send(server.client())?.collect::<Result<_>>()?;
+    responses.sort();
+    assert_eq!(
+        responses,
+        vec![
+            ("key1".to_owned(), Some("value1".to_owned())),
+            ("key2".to_owned(), None),
+            ("key3".to_owned(), None),
+        ]
+    );
+    let results: Vec<_> = batch.send(server.client())?.collect();
+    assert_eq!(results.iter().filter(|res| res.is_err()).count(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn shutdown_timeout_with_stuck_handler() -> Result<()> {
+    let server = TestServer::run("127.0.0.1:4011");
+    server
+        .client()
+        .set(once(("key1".to_owned(), "value1".to_owned())))?
+        .next()
+        .unwrap()?;
+
+    // Announce one request but never send it, so the handler blocks reading from the stream
+    let mut stream = TcpStream::connect(&server.addr)?;
+    Handshake::default().write(&mut stream)?;
+    write_batch_len(&mut stream, 1)?;
+    stream.flush()?;
+    thread::sleep(Duration::from_millis(100));
+
+    let summary = server
+        .server
+        .shutdown_timeout(&server.addr, Duration::from_millis(200))?;
+    assert_eq!(summary.active_jobs, 1);
+
+    Ok(())
+}
+
+// Errors from the server should keep their type on the client side
+#[test]
+fn remove_missing_key_error() -> Result<()> {
+    let server = TestServer::run("127.0.0.1:4012");
+
+    let err = server
+        .client()
+        .remove(once("key1".to_owned()))?
+        .next()
+        .unwrap()
+        .unwrap_err();
+    assert!(err.downcast_ref::<KeyNotFound>().is_some());
+
+    Ok(())
+}
+
+#[test]
+fn threaded_get_stream() -> Result<()> {
+    let server = TestServer::run("127.0.0.1:4013");
+    let client = ThreadedKvsClient::<SharedQueueThreadPool>::new(server.addr, 4)?;
+
+    let pairs: Vec<_> = (0..50)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    client.set(pairs.clone())?;
+
+    let mut keys: Vec<_> = pairs.iter().map(|(k, _)| k.clone()).collect();
+    keys.push("missing".to_owned());
+    let mut results: Vec<_> = client.get_stream(keys)?.iter().collect::<Result<_>>()?;
+    results.sort();
+
+    let mut expected: Vec<_> = pairs.into_iter().map(|(k, v)| (k, Some(v))).collect();
+    expected.push(("missing".to_owned(), None));
+    expected.sort();
+    assert_eq!(results, expected);
+
+    Ok(())
+}
+
+#[test]
+fn server_status() -> Result<()> {
+    let before = SystemTime::now();
+    let server = TestServer::run("127.0.0.1:4014");
+    thread::sleep(Duration::from_millis(50));
+
+    assert!(server.server.uptime() >= Duration::from_millis(50));
+    assert!(server.server.started_at() >= before);
+
+    let status = server.client().status()?;
+    assert!(status.uptime >= Duration::from_millis(50));
+    assert!(status.uptime <= server.server.uptime());
+    // The wire format only keeps whole milliseconds
+    let started_at = status.started_at + Duration::from_millis(1);
+    assert!(started_at >= server.server.started_at());
+    assert!(status.started_at <= server.server.started_at());
+    Ok(())
+}
+
+#[test]
+fn threaded_remove() -> Result<()> {
+    let server = TestServer::run("127.0.0.1:4015");
+    let client = ThreadedKvsClient::<SharedQueueThreadPool>::new(server.addr, 4)?;
+
+    let keys: Vec<_> = (0..200).map(|i| format!("key{}", i)).collect();
+    client.set(
+        keys.iter()
+            .map(|k| (k.clone(), "value".to_owned()))
+            .collect(),
+    )?;
+    client.remove(keys.clone())?;
+
+    let found: Vec<_> = client
+        .get_stream(keys.clone())?
+        .iter()
+        .collect::<Result<_>>()?;
+    assert_eq!(found.len(), 200);
+    assert!(found.iter().all(|(_, value)| value.is_none()));
+
+    // Every key is gone, so removing them again fails
+    assert!(client.remove(keys).is_err());
+    Ok(())
+}
+
+#[test]
+fn request_deadline() -> Result<()> {
+    let server = TestServer::run("127.0.0.1:4016");
+
+    // Requests that reach the server after their deadline are skipped
+    let mut stream = TcpStream::connect(&server.addr)?;
+    Handshake::default().write(&mut stream)?;
+    write_batch_len(&mut stream, 1)?;
+    Message::Deadline(0, vec![GET.to_owned(), "key1".to_owned()])
+        .write(&mut stream, Codec::Cbor)?;
+    match Message::read(&mut stream, Codec::Cbor)? {
+        Message::Error(code, _) => assert_eq!(code, ErrorCode::DeadlineExceeded),
+        msg => panic!("unexpected reply {:?}", msg),
+    }
+
+    // Requests within their deadline go through as usual
+    let client = server.client().with_timeout(Duration::from_secs(5))?;
+    let key = client
+        .set(once(("key1".to_owned(), "value1".to_owned())))?
+        .next()
+        .unwrap()?;
+    assert_eq!(key, "key1");
+
+    let client = server.client().with_timeout(Duration::from_secs(5))?;
+    let pair = client.get(once("key1".to_owned()))?.next().unwrap()?;
+    assert_eq!(pair, ("key1".to_owned(), Some("value1".to_owned())));
+
+    Ok(())
+}
+
+#[test]
+fn dump_store() -> Result<()> {
+    let server = TestServer::run("127.0.0.1:4017");
+    assert_eq!(server.client().dump()?.count(), 0);
+
+    let mut pairs: Vec<_> = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    let keys: Result<Vec<_>> = server.client().set(pairs.clone().into_iter())?.collect();
+    keys?;
+
+    let mut dumped: Vec<_> = server.client().dump()?.collect::<Result<_>>()?;
+    dumped.sort();
+    pairs.sort();
+    assert_eq!(dumped, pairs);
+    Ok(())
+}
+
+#[test]
+fn shared_secret() -> Result<()> {
+    let server = TestServer::run_with("127.0.0.1:4018", |server| server.with_secret("hunter2"));
+    let set = |client: KvsClient| -> Result<String> {
+        client
+            .set(once(("key1".to_owned(), "value1".to_owned())))?
+            .next()
+            .unwrap()
+    };
+
+    let err = set(server.client()).unwrap_err();
+    assert!(err.downcast_ref::<Unauthorized>().is_some());
+    let err = set(server.client().with_token("wrong".to_owned())).unwrap_err();
+    assert!(err.downcast_ref::<Unauthorized>().is_some());
+
+    set(server.client().with_token("hunter2".to_owned()))?;
+    let client = ThreadedKvsClient::<SharedQueueThreadPool>::new(server.addr, 2)?
+        .with_token("hunter2".to_owned());
+    client.set(vec![("key2".to_owned(), "value2".to_owned())])?;
+    let pairs: Vec<_> = client
+        .get_stream(vec!["key1".to_owned(), "key2".to_owned()])?
+        .iter()
+        .collect::<Result<_>>()?;
+    assert_eq!(pairs.len(), 2);
+
+    // Servers without a secret ignore tokens
+    let open = TestServer::run("127.0.0.1:4019");
+    set(open.client())?;
+    set(open.client().with_token("anything".to_owned()))?;
+    Ok(())
+}
+
+#[test]
+fn scan_pages() -> Result<()> {
+    let server = TestServer::run("127.0.0.1:4020");
+    let page = server.client().scan(SCAN_START, 10)?;
+    assert!(page.pairs.is_empty());
+    assert_eq!(page.cursor, None);
+
+    let mut pairs: Vec<_> = (0..25)
+        .map(|i| (format!("key{:02}", i), format!("value{}", i)))
+        .collect();
+    let keys: Result<Vec<_>> = server.client().set(pairs.clone().into_iter())?.collect();
+    keys?;
+
+    let mut page = server.client().scan(SCAN_START, 10)?;
+    let mut scanned = Vec::new();
+    let mut pages = 1;
+    while let Some(cursor) = page.cursor.take() {
+        assert_eq!(page.pairs.len(), 10);
+        scanned.append(&mut page.pairs);
+        page = server.client().scan_continue(&cursor)?;
+        pages += 1;
+    }
+    scanned.append(&mut page.pairs);
+    assert_eq!(pages, 3);
+    pairs.sort();
+    assert_eq!(scanned, pairs);
+
+    assert!(server.client().scan("bogus", 10).is_err());
+    assert!(server.client().scan(SCAN_START, 0).is_err());
+    Ok(())
+}
+
+#[test]
+fn write_rate_limit() -> Result<()> {
+    let server = TestServer::run_with("127.0.0.1:4021", |server| {
+        server.with_write_limit(10, 5).unwrap()
+    });
+    let start = SystemTime::now();
+    let results: Vec<_> = server
+        .client()
+        .set((0..50).map(|i| (format!("key{}", i), format!("value{}", i))))?
+        .collect();
+    let elapsed = start.elapsed().unwrap().as_secs_f64();
+
+    let accepted = results.iter().filter(|res| res.is_ok()).count();
+    for err in results.into_iter().filter_map(|res| res.err()) {
+        assert!(err.downcast_ref::<RateLimited>().is_some());
+    }
+    // The burst goes through, and anything more has to wait for tokens to refill
+    assert!(accepted >= 5);
+    assert!(accepted as f64 <= 5.0 + 10.0 * elapsed + 1.0);
+
+    // Reads are never limited
+    let gets: Result<Vec<_>> = server
+        .client()
+        .get((0..50).map(|i| format!("key{}", i)))?
+        .collect();
+    assert_eq!(gets?.len(), 50);
+    Ok(())
+}
+
+// Large calls are split into many batches per thread, each over its own connection
+#[test]
+fn threaded_chunks() -> Result<()> {
+    let server = TestServer::run("127.0.0.1:4022");
+    let client =
+        ThreadedKvsClient::<SharedQueueThreadPool>::new(server.addr, 4)?.with_chunk_size(5000)?;
+
+    let pairs: Vec<_> = (0..100_000)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    client.set(pairs.clone())?;
+
+    let keys: Vec<_> = pairs.iter().map(|(key, _)| key.clone()).collect();
+    let mut found: Vec<_> = client.get_stream(keys)?.iter().collect::<Result<_>>()?;
+    found.sort();
+    let mut expected: Vec<_> = pairs
+        .into_iter()
+        .map(|(key, value)| (key, Some(value)))
+        .collect();
+    expected.sort();
+    assert_eq!(found, expected);
+
+    let client = ThreadedKvsClient::<SharedQueueThreadPool>::new(server.addr, 4)?;
+    assert!(client.with_chunk_size(0).is_err());
+    assert!(ThreadedKvsClient::<SharedQueueThreadPool>::new(server.addr, 0).is_err());
+    Ok(())
+}
+
+// A client that asks for bincode should get every reply in bincode, errors included
+#[test]
+fn bincode_codec() -> Result<()> {
+    let server = TestServer::run("127.0.0.1:4023");
+    let client = || server.client().with_codec(Codec::Bincode);
+
+    let pairs: Vec<_> = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    let keys: Vec<_> = client()
+        .set(pairs.clone().into_iter())?
+        .collect::<Result<_>>()?;
+    assert_eq!(keys.len(), 100);
+
+    let found: Vec<_> = client()
+        .get(vec!["key1".to_owned(), "missing".to_owned()].into_iter())?
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        found,
+        vec![
+            ("key1".to_owned(), Some("value1".to_owned())),
+            ("missing".to_owned(), None)
+        ]
+    );
+
+    let err = client()
+        .remove(once("missing".to_owned()))?
+        .next()
+        .unwrap()
+        .unwrap_err();
+    assert!(err.downcast_ref::<KeyNotFound>().is_some());
+
+    let mut dumped: Vec<_> = client().dump()?.collect::<Result<_>>()?;
+    dumped.sort();
+    let mut pairs = pairs;
+    pairs.sort();
+    assert_eq!(dumped, pairs);
+    Ok(())
+}
+
+// A paused server should close new connections until it's resumed
+#[test]
+fn pause_and_resume() -> Result<()> {
+    let server = TestServer::run("127.0.0.1:4024");
+    server
+        .client()
+        .set(once(("key1".to_owned(), "value1".to_owned())))?
+        .next()
+        .unwrap()?;
+
+    server.server.pause();
+    let reply = server
+        .client()
+        .get(once("key1".to_owned()))
+        .and_then(|mut replies| replies.next().unwrap());
+    assert!(reply.is_err());
+
+    server.server.resume();
+    let (_, value) = server
+        .client()
+        .get(once("key1".to_owned()))?
+        .next()
+        .unwrap()?;
+    assert_eq!(value, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A read-only server should refuse writes but keep serving reads, whether or not it coalesces
+// writes, and take writes again once it's switched back
+#[test]
+fn read_only_mode() -> Result<()> {
+    let plain = TestServer::run("127.0.0.1:4030");
+    let coalescing =
+        TestServer::run_with("127.0.0.1:4031", |server| server.with_write_coalescing());
+    for server in &[plain, coalescing] {
+        let set = |key: &str, value: &str| {
+            server
+                .client()
+                .set(once((key.to_owned(), value.to_owned())))?
+                .next()
+                .unwrap()
+        };
+        let get = |key: &str| {
+            let mut replies = server.client().get(once(key.to_owned()))?;
+            replies.next().unwrap().map(|(_, value)| value)
+        };
+        set("key1", "value1")?;
+
+        server.server.set_read_only(true);
+        assert!(server.server.is_read_only());
+        let err = set("key1", "value2").unwrap_err();
+        assert!(err.downcast_ref::<ReadOnly>().is_some());
+        let err = server
+            .client()
+            .remove(once("key1".to_owned()))?
+            .next()
+            .unwrap();
+        assert!(err.unwrap_err().downcast_ref::<ReadOnly>().is_some());
+        let err = server
+            .client()
+            .set_nx("key2".to_owned(), "value2".to_owned());
+        assert!(err.unwrap_err().downcast_ref::<ReadOnly>().is_some());
+        assert_eq!(get("key1")?, Some("value1".to_owned()));
+
+        server.server.set_read_only(false);
+        set("key1", "value2")?;
+        assert_eq!(get("key1")?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+// Reads sent to a read pool should get the same replies as any other request, in batches that
+// mix them with writes, whether or not writes are coalesced
+#[test]
+fn read_pool() -> Result<()> {
+    let plain = TestServer::run_with("127.0.0.1:4032", |server| {
+        server.with_read_pool(2).expect("read pool problem")
+    });
+    let coalescing = TestServer::run_with("127.0.0.1:4033", |server| {
+        server
+            .with_write_coalescing()
+            .with_read_pool(2)
+            .expect("read pool problem")
+    });
+    for server in &[plain, coalescing] {
+        let mut batch = BatchBuilder::new();
+        for i in 0..50 {
+            batch.push_set(format!("key{}", i), format!("value{}", i));
+        }
+        assert_eq!(batch.send(server.client())?.count(), 50);
+
+        let mut batch = BatchBuilder::new();
+        for i in 0..50 {
+            batch.push_get(format!("key{}", i));
+        }
+        batch.push_set("key50".to_owned(), "value50".to_owned());
+        let mut responses: Vec<_> = batch.send(server.client())?.collect::<Result<_>>()?;
+        responses.sort();
+        assert_eq!(responses.len(), 51);
+        for (key, value) in responses {
+            match value {
+                Some(value) => assert_eq!(value, key.replace("key", "value")),
+                None => assert_eq!(key, "key50"),
+            }
+        }
+
+        let page = server.client().scan(SCAN_START, 100)?;
+        assert_eq!(page.pairs.len(), 51);
+    }
+
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let server: Server = KvsServer::new(KvStore::open(dir.path())?, 1)?;
+    assert!(server.with_read_pool(0).is_err());
+    Ok(())
+}
+
+// A coalescing server should give every request of a mixed batch its own reply
+#[test]
+fn coalesced_writes() -> Result<()> {
+    let server = TestServer::run_with("127.0.0.1:4025", |server| server.with_write_coalescing());
+
+    let pairs: Vec<_> = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    let keys: Vec<_> = server
+        .client()
+        .set(pairs.clone().into_iter())?
+        .collect::<Result<_>>()?;
+    assert_eq!(keys.len(), 100);
+
+    let mut batch = BatchBuilder::new();
+    batch
+        .push_set("key1".to_owned(), "value2".to_owned())
+        .push_remove("key2".to_owned())
+        .push_remove("key2".to_owned())
+        .push_get("key3".to_owned());
+    let results: Vec<_> = batch.send(server.client())?.collect();
+    assert_eq!(results.len(), 4);
+    let failed: Vec<_> = results
+        .iter()
+        .filter_map(|res| res.as_ref().err())
+        .collect();
+    assert_eq!(failed.len(), 1);
+    assert!(failed[0].downcast_ref::<KeyNotFound>().is_some());
+    assert!(results
+        .iter()
+        .any(|res| res.as_ref().ok() == Some(&("key3".to_owned(), Some("value3".to_owned())))));
+
+    let found: Vec<_> = server
+        .client()
+        .get(vec!["key1".to_owned(), "key2".to_owned()].into_iter())?
+        .collect::<Result<_>>()?;
+    assert!(found.contains(&("key1".to_owned(), Some("value2".to_owned()))));
+    assert!(found.contains(&("key2".to_owned(), None)));
+    Ok(())
+}
+
+// Commands the server doesn't know should be rejected with an error naming the command
+#[test]
+fn unknown_command() -> Result<()> {
+    let server = TestServer::run("127.0.0.1:4026");
+
+    let mut stream = TcpStream::connect(&server.addr)?;
+    Handshake::default().write(&mut stream)?;
+    write_batch_len(&mut stream, 1)?;
+    Message::Array(vec!["frobnicate".to_owned(), "key1".to_owned()])
+        .write(&mut stream, Codec::Cbor)?;
+    match Message::read(&mut stream, Codec::Cbor)? {
+        Message::Error(code, msg) => {
+            assert_eq!(code, ErrorCode::Other);
+            assert!(msg.contains("\"frobnicate\""));
+            assert!(COMMANDS.iter().all(|cmd| msg.contains(cmd)));
+        }
+        msg => panic!("unexpected reply {:?}", msg),
+    }
+    Ok(())
+}
+
+// Only the first setnx of a key should set it
+#[test]
+fn set_nx() -> Result<()> {
+    let server = TestServer::run("127.0.0.1:4027");
+
+    assert!(server
+        .client()
+        .set_nx("key1".to_owned(), "value1".to_owned())?);
+    assert!(!server
+        .client()
+        .set_nx("key1".to_owned(), "value2".to_owned())?);
+    let pair = server
+        .client()
+        .get(once("key1".to_owned()))?
+        .next()
+        .unwrap()?;
+    assert_eq!(pair, ("key1".to_owned(), Some("value1".to_owned())));
+    Ok(())
+}
+
+// Batches declaring more requests than the maximum should be rejected before any are read
+#[test]
+fn max_batch_size() -> Result<()> {
+    let server = TestServer::run_with("127.0.0.1:4028", |server| {
+        server.with_max_batch_size(10).unwrap()
+    });
+
+    let mut stream = TcpStream::connect(&server.addr)?;
+    Handshake::default().write(&mut stream)?;
+    write_batch_len(&mut stream, u32::MAX)?;
+    match Message::read(&mut stream, Codec::Cbor)? {
+        Message::Error(code, msg) => {
+            assert_eq!(code, ErrorCode::Other);
+            assert_eq!(msg, "batch too large");
+        }
+        msg => panic!("unexpected reply {:?}", msg),
+    }
+    // The server hangs up instead of waiting for the requests
+    assert!(Message::read(&mut stream, Codec::Cbor).is_err());
+
+    // Batches up to the maximum go through
+    let pairs: Vec<_> = (0..10)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    let keys: Vec<_> = server
+        .client()
+        .set(pairs.into_iter())?
+        .collect::<Result<_>>()?;
+    assert_eq!(keys.len(), 10);
+    Ok(())
+}
+
+// Servers should have a finite maximum even when none is configured
+#[test]
+fn default_max_batch_size() -> Result<()> {
+    let server = TestServer::run("127.0.0.1:4035");
+
+    let mut stream = TcpStream::connect(&server.addr)?;
+    Handshake::default().write(&mut stream)?;
+    write_batch_len(&mut stream, DEFAULT_MAX_BATCH_SIZE + 1)?;
+    match Message::read(&mut stream, Codec::Cbor)? {
+        Message::Error(_, msg) => assert_eq!(msg, "batch too large"),
+        msg => panic!("unexpected reply {:?}", msg),
+    }
+    Ok(())
+}
+
+// Batches should only cost the server something for the requests that actually arrive, however
+// many they declare
+#[test]
+fn huge_batch_len() -> Result<()> {
+    let server = TestServer::run_with("127.0.0.1:4034", |server| {
+        server.with_max_batch_size(u32::MAX).unwrap()
+    });
+
+    let mut stream = TcpStream::connect(&server.addr)?;
+    Handshake::default().write(&mut stream)?;
+    write_batch_len(&mut stream, u32::MAX)?;
+    Message::Array(vec!["frobnicate".to_owned(), "key1".to_owned()])
+        .write(&mut stream, Codec::Cbor)?;
+    // Replies go out once the batch is over, which hanging up early makes it
+    stream.shutdown(Shutdown::Write)?;
+    match Message::read(&mut stream, Codec::Cbor)? {
+        Message::Error(code, _) => assert_eq!(code, ErrorCode::Other),
+        msg => panic!("unexpected reply {:?}", msg),
+    }
+
+    let key = server
+        .client()
+        .set(once(("key1".to_owned(), "value1".to_owned())))?
+        .next()
+        .unwrap()?;
+    assert_eq!(key, "key1");
+    Ok(())
+}
+
+// Connections should count as active until they're done, and rejected ones should be counted
+// whatever turned them away
+#[test]
+fn connection_stats() -> Result<()> {
+    let server = TestServer::run_with("127.0.0.1:4029", |server| server.with_secret("hunter2"));
+    let client = || server.client().with_token("hunter2".to_owned());
+    let wait_until_idle = || {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while server.server.connection_stats().active > 0 {
+            assert!(Instant::now() < deadline, "connections never finished");
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    assert_eq!(server.server.connection_stats(), ConnectionStats::default());
+
+    for i in 0..3 {
+        client()
+            .set(once((format!("key{}", i), "value".to_owned())))?
+            .next()
+            .unwrap()?;
+    }
+    assert!(server.client().status().is_err());
+    server.server.pause();
+    assert!(client().status().is_err());
+    server.server.resume();
+    wait_until_idle();
+    assert_eq!(
+        server.server.connection_stats(),
+        ConnectionStats {
+            active: 0,
+            accepted: 5,
+            rejected: 2,
+        }
+    );
+
+    // Connections that announce a request and never send it stay active until they're closed,
+    // even though their handlers fail on the way out. Each one ties up a thread of the pool.
+    let mut streams = Vec::new();
+    for _ in 0..2 {
+        let mut stream = TcpStream::connect(&server.addr)?;
+        let handshake = Handshake {
+            token: Some("hunter2".to_owned()),
+            ..Default::default()
+        };
+        handshake.write(&mut stream)?;
+        write_batch_len(&mut stream, 1)?;
+        streams.push(stream);
+    }
+    // The status connection counts itself
+    let status = client().status()?;
+    assert_eq!(status.connections.active, 3);
+    assert_eq!(status.connections.accepted, 8);
+
+    drop(streams);
+    wait_until_idle();
+    assert_eq!(server.server.connection_stats().accepted, 8);
+    assert_eq!(server.server.connection_stats().rejected, 2);
+    Ok(())
+}
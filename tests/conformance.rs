@@ -0,0 +1,55 @@
+use kvs::routing::{RoutingEngine, SwappableEngine};
+use kvs::testsuite::run_conformance;
+use kvs::{IndexBackend, KvStore, KvStoreOptions, ReadMode, Result, SledKvsEngine};
+use std::fs;
+
+#[test]
+fn kvs_conformance() -> Result<()> {
+    run_conformance(|dir| KvStore::open(dir).expect("can't open kvs"))
+}
+
+#[test]
+fn sled_conformance() -> Result<()> {
+    run_conformance(|dir| SledKvsEngine::open(dir).expect("can't open sled"))
+}
+
+#[test]
+fn kvs_mmap_conformance() -> Result<()> {
+    run_conformance(|dir| {
+        let options = KvStoreOptions {
+            read_mode: ReadMode::Mmap,
+            ..Default::default()
+        };
+        KvStore::open_with_options(dir, options).expect("can't open kvs")
+    })
+}
+
+#[test]
+fn kvs_rwlock_index_conformance() -> Result<()> {
+    run_conformance(|dir| {
+        let options = KvStoreOptions {
+            index_backend: IndexBackend::RwLock,
+            ..Default::default()
+        };
+        KvStore::open_with_options(dir, options).expect("can't open kvs")
+    })
+}
+
+#[test]
+fn routing_conformance() -> Result<()> {
+    run_conformance(|dir| {
+        // Enough keys in the checks start with key1 to give both engines some of them
+        let (default_dir, routed_dir) = (dir.join("default"), dir.join("key1"));
+        fs::create_dir_all(&default_dir).expect("can't create engine directory");
+        fs::create_dir_all(&routed_dir).expect("can't create engine directory");
+        RoutingEngine::new(KvStore::open(&default_dir).expect("can't open kvs")).with_route(
+            "key1",
+            SledKvsEngine::open(&routed_dir).expect("can't open sled"),
+        )
+    })
+}
+
+#[test]
+fn swappable_conformance() -> Result<()> {
+    run_conformance(|dir| SwappableEngine::new(KvStore::open(dir).expect("can't open kvs")))
+}
@@ -0,0 +1,557 @@
+use crossbeam::sync::WaitGroup;
+use kvs::client::{IfNewer, KvsClient};
+use kvs::protocol::MAX_DECOMPRESSED;
+use kvs::routing::SwappableEngine;
+use kvs::server::KvsServer;
+use kvs::storage::MemoryStorage;
+use kvs::thread_pool::SharedQueueThreadPool;
+use kvs::{KeyNotFound, KvStore, KvStoreOptions, KvsEngine, Result};
+use std::collections::HashMap;
+use std::iter::once;
+use std::net::SocketAddr;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Runs a KVS server on a port picked by the OS for the duration of a test
+struct TestServer {
+    addr: SocketAddr,
+    server: KvsServer<KvStore, SharedQueueThreadPool>,
+    thread: Option<JoinHandle<Result<()>>>,
+    // Keep the store's directory alive until the server is gone
+    _dir: TempDir,
+}
+
+impl TestServer {
+    fn run() -> Self {
+        let dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(dir.path()).expect("can't open kvs");
+        let server = KvsServer::new(store, 4).expect("server problem");
+
+        let bind_event = WaitGroup::new();
+        let cloned_event = bind_event.clone();
+        let server_clone = server.clone();
+        // Port 0 lets the OS pick a free port
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let thread = thread::spawn(move || server_clone.run(&addr, Some(cloned_event)));
+        bind_event.wait();
+
+        Self {
+            addr: server.local_addr().expect("server didn't bind"),
+            server,
+            thread: Some(thread),
+            _dir: dir,
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.server.shutdown(&self.addr).expect("shutdown failed");
+        let thread = self.thread.take().unwrap();
+        thread
+            .join()
+            .expect("unexpected panic")
+            .expect("server error");
+    }
+}
+
+fn client(addr: &SocketAddr) -> KvsClient {
+    KvsClient::new(addr).expect("client problem")
+}
+
+fn set(addr: &SocketAddr, key: &str, value: &str) -> Result<String> {
+    client(addr)
+        .set(once((key.to_owned(), value.to_owned())))?
+        .next()
+        .unwrap()
+}
+
+fn get(addr: &SocketAddr, key: &str) -> Result<Option<String>> {
+    let (found, value) = client(addr).get(once(key.to_owned()))?.next().unwrap()?;
+    assert_eq!(found, key);
+    Ok(value)
+}
+
+fn remove(addr: &SocketAddr, key: &str) -> Result<String> {
+    client(addr).remove(once(key.to_owned()))?.next().unwrap()
+}
+
+#[test]
+fn set_get_remove() -> Result<()> {
+    let server = TestServer::run();
+
+    assert_eq!(set(&server.addr, "key1", "value1")?, "key1");
+    assert_eq!(get(&server.addr, "key1")?, Some("value1".to_owned()));
+    set(&server.addr, "key1", "value2")?;
+    assert_eq!(get(&server.addr, "key1")?, Some("value2".to_owned()));
+
+    assert_eq!(remove(&server.addr, "key1")?, "key1");
+    assert_eq!(get(&server.addr, "key1")?, None);
+    Ok(())
+}
+
+// An empty value is still a value, and must not look like a missing key
+#[test]
+fn empty_value_and_missing_key() -> Result<()> {
+    let server = TestServer::run();
+
+    set(&server.addr, "empty", "")?;
+    assert_eq!(get(&server.addr, "empty")?, Some(String::new()));
+    assert_eq!(get(&server.addr, "missing")?, None);
+
+    set(&server.addr, "", "empty key")?;
+    assert_eq!(get(&server.addr, "")?, Some("empty key".to_owned()));
+    Ok(())
+}
+
+// Removing a missing key fails with the same error type the engine raises
+#[test]
+fn remove_missing_key() -> Result<()> {
+    let server = TestServer::run();
+
+    let err = remove(&server.addr, "missing").unwrap_err();
+    assert!(err.downcast_ref::<KeyNotFound>().is_some());
+
+    // The failed remove doesn't break later requests
+    set(&server.addr, "key1", "value1")?;
+    assert_eq!(get(&server.addr, "key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Batches bigger than what fits in a byte should keep every request and reply
+#[test]
+fn large_batch() -> Result<()> {
+    let server = TestServer::run();
+
+    let pairs: Vec<_> = (0..300)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    let mut keys: Vec<_> = client(&server.addr)
+        .set(pairs.clone().into_iter())?
+        .collect::<Result<_>>()?;
+    keys.sort();
+    let mut expected: Vec<_> = pairs.iter().map(|(k, _)| k.clone()).collect();
+    expected.sort();
+    assert_eq!(keys, expected);
+
+    let mut found: Vec<_> = client(&server.addr)
+        .get(expected.clone().into_iter())?
+        .collect::<Result<_>>()?;
+    found.sort();
+    let mut pairs: Vec<_> = pairs.into_iter().map(|(k, v)| (k, Some(v))).collect();
+    pairs.sort();
+    assert_eq!(found, pairs);
+
+    let removed = client(&server.addr)
+        .remove(expected.into_iter())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(removed.len(), 300);
+    Ok(())
+}
+
+// Clients on many threads each see their own writes
+#[test]
+fn concurrent_clients() -> Result<()> {
+    let server = TestServer::run();
+
+    thread::scope(|scope| {
+        for thread_id in 0..8 {
+            let addr = server.addr;
+            scope.spawn(move || {
+                for i in 0..20 {
+                    let key = format!("key{}-{}", thread_id, i);
+                    let value = format!("value{}", i);
+                    set(&addr, &key, &value).unwrap();
+                    assert_eq!(get(&addr, &key).unwrap(), Some(value));
+                }
+            });
+        }
+    });
+
+    for thread_id in 0..8 {
+        for i in 0..20 {
+            let key = format!("key{}-{}", thread_id, i);
+            assert_eq!(get(&server.addr, &key)?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+// The bound address should only be reported while the server is running
+#[test]
+fn local_addr() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let server: KvsServer<_, SharedQueueThreadPool> =
+        KvsServer::new(KvStore::open(dir.path())?, 1)?;
+    assert_eq!(server.local_addr(), None);
+
+    let bind_event = WaitGroup::new();
+    let cloned_event = bind_event.clone();
+    let server_clone = server.clone();
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let thread = thread::spawn(move || server_clone.run(&addr, Some(cloned_event)));
+    bind_event.wait();
+
+    let addr = server.local_addr().unwrap();
+    assert_ne!(addr.port(), 0);
+    set(&addr, "key1", "value1")?;
+
+    server.shutdown(&addr)?;
+    thread.join().unwrap()?;
+    assert_eq!(server.local_addr(), None);
+    Ok(())
+}
+
+// A cached version only gets the value back once the key is written again
+#[test]
+fn get_if_newer() -> Result<()> {
+    let server = TestServer::run();
+    let get_if_newer = |version| client(&server.addr).get_if_newer("key1".to_owned(), version);
+    assert_eq!(get_if_newer(0)?, IfNewer::Missing);
+
+    set(&server.addr, "key1", "value1")?;
+    let version = match get_if_newer(0)? {
+        IfNewer::Modified(value, version) => {
+            assert_eq!(value, "value1");
+            version
+        }
+        reply => panic!("unexpected reply {:?}", reply),
+    };
+    assert_eq!(get_if_newer(version)?, IfNewer::NotModified(version));
+
+    set(&server.addr, "key1", "value2")?;
+    match get_if_newer(version)? {
+        IfNewer::Modified(value, newer) => {
+            assert_eq!(value, "value2");
+            assert!(newer > version);
+            assert_eq!(get_if_newer(newer)?, IfNewer::NotModified(newer));
+        }
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+
+    remove(&server.addr, "key1")?;
+    assert_eq!(get_if_newer(version)?, IfNewer::Missing);
+    Ok(())
+}
+
+// Only keys with the prefix should come back, all of them from a single request
+#[test]
+fn get_prefix() -> Result<()> {
+    let server = TestServer::run();
+    assert!(client(&server.addr)
+        .get_prefix("user:".to_owned())?
+        .is_empty());
+
+    let mut expected = Vec::new();
+    for i in 0..20 {
+        let (key, value) = (format!("user:{:02}", i), format!("value{}", i));
+        set(&server.addr, &key, &value)?;
+        expected.push((key, value));
+    }
+    set(&server.addr, "user", "other")?;
+    set(&server.addr, "users:1", "other")?;
+    set(&server.addr, "group:1", "other")?;
+    set(&server.addr, "user:gone", "value")?;
+    remove(&server.addr, "user:gone")?;
+
+    let summary = server.server.summary();
+    let pairs = client(&server.addr).get_prefix("user:".to_owned())?;
+    assert_eq!(pairs, expected);
+    assert_eq!(server.server.summary().requests, summary.requests + 1);
+    Ok(())
+}
+
+// A batch can mix compressed and raw values, and both should be stored as they were sent
+#[test]
+fn compressed_values() -> Result<()> {
+    let server = TestServer::run();
+    let large = "compressible ".repeat(80 * 1024);
+    let small = "tiny value".to_owned();
+
+    let pairs = vec![
+        ("large".to_owned(), large.clone()),
+        ("small".to_owned(), small.clone()),
+    ];
+    let mut keys: Vec<String> = client(&server.addr)
+        .with_compression(1024)
+        .set(pairs.into_iter())?
+        .collect::<Result<_>>()?;
+    keys.sort();
+    assert_eq!(keys, vec!["large", "small"]);
+
+    assert_eq!(get(&server.addr, "large")?, Some(large));
+    assert_eq!(get(&server.addr, "small")?, Some(small));
+    Ok(())
+}
+
+// A compressed value that inflates past the cap should fail on its own without being stored
+#[test]
+fn oversized_compressed_value() -> Result<()> {
+    let server = TestServer::run();
+    let huge = "0".repeat(MAX_DECOMPRESSED as usize + 1);
+    let pairs = vec![
+        ("huge".to_owned(), huge),
+        ("small".to_owned(), "value".to_owned()),
+    ];
+    let results: Vec<Result<String>> = client(&server.addr)
+        .with_compression(1024)
+        .set(pairs.into_iter())?
+        .collect();
+    assert_eq!(results.iter().filter(|res| res.is_err()).count(), 1);
+
+    assert_eq!(get(&server.addr, "huge")?, None);
+    assert_eq!(get(&server.addr, "small")?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Writes sent without reading their replies should still land
+#[test]
+fn set_nowait() -> Result<()> {
+    let server = TestServer::run();
+    let pairs: Vec<_> = (0..500)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    client(&server.addr).set_nowait(pairs.clone().into_iter())?;
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    for (key, value) in pairs {
+        while get(&server.addr, &key)?.as_ref() != Some(&value) {
+            assert!(Instant::now() < deadline, "write to {} never landed", key);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    Ok(())
+}
+
+// Parses Prometheus text into its samples, checking that every line is well formed and that
+// each sample comes right after its own HELP and TYPE lines
+fn parse_prometheus(text: &str) -> HashMap<String, f64> {
+    let valid_name = |name: &str| {
+        !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+    };
+
+    let mut samples = HashMap::new();
+    let mut lines = text.lines();
+    while let Some(help) = lines.next() {
+        let help: Vec<&str> = help.splitn(4, ' ').collect();
+        assert_eq!(&help[..2], ["#", "HELP"], "expected HELP line");
+        assert!(help.len() == 4 && !help[3].is_empty(), "HELP without text");
+        let name = help[2];
+        assert!(valid_name(name), "invalid metric name {}", name);
+
+        let kind = lines.next().expect("missing TYPE line");
+        let kind: Vec<&str> = kind.split(' ').collect();
+        assert_eq!(kind.len(), 4, "malformed TYPE line");
+        assert_eq!(&kind[..3], ["#", "TYPE", name]);
+        assert!(["counter", "gauge"].contains(&kind[3]), "unknown type");
+        if kind[3] == "counter" {
+            assert!(name.ends_with("_total"), "counter {} without _total", name);
+        }
+
+        let sample = lines.next().expect("missing sample");
+        let sample: Vec<&str> = sample.split(' ').collect();
+        assert_eq!(sample.len(), 2, "malformed sample");
+        assert_eq!(sample[0], name);
+        let value: f64 = sample[1].parse().expect("sample value isn't a number");
+        assert!(
+            samples.insert(name.to_owned(), value).is_none(),
+            "{} appears twice",
+            name
+        );
+    }
+    samples
+}
+
+// Metrics should render as valid Prometheus text with a series for every counter
+#[test]
+fn prometheus_metrics() -> Result<()> {
+    let server = TestServer::run();
+    set(&server.addr, "key1", "value1")?;
+    set(&server.addr, "key1", "value2")?;
+    set(&server.addr, "key2", "value2")?;
+    remove(&server.addr, "missing").unwrap_err();
+
+    // Every client has hung up, but the server may not have noticed yet
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let samples = loop {
+        let samples = parse_prometheus(&server.server.prometheus_metrics());
+        if samples["kvs_connections_active"] == 0.0 || Instant::now() > deadline {
+            break samples;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(samples["kvs_requests_total"], 4.0);
+    assert_eq!(samples["kvs_request_errors_total"], 1.0);
+    assert_eq!(samples["kvs_connections_accepted_total"], 4.0);
+    assert_eq!(samples["kvs_connections_rejected_total"], 0.0);
+    assert_eq!(samples["kvs_connections_active"], 0.0);
+    assert!(samples["kvs_uptime_seconds"] > 0.0);
+    assert_eq!(samples["kvs_live_keys"], 2.0);
+    assert!(samples["kvs_stale_bytes"] > 0.0);
+    assert!(samples["kvs_disk_bytes"] > 0.0);
+    Ok(())
+}
+
+// Shutting down should report the work done over the run
+#[test]
+fn shutdown_summary() -> Result<()> {
+    let server = TestServer::run();
+    set(&server.addr, "key1", "value1")?;
+    set(&server.addr, "key1", "value2")?;
+    set(&server.addr, "key2", "v")?;
+    remove(&server.addr, "key2")?;
+    remove(&server.addr, "missing").unwrap_err();
+
+    let summary = server
+        .server
+        .shutdown_timeout(&server.addr, Duration::from_secs(1))?;
+    assert_eq!(summary.requests, 5);
+    assert_eq!(summary.failed_requests, 1);
+    assert_eq!(summary.bytes_written, 10 + 10 + 5);
+    assert_eq!(summary.connections.accepted, 5);
+    assert_eq!(summary.connections.rejected, 0);
+    assert_eq!(summary.active_jobs, 0);
+    assert!(summary.uptime > Duration::from_secs(0));
+    assert_eq!(summary.engine.expect("missing engine stats").live_keys, 1);
+    Ok(())
+}
+
+// Swapping engines should carry the data over and send later writes to the new engine only
+#[test]
+fn swap_engine() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let old = KvStore::open(dir.path())?;
+    let server: KvsServer<_, SharedQueueThreadPool> =
+        KvsServer::new(SwappableEngine::new(old.clone()), 4)?;
+
+    let bind_event = WaitGroup::new();
+    let cloned_event = bind_event.clone();
+    let server_clone = server.clone();
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let thread = thread::spawn(move || server_clone.run(&addr, Some(cloned_event)));
+    bind_event.wait();
+    let addr = server.local_addr().unwrap();
+
+    for i in 0..2500 {
+        set(&addr, &format!("key{}", i), &format!("value{}", i))?;
+    }
+    let new = KvStore::open_with_storage(MemoryStorage::new(), KvStoreOptions::default())?;
+    server.swap_engine(new.clone())?;
+
+    assert_eq!(get(&addr, "key0")?, Some("value0".to_owned()));
+    assert_eq!(get(&addr, "key2499")?, Some("value2499".to_owned()));
+    set(&addr, "key0", "new")?;
+    remove(&addr, "key1")?;
+    assert_eq!(new.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(new.get("key1".to_owned())?, None);
+    assert_eq!(new.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(old.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(old.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    server.shutdown(&addr)?;
+    thread.join().unwrap()?;
+    Ok(())
+}
+
+// Records every span along with the name of its parent and its fields
+#[cfg(feature = "tracing")]
+mod spans {
+    use std::collections::HashMap;
+    use std::fmt::Debug;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::Subscriber;
+    use tracing_subscriber::layer::{Context, Layer};
+    use tracing_subscriber::registry::LookupSpan;
+
+    #[derive(Clone, Debug, Default)]
+    pub struct RecordedSpan {
+        pub name: &'static str,
+        pub parent: Option<&'static str>,
+        pub fields: HashMap<&'static str, String>,
+    }
+
+    impl Visit for RecordedSpan {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.fields.insert(field.name(), value.to_owned());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.fields.insert(field.name(), format!("{:?}", value));
+        }
+    }
+
+    #[derive(Clone, Default)]
+    pub struct SpanRecorder(pub Arc<Mutex<HashMap<u64, RecordedSpan>>>);
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanRecorder {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let mut span = RecordedSpan {
+                name: attrs.metadata().name(),
+                parent: ctx
+                    .span(id)
+                    .and_then(|span| span.parent())
+                    .map(|p| p.name()),
+                fields: HashMap::new(),
+            };
+            attrs.record(&mut span);
+            self.0.lock().unwrap().insert(id.into_u64(), span);
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+            if let Some(span) = self.0.lock().unwrap().get_mut(&id.into_u64()) {
+                values.record(span);
+            }
+        }
+    }
+}
+
+// Each request should get a span under its connection, with the engine call under the request
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_spans() -> Result<()> {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let recorder = spans::SpanRecorder::default();
+    // Server threads need the subscriber too, so it can't be scoped to this thread
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(recorder.clone()))
+        .expect("subscriber already set");
+
+    let server = TestServer::run();
+    set(&server.addr, "traced", "value")?;
+    remove(&server.addr, "traced_missing").unwrap_err();
+
+    // Other tests run at the same time, so only the spans of this test's keys are checked
+    let spans: Vec<_> = recorder.0.lock().unwrap().values().cloned().collect();
+    let request = |key: &str| {
+        spans
+            .iter()
+            .find(|span| {
+                span.name == "request" && span.fields.get("key").map(|k| &k[..]) == Some(key)
+            })
+            .cloned()
+            .expect("missing request span")
+    };
+    let traced = request("traced");
+    assert_eq!(traced.parent, Some("connection"));
+    assert_eq!(traced.fields["command"], "set");
+    assert_eq!(traced.fields["result"], "ok");
+    let missing = request("traced_missing");
+    assert_eq!(missing.fields["command"], "remove");
+    assert_eq!(missing.fields["result"], "Key not found");
+
+    let engine_ops: Vec<_> = spans
+        .iter()
+        .filter(|span| span.name == "engine" && span.parent == Some("request"))
+        .map(|span| span.fields["op"].clone())
+        .collect();
+    assert!(engine_ops.contains(&"set".to_owned()));
+    assert!(engine_ops.contains(&"remove".to_owned()));
+    Ok(())
+}
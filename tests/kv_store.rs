@@ -1,6 +1,20 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use kvs::storage::{LogFile, LogStorage, MemoryStorage};
+use kvs::typed::TypedStore;
+use kvs::{
+    format_version, generations, migrate, verify, CompactionEvent, CorruptData,
+    CorruptRecordPolicy, DiskFull, DurabilityMode, HistoryValue, IndexBackend, InvalidValue,
+    KeyNotFound, KvStore, KvStoreOptions, KvsEngine, NonUtf8Value, OptionsMismatch,
+    ReadConsistency, ReadMode, Result, SledKvsEngine, Timeout, ValueCodec, WriteOp,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, prelude::*, SeekFrom};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -209,3 +223,2477 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Repeatedly set, remove, and re-set the same keys so that compaction happens while concurrent
+// readers are running. Readers should only ever see the last committed write to a key or a newer
+// one, never a value from before it.
+#[test]
+fn readd_across_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let done = Arc::new(AtomicBool::new(false));
+    // Last committed write to every key, as its sequence number times two, plus one for a set
+    // and zero for a remove. Published only once the write has returned.
+    let committed: Arc<Vec<AtomicUsize>> = Arc::new((0..10).map(|_| AtomicUsize::new(0)).collect());
+    // Sequence number of the last write to every key that has started, published before the
+    // write begins
+    let started: Arc<Vec<AtomicUsize>> = Arc::new((0..10).map(|_| AtomicUsize::new(0)).collect());
+    // Every value carries the sequence number of the write that set it
+    let value = |key_id: usize, seq: usize| {
+        format!("key{}-{}-{}", key_id, seq, "x".repeat(seq % 100 + 100))
+    };
+    let seq_of = |value: &str| -> usize { value.split('-').nth(1).unwrap().parse().unwrap() };
+
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let store = store.clone();
+        let done = done.clone();
+        let committed = committed.clone();
+        let started = started.clone();
+        handles.push(thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                for key_id in 0..10 {
+                    let before = committed[key_id].load(Ordering::SeqCst);
+                    let value = store.get(format!("key{}", key_id)).unwrap();
+                    let after = started[key_id].load(Ordering::SeqCst);
+                    match value {
+                        Some(value) => {
+                            assert!(value.starts_with(&format!("key{}-", key_id)));
+                            assert!(
+                                seq_of(&value) >= before / 2,
+                                "read {} after write {} was committed",
+                                seq_of(&value),
+                                before / 2
+                            );
+                        }
+                        // Absence is only current if the last write was a remove, or a later
+                        // one started during the get
+                        None => assert!(before.is_multiple_of(2) || after > before / 2),
+                    }
+                }
+            }
+        }));
+    }
+
+    let mut seq = 0;
+    let mut last = vec![None; 10];
+    for _ in 0..3000 {
+        for key_id in 0..10 {
+            let key = format!("key{}", key_id);
+            seq += 1;
+            started[key_id].store(seq, Ordering::SeqCst);
+            store.set(key.clone(), value(key_id, seq))?;
+            committed[key_id].store(seq * 2 + 1, Ordering::SeqCst);
+            seq += 1;
+            started[key_id].store(seq, Ordering::SeqCst);
+            store.remove(key.clone())?;
+            committed[key_id].store(seq * 2, Ordering::SeqCst);
+            assert_eq!(store.get(key.clone())?, None);
+            seq += 1;
+            started[key_id].store(seq, Ordering::SeqCst);
+            store.set(key.clone(), value(key_id, seq))?;
+            committed[key_id].store(seq * 2 + 1, Ordering::SeqCst);
+            last[key_id] = Some(value(key_id, seq));
+            assert_eq!(store.get(key)?, last[key_id]);
+        }
+    }
+
+    done.store(true, Ordering::SeqCst);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert!(store.stats()?.generation > 0, "no compaction happened");
+
+    store.compact()?;
+    for (key_id, last) in last.iter().enumerate() {
+        assert_eq!(store.get(format!("key{}", key_id))?, *last);
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for (key_id, last) in last.iter().enumerate() {
+        assert_eq!(store.get(format!("key{}", key_id))?, *last);
+    }
+
+    Ok(())
+}
+
+// Should be able to read an older generation's log read-only after a compaction
+#[test]
+fn open_older_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "old".to_owned())?;
+
+    let old_log = temp_dir.path().join("kvs_0.cbor");
+    let backup = fs::read(&old_log).expect("unable to read log");
+
+    // Overwrite until compaction moves the store onto the next generation
+    let value = "x".repeat(1000);
+    while !temp_dir.path().join("kvs_1.cbor").exists() {
+        store.set("key1".to_owned(), value.clone())?;
+    }
+    drop(store);
+    fs::write(&old_log, backup).expect("unable to restore log");
+
+    let old = KvStore::open_generation(temp_dir.path(), 0)?;
+    assert_eq!(old.get("key1".to_owned())?, Some("old".to_owned()));
+    assert!(old.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert!(old.remove("key1".to_owned()).is_err());
+    assert!(KvStore::open_generation(temp_dir.path(), 5).is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Command encoding of logs written before the log header was added
+#[derive(Serialize)]
+enum LegacyCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+// Should read logs without a header, and compaction should move them onto the current format
+#[test]
+fn open_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = Vec::new();
+    for cmd in &[
+        LegacyCommand::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+        LegacyCommand::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        LegacyCommand::Remove {
+            key: "key2".to_owned(),
+        },
+    ] {
+        serde_cbor::to_writer(&mut log, cmd)?;
+    }
+    fs::write(temp_dir.path().join("kvs_0.cbor"), &log).expect("unable to write log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert!(!temp_dir.path().join("kvs_0.cbor").exists());
+
+    Ok(())
+}
+
+// Migrating should rewrite a legacy log into the current format with the same data, and do
+// nothing once the store is current
+#[test]
+fn migrate_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(format_version(temp_dir.path())?, None);
+    assert!(!migrate(temp_dir.path())?.migrated);
+
+    let mut log = Vec::new();
+    for i in 0..100 {
+        let cmd = LegacyCommand::Set {
+            key: format!("key{}", i % 50),
+            value: format!("value{}", i),
+        };
+        serde_cbor::to_writer(&mut log, &cmd)?;
+    }
+    serde_cbor::to_writer(
+        &mut log,
+        &LegacyCommand::Remove {
+            key: "key0".to_owned(),
+        },
+    )?;
+    fs::write(temp_dir.path().join("kvs_0.cbor"), &log).expect("unable to write log");
+    assert_eq!(format_version(temp_dir.path())?, Some(0));
+
+    let report = migrate(temp_dir.path())?;
+    assert!(report.migrated);
+    assert_eq!(report.from_version, 0);
+    assert!(report.to_version > 0);
+    assert_eq!(report.generation, 1);
+    assert_eq!(format_version(temp_dir.path())?, Some(report.to_version));
+    assert!(!temp_dir.path().join("kvs_0.cbor").exists());
+    assert!(verify(temp_dir.path())?.is_ok());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..50 {
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("value{}", i + 50))
+        );
+    }
+    drop(store);
+
+    let again = migrate(temp_dir.path())?;
+    assert!(!again.migrated);
+    assert_eq!(again.from_version, report.to_version);
+    assert_eq!(again.generation, 1);
+
+    Ok(())
+}
+
+// Simulates a crash in the middle of a set by cutting its record in half
+#[test]
+fn torn_record_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvs_0.cbor");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let acked_len = fs::metadata(&log).expect("unable to read log").len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let mut data = fs::read(&log).expect("unable to read log");
+    let torn_len = acked_len as usize + (data.len() - acked_len as usize) / 2;
+    data.truncate(torn_len);
+    fs::write(&log, &data).expect("unable to write log");
+
+    // Read-only stores skip the torn bytes without removing them
+    let old = KvStore::open_generation(temp_dir.path(), 0)?;
+    assert_eq!(old.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(old.get("key2".to_owned())?, None);
+    drop(old);
+    assert_eq!(
+        fs::metadata(&log).expect("unable to read log").len(),
+        torn_len as u64
+    );
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        fs::metadata(&log).expect("unable to read log").len(),
+        acked_len
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A crash after compaction renames its log, but before the store moves onto it, leaves both
+// generations behind. Reopening should pick the compacted one, and ignore logs whose header
+// disagrees with their name.
+#[test]
+fn compaction_crash_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old_log = temp_dir.path().join("kvs_0.cbor");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let uncompacted = fs::read(&old_log).expect("unable to read log");
+    store.compact()?;
+    drop(store);
+
+    // Put back what compaction removed, plus a copy of the old log under a newer name
+    fs::write(&old_log, &uncompacted).expect("unable to write log");
+    fs::write(temp_dir.path().join("kvs_5.cbor"), &uncompacted).expect("unable to write log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.generation, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.stats()?.generation, 2);
+    drop(store);
+
+    assert!(!old_log.exists());
+    assert!(!temp_dir.path().join("kvs_5.cbor").exists());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    // Opening a generation whose log was written for another one fails
+    fs::copy(
+        temp_dir.path().join("kvs_2.cbor"),
+        temp_dir.path().join("kvs_3.cbor"),
+    )
+    .expect("unable to copy log");
+    assert!(KvStore::open_generation(temp_dir.path(), 3).is_err());
+    Ok(())
+}
+
+#[test]
+fn index_memory_estimate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.index_memory_bytes(), 0);
+
+    for i in 0..1000 {
+        store.set(format!("key{:05}", i), "value".to_owned())?;
+    }
+    let estimate = store.index_memory_bytes();
+    // Both copies of the index hold every 8-byte key
+    assert!(
+        estimate >= 2 * 1000 * 8,
+        "estimate {} is too small",
+        estimate
+    );
+    assert!(
+        estimate < 2 * 1000 * 1024,
+        "estimate {} is too large",
+        estimate
+    );
+
+    // Overwrites don't add entries
+    store.set("key00000".to_owned(), "other".to_owned())?;
+    assert_eq!(store.index_memory_bytes(), estimate);
+
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 1000);
+    assert_eq!(stats.generation, 0);
+    assert!(stats.stale_bytes > 0);
+    assert_eq!(stats.index_memory_bytes, estimate);
+
+    Ok(())
+}
+
+// Should only compact once enough stale data has piled up
+#[test]
+fn compact_if_worthwhile() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let stale_bytes = store.stats()?.stale_bytes;
+    assert!(stale_bytes > 0);
+
+    assert!(!store.compact_if(stale_bytes + 1)?);
+    assert_eq!(store.stats()?.generation, 0);
+    assert_eq!(store.stats()?.stale_bytes, stale_bytes);
+
+    assert!(store.compact_if(stale_bytes)?);
+    let stats = store.stats()?;
+    assert_eq!(stats.generation, 1);
+    assert_eq!(stats.stale_bytes, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value9".to_owned()));
+    Ok(())
+}
+
+// Readers that already have the log open should see the store as cleared
+#[test]
+fn clear_with_open_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let reader = store.clone();
+    store.set("key1".to_owned(), "a much longer value1".to_owned())?;
+    assert_eq!(
+        reader.get("key1".to_owned())?,
+        Some("a much longer value1".to_owned())
+    );
+
+    store.clear()?;
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Clearing starts a new log and removes the old one
+    assert!(!temp_dir.path().join("kvs_0.cbor").exists());
+    assert!(temp_dir.path().join("kvs_1.cbor").exists());
+    drop((store, reader));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn verify_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(verify(temp_dir.path())?.is_ok());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let report = verify(temp_dir.path())?;
+    assert!(report.is_ok(), "{:?}", report.inconsistencies);
+    assert_eq!(report.valid_records, 4);
+    assert_eq!(report.unreachable_records, 2);
+    assert_eq!(report.live_keys, 1);
+    assert_eq!(report.invalid_records, 0);
+
+    // Leftovers and a torn write at the end should be reported without touching anything
+    let log = temp_dir.path().join("kvs_0.cbor");
+    let mut data = fs::read(&log).expect("unable to read log");
+    data.extend_from_slice(&[0xa1, 0x61]);
+    fs::write(&log, &data).expect("unable to write log");
+    fs::write(temp_dir.path().join("kvs_compact.cbor"), b"").expect("unable to write file");
+
+    let report = verify(temp_dir.path())?;
+    assert!(!report.is_ok());
+    assert_eq!(report.valid_records, 4);
+    assert_eq!(report.invalid_records, 1);
+    assert_eq!(report.invalid_bytes, 2);
+    assert_eq!(report.inconsistencies.len(), 2);
+    assert_eq!(fs::read(&log).expect("unable to read log"), data);
+
+    Ok(())
+}
+
+// Blobs of live keys should have to be there in full, while blob files that no record refers to
+// are leftovers
+#[test]
+fn verify_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        blob_threshold: Some(1024),
+        never_compact: true,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "a".repeat(2000))?;
+    store.set_bytes("key2".to_owned(), vec![0xff; 2000])?;
+    store.set("key3".to_owned(), "c".repeat(2000))?;
+    // The overwritten blob stays until the next compaction, which is expected
+    store.set("key1".to_owned(), "d".repeat(2000))?;
+    drop(store);
+    let report = verify(temp_dir.path())?;
+    assert!(report.is_ok(), "{:?}", report.inconsistencies);
+
+    fs::remove_file(temp_dir.path().join("blob_1")).expect("unable to remove blob");
+    fs::write(temp_dir.path().join("blob_2"), b"short").expect("unable to write blob");
+    fs::write(temp_dir.path().join("blob_99"), b"stray").expect("unable to write blob");
+    let report = verify(temp_dir.path())?;
+    assert_eq!(
+        report.inconsistencies.len(),
+        3,
+        "{:?}",
+        report.inconsistencies
+    );
+    assert!(report
+        .inconsistencies
+        .iter()
+        .any(|problem| problem.contains("blob_1") && problem.contains("key2")));
+    assert!(report
+        .inconsistencies
+        .iter()
+        .any(|problem| problem.contains("blob_2") && problem.contains("5 bytes")));
+    assert!(report
+        .inconsistencies
+        .iter()
+        .any(|problem| problem.contains("Orphaned") && problem.contains("blob_99")));
+    Ok(())
+}
+
+fn check_disk_size(store: impl KvsEngine) -> Result<()> {
+    // Write about 100KB of keys and values
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), "x".repeat(1000))?;
+    }
+
+    let size = store.disk_size()?;
+    assert!(size >= 100 * 1000, "disk size {} is too small", size);
+    assert!(size < 100 * 1024 * 1024, "disk size {} is too large", size);
+
+    Ok(())
+}
+
+#[test]
+fn kvs_disk_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // A new log holds nothing but its header
+    assert!(store.disk_size()? < 16);
+    check_disk_size(store.clone())?;
+    assert!(store.disk_size()? < 2 * 100 * 1000);
+    Ok(())
+}
+
+#[test]
+fn sled_disk_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_disk_size(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// sled values should survive a reopen now that they're stored as records
+#[test]
+fn sled_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), String::new())?;
+    store.remove("key1".to_owned())?;
+
+    drop(store);
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some(String::new()));
+
+    Ok(())
+}
+
+// Values sled holds that the engine didn't write should fail reads instead of panicking
+#[test]
+fn sled_foreign_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::Db::start_default(temp_dir.path())?;
+    // A record whose value is the bytes ff fe, which aren't UTF-8
+    db.set(
+        "record",
+        vec![0xa2, 0x61, b'v', 0x42, 0xff, 0xfe, 0x61, b'n', 0x01],
+    )?;
+    // Bare values from before records existed
+    db.set("legacy", b"value".to_vec())?;
+    db.set("raw", vec![0xff, 0xfe, 0xfd])?;
+    // A map that isn't a record
+    db.set("broken", vec![0xa1, 0x61, b'v', 0x01])?;
+    db.flush()?;
+    drop(db);
+
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    let err = store.get("record".to_owned()).unwrap_err();
+    assert_eq!(err.downcast::<NonUtf8Value>()?.key, "record");
+    assert_eq!(
+        store.get_versioned("legacy".to_owned())?,
+        Some(("value".to_owned(), 0))
+    );
+    let err = store.get("raw".to_owned()).unwrap_err();
+    assert_eq!(err.downcast::<NonUtf8Value>()?.key, "raw");
+    let err = store.get("broken".to_owned()).unwrap_err();
+    assert!(err.downcast::<CorruptData>().is_ok());
+
+    // Overwriting a legacy value turns it into a record
+    store.set("legacy".to_owned(), "value2".to_owned())?;
+    let (value, version) = store.get_versioned("legacy".to_owned())?.unwrap();
+    assert_eq!(value, "value2");
+    assert!(version > 0);
+    Ok(())
+}
+
+#[test]
+fn sled_expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    let start = Instant::now();
+    let ttl = Duration::from_secs(1);
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), ttl)?;
+    store.set_with_ttl("key2".to_owned(), "value2".to_owned(), ttl)?;
+    store.set_with_ttl("key3".to_owned(), "value3".to_owned(), ttl)?;
+    store.set_with_ttl(
+        "key4".to_owned(),
+        "value4".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    // A plain set takes the TTL away
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(!store.set_nx("key3".to_owned(), "new".to_owned())?);
+
+    while store.get("key1".to_owned())?.is_some() {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "key1 never expired"
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(start.elapsed() >= ttl);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    let keys: Vec<String> = store
+        .scan(None, 10)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, vec!["key2", "key4", "key5"]);
+
+    // Expired keys count as absent for writes too
+    let err = store.remove("key1".to_owned()).unwrap_err();
+    assert!(err.downcast::<KeyNotFound>().is_ok());
+    assert!(store.set_nx("key3".to_owned(), "new".to_owned())?);
+    assert_eq!(store.get("key3".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// Losing the end of the log should leave every write up to some point, in either durability mode
+#[test]
+fn durability_ordering() -> Result<()> {
+    for &durability in &[DurabilityMode::OsBuffered, DurabilityMode::Sync] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let log = temp_dir.path().join("kvs_0.cbor");
+        let options = KvStoreOptions {
+            durability,
+            ..Default::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        // Length of the log after each write
+        let mut ends = Vec::new();
+        for i in 0..50 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+            ends.push(fs::metadata(&log).expect("unable to read log").len());
+        }
+        drop(store);
+
+        let data = fs::read(&log).expect("unable to read log");
+        for cut in (ends[0]..=data.len() as u64).step_by(37) {
+            fs::write(&log, &data[..cut as usize]).expect("unable to write log");
+            let store = KvStore::open(temp_dir.path())?;
+            let kept = ends.iter().filter(|&&end| end <= cut).count();
+            for i in 0..50 {
+                let expected = if i < kept {
+                    Some(format!("value{}", i))
+                } else {
+                    None
+                };
+                assert_eq!(store.get(format!("key{}", i))?, expected);
+            }
+        }
+    }
+    Ok(())
+}
+
+// A store opened with a lazy index should serve the same data once the index is ready
+#[test]
+fn lazy_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let options = KvStoreOptions {
+        lazy_index: true,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+    assert!(store.remove("key0".to_owned()).is_err());
+
+    // Errors from reading a bad log come from operations rather than open
+    drop(store);
+    fs::write(temp_dir.path().join("kvs_0.cbor"), [0xff, 0xff, 0xff]).unwrap();
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(store.get("key1".to_owned()).is_err());
+    assert!(store.set("key1".to_owned(), "value1".to_owned()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn case_insensitive_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        normalize_key: Some(Arc::new(|key: &str| key.to_lowercase())),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("User".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("user".to_owned())?, Some("value1".to_owned()));
+    store.set("USER".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("uSeR".to_owned())?, Some("value2".to_owned()));
+    store.set("Other".to_owned(), "value3".to_owned())?;
+    store.remove("OTHER".to_owned())?;
+    assert_eq!(store.get("other".to_owned())?, None);
+    drop(store);
+
+    // The normalized key is what was stored
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("USER".to_owned())?, Some("value2".to_owned()));
+    let pairs: Vec<_> = store.iter()?.collect::<Result<_>>()?;
+    assert_eq!(pairs, vec![("user".to_owned(), "value2".to_owned())]);
+
+    Ok(())
+}
+
+// Values over the threshold go to blobs, so the log stays small and compacting it stays quick
+#[test]
+fn blob_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        blob_threshold: Some(1024),
+        ..Default::default()
+    };
+    let blobs = || -> Result<usize> {
+        Ok(fs::read_dir(temp_dir.path())?
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .is_ok_and(|entry| entry.file_name().to_string_lossy().starts_with("blob_"))
+            })
+            .count())
+    };
+    let log_len = || -> Result<u64> {
+        let gen = *generations(temp_dir.path())?.last().unwrap();
+        let log = temp_dir.path().join(format!("kvs_{}.cbor", gen));
+        Ok(fs::metadata(log)?.len())
+    };
+    let big = |c: char| c.to_string().repeat(10 * 1024 * 1024);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..1000 {
+        store.set(format!("small{}", i), format!("value{}", i))?;
+    }
+    store.set("big1".to_owned(), big('a'))?;
+    store.set_tagged("big2".to_owned(), big('b'), "tag".to_owned())?;
+    store.set("big3".to_owned(), big('c'))?;
+    assert_eq!(store.get("big1".to_owned())?, Some(big('a')));
+    assert_eq!(
+        store.get_tagged("big2".to_owned())?,
+        Some((big('b'), "tag".to_owned()))
+    );
+    assert_eq!(store.get("small7".to_owned())?, Some("value7".to_owned()));
+    assert_eq!(blobs()?, 3);
+    assert!(log_len()? < 64 * 1024);
+
+    // Overwriting a big value leaves its whole blob stale, which is enough to compact on its own
+    store.set("big1".to_owned(), "small".to_owned())?;
+    assert_eq!(store.compaction_history()?.len(), 1);
+    assert_eq!(blobs()?, 2);
+    assert_eq!(store.get("big1".to_owned())?, Some("small".to_owned()));
+
+    let start = Instant::now();
+    store.compact()?;
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert!(log_len()? < 64 * 1024);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(
+        store.get_tagged("big2".to_owned())?,
+        Some((big('b'), "tag".to_owned()))
+    );
+    assert_eq!(store.get("big3".to_owned())?, Some(big('c')));
+    store.remove("big3".to_owned())?;
+    store.set("big4".to_owned(), big('d'))?;
+    store.compact()?;
+    assert_eq!(blobs()?, 2);
+    assert_eq!(store.get("big4".to_owned())?, Some(big('d')));
+    assert_eq!(store.get("big3".to_owned())?, None);
+    Ok(())
+}
+
+// Binary values should round trip as is, inline and in blobs, while text gets still work on them
+// as long as they're valid UTF-8
+#[test]
+fn binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        blob_threshold: Some(1024),
+        ..Default::default()
+    };
+    let binary = vec![0, 159, 146, 150, 255];
+    let big: Vec<u8> = (0..10_000).map(|i| (i % 256) as u8).collect();
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set_bytes("key1".to_owned(), binary.clone())?;
+    store.set_bytes("key2".to_owned(), big.clone())?;
+    store.set_bytes("key3".to_owned(), b"text".to_vec())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(binary.clone()));
+    assert_eq!(store.get_bytes("key2".to_owned())?, Some(big.clone()));
+    assert_eq!(
+        store.get_bytes("key4".to_owned())?,
+        Some(b"value4".to_vec())
+    );
+    assert_eq!(store.get_bytes("key5".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("text".to_owned()));
+    let err = store.get("key1".to_owned()).unwrap_err();
+    assert_eq!(err.downcast::<NonUtf8Value>()?.key, "key1");
+    assert!(store.get("key2".to_owned()).is_err());
+
+    store.compact()?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(binary.clone()));
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(binary));
+    assert_eq!(store.get_bytes("key2".to_owned())?, Some(big));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, None);
+    assert!(verify(temp_dir.path())?.is_ok());
+    Ok(())
+}
+
+// Expired keys should read as absent right away, including after a restart, and compaction should
+// drop them from disk
+#[test]
+fn expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let start = Instant::now();
+    let ttl = Duration::from_secs(1);
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), ttl)?;
+    store.set_with_ttl("key2".to_owned(), "value2".to_owned(), ttl)?;
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    let value = "x".repeat(1000);
+    for i in 0..100 {
+        store.set_with_ttl(format!("big{}", i), value.clone(), ttl)?;
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    // A plain set takes the TTL away
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    while store.get("key1".to_owned())?.is_some() {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "key1 never expired"
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(start.elapsed() >= ttl);
+    assert_eq!(store.get("big7".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    // The expired keys sort first, and a page has to get past all of them to fill up
+    let keys: Vec<String> = store
+        .scan(None, 2)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, vec!["key2", "key3"]);
+
+    // Nothing was compacted, so the expired values are all still in the log a crash would leave
+    let crashed_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::copy(
+        temp_dir.path().join("kvs_0.cbor"),
+        crashed_dir.path().join("kvs_0.cbor"),
+    )
+    .expect("unable to copy log");
+    let crashed = KvStore::open(crashed_dir.path())?;
+    assert_eq!(crashed.get("key1".to_owned())?, None);
+    assert_eq!(crashed.get("big7".to_owned())?, None);
+    assert_eq!(crashed.keys(true)?, vec!["key2", "key3", "key4"]);
+    assert!(crashed.remove("key1".to_owned()).is_err());
+
+    assert!(store.remove("big7".to_owned()).is_err());
+    assert!(store.set_nx("key1".to_owned(), "new".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    let size = store.disk_size()?;
+    store.compact()?;
+    assert!(store.disk_size()? < size - 100_000);
+    assert_eq!(store.keys(true)?, vec!["key1", "key2", "key3", "key4"]);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys(true)?, vec!["key1", "key2", "key3", "key4"]);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Touching should keep a key from expiring without writing its value again, across reopens and
+// compactions
+#[test]
+fn touch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let start = Instant::now();
+    let ttl = Duration::from_secs(1);
+    let hour = Duration::from_secs(3600);
+    let value = "x".repeat(10000);
+    store.set_with_ttl("key1".to_owned(), value.clone(), ttl)?;
+    store.set_with_ttl("key2".to_owned(), "value2".to_owned(), ttl)?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set_with_ttl("key4".to_owned(), "value4".to_owned(), ttl)?;
+
+    let size = store.disk_size()?;
+    assert!(store.touch("key1".to_owned(), hour)?);
+    assert!(store.disk_size()? < size + 100);
+    assert!(!store.touch("missing".to_owned(), hour)?);
+    // Touching can give a TTL to keys that had none
+    assert!(store.touch("key3".to_owned(), ttl)?);
+    // A later set replaces the touched TTL like any other
+    assert!(store.touch("key4".to_owned(), hour)?);
+    store.set_with_ttl("key4".to_owned(), "value4".to_owned(), ttl)?;
+
+    while store.get("key2".to_owned())?.is_some() {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "key2 never expired"
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert!(!store.touch("key2".to_owned(), hour)?);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys(true)?, vec!["key1"]);
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+    assert!(verify(temp_dir.path())?.is_ok());
+    Ok(())
+}
+
+// Sweeping should remove expired keys from the index, whether called directly or on a timer,
+// while reads keep finding nothing for them
+#[test]
+fn sweep_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let ttl = Duration::from_secs(1);
+    for i in 0..5 {
+        store.set_with_ttl(format!("key{}", i), format!("value{}", i), ttl)?;
+    }
+    store.set("kept".to_owned(), "value".to_owned())?;
+    store.set_with_ttl("later".to_owned(), "value".to_owned(), ttl * 3600)?;
+    assert_eq!(store.sweep_expired()?, 0);
+    thread::sleep(ttl + Duration::from_secs(1));
+    assert_eq!(store.stats()?.keys, 7);
+
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        thread::spawn(move || -> Result<()> {
+            while !done.load(Ordering::SeqCst) {
+                for i in 0..5 {
+                    assert_eq!(store.get(format!("key{}", i))?, None);
+                }
+            }
+            Ok(())
+        })
+    };
+    assert_eq!(store.sweep_expired()?, 5);
+    done.store(true, Ordering::SeqCst);
+    reader.join().unwrap()?;
+    assert_eq!(store.stats()?.keys, 2);
+    assert_eq!(store.sweep_expired()?, 0);
+    assert_eq!(store.keys(true)?, vec!["kept", "later"]);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys(true)?, vec!["kept", "later"]);
+    assert!(verify(temp_dir.path())?.is_ok());
+    drop(store);
+
+    let options = KvStoreOptions {
+        sweep_interval: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let start = Instant::now();
+    for i in 0..5 {
+        store.set_with_ttl(format!("key{}", i), format!("value{}", i), ttl)?;
+    }
+    assert_eq!(store.stats()?.keys, 7);
+    while store.stats()?.keys > 2 {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "expired keys were never swept"
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+// Exporting should write every pair as JSON that importing adds back, on either engine
+#[test]
+fn export_import_json() -> Result<()> {
+    fn round_trip(source: impl KvsEngine, dest: impl KvsEngine) -> Result<()> {
+        for i in 0..1500 {
+            source.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        source.set("quoted".to_owned(), "\"line\nbreak\"".to_owned())?;
+        let mut json = Vec::new();
+        assert_eq!(source.export_json(&mut json)?, 1501);
+
+        dest.set("other".to_owned(), "value".to_owned())?;
+        dest.set("key7".to_owned(), "old".to_owned())?;
+        assert_eq!(dest.import_json(&json[..])?, 1501);
+        assert_eq!(dest.iter()?.count(), 1502);
+        assert_eq!(dest.get("key7".to_owned())?, Some("value7".to_owned()));
+        assert_eq!(
+            dest.get("quoted".to_owned())?,
+            Some("\"line\nbreak\"".to_owned())
+        );
+        assert!(dest.import_json(&b"{\"key\": 1}"[..]).is_err());
+
+        // Replacing reads all of the JSON before touching the store
+        let mut bad = json.clone();
+        bad.extend_from_slice(b"{\"key\": 1}");
+        assert!(dest.replace_json(&bad[..]).is_err());
+        assert_eq!(dest.iter()?.count(), 1502);
+        assert_eq!(dest.replace_json(&json[..])?, 1501);
+        assert_eq!(dest.iter()?.count(), 1501);
+        assert_eq!(dest.get("other".to_owned())?, None);
+        Ok(())
+    }
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    round_trip(
+        KvStore::open(kvs_dir.path())?,
+        SledKvsEngine::open(sled_dir.path())?,
+    )?;
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    round_trip(
+        SledKvsEngine::open(sled_dir.path())?,
+        KvStore::open(kvs_dir.path())?,
+    )
+}
+
+// Encoding of an expiring set in the current log format
+#[derive(Serialize)]
+enum ExpiringCommand {
+    #[serde(rename = "s")]
+    Set {
+        #[serde(rename = "k")]
+        key: String,
+        #[serde(rename = "v")]
+        value: String,
+        #[serde(rename = "e")]
+        expires_at: u64,
+        #[serde(rename = "w")]
+        written_at: u64,
+    },
+}
+
+// Open should count expiring records written further in the future than the tolerance allows
+#[test]
+fn clock_skew() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert!(store.touch("key1".to_owned(), Duration::from_secs(7200))?);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.skewed_records, 0);
+    drop(store);
+
+    // Written by a clock a day ahead
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("clock is before 1970")
+        .as_secs();
+    let cmd = ExpiringCommand::Set {
+        key: "key2".to_owned(),
+        value: "value2".to_owned(),
+        expires_at: now + 86400 + 60,
+        written_at: now + 86400,
+    };
+    let mut log = fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("kvs_0.cbor"))
+        .expect("unable to open log");
+    serde_cbor::to_writer(&mut log, &cmd)?;
+    drop(log);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.skewed_records, 1);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let options = KvStoreOptions {
+        clock_skew_tolerance: Some(Duration::from_secs(2 * 86400)),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.stats()?.skewed_records, 0);
+    // Compaction keeps the time the record was written
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.skewed_records, 1);
+    Ok(())
+}
+
+// Sets past the disk cap should compact first, and fail once compacting doesn't free enough
+#[test]
+fn max_disk_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_disk_bytes: Some(4000),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    // Overwrites only ever fill the cap with stale data
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("{:0100}", i))?;
+        assert!(store.disk_size()? <= 4000);
+    }
+    assert!(!store.compaction_history()?.is_empty());
+
+    let mut written = 0;
+    let err = loop {
+        match store.set(format!("key{}", written), "v".repeat(100)) {
+            Ok(()) => written += 1,
+            Err(err) => break err,
+        }
+    };
+    assert!(err.downcast::<DiskFull>().is_ok());
+    assert!(written > 10);
+    assert!(store.disk_size()? <= 4000);
+    assert_eq!(store.get("key0".to_owned())?, Some("v".repeat(100)));
+    assert!(store
+        .write_batch(vec![WriteOp::Set("new".to_owned(), "v".repeat(100))])
+        .is_err());
+
+    // Removing makes room again
+    store.remove("key0".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("new".to_owned(), "v".repeat(100))?;
+    assert!(store.disk_size()? <= 4000);
+    Ok(())
+}
+
+// Leaves values as they are apart from a tag, so tests can see what reached the log
+struct TaggedCodec;
+
+impl ValueCodec for TaggedCodec {
+    fn encode(&self, value: String) -> Result<String> {
+        Ok(format!("tag:{}", value))
+    }
+
+    fn decode(&self, stored: String) -> Result<String> {
+        match stored.strip_prefix("tag:") {
+            Some(value) => Ok(value.to_owned()),
+            None => Err(CorruptData.into()),
+        }
+    }
+}
+
+// Values should go through the codec exactly once on the way in and out, compaction included
+#[test]
+fn value_codec() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        value_codec: Some(Arc::new(TaggedCodec)),
+        never_compact: true,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(store.set_nx("key2".to_owned(), "value3".to_owned())?);
+    store.write_batch(vec![WriteOp::Set("key3".to_owned(), "value4".to_owned())])?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    let history: Vec<_> = store
+        .history("key1".to_owned())?
+        .into_iter()
+        .map(|entry| entry.value)
+        .collect();
+    assert_eq!(
+        history,
+        vec![
+            Some(HistoryValue::Text("value1".to_owned())),
+            Some(HistoryValue::Text("value2".to_owned())),
+        ]
+    );
+
+    store.compact()?;
+    let mut pairs: Vec<_> = store.iter()?.collect::<Result<_>>()?;
+    pairs.sort();
+    assert_eq!(
+        pairs,
+        vec![
+            ("key1".to_owned(), "value2".to_owned()),
+            ("key2".to_owned(), "value3".to_owned()),
+            ("key3".to_owned(), "value4".to_owned()),
+        ]
+    );
+    drop(store);
+
+    // The log holds the encoded values
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("tag:value2".to_owned()));
+    store.set("key4".to_owned(), "plain".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(store.get("key4".to_owned()).is_err());
+    store.replace_all(vec![("key5".to_owned(), "value5".to_owned())])?;
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn tagged_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_tagged(
+        "key1".to_owned(),
+        "{}".to_owned(),
+        "application/json".to_owned(),
+    )?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        store.get_tagged("key1".to_owned())?,
+        Some(("{}".to_owned(), "application/json".to_owned()))
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("{}".to_owned()));
+    assert_eq!(
+        store.get_tagged("key2".to_owned())?,
+        Some(("value2".to_owned(), String::new()))
+    );
+    assert_eq!(store.get_tagged("key3".to_owned())?, None);
+
+    store.compact()?;
+    assert_eq!(
+        store.get_tagged("key1".to_owned())?,
+        Some(("{}".to_owned(), "application/json".to_owned()))
+    );
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_tagged("key1".to_owned())?,
+        Some(("{}".to_owned(), "application/json".to_owned()))
+    );
+    // A plain set drops the old tag
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        store.get_tagged("key1".to_owned())?,
+        Some(("value1".to_owned(), String::new()))
+    );
+
+    Ok(())
+}
+
+// The resolver should decide what gets stored, with merges from many threads all applying
+#[test]
+fn set_with_merge() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let keep_longer = |current: Option<&str>, incoming: &str| match current {
+        Some(current) if current.len() >= incoming.len() => current.to_owned(),
+        _ => incoming.to_owned(),
+    };
+
+    let merged = store.set_with_merge("key1".to_owned(), "abc".to_owned(), keep_longer)?;
+    assert_eq!(merged, "abc");
+    store.set_with_merge("key1".to_owned(), "x".to_owned(), keep_longer)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("abc".to_owned()));
+    store.set_with_merge("key1".to_owned(), "abcdef".to_owned(), keep_longer)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("abcdef".to_owned()));
+
+    let mut handles = Vec::new();
+    for _ in 0..10 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..10 {
+                store
+                    .set_with_merge("count".to_owned(), "1".to_owned(), |current, incoming| {
+                        let current: u32 = current.map_or(0, |c| c.parse().unwrap());
+                        (current + incoming.parse::<u32>().unwrap()).to_string()
+                    })
+                    .unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("count".to_owned())?, Some("100".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("abcdef".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn lru_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_keys: Some(10),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // Using key0 makes key1 the least recently used key
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    for i in 10..15 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let mut evicted = Vec::new();
+    for i in 0..15 {
+        if store.get(format!("key{}", i))?.is_none() {
+            evicted.push(i);
+        }
+    }
+    assert_eq!(evicted, vec![1, 2, 3, 4, 5]);
+    drop(store);
+
+    // Evictions are persisted
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.iter()?.count(), 10);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key15".to_owned(), "value15".to_owned())?;
+    assert_eq!(store.iter()?.count(), 10);
+
+    Ok(())
+}
+
+// Opening a directory that's already open should share the store instead of adding a writer
+#[test]
+fn open_twice_shares_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let first = KvStore::open(temp_dir.path())?;
+    let second = KvStore::open(&temp_dir.path().join("."))?;
+
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(second.get("key1".to_owned())?, Some("value1".to_owned()));
+    second.remove("key1".to_owned())?;
+    assert_eq!(first.get("key1".to_owned())?, None);
+    first.compact()?;
+    second.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(first.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // The open store can't take on different options
+    let options = KvStoreOptions {
+        never_compact: true,
+        ..Default::default()
+    };
+    let err = KvStore::open_with_options(temp_dir.path(), options.clone())
+        .err()
+        .expect("opened with different options");
+    assert!(err.downcast::<OptionsMismatch>().is_ok());
+
+    // Once every handle is gone, the next open reads the directory again, with any options
+    drop(first);
+    drop(second);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Concurrent opens of one directory should all get the same store, even while another directory
+// is opened at the same time
+#[test]
+fn concurrent_opens() -> Result<()> {
+    let slow_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(slow_dir.path())?;
+    let value = "x".repeat(100);
+    for i in 0..20000 {
+        store.set(format!("key{}", i % 100), value.clone())?;
+    }
+    drop(store);
+
+    let barrier = Arc::new(Barrier::new(5));
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let barrier = barrier.clone();
+            let dir = slow_dir.path().to_owned();
+            thread::spawn(move || -> Result<()> {
+                barrier.wait();
+                let store = KvStore::open(&dir)?;
+                store.set(format!("thread{}", i), "value".to_owned())?;
+                barrier.wait();
+                // Every thread's write went to the same store
+                for i in 0..4 {
+                    assert_eq!(store.get(format!("thread{}", i))?, Some("value".to_owned()));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    barrier.wait();
+    let fast_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(fast_dir.path())?.set("key".to_owned(), "value".to_owned())?;
+    barrier.wait();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// Latest stores should show every write right away, while Refreshed stores only promise it after
+// sync_reads
+#[test]
+fn read_consistency() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let other = store.clone();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop((store, other));
+
+    let options = KvStoreOptions {
+        read_consistency: ReadConsistency::Refreshed,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let other = store.clone();
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    // Writes still see unrefreshed writes
+    store.remove("key2".to_owned())?;
+    assert_eq!(
+        store.set_and_get_old("key1".to_owned(), "value4".to_owned())?,
+        Some("value2".to_owned())
+    );
+
+    store.sync_reads()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(other.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(other.get("key2".to_owned())?, None);
+
+    // Enough writes get refreshed without asking
+    for i in 0..100 {
+        store.set(format!("key{}", i), "batch".to_owned())?;
+    }
+    assert_eq!(other.get("key0".to_owned())?, Some("batch".to_owned()));
+    drop((store, other));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("batch".to_owned()));
+    Ok(())
+}
+
+// Should behave the same as a file-backed store without touching the filesystem
+#[test]
+fn memory_storage() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::default())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let size = store.disk_size()?;
+    store.compact()?;
+    assert!(store.disk_size()? < size);
+    assert_eq!(store.stats()?.generation, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // A store reopened from the same storage sees the compacted log
+    let store = KvStore::open_with_storage(storage, KvStoreOptions::default())?;
+    assert_eq!(store.stats()?.generation, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.clear()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Memory storage whose logs panic on writes, panic on reads from the store's own threads, or hang on
+// reads while the flags are set, and which counts how often its logs are flushed
+#[derive(Clone, Default)]
+struct FaultyStorage {
+    inner: MemoryStorage,
+    panic: Arc<AtomicBool>,
+    background_panic: Arc<AtomicBool>,
+    hang: Arc<AtomicBool>,
+    flushes: Arc<AtomicUsize>,
+}
+
+struct FaultyLog {
+    inner: Box<dyn LogFile>,
+    panic: Arc<AtomicBool>,
+    background_panic: Arc<AtomicBool>,
+    hang: Arc<AtomicBool>,
+    flushes: Arc<AtomicUsize>,
+}
+
+impl FaultyStorage {
+    fn wrap(&self, inner: Box<dyn LogFile>) -> Box<dyn LogFile> {
+        Box::new(FaultyLog {
+            inner,
+            panic: Arc::clone(&self.panic),
+            background_panic: Arc::clone(&self.background_panic),
+            hang: Arc::clone(&self.hang),
+            flushes: Arc::clone(&self.flushes),
+        })
+    }
+}
+
+impl LogStorage for FaultyStorage {
+    fn read(&self, gen: u64) -> io::Result<Box<dyn LogFile>> {
+        Ok(self.wrap(self.inner.read(gen)?))
+    }
+
+    fn append(&self, gen: u64) -> io::Result<Box<dyn LogFile>> {
+        Ok(self.wrap(self.inner.append(gen)?))
+    }
+
+    fn create_temp(&self) -> io::Result<Box<dyn LogFile>> {
+        Ok(self.wrap(self.inner.create_temp()?))
+    }
+
+    fn commit_temp(&self, gen: u64) -> io::Result<()> {
+        self.inner.commit_temp(gen)
+    }
+
+    fn generations(&self) -> io::Result<Vec<u64>> {
+        self.inner.generations()
+    }
+
+    fn remove_except(&self, gen: u64) -> io::Result<()> {
+        self.inner.remove_except(gen)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+}
+
+impl Read for FaultyLog {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.hang.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Test threads are named after their test, while the store's own threads aren't
+        let background = thread::current().name().is_none();
+        assert!(
+            !(background && self.background_panic.load(Ordering::SeqCst)),
+            "background read panicked"
+        );
+        self.inner.read(buf)
+    }
+}
+
+impl Write for FaultyLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        assert!(!self.panic.load(Ordering::SeqCst), "write panicked");
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+        self.inner.flush()
+    }
+}
+
+impl Seek for FaultyLog {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl LogFile for FaultyLog {
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+}
+
+// A panic in the middle of a write or compaction shouldn't leave the store unusable
+#[test]
+fn recover_from_panic() -> Result<()> {
+    let storage = FaultyStorage::default();
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::default())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    storage.panic.store(true, Ordering::SeqCst);
+    let set = panic::catch_unwind(AssertUnwindSafe(|| {
+        store.set("key1".to_owned(), "value3".to_owned())
+    }));
+    assert!(set.is_err());
+    let compact = panic::catch_unwind(AssertUnwindSafe(|| store.compact()));
+    assert!(compact.is_err());
+    storage.panic.store(false, Ordering::SeqCst);
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    let store = KvStore::open_with_storage(storage, KvStoreOptions::default())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A lazy index build that panics should make operations fail instead of waiting for it forever
+#[test]
+fn lazy_index_panic() -> Result<()> {
+    let storage = FaultyStorage::default();
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::default())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    storage.background_panic.store(true, Ordering::SeqCst);
+    let options = KvStoreOptions {
+        lazy_index: true,
+        sweep_interval: Some(Duration::from_millis(10)),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_storage(storage.clone(), options)?;
+    assert!(store.get("key1".to_owned()).is_err());
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert!(store.iter().is_err());
+    storage.background_panic.store(false, Ordering::SeqCst);
+    assert!(store.get("key1".to_owned()).is_err());
+    drop(store);
+
+    let store = KvStore::open_with_storage(storage, KvStoreOptions::default())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A get stuck on a hung read should time out, and later reads should work once the log recovers
+#[test]
+fn read_timeout() -> Result<()> {
+    let storage = FaultyStorage::default();
+    let options = KvStoreOptions {
+        read_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_storage(storage.clone(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    storage.hang.store(true, Ordering::SeqCst);
+    let start = Instant::now();
+    let err = store.get("key1".to_owned()).unwrap_err();
+    assert!(err.downcast_ref::<Timeout>().is_some());
+    assert!(start.elapsed() < Duration::from_secs(5));
+    storage.hang.store(false, Ordering::SeqCst);
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A get stuck reading its value shouldn't hold up writes, which refresh the index it reads from
+#[test]
+fn writes_during_slow_read() -> Result<()> {
+    let storage = FaultyStorage::default();
+    let store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::default())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    storage.hang.store(true, Ordering::SeqCst);
+    let reader = store.clone();
+    let slow_get = thread::spawn(move || reader.get("key1".to_owned()));
+    // Give the get time to get stuck in the read
+    thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    for i in 0..20 {
+        store.set("key1".to_owned(), format!("value{}", i + 2))?;
+        store.set(format!("key{}", i + 2), "value".to_owned())?;
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(store.stats()?.keys, 21);
+    assert!(!slow_get.is_finished());
+
+    storage.hang.store(false, Ordering::SeqCst);
+    // The slow get read the value that was there when it started
+    assert_eq!(slow_get.join().unwrap()?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value21".to_owned()));
+    Ok(())
+}
+
+// Concurrent writes should share flushes, while each still gets its own result
+#[test]
+fn group_commit() -> Result<()> {
+    let storage = FaultyStorage::default();
+    let options = KvStoreOptions {
+        group_commit_window: Some(Duration::from_millis(20)),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_storage(storage.clone(), options)?;
+    let barrier = Arc::new(Barrier::new(8));
+    let flushes = storage.flushes.load(Ordering::SeqCst);
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..10 {
+                    let key = format!("key{}-{}", thread_id, i);
+                    store.set(key.clone(), format!("value{}", i)).unwrap();
+                    assert_eq!(store.get(key).unwrap(), Some(format!("value{}", i)));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert!(storage.flushes.load(Ordering::SeqCst) - flushes < 40);
+    assert_eq!(store.stats()?.keys, 80);
+
+    // Removes go through groups too, and a missing key fails only its own remove
+    store.remove("key0-0".to_owned())?;
+    let err = store.remove("key0-0".to_owned()).unwrap_err();
+    assert!(err.downcast_ref::<KeyNotFound>().is_some());
+    assert_eq!(store.get("key0-0".to_owned())?, None);
+    assert_eq!(store.get("key0-1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Cloned handles should share a bounded pool of logs instead of each keeping its own open
+#[test]
+fn bounded_open_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let clones: Vec<_> = (0..16)
+        .map(|_| {
+            let store = store.clone();
+            store.get("key".to_owned()).unwrap();
+            store
+        })
+        .collect();
+    assert_eq!(store.open_fds(), 2 + 16);
+    drop(clones);
+    assert_eq!(store.open_fds(), 2);
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_open_logs: Some(3),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let barrier = Arc::new(Barrier::new(16));
+    let threads: Vec<_> = (0..16)
+        .map(|_| {
+            let store = store.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..100 {
+                    let value = store.get(format!("key{}", i)).unwrap();
+                    assert_eq!(value, Some(format!("value{}", i)));
+                    assert!(store.open_fds() <= 2 + 3);
+                }
+                store
+            })
+        })
+        .collect();
+    let clones: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    assert!(store.open_fds() <= 2 + 3);
+
+    // Logs from before a compaction make way for the new generation's
+    store.compact()?;
+    for clone in &clones {
+        assert_eq!(clone.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    assert!(store.open_fds() <= 2 + 3);
+
+    let options = KvStoreOptions {
+        max_open_logs: Some(0),
+        ..Default::default()
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::open_with_options(temp_dir.path(), options).is_err());
+    Ok(())
+}
+
+// Sorted keys should come back in lexicographic order, and unsorted ones in any order
+#[test]
+fn sorted_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.keys(true)?.is_empty());
+
+    for key in &["b", "a10", "a2", "c", "A", "removed"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    store.remove("removed".to_owned())?;
+
+    assert_eq!(store.keys(true)?, vec!["A", "a10", "a2", "b", "c"]);
+    let mut keys = store.keys(false)?;
+    keys.sort();
+    assert_eq!(keys, store.keys(true)?);
+    Ok(())
+}
+
+// Prefix iteration should yield exactly the matching pairs in key order, skipping keys removed
+// after it started
+#[test]
+fn iter_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.iter_prefix("user:")?.next().is_none());
+
+    for key in &[
+        "user:2", "user:10", "user:1", "users", "admin:1", "user:", "user:3",
+    ] {
+        store.set(key.to_string(), format!("{}-value", key))?;
+    }
+    store.remove("user:3".to_owned())?;
+
+    let pairs = store.iter_prefix("user:")?.collect::<Result<Vec<_>>>()?;
+    let expected: Vec<_> = ["user:", "user:1", "user:10", "user:2"]
+        .iter()
+        .map(|key| (key.to_string(), format!("{}-value", key)))
+        .collect();
+    assert_eq!(pairs, expected);
+
+    let mut iter = store.iter_prefix("user:")?;
+    assert_eq!(iter.next().unwrap()?.0, "user:");
+    store.remove("user:1".to_owned())?;
+    assert_eq!(iter.next().unwrap()?.0, "user:10");
+    drop(iter);
+
+    assert_eq!(store.iter_prefix("")?.count(), store.keys(false)?.len());
+    assert_eq!(store.scan_prefix("user:")?.len(), 3);
+    Ok(())
+}
+
+// The compaction listener should see every compaction start and finish
+#[test]
+fn compaction_events() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+    let events = Arc::clone(&recorded);
+    let options = KvStoreOptions {
+        on_compaction: Some(Arc::new(move |event| events.lock().unwrap().push(event))),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    // Overwrites pile up past the threshold and trigger a compaction
+    let value = "x".repeat(100 * 1024);
+    for _ in 0..20 {
+        store.set("key".to_owned(), value.clone())?;
+    }
+    let events = recorded.lock().unwrap().clone();
+    assert_eq!(events.len(), 2);
+    let stale_bytes = match events[0] {
+        CompactionEvent::Started { stale_bytes } => stale_bytes,
+        event => panic!("unexpected event {:?}", event),
+    };
+    assert!(stale_bytes > 1024 * 1024);
+    match events[1] {
+        CompactionEvent::Finished {
+            reclaimed_bytes, ..
+        } => {
+            assert!(reclaimed_bytes >= stale_bytes);
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+
+    // Compacting by hand counts too
+    store.compact()?;
+    assert_eq!(recorded.lock().unwrap().len(), 4);
+    assert_eq!(store.get("key".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Keys should come back in the order they were first set, across compactions and reopening
+#[test]
+fn keys_insertion_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["c", "a", "d", "b"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    // Overwrites keep their place, while a removed key goes last once it's set again
+    store.set("c".to_owned(), "new value".to_owned())?;
+    store.remove("a".to_owned())?;
+    store.remove("d".to_owned())?;
+    store.set("a".to_owned(), "value".to_owned())?;
+    let expected = vec!["c", "b", "a"];
+    assert_eq!(store.keys_insertion_order()?, expected);
+
+    store.compact()?;
+    assert_eq!(store.keys_insertion_order()?, expected);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys_insertion_order()?, expected);
+    store.set("e".to_owned(), "value".to_owned())?;
+    assert_eq!(store.keys_insertion_order()?, vec!["c", "b", "a", "e"]);
+    Ok(())
+}
+
+// Every compaction should leave a record of how much it reclaimed, oldest first
+#[test]
+fn compaction_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.compaction_history()?.is_empty());
+    let started = SystemTime::now();
+
+    // The first compaction is triggered by overwrites, the second is done by hand
+    let value = "x".repeat(100 * 1024);
+    for _ in 0..20 {
+        store.set("key".to_owned(), value.clone())?;
+    }
+    store.compact()?;
+
+    let history = store.compaction_history()?;
+    assert_eq!(history.len(), 2);
+    assert!(history[0].stale_bytes > 1024 * 1024);
+    assert!(history[0].reclaimed_bytes >= history[0].stale_bytes);
+    assert!(history[1].stale_bytes >= value.len() as u64);
+    assert!(history[1].reclaimed_bytes >= history[1].stale_bytes);
+    assert!(started <= history[0].finished_at);
+    assert!(history[0].finished_at <= history[1].finished_at);
+    assert!(history[1].finished_at <= SystemTime::now());
+    assert!(history.iter().all(|r| r.duration < Duration::from_secs(60)));
+    Ok(())
+}
+
+// Should list every log generation in a directory in order and skip files that aren't logs
+#[test]
+fn list_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(generations(temp_dir.path())?.is_empty());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(generations(temp_dir.path())?, vec![0]);
+    store.compact()?;
+    assert_eq!(generations(temp_dir.path())?, vec![1]);
+    drop(store);
+
+    // Leftover logs from older generations
+    for gen in &[10, 0, 2] {
+        fs::write(temp_dir.path().join(format!("kvs_{}.cbor", gen)), b"")?;
+    }
+    // Decoys that look like logs
+    for name in &[
+        "kvs_compact.cbor",
+        "kvs_007.cbor",
+        "kvs_4_5.cbor",
+        "kvs_-3.cbor",
+        "kvs_6.txt",
+        "store_8.cbor",
+    ] {
+        fs::write(temp_dir.path().join(name), b"")?;
+    }
+    fs::create_dir(temp_dir.path().join("kvs_9.cbor"))?;
+
+    assert_eq!(generations(temp_dir.path())?, vec![0, 1, 2, 10]);
+    Ok(())
+}
+
+// Typed values should round trip, and values stored with another shape should fail to decode
+#[test]
+fn typed_values() -> Result<()> {
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let points: TypedStore<Point> = TypedStore::new(store.clone());
+    points.set("origin".to_owned(), &Point { x: 0, y: 0 })?;
+    points.set("p".to_owned(), &Point { x: 3, y: -4 })?;
+    assert_eq!(points.get("p".to_owned())?, Some(Point { x: 3, y: -4 }));
+    assert_eq!(points.get("missing".to_owned())?, None);
+    points.remove("origin".to_owned())?;
+    assert_eq!(points.get("origin".to_owned())?, None);
+
+    // Values written with an older shape of the type
+    store.set("old".to_owned(), r#"{"x":1}"#.to_owned())?;
+    store.set("raw".to_owned(), "not json".to_owned())?;
+    for key in &["old", "raw"] {
+        let err = points.get(key.to_string()).unwrap_err();
+        let invalid = err
+            .downcast_ref::<InvalidValue>()
+            .expect("expected InvalidValue");
+        assert_eq!(invalid.key, *key);
+    }
+    Ok(())
+}
+
+// Preallocating should reserve space without changing what's in the log, and writes should
+// keep going past the reserved space
+#[test]
+fn preallocate_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        preallocate_bytes: Some(1024 * 1024),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let log = temp_dir.path().join("kvs_0.cbor");
+    assert!(fs::metadata(&log)?.len() < 1024);
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert!(fs::metadata(&log)?.blocks() * 512 >= 1024 * 1024);
+    }
+
+    let value = "x".repeat(1024);
+    for i in 0..2000 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    assert!(fs::metadata(&log)?.len() > 3 * 512 * 1024);
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1999".to_owned())?, Some(value));
+    assert_eq!(store.keys(false)?.len(), 2000);
+    Ok(())
+}
+
+// A checkpoint should point at the end of the flushed log
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let first = store.checkpoint()?;
+    assert_eq!(first.generation, 0);
+    let log_len = fs::metadata(temp_dir.path().join("kvs_0.cbor"))?.len();
+    assert_eq!(first.offset, log_len);
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let second = store.checkpoint()?;
+    assert!(second.offset > first.offset);
+    assert_eq!(store.checkpoint()?, second);
+
+    store.compact()?;
+    let compacted = store.checkpoint()?;
+    assert_eq!(compacted.generation, 1);
+    let log_len = fs::metadata(temp_dir.path().join("kvs_1.cbor"))?.len();
+    assert_eq!(compacted.offset, log_len);
+    Ok(())
+}
+
+// Replacing the dataset should swap every key at once and survive a reopen
+#[test]
+fn replace_all() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let old_keys: Vec<_> = (0..10).map(|i| format!("old{}", i)).collect();
+    for key in &old_keys {
+        store.set(key.clone(), "value".to_owned())?;
+    }
+    let mut new_keys: Vec<_> = (0..5).map(|i| format!("new{}", i)).collect();
+    new_keys.sort();
+
+    // Every snapshot of the keys is either the whole old set or the whole new one
+    let done = Arc::new(AtomicBool::new(false));
+    let watcher = {
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        let (old_keys, new_keys) = (old_keys.clone(), new_keys.clone());
+        thread::spawn(move || {
+            let mut saw_new = false;
+            while !done.load(Ordering::SeqCst) {
+                let keys = store.keys(true).unwrap();
+                if keys == new_keys {
+                    saw_new = true;
+                } else {
+                    assert!(!saw_new, "old keys came back");
+                    assert_eq!(keys, old_keys);
+                }
+            }
+        })
+    };
+
+    let pairs: Vec<_> = new_keys
+        .iter()
+        .map(|key| (key.clone(), format!("{}-value", key)))
+        .collect();
+    store.replace_all(pairs)?;
+    thread::sleep(Duration::from_millis(20));
+    done.store(true, Ordering::SeqCst);
+    watcher.join().unwrap();
+
+    for key in &old_keys {
+        assert_eq!(store.get(key.clone())?, None);
+    }
+    assert_eq!(store.keys(true)?, new_keys);
+    assert_eq!(store.get("new3".to_owned())?, Some("new3-value".to_owned()));
+    assert_eq!(store.stats()?.generation, 1);
+
+    // Later pairs win, and the replaced data is what gets reopened
+    store.replace_all(vec![
+        ("key".to_owned(), "first".to_owned()),
+        ("key".to_owned(), "second".to_owned()),
+    ])?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys(false)?, vec!["key"]);
+    assert_eq!(store.get("key".to_owned())?, Some("second".to_owned()));
+    assert!(store.stats()?.stale_bytes > 0);
+    Ok(())
+}
+
+// Mapped reads should see values appended after the log was mapped and follow compactions
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        read_mode: ReadMode::Mmap,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Written past the end of the current mapping
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    store.clear()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// A lower compaction threshold should compact after much less stale data than the default
+#[test]
+fn custom_compaction_threshold() -> Result<()> {
+    let value = "x".repeat(100);
+    let write_stale = |store: &KvStore| -> Result<()> {
+        for _ in 0..100 {
+            store.set("key1".to_owned(), value.clone())?;
+        }
+        Ok(())
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    write_stale(&store)?;
+    assert_eq!(store.stats()?.generation, 0);
+    assert!(store.disk_size()? > 10_000);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: Some(1024),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    write_stale(&store)?;
+    assert!(store.stats()?.generation > 0);
+    assert!(store.disk_size()? < 2048);
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+
+    Ok(())
+}
+
+// History should hand back bytes set with set_bytes as they are, without the codec, whether or not
+// they're UTF-8
+#[test]
+fn history_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        value_codec: Some(Arc::new(TaggedCodec)),
+        never_compact: true,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_bytes("key1".to_owned(), vec![0xff, 0x00])?;
+    store.set_bytes("key1".to_owned(), b"value2".to_vec())?;
+    store.remove("key1".to_owned())?;
+
+    let history: Vec<_> = store
+        .history("key1".to_owned())?
+        .into_iter()
+        .map(|entry| entry.value)
+        .collect();
+    assert_eq!(
+        history,
+        vec![
+            Some(HistoryValue::Text("value1".to_owned())),
+            Some(HistoryValue::Bytes(vec![0xff, 0x00])),
+            Some(HistoryValue::Bytes(b"value2".to_vec())),
+            None,
+        ]
+    );
+    Ok(())
+}
+
+// Without compaction every write to a key stays readable from the log
+#[test]
+fn never_compact_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        never_compact: true,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "other".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+
+    let history = store.history("key1".to_owned())?;
+    let values: Vec<_> = history.iter().map(|entry| entry.value.clone()).collect();
+    assert_eq!(
+        values,
+        vec![
+            Some(HistoryValue::Text("value1".to_owned())),
+            Some(HistoryValue::Text("value2".to_owned())),
+            Some(HistoryValue::Text("value3".to_owned())),
+        ]
+    );
+    assert!(history
+        .windows(2)
+        .all(|pair| pair[0].offset < pair[1].offset));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(
+        store.history("key1".to_owned())?.last().unwrap().value,
+        None
+    );
+    assert!(store.history("missing".to_owned())?.is_empty());
+
+    // Far more stale data than would normally trigger a compaction
+    let value = "x".repeat(1024);
+    for _ in 0..2000 {
+        store.set("key2".to_owned(), value.clone())?;
+    }
+    assert_eq!(store.stats()?.generation, 0);
+    assert_eq!(store.history("key2".to_owned())?.len(), 2001);
+
+    Ok(())
+}
+
+// Out of many threads racing to initialize the same key with set_nx, exactly one should win
+#[test]
+fn concurrent_set_nx() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(8));
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                store
+                    .set_nx("lock".to_owned(), thread_id.to_string())
+                    .unwrap()
+            })
+        })
+        .collect();
+
+    let won: Vec<bool> = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect();
+    let winners: Vec<_> = (0..8).filter(|&thread_id| won[thread_id]).collect();
+    assert_eq!(winners.len(), 1);
+    assert_eq!(store.get("lock".to_owned())?, Some(winners[0].to_string()));
+
+    Ok(())
+}
+
+// Concurrent swaps should each see the value written by exactly one other swap, so no write is
+// ever lost between reading the old value and writing the new one
+#[test]
+fn concurrent_swaps() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "start".to_owned())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                (0..100)
+                    .map(|i| {
+                        store
+                            .set_and_get_old("key".to_owned(), format!("{}-{}", thread_id, i))
+                            .unwrap()
+                            .unwrap()
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+
+    let mut seen: Vec<_> = handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect();
+    seen.push(store.get("key".to_owned())?.unwrap());
+    seen.sort();
+    let mut expected: Vec<_> = (0..8)
+        .flat_map(|thread_id| (0..100).map(move |i| format!("{}-{}", thread_id, i)))
+        .collect();
+    expected.push("start".to_owned());
+    expected.sort();
+    assert_eq!(seen, expected);
+
+    Ok(())
+}
+
+// The RwLock index should hide writes until they're refreshed and survive compactions, just like
+// evmap, while only keeping one copy of the map
+#[test]
+fn rwlock_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        index_backend: IndexBackend::RwLock,
+        read_consistency: ReadConsistency::Refreshed,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store
+        .set_and_get_old("key1".to_owned(), "value2".to_owned())?
+        .is_some());
+    store.sync_reads()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    for i in 0..1000 {
+        store.set(format!("key{:03}", i), "value".to_owned())?;
+    }
+    store.remove("key001".to_owned())?;
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.generation, 1);
+    assert_eq!(stats.keys, 1000);
+    // Each 6-byte key is only counted once
+    assert!(stats.index_memory_bytes < 2 * 1000 * (6 + 64));
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key001".to_owned())?, None);
+    assert_eq!(store.get("key999".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Opens a store with the policy and overwrites the record of key1 with bytes that don't decode,
+// behind the store's back
+fn store_with_corrupt_record(dir: &Path, policy: CorruptRecordPolicy) -> Result<KvStore> {
+    let options = KvStoreOptions {
+        on_corrupt_record: policy,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(dir, options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let offset = store.history("key1".to_owned())?[0].offset;
+    let mut log = fs::OpenOptions::new()
+        .write(true)
+        .open(dir.join("kvs_0.cbor"))?;
+    log.seek(SeekFrom::Start(offset))?;
+    log.write_all(&[0xff; 4])?;
+    Ok(store)
+}
+
+#[test]
+fn corrupt_record_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = store_with_corrupt_record(temp_dir.path(), CorruptRecordPolicy::Error)?;
+
+    let err = store.get("key1".to_owned()).unwrap_err();
+    assert!(err.downcast_ref::<CorruptData>().is_some());
+    assert!(store.iter()?.any(|pair| pair.is_err()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn corrupt_record_skip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = store_with_corrupt_record(temp_dir.path(), CorruptRecordPolicy::SkipAsAbsent)?;
+
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(
+        store.scan(None, 10)?,
+        vec![("key2".to_owned(), "value2".to_owned())]
+    );
+    // The key is still in the index, so later reads skip it again
+    assert_eq!(
+        store.keys(true)?,
+        vec!["key1".to_owned(), "key2".to_owned()]
+    );
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Writing the key again gets it back
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn corrupt_record_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = store_with_corrupt_record(temp_dir.path(), CorruptRecordPolicy::Repair)?;
+
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.keys(true)?, vec!["key2".to_owned()]);
+    let err = store.remove("key1".to_owned()).unwrap_err();
+    assert!(err.downcast_ref::<KeyNotFound>().is_some());
+
+    // The removal is in the log, so compacting drops the corrupt record for good
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,202 @@
+use kvs::manager::KvStoreManager;
+use kvs::{AlreadyManaged, CompactionEvent, KvStoreOptions, KvsEngine, Result};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Many tiny stores should open through one manager, and opening a directory again should give a
+// handle to the same store
+#[test]
+fn open_many_stores() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let manager = KvStoreManager::new(KvStoreOptions::default())?;
+
+    let mut dirs = Vec::new();
+    for i in 0..100 {
+        let dir = temp_dir.path().join(format!("tenant{}", i));
+        std::fs::create_dir(&dir).expect("unable to create store directory");
+        let store = manager.open(&dir)?;
+        store.set("tenant".to_owned(), i.to_string())?;
+        dirs.push(dir);
+    }
+    assert_eq!(manager.len(), 100);
+
+    for (i, dir) in dirs.iter().enumerate() {
+        let store = manager.open(&dir.join("."))?;
+        assert_eq!(store.get("tenant".to_owned())?, Some(i.to_string()));
+    }
+    assert_eq!(manager.len(), 100);
+
+    assert!(manager.close(&dirs[0])?);
+    assert!(!manager.close(&dirs[0])?);
+    assert_eq!(manager.len(), 99);
+    let store = manager.open(&dirs[0])?;
+    assert_eq!(store.get("tenant".to_owned())?, Some("0".to_owned()));
+    Ok(())
+}
+
+// Threads racing to open the same directory should all end up with the one store
+#[test]
+fn concurrent_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let manager = KvStoreManager::new(KvStoreOptions::default())?;
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let manager = manager.clone();
+            let barrier = Arc::clone(&barrier);
+            let dir = temp_dir.path().to_owned();
+            thread::spawn(move || -> Result<()> {
+                barrier.wait();
+                manager.open(&dir)?.set(format!("key{}", i), i.to_string())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(manager.len(), 1);
+    let store = manager.open(temp_dir.path())?;
+    for i in 0..8 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(i.to_string()));
+    }
+    Ok(())
+}
+
+// Only one manager should look after a store, so others can't take over or stop its compactions
+#[test]
+fn second_manager() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let first = KvStoreManager::new(KvStoreOptions::default())?;
+    let second = KvStoreManager::new(KvStoreOptions::default())?;
+    let store = first.open(temp_dir.path())?;
+
+    let err = second
+        .open(temp_dir.path())
+        .err()
+        .expect("second manager opened the store");
+    assert!(err.downcast_ref::<AlreadyManaged>().is_some());
+    assert!(!second.close(temp_dir.path())?);
+    drop(second);
+
+    // The first manager still compacts the store
+    let value = "x".repeat(100 * 1024);
+    for _ in 0..20 {
+        store.set("key".to_owned(), value.clone())?;
+    }
+    store.wait_for_compaction()?;
+    assert!(store.stats()?.generation > 0);
+
+    // Once it lets go, another manager can take over
+    assert!(first.close(temp_dir.path())?);
+    let second = KvStoreManager::new(KvStoreOptions::default())?;
+    assert_eq!(
+        second.open(temp_dir.path())?.get("key".to_owned())?,
+        Some(value)
+    );
+    Ok(())
+}
+
+// Writes to a managed store should leave compaction to the manager's thread
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let manager = KvStoreManager::new(KvStoreOptions::default())?;
+    let store = manager.open(temp_dir.path())?;
+
+    let value = "x".repeat(100 * 1024);
+    for _ in 0..20 {
+        store.set("key".to_owned(), value.clone())?;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while store.stats()?.generation == 0 {
+        assert!(Instant::now() < deadline, "store was never compacted");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.get("key".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Waiting for a compaction handed off to the manager should return once the stale data is gone
+#[test]
+fn wait_for_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let manager = KvStoreManager::new(KvStoreOptions::default())?;
+    let store = manager.open(temp_dir.path())?;
+    // Nothing to wait for yet
+    store.wait_for_compaction()?;
+
+    // Stop writing as soon as a compaction is asked for, so no stale data comes after it
+    let value = "x".repeat(100 * 1024);
+    loop {
+        store.set("key".to_owned(), value.clone())?;
+        let stats = store.stats()?;
+        if stats.stale_bytes > 1024 * 1024 || stats.generation > 0 {
+            break;
+        }
+    }
+
+    store.wait_for_compaction()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.stale_bytes, 0);
+    assert_eq!(stats.generation, 1);
+    assert_eq!(store.get("key".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Stores still in use once the manager is gone should compact on their own
+#[test]
+fn outlive_manager() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let manager = KvStoreManager::new(KvStoreOptions::default())?;
+    let store = manager.open(temp_dir.path())?;
+    drop(manager);
+
+    let value = "x".repeat(100 * 1024);
+    for _ in 0..20 {
+        store.set("key".to_owned(), value.clone())?;
+    }
+    store.wait_for_compaction()?;
+    assert_eq!(store.stats()?.generation, 1);
+    Ok(())
+}
+
+// A background compaction should show up as running until it's over
+#[test]
+fn is_compacting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Holds the compaction up once it starts until the test has looked at it
+    let started = Arc::new(Barrier::new(2));
+    let checked = Arc::new(Barrier::new(2));
+    let (listener_started, listener_checked) = (Arc::clone(&started), Arc::clone(&checked));
+    let options = KvStoreOptions {
+        on_compaction: Some(Arc::new(move |event| {
+            if let CompactionEvent::Started { .. } = event {
+                listener_started.wait();
+                listener_checked.wait();
+            }
+        })),
+        ..Default::default()
+    };
+    let manager = KvStoreManager::new(options)?;
+    let store = manager.open(temp_dir.path())?;
+    assert!(!store.is_compacting());
+
+    // The last write is the first to leave over 1 MiB of stale data, so the compaction it asks
+    // for can't hold up any of them
+    let value = "x".repeat(100 * 1024);
+    for _ in 0..12 {
+        store.set("key".to_owned(), value.clone())?;
+    }
+    started.wait();
+    assert!(store.is_compacting());
+    checked.wait();
+
+    store.wait_for_compaction()?;
+    assert!(!store.is_compacting());
+    assert_eq!(store.stats()?.generation, 1);
+    Ok(())
+}
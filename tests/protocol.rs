@@ -0,0 +1,140 @@
+use kvs::protocol::*;
+use kvs::Result;
+use std::io::Cursor;
+
+// The batch length must have the same byte layout on every platform
+#[test]
+fn batch_len_little_endian() -> Result<()> {
+    let mut buf = Vec::new();
+    write_batch_len(&mut buf, 300)?;
+    assert_eq!(buf, vec![0x2c, 0x01, 0x00, 0x00]);
+    assert_eq!(read_batch_len(Cursor::new(&buf))?, 300);
+
+    buf.clear();
+    write_batch_len(&mut buf, u32::MAX)?;
+    assert_eq!(read_batch_len(Cursor::new(&buf))?, u32::MAX);
+
+    // A truncated length is an error
+    assert!(read_batch_len(Cursor::new(&buf[..3])).is_err());
+
+    Ok(())
+}
+
+// Messages should survive a round trip through every codec
+#[test]
+fn message_round_trip() -> Result<()> {
+    for &codec in &[Codec::Cbor, Codec::Bincode] {
+        let mut buf = Vec::new();
+        write_batch_len(&mut buf, 3)?;
+        Message::Array(vec![SET.to_owned(), "key".to_owned(), "value".to_owned()])
+            .write(&mut buf, codec)?;
+        Message::Error(ErrorCode::KeyNotFound, "Key not found".to_owned())
+            .write(&mut buf, codec)?;
+        Message::Deadline(7, vec![GET.to_owned(), "key".to_owned()]).write(&mut buf, codec)?;
+
+        let mut reader = Cursor::new(buf);
+        assert_eq!(read_batch_len(&mut reader)?, 3);
+        match Message::read(&mut reader, codec)? {
+            Message::Array(arr) => assert_eq!(arr, vec![SET, "key", "value"]),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        match Message::read(&mut reader, codec)? {
+            Message::Error(code, msg) => {
+                assert_eq!(code, ErrorCode::KeyNotFound);
+                assert_eq!(msg, "Key not found");
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        match Message::read(&mut reader, codec)? {
+            Message::Deadline(deadline, arr) => {
+                assert_eq!(deadline, 7);
+                assert_eq!(arr, vec![GET, "key"]);
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        // Nothing is left to read
+        assert!(Message::read(&mut reader, codec).is_err());
+    }
+
+    Ok(())
+}
+
+// Bincode messages are framed by their length prefix, so a short stream is an error
+#[test]
+fn bincode_truncated() -> Result<()> {
+    let mut buf = Vec::new();
+    Message::Array(vec![GET.to_owned(), "key".to_owned()]).write(&mut buf, Codec::Bincode)?;
+    assert!(Message::read(Cursor::new(&buf[..buf.len() - 1]), Codec::Bincode).is_err());
+    assert!(Message::read(Cursor::new(&buf[..2]), Codec::Bincode).is_err());
+    Ok(())
+}
+
+// Handshakes from before codecs existed should still decode, and pick CBOR
+#[test]
+fn handshake_default_codec() -> Result<()> {
+    #[derive(serde::Serialize)]
+    struct OldHandshake {
+        t: Option<String>,
+    }
+    let buf = serde_cbor::to_vec(&OldHandshake { t: None })?;
+    let handshake = Handshake::read(Cursor::new(buf))?;
+    assert_eq!(handshake.codec, Codec::Cbor);
+
+    let mut buf = Vec::new();
+    Handshake {
+        token: None,
+        codec: Codec::Bincode,
+    }
+    .write(&mut buf)?;
+    assert_eq!(Handshake::read(Cursor::new(buf))?.codec, Codec::Bincode);
+    Ok(())
+}
+
+// Compressed values should come out of every codec the same as they went in, and compressible
+// values should get much smaller on the wire
+#[test]
+fn compressed_round_trip() -> Result<()> {
+    let value = "abcdefgh".repeat(128 * 1024);
+    for &codec in &[Codec::Cbor, Codec::Bincode] {
+        let arr = vec![SET.to_owned(), "key".to_owned(), value.clone()];
+        let mut buf = Vec::new();
+        Message::compress_value(Some(7), arr)?.write(&mut buf, codec)?;
+        assert!(buf.len() < value.len() / 10);
+
+        match Message::read(Cursor::new(&buf), codec)?.decompress()? {
+            Message::Deadline(deadline, arr) => {
+                assert_eq!(deadline, 7);
+                assert_eq!(arr, vec![SET, "key", &value]);
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+
+    // Bytes that aren't deflate data are rejected
+    let msg = Message::Compressed(None, vec![SET.to_owned(), "key".to_owned()], vec![0xff; 8]);
+    assert!(msg.decompress().is_err());
+    Ok(())
+}
+
+// A value that inflates past the cap should be rejected, while one right at it still goes through
+#[test]
+fn decompression_cap() -> Result<()> {
+    let compress = |len: u64| -> Result<Message> {
+        let value = "0".repeat(len as usize);
+        Message::compress_value(None, vec![SET.to_owned(), "key".to_owned(), value])
+    };
+
+    let bomb = compress(MAX_DECOMPRESSED + 1)?;
+    match bomb {
+        Message::Compressed(_, _, ref value) => assert!(value.len() < 1024 * 1024),
+        ref msg => panic!("unexpected message {:?}", msg),
+    }
+    let err = bomb.decompress().unwrap_err();
+    assert!(err.to_string().contains("inflates to over"));
+
+    match compress(MAX_DECOMPRESSED)?.decompress()? {
+        Message::Array(arr) => assert_eq!(arr[2].len() as u64, MAX_DECOMPRESSED),
+        msg => panic!("unexpected message {:?}", msg),
+    }
+    Ok(())
+}
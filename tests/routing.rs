@@ -0,0 +1,65 @@
+use kvs::routing::{DynEngine, RoutingEngine};
+use kvs::{KvStore, KvsEngine, Result, SledKvsEngine, WriteOp};
+use tempfile::TempDir;
+
+// Keys should land in the engine of their longest matching prefix, or the default engine
+#[test]
+fn route_by_prefix() -> Result<()> {
+    let dirs: Vec<_> = (0..3)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let persist = KvStore::open(dirs[0].path())?;
+    let cache = SledKvsEngine::open(dirs[1].path())?;
+    let hot_cache = KvStore::open(dirs[2].path())?;
+    let engine = RoutingEngine::new(persist.clone())
+        .with_route("cache:", cache.clone())
+        .with_route("cache:hot:", DynEngine::new(hot_cache.clone()));
+
+    engine.set("user:1".to_owned(), "alice".to_owned())?;
+    engine.set("cache:page".to_owned(), "html".to_owned())?;
+    engine.set("cache:hot:page".to_owned(), "hot html".to_owned())?;
+    let results = engine.write_batch(vec![
+        WriteOp::Set("cache:a".to_owned(), "1".to_owned()),
+        WriteOp::Set("b".to_owned(), "2".to_owned()),
+        WriteOp::Remove("cache:missing".to_owned()),
+        WriteOp::Remove("b".to_owned()),
+    ])?;
+    assert!(results[0].is_ok() && results[1].is_ok() && results[3].is_ok());
+    assert!(results[2].is_err());
+
+    assert_eq!(persist.get("user:1".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(cache.get("cache:page".to_owned())?, Some("html".to_owned()));
+    assert_eq!(cache.get("cache:a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(
+        hot_cache.get("cache:hot:page".to_owned())?,
+        Some("hot html".to_owned())
+    );
+    assert_eq!(cache.get("cache:hot:page".to_owned())?, None);
+    assert_eq!(persist.get("cache:page".to_owned())?, None);
+    assert_eq!(
+        engine.get("cache:page".to_owned())?,
+        Some("html".to_owned())
+    );
+    assert_eq!(engine.engine_stats()?.live_keys, 4);
+
+    // Keys written to an engine directly that don't route to it stay hidden
+    persist.set("cache:stray".to_owned(), "x".to_owned())?;
+    let keys: Vec<_> = engine
+        .scan(None, 10)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(
+        keys,
+        vec!["cache:a", "cache:hot:page", "cache:page", "user:1"]
+    );
+    assert_eq!(engine.iter()?.count(), 4);
+
+    // Clear empties every engine
+    engine.clear()?;
+    assert_eq!(engine.iter()?.count(), 0);
+    assert_eq!(persist.get("user:1".to_owned())?, None);
+    assert_eq!(cache.get("cache:page".to_owned())?, None);
+    assert_eq!(hot_cache.get("cache:hot:page".to_owned())?, None);
+    Ok(())
+}
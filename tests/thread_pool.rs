@@ -1,9 +1,12 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use kvs::thread_pool::*;
 use kvs::Result;
 
+use crossbeam::channel::bounded;
 use crossbeam::sync::WaitGroup;
 use panic_control;
 
@@ -69,3 +72,148 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
+// Spawning should hand the job off and return without waiting for it. The job waits to be
+// released by the caller, which a pool that ran it before returning would never let happen.
+fn spawn_returns_early<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(1)?;
+    let (release_tx, release_rx) = bounded(0);
+    let (done_tx, done_rx) = bounded(1);
+    pool.spawn(move || {
+        let released = release_rx.recv_timeout(Duration::from_secs(5)).is_ok();
+        done_tx.send(released).unwrap();
+    });
+
+    release_tx
+        .send(())
+        .expect("job finished before spawn returned");
+    assert!(done_rx.recv().unwrap());
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_returns_early() -> Result<()> {
+    spawn_returns_early::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_returns_early() -> Result<()> {
+    spawn_returns_early::<RayonThreadPool>()
+}
+
+// Jobs should run on threads named after the pool's prefix
+fn thread_names<P: ThreadPool>() -> Result<()> {
+    for (pool, prefix) in [
+        (P::new(2)?, DEFAULT_THREAD_PREFIX),
+        (P::with_name_prefix(2, "custom")?, "custom"),
+    ] {
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        for _ in 0..4 {
+            let sender = sender.clone();
+            pool.spawn(move || {
+                let name = std::thread::current().name().map(str::to_owned);
+                sender.send(name).unwrap();
+            });
+        }
+        for _ in 0..4 {
+            let name = receiver.recv().unwrap().expect("thread has no name");
+            assert!(name.starts_with(&format!("{}-", prefix)), "{}", name);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_thread_names() -> Result<()> {
+    thread_names::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_thread_names() -> Result<()> {
+    thread_names::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_thread_names() -> Result<()> {
+    thread_names::<RayonThreadPool>()
+}
+
+// Polls until the condition holds, failing the test if it takes too long
+fn wait_until(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting for condition");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Idle workers should exit down to the minimum, and come back once there's work again
+#[test]
+fn shared_queue_thread_pool_idle_timeout() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_idle_timeout(
+        4,
+        1,
+        Duration::from_millis(50),
+        DEFAULT_THREAD_PREFIX,
+    )?;
+    assert_eq!(pool.workers(), 4);
+    wait_until(|| pool.workers() == 1);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(pool.workers(), 1);
+
+    // Every job waits until all of them have started, which takes 4 workers at once
+    let (started_tx, started_rx) = bounded(4);
+    let (release_tx, release_rx) = bounded::<()>(0);
+    for _ in 0..4 {
+        let started_tx = started_tx.clone();
+        let release_rx = release_rx.clone();
+        pool.spawn(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+    }
+    for _ in 0..4 {
+        started_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("pool didn't grow");
+    }
+    assert_eq!(pool.workers(), 4);
+    // Releases every job
+    drop(release_tx);
+
+    wait_until(|| pool.workers() == 1);
+    Ok(())
+}
+
+// Jobs spawned just as the last worker times out should still run, even with no minimum
+#[test]
+fn shared_queue_thread_pool_idle_to_zero() -> Result<()> {
+    let timeout = Duration::from_millis(1);
+    let pool = SharedQueueThreadPool::with_idle_timeout(1, 0, timeout, DEFAULT_THREAD_PREFIX)?;
+    let (tx, rx) = bounded(1);
+    for i in 0..2000 {
+        let tx = tx.clone();
+        pool.spawn(move || tx.send(()).unwrap());
+        rx.recv_timeout(Duration::from_secs(5))
+            .expect("job never ran");
+        // Spawn the next job at different points around the worker's timeout
+        thread::sleep(timeout * (i % 20) / 10);
+    }
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_bad_idle_minimum() {
+    assert!(SharedQueueThreadPool::with_idle_timeout(
+        2,
+        3,
+        Duration::from_secs(1),
+        DEFAULT_THREAD_PREFIX
+    )
+    .is_err());
+}